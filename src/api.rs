@@ -0,0 +1,624 @@
+// [[file:../zotero.note::*imports][imports:1]]
+use gut::prelude::*;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::collections::HashMap;
+
+use crate::db::{Item, Map};
+use crate::server::ZoteroServer;
+use reqwest::blocking::Response;
+// imports:1 ends here
+
+// [[file:../zotero.note::0b7c5e92][0b7c5e92]]
+/// A zotero item in Web API json format, as returned by zotero web API and
+/// the local API of zotero 7.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ApiItem {
+    pub key: String,
+    pub version: i64,
+    #[serde(default)]
+    pub meta: ApiMeta,
+    /// Links to related resources, such as `enclosure` for attachment file
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub links: HashMap<String, Value>,
+    pub data: ApiItemData,
+}
+
+/// Derived item data computed by zotero
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiMeta {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub creator_summary: Option<String>,
+    /// Date in ISO format, such as 2020-08 or 2020-08-01
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parsed_date: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub num_children: Option<i64>,
+}
+
+/// Editable item data. Fields not listed here are kept in `fields`.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiItemData {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub key: String,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub version: i64,
+    pub item_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent_item: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub creators: Vec<ApiCreator>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub date: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extra: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<ApiTagRef>,
+    #[serde(default)]
+    pub collections: Vec<String>,
+    #[serde(default)]
+    pub relations: HashMap<String, Value>,
+    #[serde(flatten)]
+    pub fields: HashMap<String, Value>,
+}
+
+fn is_zero(v: &i64) -> bool {
+    *v == 0
+}
+
+/// Item creator in Web API format. Creators with single name field use
+/// `name` only.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiCreator {
+    pub creator_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub first_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+/// Tag attached to an item
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ApiTagRef {
+    pub tag: String,
+    /// 0 for manual tags, 1 for automatic tags
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<i64>,
+}
+
+/// A tag in the library, as returned by `/tags` endpoint.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ApiTag {
+    pub tag: String,
+    #[serde(default)]
+    pub meta: ApiTagMeta,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiTagMeta {
+    #[serde(rename = "type", default)]
+    pub kind: i64,
+    #[serde(default)]
+    pub num_items: i64,
+}
+
+/// A zotero collection in Web API format
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ApiCollection {
+    pub key: String,
+    pub version: i64,
+    pub data: ApiCollectionData,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiCollectionData {
+    pub name: String,
+    // collection key, or false for top level collections
+    #[serde(default)]
+    pub parent_collection: Value,
+}
+
+impl ApiCollection {
+    /// Key of parent collection, if any.
+    pub fn parent(&self) -> Option<&str> {
+        self.data.parent_collection.as_str()
+    }
+}
+
+/// A saved search in Web API format
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ApiSearch {
+    pub key: String,
+    pub version: i64,
+    pub data: ApiSearchData,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ApiSearchData {
+    pub name: String,
+    #[serde(default)]
+    pub conditions: Vec<ApiSearchCondition>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ApiSearchCondition {
+    pub condition: String,
+    pub operator: String,
+    pub value: String,
+}
+
+impl ApiItem {
+    /// Convert to the item model shared with sqlite backend.
+    pub fn to_item(&self) -> Item {
+        let mut d = Map::new();
+        if let Some(title) = &self.data.title {
+            d.insert("title".into(), title.into());
+        }
+        if let Some(extra) = &self.data.extra {
+            d.insert("extra".into(), extra.into());
+        }
+        // zotero 7 field
+        if let Some(citekey) = self.data.fields.get("citationKey").and_then(|x| x.as_str()) {
+            d.insert("citationKey".into(), citekey.into());
+        }
+        // free-form dates like "August 1, 2020" are parsed by zotero
+        if let Some(date) = self.meta.parsed_date.as_ref().or(self.data.date.as_ref()) {
+            d.insert("date".into(), date.into());
+        }
+        Item::from_fields(&self.key, &d)
+    }
+}
+// 0b7c5e92 ends here
+
+// [[file:../zotero.note::a3e61f0d][a3e61f0d]]
+/// Query parameters for listing items
+#[derive(Debug, Clone, Default)]
+pub struct ApiQuery {
+    q: Option<String>,
+    everything: bool,
+    tags: Vec<String>,
+    item_type: Option<String>,
+    since: Option<i64>,
+    sort: Option<String>,
+    start: usize,
+    limit: Option<usize>,
+    top: bool,
+    item_keys: Vec<String>,
+}
+
+impl ApiQuery {
+    /// Quick search in titles and creators
+    pub fn q(mut self, q: &str) -> Self {
+        self.q = Some(q.into());
+        self
+    }
+
+    /// Make quick search `q` match all fields and full-text content
+    pub fn everything(mut self) -> Self {
+        self.everything = true;
+        self
+    }
+
+    /// Items having tag `tag`. Can be called multiple times to require all
+    /// tags. Zotero tag search syntax such as "foo || bar" or "-foo" is
+    /// supported.
+    pub fn tag(mut self, tag: &str) -> Self {
+        self.tags.push(tag.into());
+        self
+    }
+
+    /// Items in `item_type`, such as "book" or "-attachment".
+    pub fn item_type(mut self, item_type: &str) -> Self {
+        self.item_type = Some(item_type.into());
+        self
+    }
+
+    /// Only items modified after library `version`
+    pub fn since(mut self, version: i64) -> Self {
+        self.since = Some(version);
+        self
+    }
+
+    /// Sort items by `field`, such as "dateModified" or "title"
+    pub fn sort(mut self, field: &str) -> Self {
+        self.sort = Some(field.into());
+        self
+    }
+
+    /// Index of the first result
+    pub fn start(mut self, start: usize) -> Self {
+        self.start = start;
+        self
+    }
+
+    /// Number of results per request
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Top level items only, without child notes and attachments
+    pub fn top(mut self) -> Self {
+        self.top = true;
+        self
+    }
+
+    /// Only items in `keys`. Zotero accepts up to 50 keys at once.
+    pub fn item_keys(mut self, keys: &[&str]) -> Self {
+        self.item_keys = keys.iter().map(|x| x.to_string()).collect();
+        self
+    }
+
+    pub(crate) fn params(&self) -> Vec<(String, String)> {
+        let mut params = vec![];
+        if let Some(q) = &self.q {
+            params.push(("q".into(), q.clone()));
+            if self.everything {
+                params.push(("qmode".into(), "everything".into()));
+            }
+        }
+        for tag in &self.tags {
+            params.push(("tag".into(), tag.clone()));
+        }
+        if !self.item_keys.is_empty() {
+            params.push(("itemKey".into(), self.item_keys.join(",")));
+        }
+        if let Some(item_type) = &self.item_type {
+            params.push(("itemType".into(), item_type.clone()));
+        }
+        if let Some(since) = self.since {
+            params.push(("since".into(), since.to_string()));
+        }
+        if let Some(sort) = &self.sort {
+            params.push(("sort".into(), sort.clone()));
+        }
+        if self.start > 0 {
+            params.push(("start".into(), self.start.to_string()));
+        }
+        if let Some(limit) = self.limit {
+            params.push(("limit".into(), limit.to_string()));
+        }
+        params
+    }
+}
+
+/// Results collected from all pages of a listing request
+#[derive(Debug, Clone)]
+pub struct ApiResults<T> {
+    pub items: Vec<T>,
+    /// From `Total-Results` header
+    pub total_results: Option<usize>,
+    /// From `Last-Modified-Version` header, for incremental fetches with `since`
+    pub last_modified_version: Option<i64>,
+}
+
+// Parse url of next page from `Link` header, e.g.
+// <https://api.zotero.org/users/1/items?start=25>; rel="next", <...>; rel="last"
+pub(crate) fn next_link(resp: &Response) -> Option<String> {
+    let link = resp.headers().get("link")?.to_str().ok()?;
+    link.split(',').find_map(|part| {
+        let mut parts = part.split(';');
+        let url = parts.next()?.trim();
+        let is_next = parts.any(|x| x.trim() == r#"rel="next""#);
+        if is_next && url.starts_with('<') && url.ends_with('>') {
+            Some(url[1..url.len() - 1].to_string())
+        } else {
+            None
+        }
+    })
+}
+
+pub(crate) fn header_value<T: std::str::FromStr>(resp: &Response, name: &str) -> Option<T> {
+    resp.headers().get(name)?.to_str().ok()?.parse().ok()
+}
+
+// Collect results from all pages starting from `first` url, following
+// `Link` headers, or `Total-Results` if no links provided.
+pub(crate) fn collect_pages<T, F>(
+    first: &str,
+    mut params: Vec<(String, String)>,
+    get: F,
+    parse: fn(&str) -> Result<Vec<T>>,
+) -> Result<ApiResults<T>>
+where
+    F: Fn(&str, &[(String, String)]) -> Result<Response>,
+{
+    let mut results = ApiResults {
+        items: vec![],
+        total_results: None,
+        last_modified_version: None,
+    };
+
+    let mut url = first.to_string();
+    loop {
+        let resp = get(&url, &params)?;
+        let next = next_link(&resp);
+        let total: Option<usize> = header_value(&resp, "Total-Results");
+        if results.last_modified_version.is_none() {
+            results.last_modified_version = header_value(&resp, "Last-Modified-Version");
+            results.total_results = total;
+        }
+        let page = parse(&response_text(resp)?)?;
+        let n = page.len();
+        results.items.extend(page);
+
+        if let Some(next) = next {
+            url = next;
+            params.clear();
+        } else if n > 0 && total.map(|t| results.items.len() < t).unwrap_or(false) {
+            let start = params
+                .iter()
+                .find(|(k, _)| k == "start")
+                .and_then(|(_, v)| v.parse().ok());
+            let start = start.unwrap_or(0) + n;
+            params.retain(|(k, _)| k != "start");
+            params.push(("start".into(), start.to_string()));
+            url = first.to_string();
+        } else {
+            break;
+        }
+    }
+    Ok(results)
+}
+
+// Return response text, or error for unsuccessful status
+pub(crate) fn response_text(resp: Response) -> Result<String> {
+    let status = resp.status();
+    let text = resp.text()?;
+    if !status.is_success() {
+        bail!("zotero api error {}: {}", status, text);
+    }
+    Ok(text)
+}
+// a3e61f0d ends here
+
+// [[file:../zotero.note::6f2d94b1][6f2d94b1]]
+/// Client for the read-only local API of zotero 7, which is compatible with
+/// zotero Web API v3. It needs to be enabled in zotero settings.
+pub struct LocalApi<'a> {
+    server: &'a ZoteroServer,
+    library: String,
+}
+
+impl ZoteroServer {
+    /// Local API client for the personal library
+    pub fn local_api(&self) -> LocalApi<'_> {
+        LocalApi {
+            server: self,
+            library: "users/0".into(),
+        }
+    }
+}
+
+impl<'a> LocalApi<'a> {
+    /// Access group library in `group_id` instead.
+    pub fn group(mut self, group_id: i64) -> Self {
+        self.library = format!("groups/{}", group_id);
+        self
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}/api/{}{}", self.server.base_url(), self.library, path)
+    }
+
+    fn get(&self, url: &str, params: &[(String, String)]) -> Result<Response> {
+        self.server
+            .execute(|client| client.get(url).header("Zotero-API-Version", "3").query(params))
+    }
+
+    fn get_all<T>(
+        &self,
+        path: &str,
+        params: Vec<(String, String)>,
+        parse: fn(&str) -> Result<Vec<T>>,
+    ) -> Result<ApiResults<T>> {
+        collect_pages(&self.url(path), params, |url, params| self.get(url, params), parse)
+    }
+
+    /// List items in `query`, collecting all pages.
+    pub fn items(&self, query: &ApiQuery) -> Result<ApiResults<ApiItem>> {
+        self.get_all(&items_path(query, "/items"), query.params(), parse_json)
+    }
+
+    /// List items in collection `key` matching `query`.
+    pub fn collection_items(&self, key: &str, query: &ApiQuery) -> Result<ApiResults<ApiItem>> {
+        let path = items_path(query, &format!("/collections/{}/items", key));
+        self.get_all(&path, query.params(), parse_json)
+    }
+
+    /// Return item in `key`
+    pub fn item(&self, key: &str) -> Result<ApiItem> {
+        let resp = self.get(&self.url(&format!("/items/{}", key)), &[])?;
+        let text = response_text(resp)?;
+        serde_json::from_str(&text).with_context(|| format!("invalid item json: {}", text))
+    }
+
+    /// Return child notes and attachments of item in `key`
+    pub fn children(&self, key: &str) -> Result<Vec<ApiItem>> {
+        let path = format!("/items/{}/children", key);
+        Ok(self.get_all(&path, vec![], parse_json)?.items)
+    }
+
+    /// Return items in `query`, in the same model as sqlite backend
+    pub fn get_items(&self, query: &ApiQuery) -> Result<Vec<Item>> {
+        let items = self.items(query)?.items.iter().map(|x| x.to_item()).collect();
+        Ok(items)
+    }
+
+    /// List all collections
+    pub fn collections(&self) -> Result<Vec<ApiCollection>> {
+        Ok(self.get_all("/collections", vec![], parse_json)?.items)
+    }
+
+    /// List all saved searches
+    pub fn searches(&self) -> Result<Vec<ApiSearch>> {
+        Ok(self.get_all("/searches", vec![], parse_json)?.items)
+    }
+
+    /// List all tags in library
+    pub fn tags(&self) -> Result<Vec<ApiTag>> {
+        Ok(self.get_all("/tags", vec![], parse_json)?.items)
+    }
+
+    /// Export items in `query` as BibTeX
+    pub fn bibtex(&self, query: &ApiQuery) -> Result<String> {
+        let mut params = query.params();
+        params.push(("format".into(), "bibtex".into()));
+        let entries = self.get_all(&items_path(query, "/items"), params, parse_bibtex)?;
+        Ok(entries.items.join("\n\n"))
+    }
+
+    /// Export items in `query` as CSL-JSON items
+    pub fn csl_json(&self, query: &ApiQuery) -> Result<Vec<Value>> {
+        let mut params = query.params();
+        params.push(("format".into(), "csljson".into()));
+        let results = self.get_all(&items_path(query, "/items"), params, parse_csl_json)?;
+        Ok(results.items)
+    }
+}
+
+pub(crate) fn items_path(query: &ApiQuery, path: &str) -> String {
+    if query.top {
+        format!("{}/top", path)
+    } else {
+        path.into()
+    }
+}
+
+pub(crate) fn parse_json<T: DeserializeOwned>(text: &str) -> Result<Vec<T>> {
+    serde_json::from_str(text).with_context(|| format!("invalid api json: {}", text))
+}
+
+// Split BibTeX `text` into entries starting with `@`, so that pages are
+// counted by items
+fn parse_bibtex(text: &str) -> Result<Vec<String>> {
+    let mut entries: Vec<String> = vec![];
+    for line in text.lines() {
+        if line.starts_with('@') || entries.is_empty() {
+            entries.push(String::new());
+        }
+        if let Some(entry) = entries.last_mut() {
+            entry.push_str(line);
+            entry.push('\n');
+        }
+    }
+    let entries = entries
+        .into_iter()
+        .map(|x| x.trim().to_string())
+        .filter(|x| !x.is_empty())
+        .collect();
+    Ok(entries)
+}
+
+pub(crate) fn parse_csl_json(text: &str) -> Result<Vec<Value>> {
+    #[derive(Deserialize)]
+    struct CslJson {
+        items: Vec<Value>,
+    }
+    let json: CslJson = serde_json::from_str(text).with_context(|| format!("invalid csl json: {}", text))?;
+    Ok(json.items)
+}
+// 6f2d94b1 ends here
+
+// [[file:../zotero.note::*test][test:1]]
+#[test]
+fn test_local_api() -> Result<()> {
+    use crate::mock::*;
+
+    let item = |key: &str, title: &str| {
+        format!(
+            r#"{{"key": "{0}", "version": 7, "meta": {{"parsedDate": "2021-03-02"}},
+                 "data": {{"key": "{0}", "version": 7, "itemType": "journalArticle", "title": "{1}",
+                           "date": "March 2, 2021", "extra": "", "DOI": "10.1/x",
+                           "creators": [{{"creatorType": "author", "firstName": "A", "lastName": "B"}}],
+                           "tags": [{{"tag": "dft"}}], "collections": [], "relations": {{}}}}}}"#,
+            key, title
+        )
+    };
+    let page1 = format!("[{}, {}]", item("AAAAAAAA", "one"), item("BBBBBBBB", "two"));
+    let page2 = format!("[{}]", item("CCCCCCCC", "three"));
+    let mock = MockServer::start(move |req| {
+        let host = req.header("host").unwrap_or_default().to_string();
+        match (
+            req.path(),
+            req.query("start").as_deref(),
+            req.query("format").as_deref(),
+        ) {
+            ("/api/users/0/items", None, None) => MockResponse::ok(&page1)
+                .header("Total-Results", "3")
+                .header("Last-Modified-Version", "42")
+                .header(
+                    "Link",
+                    &format!(r#"<http://{}/api/users/0/items?start=2&limit=2>; rel="next""#, host),
+                ),
+            ("/api/users/0/items", Some("2"), None) => MockResponse::ok(&page2).header("Total-Results", "3"),
+            ("/api/users/0/items/top", _, Some("bibtex")) => MockResponse::ok("@article{b2021,}"),
+            // pages without links
+            ("/api/users/0/items", None, Some("bibtex")) => {
+                MockResponse::ok("@article{a2021,\n  title = {one}\n}\n\n@article{b2021,\n}\n")
+                    .header("Total-Results", "3")
+            }
+            ("/api/users/0/items", Some("2"), Some("bibtex")) => {
+                MockResponse::ok("@book{c2021,\n}").header("Total-Results", "3")
+            }
+            ("/api/users/0/collections", _, _) => MockResponse::ok(
+                r#"[{"key": "COLL0001", "version": 3, "data": {"name": "A", "parentCollection": false}},
+                    {"key": "COLL0002", "version": 3, "data": {"name": "B", "parentCollection": "COLL0001"}}]"#,
+            ),
+            ("/api/users/0/tags", _, _) => {
+                MockResponse::ok(r#"[{"tag": "dft", "meta": {"type": 0, "numItems": 3}}]"#).header("Total-Results", "1")
+            }
+            _ => MockResponse::status(404, "Not found"),
+        }
+    });
+
+    let server = ZoteroServer::new(mock.url());
+    let api = server.local_api();
+    let query = ApiQuery::default()
+        .q("zeolite")
+        .tag("dft")
+        .item_type("-attachment")
+        .since(5);
+    let results = api.items(&query)?;
+    assert_eq!(results.items.len(), 3);
+    assert_eq!(results.total_results, Some(3));
+    assert_eq!(results.last_modified_version, Some(42));
+    let first = &mock.requests()[0];
+    assert_eq!(first.query("q").as_deref(), Some("zeolite"));
+    assert_eq!(first.query("tag").as_deref(), Some("dft"));
+    assert_eq!(first.query("itemType").as_deref(), Some("-attachment"));
+    assert_eq!(first.query("since").as_deref(), Some("5"));
+
+    let x = &results.items[0];
+    assert_eq!(x.data.fields["DOI"], "10.1/x");
+    assert_eq!(x.data.creators[0].last_name.as_deref(), Some("B"));
+    let items = api.get_items(&query)?;
+    assert_eq!(items[2].key(), "CCCCCCCC");
+    assert_eq!(items[2].title(), "three");
+    assert_eq!(items[2].date(), "2021");
+
+    let bib = api.bibtex(&ApiQuery::default().top())?;
+    assert_eq!(bib, "@article{b2021,}");
+    let bib = api.bibtex(&ApiQuery::default())?;
+    assert_eq!(
+        bib,
+        "@article{a2021,\n  title = {one}\n}\n\n@article{b2021,\n}\n\n@book{c2021,\n}"
+    );
+    let colls = api.collections()?;
+    assert_eq!(colls[0].parent(), None);
+    assert_eq!(colls[1].parent(), Some("COLL0001"));
+    assert_eq!(api.tags()?[0].meta.num_items, 3);
+    assert!(api.item("NOTFOUND").is_err());
+
+    Ok(())
+}
+// test:1 ends here
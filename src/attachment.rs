@@ -0,0 +1,272 @@
+// [[file:../zotero.note::*imports][imports:1]]
+use gut::prelude::*;
+use std::path::{Path, PathBuf};
+
+use sqlx::prelude::*;
+
+use crate::backend::SqliteBackend;
+use crate::db::{ZoteroDb, DB_FILE};
+use crate::profile::{discover_zotero_installs, ZoteroInstall, ZoteroPrefs};
+use crate::trash::TrashFilter;
+// imports:1 ends here
+
+// [[file:../zotero.note::0e6b4d27][0e6b4d27]]
+/// Resolve paths of attachment files as stored in zotero database:
+///
+/// - `storage:file.pdf` for files stored in `storage/KEY/` of data directory
+/// - `attachments:dir/file.pdf` for linked files relative to the base
+///   directory set in zotero preferences (`baseAttachmentPath`)
+/// - absolute paths for other linked files
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttachmentPaths {
+    storage: PathBuf,
+    base: Option<PathBuf>,
+}
+
+impl AttachmentPaths {
+    /// Resolve stored files in zotero data directory `data_dir`
+    pub fn new<P: AsRef<Path>>(data_dir: P) -> Self {
+        Self {
+            storage: data_dir.as_ref().join("storage"),
+            base: None,
+        }
+    }
+
+    /// Set base directory for linked files in `attachments:` paths
+    pub fn base_dir<P: AsRef<Path>>(mut self, base: P) -> Self {
+        self.base = Some(base.as_ref().to_owned());
+        self
+    }
+
+    /// Use base directory for linked files set in zotero `prefs`
+    pub fn from_prefs<P: AsRef<Path>>(data_dir: P, prefs: &ZoteroPrefs) -> Self {
+        Self {
+            base: prefs.base_attachment_path.clone(),
+            ..Self::new(data_dir)
+        }
+    }
+
+    /// Resolve stored files in data directory of zotero `install`, with
+    /// base directory set in preferences of its profile if any.
+    pub fn for_install(install: &ZoteroInstall) -> Self {
+        match install.prefs() {
+            Some(prefs) => Self::from_prefs(&install.data_dir, &prefs),
+            None => Self::new(&install.data_dir),
+        }
+    }
+
+    /// Resolve stored files in data directory of zotero database in
+    /// `db_file`, with base directory set in preferences of the zotero
+    /// profile using that data directory if any.
+    pub fn for_db_file<P: AsRef<Path>>(db_file: P) -> Self {
+        let data_dir = db_file.as_ref().parent().unwrap_or_else(|| Path::new("."));
+        let canonical = |dir: &Path| dir.canonicalize().unwrap_or_else(|_| dir.to_owned());
+        let install = discover_zotero_installs()
+            .into_iter()
+            .find(|x| canonical(&x.data_dir) == canonical(data_dir));
+        match install {
+            Some(install) => Self::for_install(&install),
+            None => Self::new(data_dir),
+        }
+    }
+
+    /// The storage directory for stored files
+    pub fn storage_dir(&self) -> &Path {
+        &self.storage
+    }
+
+    /// Return full path of attachment in `key` with stored `path`. Return
+    /// None if it can not be resolved, e.g. a relative linked file when the
+    /// base directory is unknown.
+    pub fn resolve(&self, key: &str, path: &str) -> Option<PathBuf> {
+        if let Some(file) = path.strip_prefix("storage:") {
+            Some(self.storage.join(key).join(file))
+        } else if let Some(rel) = path.strip_prefix("attachments:") {
+            self.base.as_ref().map(|base| base.join(rel))
+        } else if Path::new(path).is_absolute() {
+            Some(path.into())
+        } else {
+            None
+        }
+    }
+}
+
+impl Default for AttachmentPaths {
+    /// Use data directory and base directory of the preferred zotero install
+    /// if found.
+    fn default() -> Self {
+        match discover_zotero_installs().into_iter().next() {
+            Some(install) => Self::for_install(&install),
+            None => Self::new(Path::new(DB_FILE).parent().unwrap_or_else(|| Path::new("."))),
+        }
+    }
+}
+// 0e6b4d27 ends here
+
+// [[file:../zotero.note::58c1f3a9][58c1f3a9]]
+/// An attachment item with a file, stored or linked
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AttachmentFile {
+    /// Key of attachment item
+    pub key: String,
+    /// Key of parent item, None for standalone attachments
+    pub parent: Option<String>,
+    /// 0: imported file, 1: imported url, 2: linked file
+    pub link_mode: i64,
+    pub content_type: Option<String>,
+    /// Path as stored in zotero database
+    pub path: String,
+    /// Resolved full path, if possible
+    pub file: Option<PathBuf>,
+}
+
+impl AttachmentFile {
+    /// Return true if the file exists
+    pub fn exists(&self) -> bool {
+        self.file.as_ref().map(|x| x.is_file()).unwrap_or(false)
+    }
+}
+
+impl ZoteroDb {
+    /// Return all attachments with files. Attachments are trashed with their
+    /// parent item.
+    pub(crate) async fn get_attachment_files(
+        &self,
+        paths: &AttachmentPaths,
+        trash: TrashFilter,
+    ) -> Result<Vec<AttachmentFile>> {
+        let sql = format!(
+            r#"
+SELECT a.key as key, p.key as parent, itemAttachments.linkMode as link_mode,
+       itemAttachments.contentType as content_type, itemAttachments.path as path
+FROM itemAttachments
+    JOIN items a ON a.itemID = itemAttachments.itemID
+    LEFT JOIN items p ON p.itemID = itemAttachments.parentItemID
+WHERE itemAttachments.path IS NOT NULL
+  AND {}
+ORDER BY a.key
+"#,
+            trash.sql(&["itemAttachments.itemID", "itemAttachments.parentItemID"])
+        );
+        let recs = sqlx::query(&sql).fetch_all(self.pool()).await?;
+
+        let mut files = vec![];
+        for rec in recs {
+            let key: String = rec.try_get("key")?;
+            let path: String = rec.try_get("path")?;
+            files.push(AttachmentFile {
+                file: paths.resolve(&key, &path),
+                parent: rec.try_get("parent")?,
+                link_mode: rec.try_get::<Option<i64>, _>("link_mode")?.unwrap_or_default(),
+                content_type: rec.try_get("content_type")?,
+                key,
+                path,
+            });
+        }
+        Ok(files)
+    }
+}
+
+impl SqliteBackend {
+    /// Return all attachments with files in library
+    pub fn attachment_files(&self) -> Result<Vec<AttachmentFile>> {
+        self.runtime().block_on(
+            self.db()
+                .get_attachment_files(self.attachment_paths(), self.trash_filter()),
+        )
+    }
+
+    /// Return attachments whose files are missing or can not be resolved
+    pub fn broken_attachments(&self) -> Result<Vec<AttachmentFile>> {
+        let files = self.attachment_files()?;
+        Ok(files.into_iter().filter(|x| !x.exists()).collect())
+    }
+}
+// 58c1f3a9 ends here
+
+// [[file:../zotero.note::*test][test:1]]
+#[test]
+fn test_attachment_paths() -> Result<()> {
+    use crate::backend::ZoteroBackend;
+    use crate::fixture::TestDb;
+
+    let paths = AttachmentPaths::new("/data/zotero").base_dir("/papers");
+    let resolve = |path| paths.resolve("ABCD1234", path);
+    assert_eq!(
+        resolve("storage:a.pdf"),
+        Some("/data/zotero/storage/ABCD1234/a.pdf".into())
+    );
+    assert_eq!(resolve("attachments:2020/b.pdf"), Some("/papers/2020/b.pdf".into()));
+    assert_eq!(resolve("/tmp/c.pdf"), Some("/tmp/c.pdf".into()));
+    assert_eq!(resolve("c.pdf"), None);
+    assert_eq!(
+        AttachmentPaths::new("/data/zotero").resolve("ABCD1234", "attachments:b.pdf"),
+        None
+    );
+
+    let test_db = TestDb::with_sql(
+        r#"
+INSERT INTO items (itemID, itemTypeID, libraryID, key) VALUES (6, 14, 1, 'FFFFFFFF'), (7, 14, 1, 'GGGGGGGG');
+INSERT INTO itemAttachments VALUES (6, 2, 2, 'application/pdf', NULL, 'attachments:parr1989.pdf', 0),
+    (7, NULL, 2, 'application/pdf', NULL, '/nonexistent/missing.pdf', 0);
+"#,
+    );
+    let data_dir = test_db.path().parent().unwrap().to_owned();
+    let base = data_dir.join("papers");
+    std::fs::create_dir_all(&base)?;
+    std::fs::write(base.join("parr1989.pdf"), "%PDF-1.4")?;
+
+    // storage next to the opened database by default
+    let backend = SqliteBackend::open(test_db.path())?;
+    assert_eq!(
+        backend.attachment_paths(),
+        &AttachmentPaths::for_db_file(test_db.path())
+    );
+    assert_eq!(backend.attachment_paths().storage_dir(), data_dir.join("storage"));
+
+    let paths = AttachmentPaths::new(&data_dir).base_dir(&base);
+    let backend = SqliteBackend::open(test_db.path())?.with_attachment_paths(paths);
+    let files = backend.attachment_files()?;
+    assert_eq!(files.len(), 3);
+    assert_eq!(files[1].parent.as_deref(), Some("BBBBBBBB"));
+    assert!(files[1].exists());
+    let broken: Vec<_> = backend.broken_attachments()?.into_iter().map(|x| x.key).collect();
+    assert_eq!(broken, vec!["CCCCCCCC", "GGGGGGGG"]);
+    assert_eq!(
+        backend.attachments("BBBBBBBB")?,
+        vec![base.join("parr1989.pdf").display().to_string()]
+    );
+
+    // base directory from prefs of zotero profile
+    let profile_dir = data_dir.join("profile");
+    std::fs::create_dir_all(&profile_dir)?;
+    std::fs::write(
+        profile_dir.join("prefs.js"),
+        format!(
+            "user_pref(\"extensions.zotero.baseAttachmentPath\", \"{}\");\n",
+            base.display()
+        ),
+    )?;
+    let install = ZoteroInstall {
+        source: "profile".into(),
+        profile: Some(crate::profile::Profile {
+            name: "default".into(),
+            path: profile_dir,
+            default: true,
+        }),
+        data_dir: data_dir.clone(),
+        db_size: 0,
+    };
+    assert_eq!(
+        AttachmentPaths::for_install(&install),
+        AttachmentPaths::new(&data_dir).base_dir(&base)
+    );
+    let install = ZoteroInstall {
+        profile: None,
+        ..install
+    };
+    assert_eq!(AttachmentPaths::for_install(&install), AttachmentPaths::new(&data_dir));
+
+    Ok(())
+}
+// test:1 ends here
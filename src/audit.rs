@@ -0,0 +1,405 @@
+// [[file:../zotero.note::*imports][imports:1]]
+use gut::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use sqlx::prelude::*;
+
+use crate::attachment::AttachmentFile;
+use crate::backend::SqliteBackend;
+use crate::db::{Collection, ZoteroDb};
+use crate::trash::{TrashFilter, TrashedItem};
+// imports:1 ends here
+
+// [[file:../zotero.note::b7e40c5a][b7e40c5a]]
+/// Attachments sharing the same file content
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DuplicateFiles {
+    /// SHA-256 digest of file content in hex
+    pub hash: String,
+    /// File size in bytes
+    pub size: u64,
+    pub attachments: Vec<AttachmentFile>,
+}
+
+/// An attachment file larger than the size limit
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LargeFile {
+    pub attachment: AttachmentFile,
+    /// File size in bytes
+    pub size: u64,
+}
+
+/// Problems found in zotero database and storage directory
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HealthReport {
+    /// Attachments whose files are missing or can not be resolved
+    pub missing_files: Vec<AttachmentFile>,
+    /// Folders in storage directory not belonging to any item
+    pub orphaned_folders: Vec<PathBuf>,
+    /// Keys of regular items without any attachment
+    pub items_without_attachments: Vec<String>,
+    pub duplicate_files: Vec<DuplicateFiles>,
+    /// Collections without items or subcollections
+    pub empty_collections: Vec<Collection>,
+    /// Tags not used by any item outside trash
+    pub unused_tags: Vec<String>,
+    /// Items in trash for longer than the limit
+    pub old_trash: Vec<TrashedItem>,
+    pub large_files: Vec<LargeFile>,
+}
+
+impl ZoteroDb {
+    async fn get_item_keys(&self) -> Result<HashSet<String>> {
+        let recs = sqlx::query("SELECT key FROM items").fetch_all(self.pool()).await?;
+        recs.iter().map(|x| Ok(x.try_get("key")?)).collect()
+    }
+
+    async fn get_items_without_attachments(&self, trash: TrashFilter) -> Result<Vec<String>> {
+        // attachments are trashed with their parent item
+        let sql = format!(
+            r#"
+SELECT key FROM items
+WHERE {}
+  AND itemID NOT IN (select itemID from itemAttachments)
+  AND itemID NOT IN (select itemID from itemNotes)
+  AND itemTypeID NOT IN (select itemTypeID from itemTypes where typeName = 'annotation')
+  AND itemID NOT IN (
+    SELECT parentItemID FROM itemAttachments WHERE parentItemID IS NOT NULL AND {})
+ORDER BY key
+"#,
+            trash.sql(&["itemID"]),
+            trash.sql(&["itemAttachments.itemID", "itemAttachments.parentItemID"])
+        );
+        let recs = sqlx::query(&sql).fetch_all(self.pool()).await?;
+        recs.iter().map(|x| Ok(x.try_get("key")?)).collect()
+    }
+
+    async fn get_empty_collections(&self, trash: TrashFilter) -> Result<Vec<Collection>> {
+        let trashed = self.trashed_collections().await?;
+        let sql = format!(
+            r#"
+SELECT c.key as key, c.collectionName as name, p.key as parent
+FROM collections c
+LEFT JOIN collections p ON c.parentCollectionID = p.collectionID
+WHERE {}
+  AND c.collectionID NOT IN (select collectionID from collectionItems WHERE {})
+  AND c.collectionID NOT IN (
+    SELECT parentCollectionID FROM collections WHERE parentCollectionID IS NOT NULL AND {})
+ORDER BY c.collectionName
+"#,
+            trash.sql_in(&["c.collectionID"], trashed),
+            trash.sql(&["itemID"]),
+            trash.sql_in(&["collectionID"], trashed)
+        );
+        let collections = sqlx::query_as::<_, Collection>(&sql).fetch_all(self.pool()).await?;
+        Ok(collections)
+    }
+
+    async fn get_unused_tags(&self, trash: TrashFilter) -> Result<Vec<String>> {
+        let sql = format!(
+            r#"
+SELECT name FROM tags
+WHERE tagID NOT IN (SELECT tagID FROM itemTags WHERE {})
+ORDER BY name
+"#,
+            trash.sql(&["itemID"])
+        );
+        let recs = sqlx::query(&sql).fetch_all(self.pool()).await?;
+        recs.iter().map(|x| Ok(x.try_get("name")?)).collect()
+    }
+}
+
+// SHA-256 digest of file content in hex
+fn file_digest(path: &Path) -> Result<String> {
+    use sha2::{Digest, Sha256};
+
+    let mut f = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut f, &mut hasher)?;
+    let digest = hasher.finalize();
+    Ok(digest.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+// Group existing files by size, then by content
+fn find_duplicate_files(files: &[(AttachmentFile, u64)]) -> Result<Vec<DuplicateFiles>> {
+    let mut by_size: HashMap<u64, Vec<&AttachmentFile>> = HashMap::new();
+    for (attachment, size) in files.iter().filter(|x| x.1 > 0) {
+        by_size.entry(*size).or_default().push(attachment);
+    }
+    let mut duplicates = vec![];
+    for (size, attachments) in by_size.into_iter().filter(|x| x.1.len() > 1) {
+        let mut by_hash: HashMap<String, Vec<AttachmentFile>> = HashMap::new();
+        for attachment in attachments {
+            let path = attachment.file.as_ref().expect("existing file");
+            by_hash.entry(file_digest(path)?).or_default().push(attachment.clone());
+        }
+        for (hash, attachments) in by_hash.into_iter().filter(|x| x.1.len() > 1) {
+            duplicates.push(DuplicateFiles {
+                hash,
+                size,
+                attachments,
+            });
+        }
+    }
+    duplicates.sort_by(|a, b| a.attachments[0].key.cmp(&b.attachments[0].key));
+    Ok(duplicates)
+}
+// b7e40c5a ends here
+
+// [[file:../zotero.note::2d91f6c3][2d91f6c3]]
+/// Library health check, with limits for reporting
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    trash_days: u32,
+    max_file_size: u64,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        Self {
+            trash_days: 30,
+            max_file_size: 50 * 1024 * 1024,
+        }
+    }
+}
+
+impl HealthCheck {
+    /// Report items in trash for more than `days`. The default is 30 days.
+    pub fn trash_days(mut self, days: u32) -> Self {
+        self.trash_days = days;
+        self
+    }
+
+    /// Report attachment files larger than `size` in bytes. The default is
+    /// 50 MB.
+    pub fn max_file_size(mut self, size: u64) -> Self {
+        self.max_file_size = size;
+        self
+    }
+
+    /// Scan zotero database in `backend` and its storage directory
+    pub fn run(&self, backend: &SqliteBackend) -> Result<HealthReport> {
+        let mut report = HealthReport::default();
+        let attachments = backend.attachment_files()?;
+        let db = backend.db();
+        let trash = backend.trash_filter();
+        let keys = backend.runtime().block_on(async {
+            report.items_without_attachments = db.get_items_without_attachments(trash).await?;
+            report.empty_collections = db.get_empty_collections(trash).await?;
+            report.unused_tags = db.get_unused_tags(trash).await?;
+            report.old_trash = db.get_trash_items(Some(self.trash_days)).await?;
+            db.get_item_keys().await
+        })?;
+
+        let mut files = vec![];
+        for attachment in attachments {
+            match attachment
+                .file
+                .as_ref()
+                .and_then(|x| x.metadata().ok())
+                .filter(|x| x.is_file())
+            {
+                Some(m) => files.push((attachment, m.len())),
+                None => report.missing_files.push(attachment),
+            }
+        }
+        report.large_files = files
+            .iter()
+            .filter(|x| x.1 > self.max_file_size)
+            .map(|(attachment, size)| LargeFile {
+                attachment: attachment.clone(),
+                size: *size,
+            })
+            .collect();
+        report.duplicate_files = find_duplicate_files(&files)?;
+
+        let storage = backend.attachment_paths().storage_dir();
+        if storage.is_dir() {
+            for entry in std::fs::read_dir(storage)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if entry.path().is_dir() && !keys.contains(&name) {
+                    report.orphaned_folders.push(entry.path());
+                }
+            }
+            report.orphaned_folders.sort();
+        }
+
+        Ok(report)
+    }
+}
+
+impl SqliteBackend {
+    /// Check library health with default limits
+    pub fn health_report(&self) -> Result<HealthReport> {
+        HealthCheck::default().run(self)
+    }
+}
+
+impl HealthReport {
+    /// Return true if no problem found
+    pub fn is_ok(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// Format report in pretty json
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+fn file_display(attachment: &AttachmentFile) -> String {
+    match &attachment.file {
+        Some(file) => file.display().to_string(),
+        None => attachment.path.clone(),
+    }
+}
+
+/// Format report as a summary table followed by details of each problem
+impl std::fmt::Display for HealthReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let summary = [
+            ("missing files", self.missing_files.len()),
+            ("orphaned folders", self.orphaned_folders.len()),
+            ("items without attachments", self.items_without_attachments.len()),
+            ("duplicate files", self.duplicate_files.len()),
+            ("empty collections", self.empty_collections.len()),
+            ("unused tags", self.unused_tags.len()),
+            ("old items in trash", self.old_trash.len()),
+            ("large files", self.large_files.len()),
+        ];
+        writeln!(f, "{:<28}{:>8}", "check", "count")?;
+        writeln!(f, "{}", "-".repeat(36))?;
+        for (check, count) in summary.iter() {
+            writeln!(f, "{:<28}{:>8}", check, count)?;
+        }
+
+        let mut section = |title: &str, rows: Vec<String>| -> std::fmt::Result {
+            if !rows.is_empty() {
+                writeln!(f, "\n{}:", title)?;
+                for row in rows {
+                    writeln!(f, "  {}", row)?;
+                }
+            }
+            Ok(())
+        };
+        section(
+            "missing files",
+            self.missing_files
+                .iter()
+                .map(|x| format!("{:<10}{}", x.key, file_display(x)))
+                .collect(),
+        )?;
+        section(
+            "orphaned folders",
+            self.orphaned_folders.iter().map(|x| x.display().to_string()).collect(),
+        )?;
+        section("items without attachments", self.items_without_attachments.clone())?;
+        section(
+            "duplicate files",
+            self.duplicate_files
+                .iter()
+                .flat_map(|d| {
+                    d.attachments
+                        .iter()
+                        .map(move |x| format!("{} {:<10}{}", d.hash, x.key, file_display(x)))
+                })
+                .collect(),
+        )?;
+        section(
+            "empty collections",
+            self.empty_collections
+                .iter()
+                .map(|x| format!("{:<10}{}", x.key, x.name))
+                .collect(),
+        )?;
+        section("unused tags", self.unused_tags.clone())?;
+        section(
+            "old items in trash",
+            self.old_trash
+                .iter()
+                .map(|x| format!("{:<10}{}", x.key, x.date_deleted))
+                .collect(),
+        )?;
+        section(
+            "large files",
+            self.large_files
+                .iter()
+                .map(|x| format!("{:<10}{:>12} {}", x.attachment.key, x.size, file_display(&x.attachment)))
+                .collect(),
+        )?;
+        Ok(())
+    }
+}
+// 2d91f6c3 ends here
+
+// [[file:../zotero.note::*test][test:1]]
+#[test]
+fn test_health_report() -> Result<()> {
+    use crate::attachment::AttachmentPaths;
+    use crate::fixture::TestDb;
+
+    let test_db = TestDb::with_sql(
+        r#"
+INSERT INTO items (itemID, itemTypeID, libraryID, key) VALUES (6, 14, 1, 'FFFFFFFF'), (7, 14, 1, 'GGGGGGGG');
+INSERT INTO itemAttachments VALUES (6, 2, 0, 'application/pdf', NULL, 'storage:parr1989.pdf', 0),
+    (7, NULL, 0, 'application/pdf', NULL, 'storage:copy.pdf', 0);
+INSERT INTO collections (collectionID, collectionName, parentCollectionID, libraryID, key) VALUES
+    (3, 'Reading list', NULL, 1, 'COLLCCCC'), (4, 'Old', NULL, 1, 'COLLDDDD');
+INSERT INTO deletedCollections VALUES (4, '2021-01-01 00:00:00');
+INSERT INTO tags VALUES (3, 'unused');
+"#,
+    );
+    let data_dir = test_db.path().parent().unwrap().to_owned();
+    let storage = data_dir.join("storage");
+    for (key, file, content) in [
+        ("CCCCCCCC", "smith2008.pdf", "%PDF-1.4 smith"),
+        ("FFFFFFFF", "parr1989.pdf", "%PDF-1.4 parr, a long book"),
+        ("GGGGGGGG", "copy.pdf", "%PDF-1.4 smith"),
+        ("ZZZZZZZZ", "orphan.pdf", ""),
+    ]
+    .iter()
+    {
+        std::fs::create_dir_all(storage.join(key))?;
+        std::fs::write(storage.join(key).join(file), content)?;
+    }
+    std::fs::remove_file(storage.join("FFFFFFFF/parr1989.pdf"))?;
+    std::fs::write(storage.join("FFFFFFFF/parr1989.pdf.bak"), "")?;
+
+    let backend = SqliteBackend::open(test_db.path())?.with_attachment_paths(AttachmentPaths::new(&data_dir));
+    let report = HealthCheck::default().max_file_size(14).run(&backend)?;
+    assert_eq!(report.missing_files.len(), 1);
+    assert_eq!(report.missing_files[0].key, "FFFFFFFF");
+    assert_eq!(report.orphaned_folders, vec![storage.join("ZZZZZZZZ")]);
+    assert!(report.items_without_attachments.is_empty());
+    assert_eq!(report.duplicate_files.len(), 1);
+    let keys: Vec<_> = report.duplicate_files[0].attachments.iter().map(|x| &x.key).collect();
+    assert_eq!(keys, vec!["CCCCCCCC", "GGGGGGGG"]);
+    assert_eq!(
+        report.duplicate_files[0].hash,
+        "d054992ec26e51402ed75c8be861f9d99be056d5fa793ed33928c5d58ec89475"
+    );
+    assert_eq!(report.empty_collections.len(), 1);
+    assert_eq!(report.empty_collections[0].key, "COLLCCCC");
+    assert_eq!(report.unused_tags, vec!["unused"]);
+    assert_eq!(report.old_trash[0].key, "DDDDDDDD");
+    assert!(report.large_files.is_empty());
+    assert!(!report.is_ok());
+
+    let json: HealthReport = serde_json::from_str(&report.to_json()?)?;
+    assert_eq!(json, report);
+    let table = report.to_string();
+    assert!(table.contains("missing files                      1"));
+    assert!(table.contains("  unused"));
+
+    // with items and collections in trash
+    let backend = backend.with_trash_filter(TrashFilter::Include);
+    let report = HealthCheck::default().run(&backend)?;
+    assert_eq!(report.items_without_attachments, vec!["DDDDDDDD"]);
+    let keys: Vec<_> = report.empty_collections.iter().map(|x| x.key.as_str()).collect();
+    assert_eq!(keys, vec!["COLLDDDD", "COLLCCCC"]);
+
+    Ok(())
+}
+// test:1 ends here
@@ -0,0 +1,399 @@
+// [[file:../zotero.note::*imports][imports:1]]
+use gut::prelude::*;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+
+use crate::api::{ApiQuery, LocalApi};
+use crate::attachment::AttachmentPaths;
+use crate::citekey::*;
+use crate::db::{Collection, Item, Map, ZoteroDb};
+use crate::server::{ZoteroServer, ZotxtQuery, ZotxtSearchMethod};
+use crate::trash::TrashFilter;
+// imports:1 ends here
+
+// [[file:../zotero.note::2c9e7b40][2c9e7b40]]
+/// Read access to zotero library, implemented by the sqlite database, the
+/// zotxt client and the local API client.
+pub trait ZoteroBackend {
+    /// Return item in `key`
+    fn get_item(&self, key: &str) -> Result<Item>;
+
+    /// Quick search items by words in title, creators or year
+    fn search(&self, text: &str) -> Result<Vec<Item>>;
+
+    /// Return full paths of attachment files of item in `key`
+    fn attachments(&self, key: &str) -> Result<Vec<String>>;
+
+    /// Return all collections
+    fn collections(&self) -> Result<Vec<Collection>>;
+
+    /// Return names of all tags
+    fn tags(&self) -> Result<Vec<String>>;
+
+    /// Combine with `other` backend, which is used when this one fails.
+    fn or<B: ZoteroBackend>(self, other: B) -> Fallback<Self, B>
+    where
+        Self: Sized,
+    {
+        Fallback {
+            primary: self,
+            secondary: other,
+        }
+    }
+}
+
+/// A backend trying `primary` first and falling back to `secondary` on
+/// errors, e.g. the live zotero server with the cached database as fallback.
+pub struct Fallback<A, B> {
+    primary: A,
+    secondary: B,
+}
+
+impl<A, B> Fallback<A, B> {
+    fn try_both<T>(&self, op: &str, f: impl Fn(&dyn ZoteroBackend) -> Result<T>) -> Result<T>
+    where
+        A: ZoteroBackend,
+        B: ZoteroBackend,
+    {
+        f(&self.primary).or_else(|e| {
+            debug!("{} failed in primary backend: {:?}, trying fallback", op, e);
+            f(&self.secondary)
+        })
+    }
+}
+
+impl<A: ZoteroBackend, B: ZoteroBackend> ZoteroBackend for Fallback<A, B> {
+    fn get_item(&self, key: &str) -> Result<Item> {
+        self.try_both("get_item", |b| b.get_item(key))
+    }
+
+    fn search(&self, text: &str) -> Result<Vec<Item>> {
+        self.try_both("search", |b| b.search(text))
+    }
+
+    fn attachments(&self, key: &str) -> Result<Vec<String>> {
+        self.try_both("attachments", |b| b.attachments(key))
+    }
+
+    fn collections(&self) -> Result<Vec<Collection>> {
+        self.try_both("collections", |b| b.collections())
+    }
+
+    fn tags(&self) -> Result<Vec<String>> {
+        self.try_both("tags", |b| b.tags())
+    }
+}
+// 2c9e7b40 ends here
+
+// [[file:../zotero.note::96d1e3a7][96d1e3a7]]
+/// Blocking backend reading zotero sqlite database
+pub struct SqliteBackend {
+    db: ZoteroDb,
+    rt: tokio::runtime::Runtime,
+    path: PathBuf,
+    attachment_paths: AttachmentPaths,
+    trash: TrashFilter,
+    bbt_file: Option<PathBuf>,
+}
+
+impl SqliteBackend {
+    /// Open zotero database in `path` for read only access. Stored
+    /// attachment files are resolved in `storage` directory next to it.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        let uri = path.to_str().with_context(|| format!("invalid db path: {:?}", path))?;
+        let db = rt
+            .block_on(ZoteroDb::connect(uri))
+            .with_context(|| format!("open zotero db: {:?}", path))?;
+        Ok(Self {
+            db,
+            rt,
+            path: path.to_owned(),
+            attachment_paths: AttachmentPaths::for_db_file(path),
+            trash: TrashFilter::default(),
+            bbt_file: None,
+        })
+    }
+
+    /// Resolve attachment files with `paths` instead of the storage
+    /// directory next to database and the base directory in zotero
+    /// preferences.
+    pub fn with_attachment_paths(mut self, paths: AttachmentPaths) -> Self {
+        self.attachment_paths = paths;
+        self
+    }
+
+    pub(crate) fn attachment_paths(&self) -> &AttachmentPaths {
+        &self.attachment_paths
+    }
+
+    /// Treat items in trash by `trash` in searches and attachment lookups.
+    /// Items in trash are excluded by default.
+    pub fn with_trash_filter(mut self, trash: TrashFilter) -> Self {
+        self.trash = trash;
+        self
+    }
+
+    pub(crate) fn trash_filter(&self) -> TrashFilter {
+        self.trash
+    }
+
+    /// Read Better BibTeX citation keys from `path`, instead of
+    /// better-bibtex.sqlite in zotero data directory.
+    pub fn with_bbt_file<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.bbt_file = Some(path.as_ref().to_owned());
+        self
+    }
+
+    // Better BibTeX database set explicitly, or the one in data directory of
+    // attachment files, which is not next to a cached copy of database.
+    fn bbt_file(&self) -> Option<PathBuf> {
+        if self.bbt_file.is_some() {
+            return self.bbt_file.clone();
+        }
+        let data_dir = self.attachment_paths.storage_dir().parent()?;
+        let found = bbt_db_file(data_dir);
+        if found.is_none() {
+            debug!("no better bibtex database found in {:?}", data_dir);
+        }
+        found
+    }
+
+    pub(crate) fn db(&self) -> &ZoteroDb {
+        &self.db
+    }
+
+    pub(crate) fn runtime(&self) -> &tokio::runtime::Runtime {
+        &self.rt
+    }
+
+    /// Path to the opened database
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Load citation keys from zotero database, and Better BibTeX database
+    /// in zotero data directory if any. Items in trash follow the trash
+    /// filter.
+    pub fn citation_keys(&self) -> Result<CitationKeys> {
+        let bbt_file = self.bbt_file();
+        self.rt
+            .block_on(load_citation_keys(&self.db, bbt_file.as_deref(), self.trash))
+    }
+
+    // Set citation keys of `items` from Better BibTeX database if any
+    fn fill_citekeys(&self, items: &mut [Item]) -> Result<()> {
+        if let Some(bbt_file) = self.bbt_file() {
+            self.rt.block_on(fill_bbt_citekeys(&self.db, &bbt_file, items))?;
+        }
+        Ok(())
+    }
+
+    /// Find item cited as `citekey`, e.g. @smith2020catalysis
+    pub fn get_item_by_citekey(&self, citekey: &str) -> Result<Item> {
+        let bbt_file = self.bbt_file();
+        self.rt
+            .block_on(find_item_by_citekey(&self.db, bbt_file.as_deref(), citekey, self.trash))
+    }
+}
+
+impl ZoteroBackend for SqliteBackend {
+    fn get_item(&self, key: &str) -> Result<Item> {
+        let mut item = self.rt.block_on(self.db.get_item(key))?;
+        self.fill_citekeys(std::slice::from_mut(&mut item))?;
+        Ok(item)
+    }
+
+    fn search(&self, text: &str) -> Result<Vec<Item>> {
+        let mut items = self.rt.block_on(self.db.get_items_dwim(text, self.trash))?;
+        self.fill_citekeys(&mut items)?;
+        Ok(items)
+    }
+
+    fn attachments(&self, key: &str) -> Result<Vec<String>> {
+        self.rt
+            .block_on(self.db.get_attachment_paths(key, &self.attachment_paths, self.trash))
+    }
+
+    fn collections(&self) -> Result<Vec<Collection>> {
+        self.rt.block_on(self.db.get_collections(self.trash))
+    }
+
+    fn tags(&self) -> Result<Vec<String>> {
+        self.rt.block_on(self.db.get_tags(self.trash))
+    }
+}
+// 96d1e3a7 ends here
+
+// [[file:../zotero.note::5f08a2cb][5f08a2cb]]
+// zotxt wants item key with library prefix, e.g. 1_BHDGEJJP
+fn zotxt_key(key: &str) -> ZotxtQuery {
+    if key.contains('_') {
+        ZotxtQuery::Key(key.into())
+    } else {
+        ZotxtQuery::Key(format!("1_{}", key))
+    }
+}
+
+// Convert item in CSL-JSON returned by zotxt
+fn item_from_csl_json(key: &str, json: &Value) -> Item {
+    let mut d = Map::new();
+    if let Some(title) = json["title"].as_str() {
+        d.insert("title".into(), title.into());
+    }
+    // zotero exports extra field as note
+    if let Some(extra) = json["note"].as_str() {
+        d.insert("extra".into(), extra.into());
+    }
+    if let Some(citekey) = json["citation-key"].as_str() {
+        d.insert("citationKey".into(), citekey.into());
+    }
+    // year may be in number or string
+    match &json["issued"]["date-parts"][0][0] {
+        Value::Number(n) => d.insert("date".into(), format!("{:04}", n.as_u64().unwrap_or_default())),
+        Value::String(s) => d.insert("date".into(), s.into()),
+        _ => None,
+    };
+    Item::from_fields(key, &d)
+}
+
+impl ZoteroBackend for ZoteroServer {
+    fn get_item(&self, key: &str) -> Result<Item> {
+        let json = self.zotxt_json(&zotxt_key(key))?;
+        let json = json.first().with_context(|| format!("item not found: {}", key))?;
+        let key = key.rsplit('_').next().unwrap_or(key);
+        Ok(item_from_csl_json(key, json))
+    }
+
+    fn search(&self, text: &str) -> Result<Vec<Item>> {
+        let keys = self.zotxt_search(text, ZotxtSearchMethod::TitleCreatorYear)?;
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+        // all items in one request, returned in the order of keys
+        let json = self.zotxt_json(&ZotxtQuery::Key(keys.join(",")))?;
+        if json.len() != keys.len() {
+            bail!("expected {} items from zotxt, got {}", keys.len(), json.len());
+        }
+        let items = keys
+            .iter()
+            .zip(&json)
+            .map(|(key, json)| item_from_csl_json(key.rsplit('_').next().unwrap_or(key), json))
+            .collect();
+        Ok(items)
+    }
+
+    fn attachments(&self, key: &str) -> Result<Vec<String>> {
+        let paths = self.zotxt_paths(&zotxt_key(key))?;
+        Ok(paths.into_iter().flat_map(|x| x.paths).collect())
+    }
+
+    fn collections(&self) -> Result<Vec<Collection>> {
+        bail!("listing collections is not supported by zotxt");
+    }
+
+    fn tags(&self) -> Result<Vec<String>> {
+        bail!("listing tags is not supported by zotxt");
+    }
+}
+// 5f08a2cb ends here
+
+// [[file:../zotero.note::b13e4d95][b13e4d95]]
+impl<'a> ZoteroBackend for LocalApi<'a> {
+    fn get_item(&self, key: &str) -> Result<Item> {
+        Ok(self.item(key)?.to_item())
+    }
+
+    fn search(&self, text: &str) -> Result<Vec<Item>> {
+        self.get_items(&ApiQuery::default().q(text).top())
+    }
+
+    fn attachments(&self, key: &str) -> Result<Vec<String>> {
+        let mut paths = vec![];
+        for child in self.children(key)? {
+            // the local file in file:// url
+            let href = child.links.get("enclosure").and_then(|x| x["href"].as_str());
+            if let Some(url) = href.and_then(|x| reqwest::Url::parse(x).ok()) {
+                if let Ok(path) = url.to_file_path() {
+                    paths.push(path.display().to_string());
+                }
+            }
+        }
+        Ok(paths)
+    }
+
+    fn collections(&self) -> Result<Vec<Collection>> {
+        let collections = LocalApi::collections(self)?
+            .into_iter()
+            .map(|x| Collection {
+                parent: x.parent().map(|p| p.to_string()),
+                key: x.key,
+                name: x.data.name,
+            })
+            .collect();
+        Ok(collections)
+    }
+
+    fn tags(&self) -> Result<Vec<String>> {
+        let tags = LocalApi::tags(self)?.into_iter().map(|x| x.tag).collect();
+        Ok(tags)
+    }
+}
+// b13e4d95 ends here
+
+// [[file:../zotero.note::*test][test:1]]
+#[test]
+fn test_backend_fallback() -> Result<()> {
+    use crate::fixture::TestDb;
+    use crate::mock::*;
+
+    // zotxt knows item AAAAAAAA only
+    let mock = MockServer::start(|req| match (req.path(), req.query("key").as_deref()) {
+        ("/zotxt/items", Some("1_AAAAAAAA")) if req.query("format").as_deref() == Some("json") => MockResponse::ok(
+            r#"[{"id": "x", "title": "From zotxt", "issued": {"date-parts": [[2008, 5]]}, "note": "tex.key: x"}]"#,
+        ),
+        ("/zotxt/items", Some("1_AAAAAAAA,1_BBBBBBBB")) => {
+            MockResponse::ok(r#"[{"id": "x", "title": "From zotxt"}, {"id": "y", "title": "Another from zotxt"}]"#)
+        }
+        ("/zotxt/search", _) => MockResponse::ok(r#"["1_AAAAAAAA", "1_BBBBBBBB"]"#),
+        _ => MockResponse::status(400, "No item found"),
+    });
+    let server = ZoteroServer::new(mock.url());
+    let test_db = TestDb::new();
+    let db = SqliteBackend::open(test_db.path())?;
+
+    let item = server.get_item("AAAAAAAA")?;
+    assert_eq!(item.title(), "From zotxt");
+    assert_eq!(item.date(), "2008");
+    let n = mock.requests().len();
+    let found = server.search("zeolite")?;
+    assert_eq!(found.len(), 2);
+    assert_eq!(found[1].key(), "BBBBBBBB");
+    assert_eq!(found[1].title(), "Another from zotxt");
+    // one search and one batch lookup
+    assert_eq!(mock.requests().len(), n + 2);
+
+    let item = db.get_item("BBBBBBBB")?;
+    assert_eq!(item.title(), "Density functional theory of atoms and molecules");
+    assert!(db.get_item("XXXXXXXX").is_err());
+    let found = db.search("smith zeolite")?;
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].key(), "AAAAAAAA");
+    // items in trash are excluded
+    assert_eq!(db.search("deleted")?.len(), 0);
+    assert_eq!(db.tags()?, vec!["dft", "zeolite"]);
+
+    let backend = server.or(db);
+    assert_eq!(backend.get_item("AAAAAAAA")?.title(), "From zotxt");
+    assert_eq!(backend.get_item("BBBBBBBB")?.date(), "1989");
+    let attachments = backend.attachments("AAAAAAAA")?;
+    assert!(attachments[0].ends_with("CCCCCCCC/smith2008.pdf"));
+    let collections = backend.collections()?;
+    assert_eq!(collections.len(), 2);
+    assert_eq!(collections[1].parent.as_deref(), Some("COLLAAAA"));
+    assert!(backend.get_item("XXXXXXXX").is_err());
+
+    Ok(())
+}
+// test:1 ends here
@@ -0,0 +1,329 @@
+// [[file:../zotero.note::*imports][imports:1]]
+use gut::prelude::*;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use sqlx::prelude::*;
+
+use crate::backend::SqliteBackend;
+use crate::db::{Item, ZoteroDb, DB_FILE};
+// imports:1 ends here
+
+// [[file:../zotero.note::1e8c4a73][1e8c4a73]]
+/// Where a reader has caught up with zotero database. It can be stored (in
+/// json for example) for fetching changes later.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Watermark {
+    /// Latest `items.clientDateModified` seen
+    pub modified: String,
+    /// Versions of items modified at `modified` time, which have been seen
+    /// already. Other items modified in the same second are still new.
+    #[serde(default)]
+    pub modified_items: BTreeMap<String, i64>,
+    /// Latest sync version of items seen
+    pub version: i64,
+    /// Latest `items.itemID` seen, for telling new items apart
+    pub max_id: i64,
+    /// Latest time of items moved to trash or erased
+    pub deleted: String,
+    /// Number of items, for detecting erased items not logged
+    pub count: i64,
+    /// Values of synced settings, such as tag colors
+    pub settings: BTreeMap<String, String>,
+}
+
+/// Changes in zotero database since a watermark
+#[derive(Debug, Clone, Default)]
+pub struct Changes {
+    /// Keys of new items
+    pub added: Vec<String>,
+    /// Keys of modified items, including items restored from trash
+    pub modified: Vec<String>,
+    /// Keys of items moved to trash or erased
+    pub deleted: Vec<String>,
+    /// Names of changed synced settings
+    pub settings: Vec<String>,
+    /// True if some items have been erased without being logged, so the
+    /// reader should reload everything.
+    pub incomplete: bool,
+    /// Watermark for the next call
+    pub watermark: Watermark,
+}
+
+impl Changes {
+    /// Return true if nothing changed.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.modified.is_empty()
+            && self.deleted.is_empty()
+            && self.settings.is_empty()
+            && !self.incomplete
+    }
+}
+
+// 1e8c4a73 ends here
+
+// [[file:../zotero.note::a6f3d28e][a6f3d28e]]
+impl ZoteroDb {
+    /// Compute changes since `watermark`. Use the default watermark to get
+    /// all items as added.
+    pub(crate) async fn changes_since(&self, watermark: &Watermark) -> Result<Changes> {
+        let stats = sqlx::query(
+            r#"
+SELECT CAST(IFNULL(MAX(clientDateModified), '') AS TEXT) as modified,
+       IFNULL(MAX(version), 0) as version,
+       IFNULL(MAX(itemID), 0) as max_id,
+       COUNT(*) as count
+FROM items
+"#,
+        )
+        .fetch_one(self.pool())
+        .await?;
+        let mut new = Watermark {
+            modified: stats.try_get("modified")?,
+            version: stats.try_get("version")?,
+            max_id: stats.try_get("max_id")?,
+            count: stats.try_get("count")?,
+            deleted: watermark.deleted.clone(),
+            ..Default::default()
+        };
+
+        // items moved to trash, or erased with record in sync delete log
+        let recs = sqlx::query(
+            r#"
+SELECT items.key as key, CAST(deletedItems.dateDeleted AS TEXT) as deleted, 0 as erased
+FROM deletedItems JOIN items USING (itemID)
+WHERE deletedItems.dateDeleted > ?
+UNION
+SELECT syncDeleteLog.key as key, CAST(syncDeleteLog.dateDeleted AS TEXT) as deleted, 1 as erased
+FROM syncDeleteLog JOIN syncObjectTypes USING (syncObjectTypeID)
+WHERE syncObjectTypes.name = "item" AND syncDeleteLog.dateDeleted > ?
+"#,
+        )
+        .bind(&watermark.deleted)
+        .bind(&watermark.deleted)
+        .fetch_all(self.pool())
+        .await?;
+        let mut changes = Changes::default();
+        let mut erased = 0;
+        for rec in recs {
+            let key: String = rec.try_get("key")?;
+            let deleted: String = rec.try_get("deleted")?;
+            if rec.try_get::<i64, _>("erased")? == 1 {
+                erased += 1;
+            }
+            if deleted > new.deleted {
+                new.deleted = deleted;
+            }
+            changes.deleted.push(key);
+        }
+        let deleted: HashSet<_> = changes.deleted.iter().cloned().collect();
+
+        let recs = sqlx::query(
+            r#"
+SELECT itemID as id, key, version, CAST(clientDateModified AS TEXT) as modified FROM items
+WHERE clientDateModified >= ? OR version > ? OR itemID > ?
+ORDER BY itemID
+"#,
+        )
+        .bind(&watermark.modified)
+        .bind(watermark.version)
+        .bind(watermark.max_id)
+        .fetch_all(self.pool())
+        .await?;
+        // clientDateModified is in seconds, so items modified at the same
+        // time as watermark may be new
+        if new.modified == watermark.modified {
+            new.modified_items = watermark.modified_items.clone();
+        }
+        let mut added = 0;
+        for rec in recs {
+            let id: i64 = rec.try_get("id")?;
+            let key: String = rec.try_get("key")?;
+            let version: i64 = rec.try_get("version")?;
+            let modified: String = rec.try_get("modified")?;
+            if id > watermark.max_id {
+                added += 1;
+            }
+            if modified == new.modified {
+                new.modified_items.insert(key.clone(), version);
+            }
+            let seen = modified == watermark.modified && watermark.modified_items.get(&key) == Some(&version);
+            if deleted.contains(&key) || (seen && id <= watermark.max_id && version <= watermark.version) {
+                continue;
+            }
+            if id > watermark.max_id {
+                changes.added.push(key);
+            } else {
+                changes.modified.push(key);
+            }
+        }
+        // trashed items are still counted
+        changes.incomplete = watermark.count + added - erased > new.count;
+
+        let recs =
+            sqlx::query("SELECT setting, libraryID as library, CAST(value AS TEXT) as value FROM syncedSettings")
+                .fetch_all(self.pool())
+                .await?;
+        let mut names: HashMap<String, String> = HashMap::new();
+        for rec in recs {
+            let setting: String = rec.try_get("setting")?;
+            let library: i64 = rec.try_get("library")?;
+            let value: String = rec.try_get("value")?;
+            let id = format!("{}:{}", library, setting);
+            new.settings.insert(id.clone(), value);
+            names.insert(id, setting);
+        }
+        let old = &watermark.settings;
+        for (id, value) in &new.settings {
+            if old.get(id) != Some(value) {
+                changes.settings.push(names[id].clone());
+            }
+        }
+        // removed settings
+        for id in old.keys().filter(|id| !new.settings.contains_key(*id)) {
+            let name = id.split_once(':').map(|x| x.1).unwrap_or(id);
+            changes.settings.push(name.to_string());
+        }
+        changes.settings.sort();
+        changes.settings.dedup();
+
+        // keep watermark when database is empty or reset
+        if new.modified < watermark.modified {
+            new.modified = watermark.modified.clone();
+        }
+        changes.watermark = new;
+        Ok(changes)
+    }
+}
+
+impl SqliteBackend {
+    /// Return changes since `watermark`. Note that the database needs to be
+    /// opened again after it has been changed by zotero.
+    pub fn changes_since(&self, watermark: &Watermark) -> Result<Changes> {
+        self.runtime().block_on(self.db().changes_since(watermark))
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+/// Return changes in zotero database since `watermark`
+pub async fn changes_since(watermark: &Watermark) -> Result<Changes> {
+    let db = ZoteroDb::connect(DB_FILE).await?;
+    db.changes_since(watermark).await
+}
+// a6f3d28e ends here
+
+// [[file:../zotero.note::0d5b9f62][0d5b9f62]]
+/// Zotero items cached in memory, updated incrementally from database
+/// changes.
+#[derive(Debug, Clone, Default)]
+pub struct ItemCache {
+    items: HashMap<String, Item>,
+    watermark: Watermark,
+}
+
+impl ItemCache {
+    /// Return cached item in `key`
+    pub fn get(&self, key: &str) -> Option<&Item> {
+        self.items.get(key)
+    }
+
+    /// Return all cached items, including items in trash
+    pub fn items(&self) -> impl Iterator<Item = &Item> {
+        self.items.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// The watermark cached items are up to date with
+    pub fn watermark(&self) -> &Watermark {
+        &self.watermark
+    }
+
+    /// Update cache with changes in database. Only changed items are read
+    /// again, unless the changes are incomplete.
+    pub fn refresh(&mut self, backend: &SqliteBackend) -> Result<Changes> {
+        backend.runtime().block_on(self.refresh_from(backend.db()))
+    }
+
+    async fn refresh_from(&mut self, db: &ZoteroDb) -> Result<Changes> {
+        let mut changes = db.changes_since(&self.watermark).await?;
+        if changes.incomplete {
+            info!("some erased items are unknown, reloading all items");
+            self.items.clear();
+            changes = db.changes_since(&Watermark::default()).await?;
+            changes.incomplete = true;
+        }
+        for key in &changes.deleted {
+            self.items.remove(key);
+        }
+        for key in changes.added.iter().chain(changes.modified.iter()) {
+            let item = db.get_item(key).await?;
+            self.items.insert(key.clone(), item);
+        }
+        self.watermark = changes.watermark.clone();
+        Ok(changes)
+    }
+}
+// 0d5b9f62 ends here
+
+// [[file:../zotero.note::*test][test:1]]
+#[test]
+fn test_changes_since() -> Result<()> {
+    use crate::fixture::TestDb;
+
+    let test_db = TestDb::new();
+    let mut cache = ItemCache::default();
+    let changes = cache.refresh(&SqliteBackend::open(test_db.path())?)?;
+    assert_eq!(changes.added, vec!["AAAAAAAA", "BBBBBBBB", "CCCCCCCC", "EEEEEEEE"]);
+    assert_eq!(changes.deleted, vec!["DDDDDDDD"]);
+    assert_eq!(changes.settings, vec!["tagColors"]);
+    assert_eq!(cache.len(), 4);
+    let watermark = changes.watermark.clone();
+    assert!(SqliteBackend::open(test_db.path())?
+        .changes_since(&watermark)?
+        .is_empty());
+
+    // modified in the same second as the latest seen
+    test_db.execute("UPDATE items SET clientDateModified = '2020-03-01 08:00:00' WHERE key = 'AAAAAAAA';");
+    let changes = cache.refresh(&SqliteBackend::open(test_db.path())?)?;
+    assert_eq!(changes.modified, vec!["AAAAAAAA"]);
+    assert_eq!(changes.watermark.modified_items.len(), 2);
+    assert!(SqliteBackend::open(test_db.path())?
+        .changes_since(&changes.watermark)?
+        .is_empty());
+
+    test_db.execute(
+        r#"
+UPDATE items SET clientDateModified = '2030-01-01 00:00:00' WHERE key = 'BBBBBBBB';
+UPDATE itemDataValues SET value = 'Density functional theory' WHERE valueID = 6;
+INSERT INTO items (itemID, itemTypeID, libraryID, key) VALUES (6, 2, 1, 'FFFFFFFF');
+DELETE FROM items WHERE key = 'EEEEEEEE';
+INSERT INTO syncDeleteLog VALUES (3, 1, 'EEEEEEEE', '2030-01-01 00:00:00');
+UPDATE syncedSettings SET value = '[]' WHERE setting = 'tagColors';
+"#,
+    );
+    let changes = cache.refresh(&SqliteBackend::open(test_db.path())?)?;
+    assert_eq!(changes.added, vec!["FFFFFFFF"]);
+    assert_eq!(changes.modified, vec!["BBBBBBBB"]);
+    assert_eq!(changes.deleted, vec!["EEEEEEEE"]);
+    assert_eq!(changes.settings, vec!["tagColors"]);
+    assert!(!changes.incomplete);
+    assert_eq!(cache.get("BBBBBBBB").unwrap().title(), "Density functional theory");
+    assert!(cache.get("EEEEEEEE").is_none());
+    assert_eq!(cache.len(), 4);
+
+    // erased without sync log
+    test_db.execute("DELETE FROM items WHERE key = 'FFFFFFFF';");
+    let changes = cache.refresh(&SqliteBackend::open(test_db.path())?)?;
+    assert!(changes.incomplete);
+    assert_eq!(cache.len(), 3);
+
+    Ok(())
+}
+// test:1 ends here
@@ -0,0 +1,349 @@
+// [[file:../zotero.note::*imports][imports:1]]
+use gut::prelude::*;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use sqlx::prelude::*;
+
+use crate::db::{citekey_from_extra, Item, ZoteroDb, DB_FILE};
+use crate::trash::TrashFilter;
+// imports:1 ends here
+
+// [[file:../zotero.note::3d6a0f81][3d6a0f81]]
+// libraryID of personal library in zotero database
+const USER_LIBRARY_ID: i64 = 1;
+
+/// Citation keys of zotero items, for lookups in both ways. Items are
+/// identified by library ID and item key, as keys are only unique within a
+/// library.
+#[derive(Debug, Clone, Default)]
+pub struct CitationKeys {
+    by_item: HashMap<(i64, String), String>,
+    by_citekey: HashMap<String, (i64, String)>,
+}
+
+impl CitationKeys {
+    fn insert(&mut self, library_id: i64, item_key: &str, citekey: &str) {
+        if let Some(old) = self.by_item.insert((library_id, item_key.into()), citekey.into()) {
+            self.by_citekey.remove(&old);
+        }
+        self.by_citekey.insert(citekey.into(), (library_id, item_key.into()));
+    }
+
+    /// Return citation key of item in `item_key` in personal library
+    pub fn citekey(&self, item_key: &str) -> Option<&str> {
+        self.library_citekey(USER_LIBRARY_ID, item_key)
+    }
+
+    /// Return citation key of item in `item_key` in library `library_id`
+    pub fn library_citekey(&self, library_id: i64, item_key: &str) -> Option<&str> {
+        self.by_item
+            .get(&(library_id, item_key.to_string()))
+            .map(|x| x.as_str())
+    }
+
+    /// Return key of item cited as `citekey`, with or without the leading
+    /// "@" as in org-cite or pandoc.
+    pub fn item_key(&self, citekey: &str) -> Option<&str> {
+        self.item(citekey).map(|(_, key)| key)
+    }
+
+    // Return library ID and key of item cited as `citekey`
+    fn item(&self, citekey: &str) -> Option<(i64, &str)> {
+        let citekey = citekey.strip_prefix('@').unwrap_or(citekey);
+        self.by_citekey.get(citekey).map(|(lib, key)| (*lib, key.as_str()))
+    }
+
+    /// Set citation key of `item` in personal library if found
+    pub fn fill(&self, item: &mut Item) {
+        self.fill_in(USER_LIBRARY_ID, item)
+    }
+
+    fn fill_in(&self, library_id: i64, item: &mut Item) {
+        if let Some(citekey) = self.library_citekey(library_id, item.key()) {
+            item.set_citekey(citekey);
+        }
+    }
+
+    /// Number of items having citation keys
+    pub fn len(&self) -> usize {
+        self.by_item.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_item.is_empty()
+    }
+}
+// 3d6a0f81 ends here
+
+// [[file:../zotero.note::8e14c2b7][8e14c2b7]]
+impl ZoteroDb {
+    /// Return library ID, item key and citation key stored in zotero
+    /// database: `citationKey` field of zotero 7, or pinned in extra field.
+    /// Items are filtered by `trash`.
+    async fn get_citation_keys(&self, trash: TrashFilter) -> Result<Vec<(i64, String, String)>> {
+        let sql = format!(
+            r#"
+SELECT items.libraryID as library_id, items.key as key, fields.fieldName as name,
+       itemDataValues.value as value
+FROM items
+    JOIN itemData USING (itemID)
+    JOIN fields USING (fieldID)
+    JOIN itemDataValues USING (valueID)
+    WHERE fields.fieldName IN ("citationKey", "extra")
+    AND {}
+"#,
+            trash.sql(&["items.itemID"])
+        );
+        let recs = sqlx::query(&sql).fetch_all(self.pool()).await?;
+
+        let mut extra_keys = vec![];
+        let mut field_keys = vec![];
+        for rec in recs {
+            let library_id: i64 = rec.try_get("library_id")?;
+            let key: String = rec.try_get("key")?;
+            let name: String = rec.try_get("name")?;
+            let value: String = rec.try_get("value")?;
+            if name == "citationKey" {
+                field_keys.push((library_id, key, value));
+            } else if let Some(citekey) = citekey_from_extra(&value) {
+                extra_keys.push((library_id, key, citekey.to_string()));
+            }
+        }
+        // the field takes precedence
+        extra_keys.extend(field_keys);
+        Ok(extra_keys)
+    }
+
+    // Return libraryID of items in `keys`, the personal library first for
+    // keys found in more than one library
+    async fn get_library_ids(&self, keys: &[String]) -> Result<HashMap<String, i64>> {
+        let sql = "SELECT key, libraryID FROM items WHERE key IN ({keys}) ORDER BY libraryID";
+        let mut ids = HashMap::new();
+        for rec in self.fetch_by_keys(sql, keys).await? {
+            ids.entry(rec.try_get("key")?).or_insert(rec.try_get("libraryID")?);
+        }
+        Ok(ids)
+    }
+}
+
+// Read library ID, item key and citation key from better-bibtex.sqlite. BBT 6
+// stores keys in `citationkey` table, older versions in a json blob.
+async fn read_bbt_citation_keys(path: &Path) -> Result<Vec<(i64, String, String)>> {
+    let uri = path.to_str().with_context(|| format!("invalid path: {:?}", path))?;
+    let db = ZoteroDb::connect(uri)
+        .await
+        .with_context(|| format!("open better bibtex db: {:?}", path))?;
+
+    let tables: Vec<String> = sqlx::query("SELECT name FROM sqlite_master WHERE type = 'table'")
+        .fetch_all(db.pool())
+        .await?
+        .iter()
+        .map(|x| x.try_get("name"))
+        .collect::<Result<_, _>>()?;
+
+    if tables.iter().any(|x| x == "citationkey") {
+        let recs = sqlx::query("SELECT libraryID, itemKey, citationKey FROM citationkey")
+            .fetch_all(db.pool())
+            .await?;
+        recs.iter()
+            .map(|x| {
+                Ok((
+                    x.try_get("libraryID")?,
+                    x.try_get("itemKey")?,
+                    x.try_get("citationKey")?,
+                ))
+            })
+            .collect()
+    } else if tables.iter().any(|x| x == "better-bibtex") {
+        #[derive(Deserialize)]
+        struct Legacy {
+            data: Vec<LegacyKey>,
+        }
+        #[derive(Deserialize)]
+        struct LegacyKey {
+            #[serde(rename = "libraryID", default)]
+            library_id: Option<i64>,
+            #[serde(rename = "itemKey")]
+            item_key: String,
+            citekey: String,
+        }
+
+        let rec = sqlx::query(r#"SELECT data FROM "better-bibtex" WHERE name = 'better-bibtex.citekey'"#)
+            .fetch_one(db.pool())
+            .await?;
+        let data: String = rec.try_get("data")?;
+        let legacy: Legacy = serde_json::from_str(&data).context("invalid better bibtex citekey data")?;
+        let keys = legacy
+            .data
+            .into_iter()
+            .map(|x| (x.library_id.unwrap_or(USER_LIBRARY_ID), x.item_key, x.citekey))
+            .collect();
+        Ok(keys)
+    } else {
+        bail!("no citation keys found in {:?}", path);
+    }
+}
+
+/// Return path to better-bibtex.sqlite in zotero data directory `data_dir`, if any.
+pub(crate) fn bbt_db_file(data_dir: &Path) -> Option<PathBuf> {
+    Some(data_dir.join("better-bibtex.sqlite")).filter(|x| x.exists())
+}
+
+/// Load citation keys of items filtered by `trash` from zotero database `db`,
+/// and from Better BibTeX database in `bbt_file` which takes precedence.
+pub(crate) async fn load_citation_keys(
+    db: &ZoteroDb,
+    bbt_file: Option<&Path>,
+    trash: TrashFilter,
+) -> Result<CitationKeys> {
+    let mut keys = CitationKeys::default();
+    for (library_id, item_key, citekey) in db.get_citation_keys(trash).await? {
+        keys.insert(library_id, &item_key, &citekey);
+    }
+    if let Some(path) = bbt_file {
+        for (library_id, item_key, citekey) in read_bbt_citation_keys(path).await? {
+            keys.insert(library_id, &item_key, &citekey);
+        }
+    }
+    Ok(keys)
+}
+
+/// Set citation keys of `items` from Better BibTeX database in `bbt_file`,
+/// which take precedence over the ones in zotero database.
+pub(crate) async fn fill_bbt_citekeys(db: &ZoteroDb, bbt_file: &Path, items: &mut [Item]) -> Result<()> {
+    let mut keys = CitationKeys::default();
+    for (library_id, item_key, citekey) in read_bbt_citation_keys(bbt_file).await? {
+        keys.insert(library_id, &item_key, &citekey);
+    }
+    let item_keys: Vec<_> = items.iter().map(|x| x.key().to_string()).collect();
+    let library_ids = db.get_library_ids(&item_keys).await?;
+    for item in items {
+        let library_id = *library_ids
+            .get(item.key())
+            .with_context(|| format!("item not found: {}", item.key()))?;
+        keys.fill_in(library_id, item);
+    }
+    Ok(())
+}
+
+/// Find item cited as `citekey` in zotero database `db`
+pub(crate) async fn find_item_by_citekey(
+    db: &ZoteroDb,
+    bbt_file: Option<&Path>,
+    citekey: &str,
+    trash: TrashFilter,
+) -> Result<Item> {
+    let keys = load_citation_keys(db, bbt_file, trash).await?;
+    let (library_id, key) = keys
+        .item(citekey)
+        .with_context(|| format!("no item found for citation key: {}", citekey))?;
+    let mut item = db.get_item(key).await?;
+    keys.fill_in(library_id, &mut item);
+    Ok(item)
+}
+
+#[tokio::main(flavor = "current_thread")]
+/// Find zotero item by Better BibTeX citation key, e.g. @smith2020catalysis
+pub async fn get_item_by_citekey(citekey: &str) -> Result<Item> {
+    let db = ZoteroDb::connect(DB_FILE).await?;
+    let bbt_file = Path::new(DB_FILE).parent().and_then(bbt_db_file);
+
+    find_item_by_citekey(&db, bbt_file.as_deref(), citekey, TrashFilter::default()).await
+}
+// 8e14c2b7 ends here
+
+// [[file:../zotero.note::*test][test:1]]
+#[tokio::test]
+async fn test_citation_keys() -> Result<()> {
+    use crate::fixture::TestDb;
+
+    let test_db = TestDb::with_sql(
+        r#"
+INSERT INTO itemDataValues VALUES (11, 'Citation Key: deleted2021');
+INSERT INTO itemData VALUES (4, 16, 11);
+"#,
+    );
+    let db = ZoteroDb::connect(test_db.path().to_str().unwrap()).await?;
+    let item = db.get_item("AAAAAAAA").await?;
+    assert_eq!(item.citekey(), Some("smith2008zeolite"));
+    let keys = load_citation_keys(&db, None, TrashFilter::default()).await?;
+    assert_eq!(keys.item_key("@smith2008zeolite"), Some("AAAAAAAA"));
+    assert_eq!(keys.item_key("@deleted2021"), None);
+    let keys = load_citation_keys(&db, None, TrashFilter::Only).await?;
+    assert_eq!(keys.len(), 1);
+    assert_eq!(keys.item_key("@deleted2021"), Some("DDDDDDDD"));
+
+    let bbt = test_db.create_sqlite(
+        "better-bibtex.sqlite",
+        r#"
+CREATE TABLE citationkey (itemID NOT NULL PRIMARY KEY, itemKey NOT NULL, libraryID NOT NULL,
+    citationKey NOT NULL, pinned CHECK (pinned in (0, 1)), UNIQUE (libraryID, itemKey));
+INSERT INTO citationkey VALUES (1, 'AAAAAAAA', 1, 'smith2008zeolite', 1), (2, 'BBBBBBBB', 1, 'parr1989density', 0),
+    (9, 'AAAAAAAA', 2, 'group2008zeolite', 0);
+"#,
+    );
+    assert_eq!(bbt_db_file(test_db.path().parent().unwrap()), Some(bbt.clone()));
+    let keys = load_citation_keys(&db, Some(&bbt), TrashFilter::default()).await?;
+    assert_eq!(keys.len(), 3);
+    assert_eq!(keys.citekey("BBBBBBBB"), Some("parr1989density"));
+    // the same item key in a group library
+    assert_eq!(keys.citekey("AAAAAAAA"), Some("smith2008zeolite"));
+    assert_eq!(keys.library_citekey(2, "AAAAAAAA"), Some("group2008zeolite"));
+    let item = find_item_by_citekey(&db, Some(&bbt), "@parr1989density", TrashFilter::default()).await?;
+    assert_eq!(item.key(), "BBBBBBBB");
+    assert_eq!(item.citekey(), Some("parr1989density"));
+    assert!(
+        find_item_by_citekey(&db, Some(&bbt), "@nobody2000", TrashFilter::default())
+            .await
+            .is_err()
+    );
+
+    // items loaded in sqlite backend
+    let mut items = vec![db.get_item("BBBBBBBB").await?];
+    assert_eq!(items[0].citekey(), None);
+    fill_bbt_citekeys(&db, &bbt, &mut items).await?;
+    assert_eq!(items[0].citekey(), Some("parr1989density"));
+
+    Ok(())
+}
+
+#[test]
+fn test_backend_citation_keys() -> Result<()> {
+    use crate::attachment::AttachmentPaths;
+    use crate::backend::{SqliteBackend, ZoteroBackend};
+    use crate::fixture::{TestDb, TestDir};
+
+    let test_db = TestDb::new();
+    let data_dir = test_db.path().parent().unwrap().to_owned();
+    let bbt = test_db.create_sqlite(
+        "better-bibtex.sqlite",
+        r#"
+CREATE TABLE citationkey (itemID NOT NULL PRIMARY KEY, itemKey NOT NULL, libraryID NOT NULL,
+    citationKey NOT NULL, pinned CHECK (pinned in (0, 1)), UNIQUE (libraryID, itemKey));
+INSERT INTO citationkey VALUES (2, 'BBBBBBBB', 1, 'parr1989density', 0);
+"#,
+    );
+    let citekey = |backend: &SqliteBackend| -> Result<Option<String>> {
+        Ok(backend.get_item("BBBBBBBB")?.citekey().map(|x| x.to_string()))
+    };
+    assert_eq!(
+        citekey(&SqliteBackend::open(test_db.path())?)?.as_deref(),
+        Some("parr1989density")
+    );
+
+    // a cached copy of database
+    let cache = TestDir::new();
+    let cached = cache.path().join("zotero.sqlite");
+    std::fs::copy(test_db.path(), &cached)?;
+    assert_eq!(citekey(&SqliteBackend::open(&cached)?)?, None);
+    let backend = SqliteBackend::open(&cached)?.with_attachment_paths(AttachmentPaths::new(&data_dir));
+    assert_eq!(citekey(&backend)?.as_deref(), Some("parr1989density"));
+    let backend = SqliteBackend::open(&cached)?.with_bbt_file(&bbt);
+    assert_eq!(citekey(&backend)?.as_deref(), Some("parr1989density"));
+    let items = backend.search("parr")?;
+    assert_eq!(items[0].citekey(), Some("parr1989density"));
+
+    Ok(())
+}
+// test:1 ends here
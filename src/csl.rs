@@ -0,0 +1,1742 @@
+// [[file:../zotero.note::*imports][imports:1]]
+use gut::prelude::*;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use crate::db::{ItemCreator, Map, ZoteroDb};
+// imports:1 ends here
+
+// [[file:../zotero.note::0c61f5a2][0c61f5a2]]
+/// Output format of formatted citations and bibliography entries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Plain,
+    Html,
+    Org,
+}
+
+impl OutputFormat {
+    fn escape(&self, s: &str) -> String {
+        match self {
+            Self::Html => s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;"),
+            _ => s.to_string(),
+        }
+    }
+
+    fn italic(&self, s: &str) -> String {
+        match self {
+            Self::Plain => s.to_string(),
+            Self::Html => format!("<i>{}</i>", s),
+            Self::Org => format!("/{}/", s),
+        }
+    }
+
+    fn bold(&self, s: &str) -> String {
+        match self {
+            Self::Plain => s.to_string(),
+            Self::Html => format!("<b>{}</b>", s),
+            Self::Org => format!("*{}*", s),
+        }
+    }
+
+    fn underline(&self, s: &str) -> String {
+        match self {
+            Self::Plain => s.to_string(),
+            Self::Html => format!(r#"<span style="text-decoration:underline;">{}</span>"#, s),
+            Self::Org => format!("_{}_", s),
+        }
+    }
+
+    fn superscript(&self, s: &str) -> String {
+        match self {
+            Self::Plain => s.to_string(),
+            Self::Html => format!("<sup>{}</sup>", s),
+            Self::Org => format!("^{{{}}}", s),
+        }
+    }
+
+    fn subscript(&self, s: &str) -> String {
+        match self {
+            Self::Plain => s.to_string(),
+            Self::Html => format!("<sub>{}</sub>", s),
+            Self::Org => format!("_{{{}}}", s),
+        }
+    }
+}
+// 0c61f5a2 ends here
+
+// [[file:../zotero.note::5b0e92d4][5b0e92d4]]
+/// A simplified XML element from CSL style or locale file
+#[derive(Debug, Clone, Default)]
+struct Node {
+    name: String,
+    attrs: HashMap<String, String>,
+    children: Vec<Node>,
+    text: String,
+}
+
+impl Node {
+    fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs.get(name).map(|x| x.as_str())
+    }
+
+    fn child(&self, name: &str) -> Option<&Node> {
+        self.children.iter().find(|x| x.name == name)
+    }
+
+    fn children_named<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Node> + 'a {
+        self.children.iter().filter(move |x| x.name == name)
+    }
+}
+
+fn parse_xml(s: &str) -> Result<Node> {
+    let doc = roxmltree::Document::parse(s).context("parse CSL xml")?;
+    Ok(convert_xml_node(doc.root_element()))
+}
+
+fn convert_xml_node(n: roxmltree::Node) -> Node {
+    let mut node = Node {
+        name: n.tag_name().name().to_string(),
+        ..Default::default()
+    };
+    for a in n.attributes() {
+        node.attrs.insert(a.name().to_string(), a.value().to_string());
+    }
+    for c in n.children() {
+        if c.is_element() {
+            node.children.push(convert_xml_node(c));
+        } else if c.is_text() {
+            node.text.push_str(c.text().unwrap_or_default());
+        }
+    }
+    node
+}
+// 5b0e92d4 ends here
+
+// [[file:../zotero.note::7f2d8c16][7f2d8c16]]
+// built-in fallback when no locale file found
+const DEFAULT_LOCALE: &str = r#"
+<locale xmlns="http://purl.org/net/xbiblio/csl" version="1.0" xml:lang="en-US">
+  <date form="text">
+    <date-part name="month" suffix=" "/>
+    <date-part name="day" suffix=", "/>
+    <date-part name="year"/>
+  </date>
+  <date form="numeric">
+    <date-part name="month" form="numeric-leading-zeros" suffix="/"/>
+    <date-part name="day" form="numeric-leading-zeros" suffix="/"/>
+    <date-part name="year"/>
+  </date>
+  <terms>
+    <term name="accessed">accessed</term>
+    <term name="and">and</term>
+    <term name="et-al">et al.</term>
+    <term name="in">in</term>
+    <term name="no date" form="short">n.d.</term>
+    <term name="retrieved">retrieved</term>
+    <term name="from">from</term>
+    <term name="open-quote">“</term>
+    <term name="close-quote">”</term>
+    <term name="open-inner-quote">‘</term>
+    <term name="close-inner-quote">’</term>
+    <term name="ordinal">th</term>
+    <term name="ordinal-01">st</term>
+    <term name="ordinal-02">nd</term>
+    <term name="ordinal-03">rd</term>
+    <term name="ordinal-11">th</term>
+    <term name="ordinal-12">th</term>
+    <term name="ordinal-13">th</term>
+    <term name="edition"><single>edition</single><multiple>editions</multiple></term>
+    <term name="edition" form="short">ed.</term>
+    <term name="page"><single>page</single><multiple>pages</multiple></term>
+    <term name="page" form="short"><single>p.</single><multiple>pp.</multiple></term>
+    <term name="volume"><single>volume</single><multiple>volumes</multiple></term>
+    <term name="volume" form="short"><single>vol.</single><multiple>vols.</multiple></term>
+    <term name="issue"><single>issue</single><multiple>issues</multiple></term>
+    <term name="issue" form="short"><single>no.</single><multiple>nos.</multiple></term>
+    <term name="editor"><single>editor</single><multiple>editors</multiple></term>
+    <term name="editor" form="short"><single>ed.</single><multiple>eds.</multiple></term>
+    <term name="translator"><single>translator</single><multiple>translators</multiple></term>
+    <term name="translator" form="short"><single>trans.</single><multiple>trans.</multiple></term>
+    <term name="editor" form="verb">edited by</term>
+    <term name="translator" form="verb">translated by</term>
+    <term name="month-01">January</term>
+    <term name="month-02">February</term>
+    <term name="month-03">March</term>
+    <term name="month-04">April</term>
+    <term name="month-05">May</term>
+    <term name="month-06">June</term>
+    <term name="month-07">July</term>
+    <term name="month-08">August</term>
+    <term name="month-09">September</term>
+    <term name="month-10">October</term>
+    <term name="month-11">November</term>
+    <term name="month-12">December</term>
+    <term name="month-01" form="short">Jan.</term>
+    <term name="month-02" form="short">Feb.</term>
+    <term name="month-03" form="short">Mar.</term>
+    <term name="month-04" form="short">Apr.</term>
+    <term name="month-05" form="short">May</term>
+    <term name="month-06" form="short">Jun.</term>
+    <term name="month-07" form="short">Jul.</term>
+    <term name="month-08" form="short">Aug.</term>
+    <term name="month-09" form="short">Sep.</term>
+    <term name="month-10" form="short">Oct.</term>
+    <term name="month-11" form="short">Nov.</term>
+    <term name="month-12" form="short">Dec.</term>
+  </terms>
+</locale>
+"#;
+
+/// Localized terms and date formats from a CSL locale file
+#[derive(Debug, Clone)]
+pub struct CslLocale {
+    lang: String,
+    // (name, form) => (single, multiple)
+    terms: HashMap<(String, String), (String, String)>,
+    // localized date formats: "text" or "numeric"
+    dates: HashMap<String, Node>,
+}
+
+// id of the built-in locale in the fallback chain
+const BUILTIN_LOCALE: &str = "built-in";
+
+// Primary dialects used as fallback for other dialects of the language, as
+// in CSL locales repository
+const PRIMARY_DIALECTS: &[(&str, &str)] = &[
+    ("de", "de-DE"),
+    ("en", "en-US"),
+    ("es", "es-ES"),
+    ("fr", "fr-FR"),
+    ("it", "it-IT"),
+    ("ja", "ja-JP"),
+    ("nl", "nl-NL"),
+    ("pt", "pt-PT"),
+    ("ru", "ru-RU"),
+    ("zh", "zh-CN"),
+];
+
+impl Default for CslLocale {
+    fn default() -> Self {
+        Self::parse_with(DEFAULT_LOCALE, Some(BUILTIN_LOCALE), &mut HashSet::new()).expect("built-in locale")
+    }
+}
+
+impl CslLocale {
+    /// Parse locale from xml string in `s`. Missing terms fall back to
+    /// built-in en-US terms.
+    pub fn parse(s: &str) -> Result<Self> {
+        Self::parse_with(s, None, &mut HashSet::new())
+    }
+
+    // Parse locale in `s` on top of its fallback. The locale is identified
+    // by `id` or its language, and `visited` holds ids of locales already in
+    // the fallback chain, which stops the built-in locale falling back to
+    // itself.
+    fn parse_with(s: &str, id: Option<&str>, visited: &mut HashSet<String>) -> Result<Self> {
+        let node = parse_xml(s)?;
+        if node.name != "locale" {
+            bail!("not a CSL locale file");
+        }
+        let lang = node.attr("lang").unwrap_or("en-US").to_string();
+        visited.insert(id.unwrap_or(&lang).to_string());
+
+        let mut locale = if visited.contains(BUILTIN_LOCALE) {
+            Self {
+                lang: String::new(),
+                terms: HashMap::new(),
+                dates: HashMap::new(),
+            }
+        } else {
+            Self::parse_with(DEFAULT_LOCALE, Some(BUILTIN_LOCALE), visited)?
+        };
+        locale.lang = lang;
+        locale.merge(&node);
+        Ok(locale)
+    }
+
+    /// Read locale from CSL locale file in `path`.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let s = std::fs::read_to_string(path).with_context(|| format!("read locale file: {:?}", path))?;
+        Self::parse(&s)
+    }
+
+    /// Find locale for language `lang` (e.g. en-US or de) in `dir`, which
+    /// contains files named as locales-en-US.xml. Terms missing in the
+    /// locale fall back to the primary dialect of the language (de-AT =>
+    /// de-DE), and then to built-in en-US locale.
+    pub fn from_dir<P: AsRef<Path>>(dir: P, lang: &str) -> Result<Self> {
+        let dir = dir.as_ref();
+        let locale = Self::from_dir_with(dir, lang, &mut HashSet::new())?;
+        if !dir.join(format!("locales-{}.xml", lang)).exists() {
+            info!("no locale file found for {} in {:?}, using {}", lang, dir, locale.lang);
+        }
+        Ok(locale)
+    }
+
+    fn from_dir_with(dir: &Path, lang: &str, visited: &mut HashSet<String>) -> Result<Self> {
+        if !visited.insert(lang.to_string()) {
+            return Ok(Self::default());
+        }
+        let primary = Self::primary_dialect(dir, lang);
+        let fallback = match &primary {
+            Some(primary) if primary != lang => Self::from_dir_with(dir, primary, visited)?,
+            _ => Self::default(),
+        };
+
+        let f = dir.join(format!("locales-{}.xml", lang));
+        if !f.exists() {
+            return Ok(fallback);
+        }
+        let s = std::fs::read_to_string(&f).with_context(|| format!("read locale file: {:?}", f))?;
+        let node = parse_xml(&s)?;
+        if node.name != "locale" {
+            bail!("not a CSL locale file: {:?}", f);
+        }
+        let mut locale = fallback;
+        locale.lang = node.attr("lang").unwrap_or(lang).to_string();
+        locale.merge(&node);
+        Ok(locale)
+    }
+
+    // Primary dialect for language of `lang`, e.g. de => de-DE, or the first
+    // dialect found in `dir` for languages not in the table.
+    fn primary_dialect(dir: &Path, lang: &str) -> Option<String> {
+        let code = lang.split('-').next().unwrap_or(lang);
+        if let Some((_, primary)) = PRIMARY_DIALECTS.iter().find(|(x, _)| *x == code) {
+            return Some(primary.to_string());
+        }
+        let prefix = format!("locales-{}-", code);
+        let mut found: Vec<String> = std::fs::read_dir(dir)
+            .ok()?
+            .filter_map(|x| x.ok())
+            .filter_map(|x| x.file_name().to_str().map(|x| x.to_string()))
+            .filter(|x| x.starts_with(&prefix) && x.ends_with(".xml"))
+            .collect();
+        found.sort();
+        let name = found.into_iter().next()?;
+        Some(name["locales-".len()..name.len() - ".xml".len()].to_string())
+    }
+
+    /// The language of this locale, e.g. en-US
+    pub fn lang(&self) -> &str {
+        &self.lang
+    }
+
+    // merge terms and dates defined in `<locale>` element
+    fn merge(&mut self, node: &Node) {
+        for terms in node.children_named("terms") {
+            for term in terms.children_named("term") {
+                let name = match term.attr("name") {
+                    Some(name) => name.to_string(),
+                    None => continue,
+                };
+                let form = term.attr("form").unwrap_or("long").to_string();
+                let value = match (term.child("single"), term.child("multiple")) {
+                    (Some(s), Some(m)) => (s.text.clone(), m.text.clone()),
+                    (Some(s), None) => (s.text.clone(), s.text.clone()),
+                    _ => (term.text.clone(), term.text.clone()),
+                };
+                self.terms.insert((name, form), value);
+            }
+        }
+        for date in node.children_named("date") {
+            if let Some(form) = date.attr("form") {
+                self.dates.insert(form.to_string(), date.clone());
+            }
+        }
+    }
+
+    fn term(&self, name: &str, form: &str, plural: bool) -> Option<&str> {
+        let fallbacks: &[&str] = match form {
+            "short" => &["short", "long"],
+            "verb-short" => &["verb-short", "verb", "long"],
+            "verb" => &["verb", "long"],
+            "symbol" => &["symbol", "short", "long"],
+            _ => &["long"],
+        };
+        fallbacks.iter().find_map(|form| {
+            self.terms
+                .get(&(name.to_string(), form.to_string()))
+                .map(|(s, m)| if plural { m.as_str() } else { s.as_str() })
+        })
+    }
+}
+// 7f2d8c16 ends here
+
+// [[file:../zotero.note::a9e4b371][a9e4b371]]
+/// A parsed CSL style
+#[derive(Debug, Clone)]
+pub struct CslStyle {
+    root: Node,
+    macros: HashMap<String, Node>,
+}
+
+impl CslStyle {
+    /// Parse CSL style from xml string in `s`
+    pub fn parse(s: &str) -> Result<Self> {
+        let root = parse_xml(s)?;
+        if root.name != "style" {
+            bail!("not a CSL style file");
+        }
+        let macros = root
+            .children_named("macro")
+            .filter_map(|m| m.attr("name").map(|name| (name.to_string(), m.clone())))
+            .collect();
+        Ok(Self { root, macros })
+    }
+
+    /// Read CSL style from `.csl` file in `path`
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let s = std::fs::read_to_string(path).with_context(|| format!("read style file: {:?}", path))?;
+        Self::parse(&s)
+    }
+
+    /// The title of the style from its `<info>` section
+    pub fn title(&self) -> Option<&str> {
+        self.root.child("info")?.child("title").map(|x| x.text.trim())
+    }
+
+    /// The default locale of the style, e.g. en-GB
+    pub fn default_locale(&self) -> Option<&str> {
+        self.root.attr("default-locale")
+    }
+
+    fn citation(&self) -> Option<&Node> {
+        self.root.child("citation")
+    }
+
+    fn bibliography(&self) -> Option<&Node> {
+        self.root.child("bibliography")
+    }
+}
+
+/// Find installed CSL style `name` (file stem, e.g. "apa") in the `styles`
+/// directory of zotero data dir.
+pub fn find_style(data_dir: &Path, name: &str) -> Option<PathBuf> {
+    let f = data_dir.join("styles").join(format!("{}.csl", name));
+    if f.exists() {
+        Some(f)
+    } else {
+        None
+    }
+}
+// a9e4b371 ends here
+
+// [[file:../zotero.note::d38f6a02][d38f6a02]]
+/// A personal or institutional name in CSL item
+#[derive(Debug, Clone, Default)]
+pub struct CslName {
+    pub family: String,
+    pub given: String,
+}
+
+/// A date in CSL item. Missing month or day is represented as 0.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CslDate {
+    pub year: i32,
+    pub month: u32,
+    pub day: u32,
+}
+
+impl CslDate {
+    /// Parse date in zotero's multipart format, e.g. "2020-08-01 2020/08/01"
+    /// or "2020-00-00 2020".
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.split_whitespace().next()?;
+        let mut parts = s.splitn(3, '-');
+        let year = parts.next()?.parse().ok()?;
+        let month = parts.next().and_then(|x| x.parse().ok()).unwrap_or(0);
+        let day = parts.next().and_then(|x| x.parse().ok()).unwrap_or(0);
+        if year == 0 {
+            return None;
+        }
+        Some(Self { year, month, day })
+    }
+}
+
+/// Item data with CSL variables for citation formatting
+#[derive(Debug, Clone, Default)]
+pub struct CslItem {
+    id: String,
+    item_type: String,
+    variables: HashMap<String, String>,
+    names: HashMap<String, Vec<CslName>>,
+    dates: HashMap<String, CslDate>,
+}
+
+impl CslItem {
+    /// Construct an empty item with `id` in CSL type `item_type`, e.g. article-journal
+    pub fn new(id: &str, item_type: &str) -> Self {
+        Self {
+            id: id.into(),
+            item_type: item_type.into(),
+            ..Default::default()
+        }
+    }
+
+    /// The item id, which is zotero item key for items from zotero db.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Set standard CSL variable `name`, such as title or container-title
+    pub fn set_variable(&mut self, name: &str, value: &str) {
+        self.variables.insert(name.into(), value.into());
+    }
+
+    /// Append a name into name variable `var`, such as author or editor
+    pub fn add_name(&mut self, var: &str, family: &str, given: &str) {
+        let name = CslName {
+            family: family.into(),
+            given: given.into(),
+        };
+        self.names.entry(var.into()).or_default().push(name);
+    }
+
+    /// Set date variable `var`, such as issued or accessed
+    pub fn set_date(&mut self, var: &str, date: CslDate) {
+        self.dates.insert(var.into(), date);
+    }
+
+    /// Construct CSL item from zotero item data
+    fn from_zotero(key: &str, item_type: &str, fields: &Map, creators: &[ItemCreator]) -> Self {
+        let mut item = Self::new(key, csl_type_from_zotero(item_type));
+        // fields mapped to the same variable in order of priority
+        let mut fields: Vec<_> = fields.iter().filter(|(_, value)| !value.is_empty()).collect();
+        fields.sort_by_key(|(field, _)| {
+            FIELD_PRIORITY
+                .iter()
+                .position(|x| x == field)
+                .unwrap_or(FIELD_PRIORITY.len())
+        });
+        for (field, value) in fields {
+            match field.as_str() {
+                "date" | "accessDate" | "filingDate" => {
+                    let var = match field.as_str() {
+                        "date" => "issued",
+                        "accessDate" => "accessed",
+                        _ => "submitted",
+                    };
+                    if let Some(date) = CslDate::parse(value) {
+                        item.set_date(var, date);
+                    }
+                }
+                _ => {
+                    if let Some(var) = csl_variable_from_zotero(field) {
+                        // keep the first mapped field, e.g. publicationTitle over bookTitle
+                        item.variables.entry(var.into()).or_insert_with(|| value.into());
+                    }
+                }
+            }
+        }
+        for c in creators {
+            let var = match c.creator_type.as_str() {
+                "author" | "artist" | "performer" | "inventor" | "programmer" | "presenter" | "sponsor"
+                | "cartographer" | "podcaster" | "interviewee" | "director" => "author",
+                "editor" => "editor",
+                "seriesEditor" => "collection-editor",
+                "bookAuthor" => "container-author",
+                "translator" => "translator",
+                "recipient" | "interviewer" => "recipient",
+                _ => continue,
+            };
+            item.add_name(var, &c.last_name, &c.first_name);
+        }
+        item
+    }
+}
+
+fn csl_type_from_zotero(item_type: &str) -> &str {
+    match item_type {
+        "journalArticle" => "article-journal",
+        "magazineArticle" => "article-magazine",
+        "newspaperArticle" => "article-newspaper",
+        "book" => "book",
+        "bookSection" => "chapter",
+        "conferencePaper" => "paper-conference",
+        "thesis" => "thesis",
+        "report" => "report",
+        "webpage" | "blogPost" | "forumPost" => "webpage",
+        "patent" => "patent",
+        "manuscript" => "manuscript",
+        "letter" | "email" => "personal_communication",
+        "presentation" => "speech",
+        "encyclopediaArticle" => "entry-encyclopedia",
+        "dictionaryEntry" => "entry-dictionary",
+        "dataset" => "dataset",
+        "computerProgram" => "software",
+        _ => "article",
+    }
+}
+
+// Zotero fields sharing a CSL variable, the preferred ones first
+const FIELD_PRIORITY: &[&str] = &[
+    "publicationTitle",
+    "bookTitle",
+    "proceedingsTitle",
+    "websiteTitle",
+    "blogTitle",
+    "encyclopediaTitle",
+    "dictionaryTitle",
+    "forumTitle",
+    "series",
+    "seriesTitle",
+    "publisher",
+    "institution",
+    "university",
+    "company",
+    "label",
+    "number",
+    "reportNumber",
+    "patentNumber",
+    "thesisType",
+    "reportType",
+    "websiteType",
+];
+
+fn csl_variable_from_zotero(field: &str) -> Option<&'static str> {
+    let var = match field {
+        "title" => "title",
+        "shortTitle" => "title-short",
+        "publicationTitle" | "bookTitle" | "proceedingsTitle" | "websiteTitle" | "blogTitle" | "encyclopediaTitle"
+        | "dictionaryTitle" | "forumTitle" => "container-title",
+        "journalAbbreviation" => "container-title-short",
+        "series" | "seriesTitle" => "collection-title",
+        "volume" => "volume",
+        "issue" => "issue",
+        "pages" => "page",
+        "numPages" => "number-of-pages",
+        "edition" => "edition",
+        "publisher" | "institution" | "university" | "company" | "label" => "publisher",
+        "place" => "publisher-place",
+        "DOI" => "DOI",
+        "ISBN" => "ISBN",
+        "ISSN" => "ISSN",
+        "url" => "URL",
+        "abstractNote" => "abstract",
+        "reportNumber" | "patentNumber" | "number" => "number",
+        "thesisType" | "reportType" | "websiteType" => "genre",
+        "language" => "language",
+        "extra" => "note",
+        "conferenceName" => "event",
+        _ => return None,
+    };
+    Some(var)
+}
+
+impl ZoteroDb {
+    /// Return CSL item for zotero item in `key`
+    pub async fn get_csl_item(&self, key: &str) -> Result<CslItem> {
+        let item_type = self.get_item_type(key).await?;
+        let fields = self.get_item_fields(key).await?;
+        let creators = self.get_item_creators(key).await?;
+        Ok(CslItem::from_zotero(key, &item_type, &fields, &creators))
+    }
+}
+// d38f6a02 ends here
+
+// [[file:../zotero.note::46b1ce8e][46b1ce8e]]
+// Rendered output of a CSL rendering element
+#[derive(Debug, Default)]
+struct Output {
+    text: String,
+    // a variable is called in this element
+    called: bool,
+    // a non-empty variable is rendered in this element
+    rendered: bool,
+}
+
+impl Output {
+    fn term(text: String) -> Self {
+        Self {
+            text,
+            ..Default::default()
+        }
+    }
+
+    fn variable(text: String) -> Self {
+        Self {
+            rendered: !text.is_empty(),
+            called: true,
+            text,
+        }
+    }
+}
+
+struct RenderContext<'a> {
+    item: &'a CslItem,
+    number: usize,
+    format: OutputFormat,
+    // inheritable name options from style, citation or bibliography element
+    name_opts: HashMap<String, String>,
+    // variables already rendered by names substitution
+    suppressed: RefCell<HashSet<String>>,
+}
+
+impl<'a> RenderContext<'a> {
+    fn is_suppressed(&self, var: &str) -> bool {
+        self.suppressed.borrow().contains(var)
+    }
+
+    fn variable(&self, var: &str, form: &str) -> Option<String> {
+        if self.is_suppressed(var) {
+            return None;
+        }
+        if var == "citation-number" {
+            return Some(self.number.to_string());
+        }
+        if form == "short" {
+            if let Some(v) = self.item.variables.get(&format!("{}-short", var)) {
+                return Some(v.to_string());
+            }
+        }
+        self.item.variables.get(var).filter(|x| !x.is_empty()).cloned()
+    }
+
+    fn has_variable(&self, var: &str) -> bool {
+        var == "citation-number"
+            || self.item.variables.get(var).map(|x| !x.is_empty()).unwrap_or(false)
+            || self.item.names.get(var).map(|x| !x.is_empty()).unwrap_or(false)
+            || self.item.dates.contains_key(var)
+    }
+}
+
+/// Format citations and bibliography entries of CSL items using CSL style and
+/// locale
+pub struct CitationFormatter {
+    style: CslStyle,
+    locale: CslLocale,
+    format: OutputFormat,
+}
+
+impl CitationFormatter {
+    /// Construct a formatter with `style` and `locale` in output `format`
+    pub fn new(style: CslStyle, locale: CslLocale, format: OutputFormat) -> Self {
+        let mut locale = locale;
+        // locale overrides embedded in the style
+        for node in style.root.children_named("locale") {
+            let lang = node.attr("lang");
+            let primary = locale.lang.split('-').next().unwrap_or_default().to_string();
+            if lang.map(|x| x == locale.lang || x == primary).unwrap_or(true) {
+                locale.merge(node);
+            }
+        }
+        Self { style, locale, format }
+    }
+
+    /// Return formatted bibliography entries of `items`, sorted as specified
+    /// in the style.
+    pub fn bibliography(&self, items: &[CslItem]) -> Vec<String> {
+        let section = match self.style.bibliography() {
+            Some(x) => x,
+            None => return vec![],
+        };
+        let layout = match section.child("layout") {
+            Some(x) => x,
+            None => return vec![],
+        };
+
+        self.sort_items(section, items)
+            .into_iter()
+            .enumerate()
+            .map(|(i, item)| {
+                let ctx = self.context(section, item, i + 1, self.format);
+                let out = self.render_children(&layout.children, &ctx, "");
+                self.decorate(layout, out.text, self.format)
+            })
+            .collect()
+    }
+
+    /// Return formatted in-text citation for `items` cited together.
+    pub fn citation(&self, items: &[CslItem]) -> String {
+        let section = match self.style.citation() {
+            Some(x) => x,
+            None => return String::new(),
+        };
+        let layout = match section.child("layout") {
+            Some(x) => x,
+            None => return String::new(),
+        };
+
+        // citation numbers follow the order in which items are given
+        let numbered: HashMap<&str, usize> = items.iter().enumerate().map(|(i, x)| (x.id.as_str(), i + 1)).collect();
+        let cites: Vec<_> = self
+            .sort_items(section, items)
+            .into_iter()
+            .map(|item| {
+                let ctx = self.context(section, item, numbered[item.id.as_str()], self.format);
+                self.render_children(&layout.children, &ctx, "").text
+            })
+            .filter(|x| !x.is_empty())
+            .collect();
+        let delimiter = self.format.escape(layout.attr("delimiter").unwrap_or_default());
+        self.decorate(layout, join_with_punctuation(&cites, &delimiter), self.format)
+    }
+
+    fn context<'a>(&self, section: &Node, item: &'a CslItem, number: usize, format: OutputFormat) -> RenderContext<'a> {
+        let inheritable = [
+            "and",
+            "delimiter-precedes-et-al",
+            "delimiter-precedes-last",
+            "et-al-min",
+            "et-al-use-first",
+            "initialize",
+            "initialize-with",
+            "name-as-sort-order",
+            "sort-separator",
+            "name-form",
+            "name-delimiter",
+            "names-delimiter",
+        ];
+        let mut name_opts = HashMap::new();
+        for node in [&self.style.root, section].iter() {
+            for opt in inheritable.iter() {
+                if let Some(v) = node.attr(opt) {
+                    let k = match *opt {
+                        "name-form" => "form",
+                        "name-delimiter" => "delimiter",
+                        k => k,
+                    };
+                    name_opts.insert(k.to_string(), v.to_string());
+                }
+            }
+        }
+        RenderContext {
+            item,
+            number,
+            format,
+            name_opts,
+            suppressed: RefCell::new(HashSet::new()),
+        }
+    }
+}
+// 46b1ce8e ends here
+
+// [[file:../zotero.note::c5e0a7b9][c5e0a7b9]]
+impl CitationFormatter {
+    fn render_node(&self, node: &Node, ctx: &RenderContext) -> Output {
+        match node.name.as_str() {
+            "text" => self.render_text(node, ctx),
+            "number" => self.render_number(node, ctx),
+            "label" => self.render_label(node, ctx),
+            "names" => self.render_names(node, ctx),
+            "date" => self.render_date(node, ctx),
+            "group" => self.render_group(node, ctx),
+            "choose" => self.render_choose(node, ctx),
+            _ => Output::default(),
+        }
+    }
+
+    fn render_children(&self, nodes: &[Node], ctx: &RenderContext, delimiter: &str) -> Output {
+        let outputs: Vec<_> = nodes.iter().map(|x| self.render_node(x, ctx)).collect();
+        let texts: Vec<_> = outputs
+            .iter()
+            .map(|x| x.text.clone())
+            .filter(|x| !x.is_empty())
+            .collect();
+        Output {
+            text: join_with_punctuation(&texts, &ctx.format.escape(delimiter)),
+            called: outputs.iter().any(|x| x.called),
+            rendered: outputs.iter().any(|x| x.rendered),
+        }
+    }
+
+    fn render_group(&self, node: &Node, ctx: &RenderContext) -> Output {
+        let out = self.render_children(&node.children, ctx, node.attr("delimiter").unwrap_or_default());
+        // suppress the group when all called variables are empty
+        if out.called && !out.rendered {
+            return Output {
+                called: true,
+                ..Default::default()
+            };
+        }
+        Output {
+            text: self.decorate(node, out.text, ctx.format),
+            ..out
+        }
+    }
+
+    fn render_choose(&self, node: &Node, ctx: &RenderContext) -> Output {
+        for branch in &node.children {
+            let ok = match branch.name.as_str() {
+                "if" | "else-if" => self.test_condition(branch, ctx),
+                "else" => true,
+                _ => false,
+            };
+            if ok {
+                return self.render_children(&branch.children, ctx, "");
+            }
+        }
+        Output::default()
+    }
+
+    fn test_condition(&self, node: &Node, ctx: &RenderContext) -> bool {
+        let mut results = vec![];
+        for (k, v) in &node.attrs {
+            let values = v.split_whitespace();
+            match k.as_str() {
+                "type" => results.extend(values.map(|t| ctx.item.item_type == t)),
+                "variable" => results.extend(values.map(|var| ctx.has_variable(var))),
+                "is-numeric" => {
+                    results.extend(values.map(|var| ctx.variable(var, "long").map(|x| is_numeric(&x)).unwrap_or(false)))
+                }
+                // only the first cite or bibliography is rendered here
+                "position" => results.extend(values.map(|p| p == "first")),
+                "is-uncertain-date" | "locator" | "disambiguate" => results.extend(values.map(|_| false)),
+                _ => {}
+            }
+        }
+        match node.attr("match").unwrap_or("all") {
+            "any" => results.iter().any(|x| *x),
+            "none" => !results.iter().any(|x| *x),
+            _ => results.iter().all(|x| *x),
+        }
+    }
+
+    fn render_text(&self, node: &Node, ctx: &RenderContext) -> Output {
+        if let Some(var) = node.attr("variable") {
+            let form = node.attr("form").unwrap_or("long");
+            let value = ctx.variable(var, form).unwrap_or_default();
+            let text = self.format_leaf(node, &value, ctx.format);
+            Output::variable(self.decorate(node, text, ctx.format))
+        } else if let Some(name) = node.attr("macro") {
+            let out = match self.style.macros.get(name) {
+                Some(m) => self.render_children(&m.children, ctx, ""),
+                None => {
+                    warn!("undefined macro: {}", name);
+                    Output::default()
+                }
+            };
+            Output {
+                text: self.decorate(node, out.text, ctx.format),
+                ..out
+            }
+        } else if let Some(term) = node.attr("term") {
+            let form = node.attr("form").unwrap_or("long");
+            let plural = node.attr("plural") == Some("true");
+            let value = self.locale.term(term, form, plural).unwrap_or_default();
+            let text = self.format_leaf(node, value, ctx.format);
+            Output::term(self.decorate(node, text, ctx.format))
+        } else if let Some(value) = node.attr("value") {
+            let text = self.format_leaf(node, value, ctx.format);
+            Output::term(self.decorate(node, text, ctx.format))
+        } else {
+            Output::default()
+        }
+    }
+
+    fn render_number(&self, node: &Node, ctx: &RenderContext) -> Output {
+        let var = node.attr("variable").unwrap_or_default();
+        let value = ctx.variable(var, "long").unwrap_or_default();
+        let value = match (node.attr("form"), value.trim().parse::<u32>()) {
+            (Some("ordinal"), Ok(n)) => format!("{}{}", n, self.ordinal_suffix(n)),
+            (Some("roman"), Ok(n)) => to_roman(n),
+            _ => value,
+        };
+        let text = self.format_leaf(node, &value, ctx.format);
+        Output::variable(self.decorate(node, text, ctx.format))
+    }
+
+    fn render_label(&self, node: &Node, ctx: &RenderContext) -> Output {
+        let var = node.attr("variable").unwrap_or_default();
+        let value = match ctx.variable(var, "long") {
+            Some(v) => v,
+            None => return Output::default(),
+        };
+        let plural = match node.attr("plural").unwrap_or("contextual") {
+            "always" => true,
+            "never" => false,
+            _ => value.contains(&['-', '–', ',', '&'][..]),
+        };
+        let term = if var == "locator" { "page" } else { var };
+        let value = self
+            .locale
+            .term(term, node.attr("form").unwrap_or("long"), plural)
+            .unwrap_or_default();
+        let text = self.format_leaf(node, value, ctx.format);
+        Output::term(self.decorate(node, text, ctx.format))
+    }
+
+    fn render_date(&self, node: &Node, ctx: &RenderContext) -> Output {
+        let var = node.attr("variable").unwrap_or_default();
+        let date = match ctx.item.dates.get(var) {
+            Some(d) if !ctx.is_suppressed(var) => *d,
+            _ => return Output::variable(String::new()),
+        };
+
+        let mut parts = vec![];
+        let delimiter;
+        if let Some(localized) = node.attr("form").and_then(|form| self.locale.dates.get(form)) {
+            let allowed: &[&str] = match node.attr("date-parts").unwrap_or("year-month-day") {
+                "year" => &["year"],
+                "year-month" => &["year", "month"],
+                _ => &["year", "month", "day"],
+            };
+            for part in localized.children_named("date-part") {
+                let name = part.attr("name").unwrap_or_default();
+                if !allowed.contains(&name) {
+                    continue;
+                }
+                // date-part attributes in style override localized ones
+                let mut part = part.clone();
+                if let Some(o) = node.children_named("date-part").find(|x| x.attr("name") == Some(name)) {
+                    for (k, v) in &o.attrs {
+                        part.attrs.insert(k.clone(), v.clone());
+                    }
+                }
+                parts.push(self.render_date_part(&part, date, ctx.format));
+            }
+            delimiter = localized.attr("delimiter").unwrap_or_default();
+        } else {
+            for part in node.children_named("date-part") {
+                parts.push(self.render_date_part(part, date, ctx.format));
+            }
+            delimiter = node.attr("delimiter").unwrap_or_default();
+        }
+        let parts: Vec<_> = parts.into_iter().filter(|x| !x.is_empty()).collect();
+        let text = join_with_punctuation(&parts, &ctx.format.escape(delimiter));
+        Output::variable(self.decorate(node, text, ctx.format))
+    }
+
+    fn render_date_part(&self, node: &Node, date: CslDate, format: OutputFormat) -> String {
+        let form = node.attr("form");
+        let value = match node.attr("name").unwrap_or_default() {
+            "year" => match form {
+                Some("short") => format!("{:02}", date.year.rem_euclid(100)),
+                _ => date.year.to_string(),
+            },
+            "month" if date.month > 0 => match form {
+                Some("numeric") => date.month.to_string(),
+                Some("numeric-leading-zeros") => format!("{:02}", date.month),
+                Some("short") => self.month_term(date.month, "short"),
+                _ => self.month_term(date.month, "long"),
+            },
+            "day" if date.day > 0 => match form {
+                Some("numeric-leading-zeros") => format!("{:02}", date.day),
+                Some("ordinal") => format!("{}{}", date.day, self.ordinal_suffix(date.day)),
+                _ => date.day.to_string(),
+            },
+            _ => String::new(),
+        };
+        let text = self.format_leaf(node, &value, format);
+        self.decorate(node, text, format)
+    }
+
+    fn month_term(&self, month: u32, form: &str) -> String {
+        let name = format!("month-{:02}", month);
+        self.locale.term(&name, form, false).unwrap_or_default().to_string()
+    }
+
+    fn ordinal_suffix(&self, n: u32) -> &str {
+        let name = match n % 100 {
+            11..=13 => format!("ordinal-{:02}", n % 100),
+            _ => format!("ordinal-{:02}", n % 10),
+        };
+        self.locale
+            .term(&name, "long", false)
+            .or_else(|| self.locale.term("ordinal", "long", false))
+            .unwrap_or_default()
+    }
+}
+// c5e0a7b9 ends here
+
+// [[file:../zotero.note::1fd6e35c][1fd6e35c]]
+impl CitationFormatter {
+    fn render_names(&self, node: &Node, ctx: &RenderContext) -> Output {
+        let name_node = node.child("name");
+        let label_node = node.child("label");
+        // label rendered before names when it comes first
+        let label_first =
+            node.children.iter().position(|x| x.name == "label") < node.children.iter().position(|x| x.name == "name");
+
+        let mut parts = vec![];
+        let mut called = false;
+        for var in node.attr("variable").unwrap_or_default().split_whitespace() {
+            if ctx.is_suppressed(var) {
+                continue;
+            }
+            called = true;
+            let names = match ctx.item.names.get(var) {
+                Some(names) if !names.is_empty() => names,
+                _ => continue,
+            };
+            let mut s = self.format_name_list(names, name_node, node.child("et-al"), ctx);
+            if let Some(label) = label_node {
+                let form = label.attr("form").unwrap_or("long");
+                let plural = match label.attr("plural").unwrap_or("contextual") {
+                    "always" => true,
+                    "never" => false,
+                    _ => names.len() > 1,
+                };
+                let term = self.locale.term(var, form, plural).unwrap_or_default();
+                let term = self.decorate(label, self.format_leaf(label, term, ctx.format), ctx.format);
+                s = if label_first { term + &s } else { s + &term };
+            }
+            parts.push(s);
+        }
+
+        if parts.is_empty() {
+            return self.render_substitute(node, ctx, called);
+        }
+        let delimiter = node
+            .attr("delimiter")
+            .or_else(|| ctx.name_opts.get("names-delimiter").map(|x| x.as_str()))
+            .unwrap_or(", ");
+        let text = join_with_punctuation(&parts, &ctx.format.escape(delimiter));
+        Output::variable(self.decorate(node, text, ctx.format))
+    }
+
+    fn render_substitute(&self, node: &Node, ctx: &RenderContext, called: bool) -> Output {
+        let substitute = match node.child("substitute") {
+            Some(x) => x,
+            None => {
+                return Output {
+                    called,
+                    ..Default::default()
+                }
+            }
+        };
+        for c in &substitute.children {
+            let out = if c.name == "names" && c.children.is_empty() {
+                // shorthand names element inherits name options from parent
+                let mut inherited = node.clone();
+                inherited
+                    .attrs
+                    .insert("variable".into(), c.attr("variable").unwrap_or_default().into());
+                inherited.children.retain(|x| x.name != "substitute");
+                self.render_names(&inherited, ctx)
+            } else {
+                self.render_node(c, ctx)
+            };
+            if !out.text.is_empty() {
+                // substituted variables will be suppressed in the rest of output
+                if let Some(vars) = c.attr("variable") {
+                    ctx.suppressed
+                        .borrow_mut()
+                        .extend(vars.split_whitespace().map(|x| x.to_string()));
+                }
+                return Output {
+                    text: self.decorate(node, out.text, ctx.format),
+                    ..out
+                };
+            }
+        }
+        Output {
+            called: true,
+            ..Default::default()
+        }
+    }
+
+    fn format_name_list(
+        &self,
+        names: &[CslName],
+        name_node: Option<&Node>,
+        et_al: Option<&Node>,
+        ctx: &RenderContext,
+    ) -> String {
+        let mut opts = ctx.name_opts.clone();
+        if let Some(n) = name_node {
+            for (k, v) in &n.attrs {
+                opts.insert(k.clone(), v.clone());
+            }
+        }
+        let opt = |k: &str| opts.get(k).map(|x| x.as_str());
+
+        let et_al_min: usize = opt("et-al-min").and_then(|x| x.parse().ok()).unwrap_or(0);
+        let et_al_use_first: usize = opt("et-al-use-first").and_then(|x| x.parse().ok()).unwrap_or(1);
+        let truncated = et_al_min > 0 && names.len() >= et_al_min && et_al_use_first < names.len();
+        let shown = if truncated {
+            &names[..et_al_use_first.max(1)]
+        } else {
+            names
+        };
+
+        let formatted: Vec<_> = shown
+            .iter()
+            .enumerate()
+            .map(|(i, name)| self.format_name(name, i, &opts, name_node, ctx.format))
+            .collect();
+
+        let delimiter = ctx.format.escape(opt("delimiter").unwrap_or(", "));
+        let inverted = opt("name-as-sort-order").is_some();
+        if truncated {
+            let et_al_term = et_al
+                .and_then(|x| x.attr("term"))
+                .and_then(|t| self.locale.term(t, "long", false))
+                .or_else(|| self.locale.term("et-al", "long", false))
+                .unwrap_or("et al.");
+            let et_al_term = match et_al {
+                Some(node) => self.decorate(node, ctx.format.escape(et_al_term), ctx.format),
+                None => ctx.format.escape(et_al_term),
+            };
+            let with_delimiter = match opt("delimiter-precedes-et-al").unwrap_or("contextual") {
+                "always" => true,
+                "never" => false,
+                "after-inverted-name" => inverted,
+                _ => formatted.len() > 1,
+            };
+            let sep = if with_delimiter { delimiter.clone() } else { " ".into() };
+            return format!("{}{}{}", formatted.join(&delimiter), sep, et_al_term);
+        }
+
+        if formatted.len() == 1 {
+            return formatted[0].clone();
+        }
+        let and = match opt("and") {
+            Some("text") => self.locale.term("and", "long", false).map(|x| ctx.format.escape(x)),
+            Some("symbol") => Some(ctx.format.escape("&")),
+            _ => None,
+        };
+        let n = formatted.len();
+        let head = formatted[..n - 1].join(&delimiter);
+        let last = &formatted[n - 1];
+        match and {
+            Some(and) => {
+                let with_delimiter = match opt("delimiter-precedes-last").unwrap_or("contextual") {
+                    "always" => true,
+                    "never" => false,
+                    "after-inverted-name" => inverted,
+                    _ => n > 2,
+                };
+                let sep = if with_delimiter { delimiter } else { " ".into() };
+                format!("{}{}{} {}", head, sep, and, last)
+            }
+            None => format!("{}{}{}", head, delimiter, last),
+        }
+    }
+
+    fn format_name(
+        &self,
+        name: &CslName,
+        i: usize,
+        opts: &HashMap<String, String>,
+        name_node: Option<&Node>,
+        format: OutputFormat,
+    ) -> String {
+        let opt = |k: &str| opts.get(k).map(|x| x.as_str());
+
+        let mut family = name.family.clone();
+        let mut given = name.given.clone();
+        if let Some(init) = opt("initialize-with") {
+            if opt("initialize") != Some("false") {
+                given = initialize_given_name(&given, init);
+            }
+        }
+        // text-case for name parts
+        if let Some(n) = name_node {
+            for part in n.children_named("name-part") {
+                match part.attr("name") {
+                    Some("family") => family = apply_text_case(part, &family),
+                    Some("given") => given = apply_text_case(part, &given),
+                    _ => {}
+                }
+            }
+        }
+        let family = format.escape(&family);
+        let given = format.escape(&given);
+
+        // institutional or single field name
+        if given.is_empty() || opt("form") == Some("short") {
+            return family;
+        }
+        let inverted = match opt("name-as-sort-order") {
+            Some("all") => true,
+            Some("first") => i == 0,
+            _ => false,
+        };
+        if inverted {
+            let sep = format.escape(opt("sort-separator").unwrap_or(", "));
+            format!("{}{}{}", family, sep, given)
+        } else {
+            format!("{} {}", given, family)
+        }
+    }
+}
+
+// "John Paul" => "J. P." with initialize-with ". "
+fn initialize_given_name(given: &str, init: &str) -> String {
+    let words: Vec<_> = given
+        .split_whitespace()
+        .map(|part| {
+            // "Jean-Paul" => "J.-P."
+            part.split('-')
+                .filter_map(|x| x.chars().next())
+                .map(|c| format!("{}{}", c, init.trim_end()))
+                .collect::<Vec<_>>()
+                .join("-")
+        })
+        .collect();
+    let sep = if init.ends_with(' ') { " " } else { "" };
+    words.join(sep)
+}
+// 1fd6e35c ends here
+
+// [[file:../zotero.note::8a3c50d1][8a3c50d1]]
+impl CitationFormatter {
+    // text-case, quotes, strip-periods then escaping for leaf values
+    fn format_leaf(&self, node: &Node, value: &str, format: OutputFormat) -> String {
+        if value.is_empty() {
+            return String::new();
+        }
+        let mut s = apply_text_case(node, value);
+        if node.attr("strip-periods") == Some("true") {
+            s = s.replace('.', "");
+        }
+        let mut s = format.escape(&s);
+        if node.attr("quotes") == Some("true") {
+            let open = self.locale.term("open-quote", "long", false).unwrap_or("\"");
+            let close = self.locale.term("close-quote", "long", false).unwrap_or("\"");
+            s = format!("{}{}{}", open, s, close);
+        }
+        s
+    }
+
+    // font formatting and affixes
+    fn decorate(&self, node: &Node, text: String, format: OutputFormat) -> String {
+        if text.is_empty() {
+            return text;
+        }
+        let mut s = text;
+        if node.attr("font-style") == Some("italic") || node.attr("font-style") == Some("oblique") {
+            s = format.italic(&s);
+        }
+        if node.attr("font-weight") == Some("bold") {
+            s = format.bold(&s);
+        }
+        if node.attr("text-decoration") == Some("underline") {
+            s = format.underline(&s);
+        }
+        match node.attr("vertical-align") {
+            Some("sup") => s = format.superscript(&s),
+            Some("sub") => s = format.subscript(&s),
+            _ => {}
+        }
+        let prefix = format.escape(node.attr("prefix").unwrap_or_default());
+        let suffix = format.escape(node.attr("suffix").unwrap_or_default());
+        append_with_punctuation(&(prefix + &s), &suffix)
+    }
+}
+
+fn apply_text_case(node: &Node, s: &str) -> String {
+    match node.attr("text-case") {
+        Some("lowercase") => s.to_lowercase(),
+        Some("uppercase") => s.to_uppercase(),
+        Some("capitalize-first") | Some("sentence") => capitalize(s),
+        Some("capitalize-all") => s.split(' ').map(capitalize).collect::<Vec<_>>().join(" "),
+        Some("title") => {
+            let stop_words = [
+                "a", "an", "and", "as", "at", "but", "by", "for", "from", "in", "into", "nor", "of", "on", "or", "the",
+                "to", "with",
+            ];
+            s.split(' ')
+                .enumerate()
+                .map(|(i, w)| {
+                    if i > 0 && stop_words.contains(&w) {
+                        w.to_string()
+                    } else {
+                        capitalize(w)
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join(" ")
+        }
+        _ => s.to_string(),
+    }
+}
+
+fn capitalize(s: &str) -> String {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) => c.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+fn is_numeric(s: &str) -> bool {
+    let s = s.trim();
+    !s.is_empty()
+        && s.chars().any(|c| c.is_ascii_digit())
+        && s.chars().all(|c| c.is_ascii_digit() || "-–,& ".contains(c))
+}
+
+fn to_roman(n: u32) -> String {
+    let table = [
+        (1000, "m"),
+        (900, "cm"),
+        (500, "d"),
+        (400, "cd"),
+        (100, "c"),
+        (90, "xc"),
+        (50, "l"),
+        (40, "xl"),
+        (10, "x"),
+        (9, "ix"),
+        (5, "v"),
+        (4, "iv"),
+        (1, "i"),
+    ];
+    let mut n = n;
+    let mut s = String::new();
+    for (v, r) in table.iter() {
+        while n >= *v {
+            s.push_str(r);
+            n -= v;
+        }
+    }
+    s
+}
+
+// append `suffix` avoiding duplicated punctuation, e.g. "ed." + ". "
+fn append_with_punctuation(s: &str, suffix: &str) -> String {
+    match (s.chars().last(), suffix.chars().next()) {
+        (Some(a), Some(b)) if a == b && ".,;:!?".contains(a) => format!("{}{}", s, &suffix[b.len_utf8()..]),
+        _ => format!("{}{}", s, suffix),
+    }
+}
+
+fn join_with_punctuation(parts: &[String], delimiter: &str) -> String {
+    let mut s = String::new();
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            s = append_with_punctuation(&s, delimiter);
+        }
+        s = append_with_punctuation(&s, part);
+    }
+    s
+}
+// 8a3c50d1 ends here
+
+// [[file:../zotero.note::e27d4b60][e27d4b60]]
+impl CitationFormatter {
+    fn sort_items<'a>(&self, section: &Node, items: &'a [CslItem]) -> Vec<&'a CslItem> {
+        let mut sorted: Vec<_> = items.iter().collect();
+        let keys: Vec<&Node> = match section.child("sort") {
+            Some(sort) => sort.children_named("key").collect(),
+            None => return sorted,
+        };
+
+        let sort_keys: HashMap<&str, Vec<String>> = items
+            .iter()
+            .enumerate()
+            .map(|(i, item)| {
+                let ctx = self.context(section, item, i + 1, OutputFormat::Plain);
+                let values = keys.iter().map(|k| self.sort_key(k, &ctx)).collect();
+                (item.id.as_str(), values)
+            })
+            .collect();
+
+        sorted.sort_by(|a, b| {
+            let (ka, kb) = (&sort_keys[a.id.as_str()], &sort_keys[b.id.as_str()]);
+            for (i, key) in keys.iter().enumerate() {
+                let (x, y) = (&ka[i], &kb[i]);
+                // empty values are always sorted last
+                let ord = match (x.is_empty(), y.is_empty()) {
+                    (true, false) => std::cmp::Ordering::Greater,
+                    (false, true) => std::cmp::Ordering::Less,
+                    _ if key.attr("sort") == Some("descending") => y.cmp(x),
+                    _ => x.cmp(y),
+                };
+                if ord != std::cmp::Ordering::Equal {
+                    return ord;
+                }
+            }
+            std::cmp::Ordering::Equal
+        });
+        sorted
+    }
+
+    fn sort_key(&self, key: &Node, ctx: &RenderContext) -> String {
+        if let Some(var) = key.attr("variable") {
+            if let Some(names) = ctx.item.names.get(var) {
+                return names
+                    .iter()
+                    .map(|x| format!("{} {}", x.family, x.given))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+                    .to_lowercase();
+            }
+            if let Some(d) = ctx.item.dates.get(var) {
+                return format!("{:04}{:02}{:02}", d.year, d.month, d.day);
+            }
+            ctx.variable(var, "long").unwrap_or_default().to_lowercase()
+        } else if let Some(name) = key.attr("macro") {
+            match self.style.macros.get(name) {
+                Some(m) => self.render_children(&m.children, ctx, "").text.to_lowercase(),
+                None => String::new(),
+            }
+        } else {
+            String::new()
+        }
+    }
+}
+// e27d4b60 ends here
+
+// [[file:../zotero.note::f0b7392e][f0b7392e]]
+#[tokio::main(flavor = "current_thread")]
+/// Format bibliography entries of zotero items in `keys` from zotero data dir
+/// `data_dir`. `style` is the name of a style installed in `styles` directory
+/// (e.g. "apa"), or path to a CSL style file. Locale files
+/// (locales-xx-XX.xml) are searched in `locales` directory.
+pub async fn format_bibliography(
+    data_dir: &Path,
+    keys: &[&str],
+    style: &str,
+    format: OutputFormat,
+) -> Result<Vec<String>> {
+    let style_file = find_style(data_dir, style).unwrap_or_else(|| PathBuf::from(style));
+    let style = CslStyle::from_file(&style_file)?;
+    let lang = style.default_locale().unwrap_or("en-US").to_string();
+    let locale = CslLocale::from_dir(data_dir.join("locales"), &lang)?;
+
+    let db_file = data_dir.join("zotero.sqlite");
+    let uri = db_file
+        .to_str()
+        .with_context(|| format!("invalid path: {:?}", db_file))?;
+    let db = ZoteroDb::connect(uri).await?;
+
+    let mut items = vec![];
+    for key in keys {
+        items.push(db.get_csl_item(key).await?);
+    }
+    let formatter = CitationFormatter::new(style, locale, format);
+    Ok(formatter.bibliography(&items))
+}
+// f0b7392e ends here
+
+// [[file:../zotero.note::*test][test:1]]
+#[test]
+fn test_csl_format() -> Result<()> {
+    let style = r#"
+<style xmlns="http://purl.org/net/xbiblio/csl" class="in-text" version="1.0">
+  <macro name="author">
+    <names variable="author">
+      <name name-as-sort-order="all" and="symbol" initialize-with=". " delimiter-precedes-last="always"/>
+    </names>
+  </macro>
+  <citation>
+    <layout prefix="(" suffix=")" delimiter="; ">
+      <group delimiter=", ">
+        <names variable="author"><name form="short" and="symbol"/></names>
+        <date variable="issued"><date-part name="year"/></date>
+      </group>
+    </layout>
+  </citation>
+  <bibliography>
+    <sort><key macro="author"/></sort>
+    <layout suffix=".">
+      <group delimiter=". ">
+        <text macro="author"/>
+        <date variable="issued" prefix="(" suffix=")"><date-part name="year"/></date>
+        <text variable="title"/>
+        <text variable="container-title" font-style="italic"/>
+      </group>
+    </layout>
+  </bibliography>
+</style>
+"#;
+    let mut item1 = CslItem::new("A", "article-journal");
+    item1.set_variable("title", "Zeolite catalysis");
+    item1.set_variable("container-title", "Journal of Tests");
+    item1.add_name("author", "Smith", "John");
+    item1.add_name("author", "Doe", "Anna");
+    item1.set_date("issued", CslDate::parse("2020-08-01 2020/08/01").unwrap());
+    let mut item2 = CslItem::new("B", "book");
+    item2.set_variable("title", "Brønsted acids");
+    item2.add_name("author", "Brown", "Bob");
+
+    let style = CslStyle::parse(style)?;
+    let formatter = CitationFormatter::new(style.clone(), CslLocale::default(), OutputFormat::Plain);
+    let bib = formatter.bibliography(&[item1.clone(), item2.clone()]);
+    assert_eq!(bib[0], "Brown, B. Brønsted acids.");
+    assert_eq!(
+        bib[1],
+        "Smith, J., & Doe, A. (2020). Zeolite catalysis. Journal of Tests."
+    );
+    assert_eq!(formatter.citation(&[item1.clone()]), "(Smith & Doe, 2020)");
+
+    let formatter = CitationFormatter::new(style.clone(), CslLocale::default(), OutputFormat::Html);
+    let bib = formatter.bibliography(&[item1.clone()]);
+    assert_eq!(
+        bib[0],
+        "Smith, J., &amp; Doe, A. (2020). Zeolite catalysis. <i>Journal of Tests</i>."
+    );
+
+    let formatter = CitationFormatter::new(style, CslLocale::default(), OutputFormat::Org);
+    let bib = formatter.bibliography(&[item1]);
+    assert_eq!(
+        bib[0],
+        "Smith, J., & Doe, A. (2020). Zeolite catalysis. /Journal of Tests/."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_csl_bibliography() -> Result<()> {
+    use crate::fixture::TestDb;
+
+    // a book title of the same item, which is not the container title
+    let test_db = TestDb::with_sql(
+        r#"
+INSERT INTO fields VALUES (115, 'bookTitle');
+INSERT INTO itemDataValues VALUES (11, 'Handbook of Catalysis');
+INSERT INTO itemData VALUES (1, 115, 11);
+"#,
+    );
+    let data_dir = test_db.path().parent().unwrap().to_owned();
+    std::fs::create_dir_all(data_dir.join("styles"))?;
+    std::fs::create_dir_all(data_dir.join("locales"))?;
+    let style = r#"<?xml version="1.0" encoding="utf-8"?>
+<style xmlns="http://purl.org/net/xbiblio/csl" class="in-text" version="1.0" default-locale="de-DE">
+  <info>
+    <title>Test Style</title>
+    <id>http://www.zotero.org/styles/test</id>
+  </info>
+  <citation>
+    <layout>
+      <names variable="author"><name form="short" and="text"/></names>
+    </layout>
+  </citation>
+  <bibliography>
+    <layout suffix=".">
+      <group delimiter=". ">
+        <names variable="author">
+          <name and="text" delimiter-precedes-last="never" initialize-with=". "/>
+        </names>
+        <date variable="issued"><date-part name="year"/></date>
+        <text variable="title"/>
+        <text variable="container-title"/>
+      </group>
+    </layout>
+  </bibliography>
+</style>
+"#;
+    std::fs::write(data_dir.join("styles/test.csl"), style)?;
+    std::fs::write(
+        data_dir.join("locales/locales-de-DE.xml"),
+        r#"<locale xmlns="http://purl.org/net/xbiblio/csl" version="1.0" xml:lang="de-DE"><terms><term name="and">und</term></terms></locale>"#,
+    )?;
+
+    let expected = "J. Smith und J. Doe. 2008. Zeolite catalysis of thiophene cracking. J. Catal.";
+    for _ in 0..5 {
+        let bib = format_bibliography(&data_dir, &["AAAAAAAA"], "test", OutputFormat::Plain)?;
+        assert_eq!(bib, vec![expected]);
+    }
+    // path to style file
+    let style_file = data_dir.join("styles/test.csl");
+    let bib = format_bibliography(
+        &data_dir,
+        &["AAAAAAAA"],
+        style_file.to_str().unwrap(),
+        OutputFormat::Plain,
+    )?;
+    assert_eq!(bib, vec![expected]);
+    assert!(format_bibliography(&data_dir, &["AAAAAAAA"], "nonexistent", OutputFormat::Plain).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_csl_locale() -> Result<()> {
+    use crate::fixture::TestDb;
+
+    // the built-in locale must not fall back to itself
+    let builtin = CslLocale::default();
+    assert_eq!(builtin.lang(), "en-US");
+    assert_eq!(builtin.term("et-al", "long", false), Some("et al."));
+
+    let locale = |lang: &str, terms: &str| {
+        format!(
+            r#"<locale xmlns="http://purl.org/net/xbiblio/csl" version="1.0" xml:lang="{}"><terms>{}</terms></locale>"#,
+            lang, terms
+        )
+    };
+    let test_db = TestDb::new();
+    let dir = test_db.path().parent().unwrap().to_owned();
+    let de = locale("de-DE", r#"<term name="and">und</term><term name="et-al">u. a.</term>"#);
+    std::fs::write(dir.join("locales-de-DE.xml"), de)?;
+    std::fs::write(
+        dir.join("locales-de-AT.xml"),
+        locale("de-AT", r#"<term name="and">und auch</term>"#),
+    )?;
+
+    // de-AT => de-DE => built-in en-US
+    let at = CslLocale::from_dir(&dir, "de-AT")?;
+    assert_eq!(at.lang(), "de-AT");
+    assert_eq!(at.term("and", "long", false), Some("und auch"));
+    assert_eq!(at.term("et-al", "long", false), Some("u. a."));
+    assert_eq!(at.term("month-01", "long", false), Some("January"));
+    // short language code for primary dialect
+    let de = CslLocale::from_dir(&dir, "de")?;
+    assert_eq!(de.lang(), "de-DE");
+    assert_eq!(de.term("and", "long", false), Some("und"));
+    // missing locale
+    assert_eq!(CslLocale::from_dir(&dir, "fr")?.lang(), "en-US");
+
+    // locale strings are parsed on top of built-in locale, even en-US ones
+    let us = CslLocale::parse(&locale("en-US", r#"<term name="and">plus</term>"#))?;
+    assert_eq!(us.term("and", "long", false), Some("plus"));
+    assert_eq!(us.term("page", "short", true), Some("pp."));
+    assert!(CslLocale::parse("<style/>").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_csl_names_and_dates() -> Result<()> {
+    let style = |name: &str, date: &str| {
+        format!(
+            r#"<style xmlns="http://purl.org/net/xbiblio/csl" class="in-text" version="1.0">
+  <citation><layout><names variable="author">{}</names></layout></citation>
+  <bibliography><layout>{}</layout></bibliography>
+</style>"#,
+            name, date
+        )
+    };
+    let date = |parts: &str| format!(r#"<date variable="issued">{}</date>"#, parts);
+    let format = |name: &str, date: &str, item: &CslItem| -> Result<(String, String)> {
+        let style = CslStyle::parse(&style(name, date))?;
+        let formatter = CitationFormatter::new(style, CslLocale::default(), OutputFormat::Plain);
+        let items = [item.clone()];
+        Ok((formatter.citation(&items), formatter.bibliography(&items).join("")))
+    };
+
+    let mut item = CslItem::new("A", "article-journal");
+    item.add_name("author", "Smith", "John Paul");
+    item.add_name("author", "Doe", "Jean-Luc");
+    item.add_name("author", "Brown", "Anna");
+    item.set_date("issued", CslDate::parse("2021-03-02 2021/03/02").unwrap());
+
+    // et-al and initials
+    let name = r#"<name et-al-min="3" et-al-use-first="1" initialize-with=". "/>"#;
+    assert_eq!(format(name, "", &item)?.0, "J. P. Smith et al.");
+    let name = r#"<name et-al-min="3" et-al-use-first="2" initialize-with="." name-as-sort-order="first"/>"#;
+    assert_eq!(format(name, "", &item)?.0, "Smith, J.P., J.-L. Doe, et al.");
+    let name = r#"<name and="text" delimiter-precedes-last="never"/>"#;
+    assert_eq!(
+        format(name, "", &item)?.0,
+        "John Paul Smith, Jean-Luc Doe and Anna Brown"
+    );
+    let name = r#"<name initialize-with=". " initialize="false"/>"#;
+    assert!(format(name, "", &item)?.0.starts_with("John Paul Smith"));
+
+    // date parts
+    let parts = r#"<date-part name="month" suffix=" "/><date-part name="day" form="ordinal" suffix=", "/><date-part name="year"/>"#;
+    assert_eq!(format("<name/>", &date(parts), &item)?.1, "March 2nd, 2021");
+    let parts =
+        r#"<date-part name="year" form="short" suffix="/"/><date-part name="month" form="numeric-leading-zeros"/>"#;
+    assert_eq!(format("<name/>", &date(parts), &item)?.1, "21/03");
+    // localized date form with limited parts; missing month and day are omitted
+    let localized = r#"<date variable="issued" form="numeric" date-parts="year-month"/>"#;
+    assert_eq!(format("<name/>", localized, &item)?.1, "03/2021");
+    item.set_date("issued", CslDate::parse("2021-00-00 2021").unwrap());
+    let parts = r#"<date-part name="month" suffix=" "/><date-part name="year"/>"#;
+    assert_eq!(format("<name/>", &date(parts), &item)?.1, "2021");
+
+    Ok(())
+}
+// test:1 ends here
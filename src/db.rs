@@ -4,6 +4,10 @@ use std::path::{Path, PathBuf};
 
 use sqlx::prelude::*;
 use sqlx::sqlite::SqlitePool;
+
+use crate::attachment::AttachmentPaths;
+use crate::query::ItemQuery;
+use crate::trash::TrashFilter;
 // imports:1 ends here
 
 // [[file:../zotero.note::b64609c9][b64609c9]]
@@ -97,12 +101,14 @@ fn test_str_width() {
 // alignment str:1 ends here
 
 // [[file:../zotero.note::*item][item:1]]
-#[derive(sqlx::FromRow, Debug, Default)]
+#[derive(sqlx::FromRow, Debug, Clone, Default)]
 pub struct Item {
     key: String,
     extra: String,
     date: String,
     title: String,
+    #[sqlx(default)]
+    citekey: String,
 }
 
 impl std::fmt::Display for Item {
@@ -147,6 +153,77 @@ impl Item {
     pub fn item_link(&self) -> String {
         format!("zotero://select/items/1_{}", self.key)
     }
+
+    /// Zotero item key
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Item title
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    /// Publication year, or "0000" if unknown
+    pub fn date(&self) -> &str {
+        &self.date
+    }
+
+    /// Item extra field
+    pub fn extra(&self) -> &str {
+        &self.extra
+    }
+
+    /// Citation key from Better BibTeX, or `Citation Key` field in zotero 7
+    pub fn citekey(&self) -> Option<&str> {
+        if self.citekey.is_empty() {
+            None
+        } else {
+            Some(&self.citekey)
+        }
+    }
+
+    pub(crate) fn set_citekey(&mut self, citekey: &str) {
+        self.citekey = citekey.into();
+    }
+
+    /// Construct item in `key` from item fields in `d`. Only the year part
+    /// of "date" field is kept.
+    pub(crate) fn from_fields(key: &str, d: &Map) -> Self {
+        let date = d.get("date").map(|x| x.as_str()).unwrap_or_default();
+        let date = match date.get(..4) {
+            Some(year) if year.chars().all(|c| c.is_ascii_digit()) => year.to_string(),
+            _ => "0000".to_string(),
+        };
+        let extra = d.get("extra").cloned().unwrap_or_default();
+        let citekey = d
+            .get("citationKey")
+            .map(|x| x.as_str())
+            .or_else(|| citekey_from_extra(&extra))
+            .unwrap_or_default()
+            .to_string();
+        Self {
+            key: key.into(),
+            title: d.get("title").cloned().unwrap_or_default(),
+            date,
+            extra,
+            citekey,
+        }
+    }
+}
+
+/// Parse citation key pinned in extra field by Better BibTeX, e.g.
+/// "Citation Key: smith2020catalysis"
+pub(crate) fn citekey_from_extra(extra: &str) -> Option<&str> {
+    extra.lines().find_map(|line| {
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next()?.trim();
+        if name.eq_ignore_ascii_case("citation key") {
+            Some(parts.next()?.trim()).filter(|x| !x.is_empty())
+        } else {
+            None
+        }
+    })
 }
 
 // 4VH9GANA => 2009 | Do Quantum Mechanical Energies Calculated for Small Models of Protein-Active Sites Converge?†        |
@@ -194,37 +271,54 @@ struct KvRec {
 }
 // rec:1 ends here
 
+// [[file:../zotero.note::3b9d0f62][3b9d0f62]]
+// Max number of keys bound in one query, below the limit of host parameters
+// in old sqlite (999)
+const MAX_KEYS_IN_QUERY: usize = 500;
+
+/// Return placeholders for binding `n` parameters, e.g. "?, ?, ?"
+fn placeholders(n: usize) -> String {
+    vec!["?"; n].join(", ")
+}
+
+impl ZoteroDb {
+    /// Fetch rows of `sql` for items in `keys`, with `{keys}` in `sql`
+    /// replaced by placeholders. Keys are bound in chunks to keep in sqlite
+    /// limit of host parameters.
+    pub(crate) async fn fetch_by_keys(&self, sql: &str, keys: &[String]) -> Result<Vec<sqlx::sqlite::SqliteRow>> {
+        let mut rows = vec![];
+        for chunk in keys.chunks(MAX_KEYS_IN_QUERY) {
+            let sql = sql.replace("{keys}", &placeholders(chunk.len()));
+            let mut query = sqlx::query(&sql);
+            for key in chunk {
+                query = query.bind(key);
+            }
+            rows.extend(query.fetch_all(self.pool()).await?);
+        }
+        Ok(rows)
+    }
+}
+// 3b9d0f62 ends here
+
 // [[file:../zotero.note::*tags][tags:1]]
-type Map = std::collections::HashMap<String, String>;
+pub(crate) type Map = std::collections::HashMap<String, String>;
 
 impl ZoteroDb {
     /// Search zotero items by `tag`
     async fn get_items_by_tag(&self, tag: &str) -> Result<Vec<Item>> {
-        let items = sqlx::query_as::<_, KvRec>(
-            r#"
-SELECT items.key as key, tags.name as value FROM items
-    JOIN itemTags USING (itemID)
-    JOIN tags USING (tagID)
-    WHERE LOWER(name) like ?
-    -- exclude deleted items
-    AND items.itemID NOT IN (select itemID from deletedItems)
-"#,
-        )
-        .bind(format!("%{}%", tag.to_lowercase()))
-        .fetch_all(self.pool())
-        .await?;
-
-        // get other fields, such as title and date
-        let mut all = vec![];
-        for x in items {
-            let item = self.get_item(&x.key).await?;
-            all.push(item);
-        }
-        Ok(all)
+        self.get_items_by_query(&ItemQuery::Tag(tag.into()), TrashFilter::default()).await
     }
 
     /// Get zotero item in `key` will interesting fields filled.
-    async fn get_item(&self, key: &str) -> Result<Item> {
+    pub(crate) async fn get_item(&self, key: &str) -> Result<Item> {
+        let found = sqlx::query("SELECT 1 FROM items WHERE key = ?")
+            .bind(key)
+            .fetch_optional(self.pool())
+            .await?;
+        if found.is_none() {
+            bail!("item not found: {}", key);
+        }
+
         let sql = r#"
 SELECT fields.FieldName as key, itemDataValues.value as value
     FROM itemData
@@ -232,104 +326,153 @@ SELECT fields.FieldName as key, itemDataValues.value as value
     LEFT JOIN fields ON itemData.fieldID = fields.fieldID
     LEFT JOIN itemDataValues ON itemData.valueID = itemDataValues.valueID
     WHERE items.key = ?
-      AND fields.fieldName IN ("extra", "date", "publicationTitle", "title")
+      AND fields.fieldName IN ("extra", "date", "publicationTitle", "title", "citationKey")
 "#;
 
         let recs = sqlx::query_as::<_, KvRec>(sql).bind(key).fetch_all(self.pool()).await?;
         let d: Map = recs.into_iter().map(|x| (x.key, x.value)).collect();
-        let item = Item {
-            key: key.into(),
-            extra: d.get("extra").unwrap_or(&String::new()).to_string(),
-            title: d.get("title").unwrap_or(&String::new()).to_string(),
-            date: d.get("date").unwrap_or(&"0000".to_string())[..4].to_string(),
-            ..Default::default()
-        };
+        let item = Item::from_fields(key, &d);
 
         Ok(item)
     }
 }
 // tags:1 ends here
 
-// [[file:../zotero.note::*collection][collection:1]]
+// [[file:../zotero.note::5d0c7e21][5d0c7e21]]
+/// A creator (author, editor, ...) of zotero item
+#[derive(sqlx::FromRow, Debug, Clone, Default)]
+pub struct ItemCreator {
+    pub first_name: String,
+    pub last_name: String,
+    pub creator_type: String,
+}
+
 impl ZoteroDb {
-    /// Search zotero items by `collection`
-    async fn get_items_by_collection(&self, collection: &str) -> Result<Vec<Item>> {
-        let items = sqlx::query_as::<_, KvRec>(
+    /// Return the type name of item in `key`, e.g. journalArticle
+    pub(crate) async fn get_item_type(&self, key: &str) -> Result<String> {
+        let rec = sqlx::query(
             r#"
-SELECT items.key as key, collectionName as value from collections
-    JOIN collectionItems USING (collectionID)
-    JOIN items using (itemID)
-    WHERE LOWER(collections.collectionName) like ?
+SELECT itemTypes.typeName as typeName
+FROM items
+JOIN itemTypes USING (itemTypeID)
+WHERE items.key = ?
 "#,
         )
-        .bind(format!("%{}%", collection.to_lowercase()))
-        .fetch_all(self.pool())
+        .bind(key)
+        .fetch_one(self.pool())
         .await?;
 
-        // get other fields, such as title and date
-        let mut all = vec![];
-        for x in items {
-            let item = self.get_item(&x.key).await?;
-            all.push(item);
-        }
-        Ok(all)
+        Ok(rec.try_get("typeName")?)
     }
-}
-// collection:1 ends here
 
-// [[file:../zotero.note::8ae891d8][8ae891d8]]
-impl ZoteroDb {
-    // key: "2X4DGF8X",
-    // value: "http://zotero.org/users/15074/items/9F6B5E9G",
-    async fn get_related_items(&self, key: &str) -> Result<Vec<Item>> {
-        let recs = sqlx::query_as::<_, KvRec>(
+    /// Return all fields of item in `key` as field name => value
+    pub(crate) async fn get_item_fields(&self, key: &str) -> Result<Map> {
+        let sql = r#"
+SELECT fields.FieldName as key, itemDataValues.value as value
+    FROM itemData
+    LEFT JOIN items ON itemData.itemID = items.itemID
+    LEFT JOIN fields ON itemData.fieldID = fields.fieldID
+    LEFT JOIN itemDataValues ON itemData.valueID = itemDataValues.valueID
+    WHERE items.key = ?
+"#;
+
+        let recs = sqlx::query_as::<_, KvRec>(sql).bind(key).fetch_all(self.pool()).await?;
+        Ok(recs.into_iter().map(|x| (x.key, x.value)).collect())
+    }
+
+    /// Return creators of item in `key` in their display order
+    pub(crate) async fn get_item_creators(&self, key: &str) -> Result<Vec<ItemCreator>> {
+        let creators = sqlx::query_as::<_, ItemCreator>(
             r#"
-SELECT items.key as key, itemRelations.object as value FROM items
-JOIN itemRelations USING (itemID)
-WHERE predicateID == 2
-      -- only matching parent item, not attachment
-      AND itemTypeID != 14
-      -- exclude deleted items
-      AND items.itemID NOT IN (select itemID from deletedItems)
-      AND items.key = ?
+SELECT IFNULL(creators.firstName, '') as first_name,
+       IFNULL(creators.lastName, '') as last_name,
+       creatorTypes.creatorType as creator_type
+FROM itemCreators
+JOIN items USING (itemID)
+JOIN creators USING (creatorID)
+JOIN creatorTypes USING (creatorTypeID)
+WHERE items.key = ?
+ORDER BY itemCreators.orderIndex
 "#,
         )
         .bind(key)
         .fetch_all(self.pool())
         .await?;
 
-        let mut related = vec![];
-        for rec in recs {
-            if let Some(key) = parse_zotero_key_from_object_url(&rec.value) {
-                let item = self.get_item(&key).await?;
-                related.push(item);
-            }
-        }
-        Ok(related)
+        Ok(creators)
     }
 }
+// 5d0c7e21 ends here
+
+// [[file:../zotero.note::*collection][collection:1]]
+impl ZoteroDb {
+    /// Search zotero items by `collection`
+    async fn get_items_by_collection(&self, collection: &str) -> Result<Vec<Item>> {
+        self.get_items_by_query(&ItemQuery::Collection(collection.into()), TrashFilter::default()).await
+    }
+}
+// collection:1 ends here
+
+// [[file:../zotero.note::e5b27c14][e5b27c14]]
+/// A zotero collection
+#[derive(sqlx::FromRow, Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Collection {
+    pub key: String,
+    pub name: String,
+    /// key of parent collection
+    pub parent: Option<String>,
+}
+
+impl ZoteroDb {
+    /// Return all collections filtered by `trash`
+    pub(crate) async fn get_collections(&self, trash: TrashFilter) -> Result<Vec<Collection>> {
+        let trashed = self.trashed_collections().await?;
+        let sql = format!(
+            r#"
+SELECT c.key as key, c.collectionName as name, p.key as parent
+FROM collections c
+LEFT JOIN collections p ON c.parentCollectionID = p.collectionID
+WHERE {}
+ORDER BY c.collectionName
+"#,
+            trash.sql_in(&["c.collectionID"], trashed)
+        );
+        let collections = sqlx::query_as::<_, Collection>(&sql).fetch_all(self.pool()).await?;
+
+        Ok(collections)
+    }
 
+    /// Return names of all tags in use by items filtered by `trash`
+    pub(crate) async fn get_tags(&self, trash: TrashFilter) -> Result<Vec<String>> {
+        let sql = format!(
+            r#"
+SELECT DISTINCT tags.name as name FROM tags
+JOIN itemTags USING (tagID)
+WHERE {}
+ORDER BY tags.name
+"#,
+            trash.sql(&["itemTags.itemID"])
+        );
+        let recs = sqlx::query(&sql).fetch_all(self.pool()).await?;
+
+        let tags = recs.iter().map(|x| x.try_get("name")).collect::<Result<_, _>>()?;
+        Ok(tags)
+    }
+}
+// e5b27c14 ends here
+
+// [[file:../zotero.note::8ae891d8][8ae891d8]]
 /// Return related items with item in `key`
 async fn get_related_items_from_key(key: &str) -> Result<Vec<Item>> {
     let db = ZoteroDb::connect(DB_FILE).await?;
-    let items = db.get_related_items(key).await?;
+    let items = db.get_related_items(key, TrashFilter::default()).await?;
     Ok(items)
 }
 
-// key  object
-// | 2787B283 | http://zotero.org/users/15074/items/M2S2HTNN |
-fn parse_zotero_key_from_object_url(url: &str) -> Option<String> {
-    if url.starts_with("http://zotero.org") {
-        url.rsplit("items/").next().map(|x| x.to_string())
-    } else {
-        None
-    }
-}
-
 #[test]
 fn test_key_from_object_url() {
     let url = "http://zotero.org/users/15074/items/M2S2HTNN";
-    let parsed_key = parse_zotero_key_from_object_url(url);
+    let parsed_key = crate::relation::ObjectUri::parse(url).map(|x| x.key);
     assert_eq!(parsed_key, Some("M2S2HTNN".to_string()))
 }
 // 8ae891d8 ends here
@@ -342,62 +485,87 @@ struct Attachment {
 }
 
 impl ZoteroDb {
-    /// Return the list of attachment for item in `key`
-    async fn get_attachments(&self, key: &str) -> Result<Vec<Attachment>> {
-        let attachments = sqlx::query_as::<_, Attachment>(
+    /// Return the list of attachment for item in `key`. Attachments are
+    /// trashed with their parent item.
+    async fn get_attachments(&self, key: &str, trash: TrashFilter) -> Result<Vec<Attachment>> {
+        let sql = format!(
             r#"
 SELECT itemAttachments.itemID as id, itemAttachments.path as path
 FROM items, itemAttachments
 WHERE itemAttachments.path is not null
   AND itemAttachments.parentItemID = items.itemID
   AND (itemAttachments.contentType = "application/pdf" OR itemAttachments.contentType = "application/x-note")
-  -- exclude deleted items
-  AND itemAttachments.itemID NOT IN (select itemID from deletedItems)
+  AND {}
   AND items.key = ?
 "#,
-        )
-        .bind(key)
-        .fetch_all(self.pool())
-        .await?;
+            trash.sql(&["itemAttachments.itemID", "itemAttachments.parentItemID"])
+        );
+        let attachments = sqlx::query_as::<_, Attachment>(&sql)
+            .bind(key)
+            .fetch_all(self.pool())
+            .await?;
 
         Ok(attachments)
     }
 }
 
+impl ZoteroDb {
+    /// Return full paths of .pdf/.note attachements associated with the item
+    /// in `key`, resolved by `paths`.
+    pub(crate) async fn get_attachment_paths(
+        &self,
+        key: &str,
+        paths: &AttachmentPaths,
+        trash: TrashFilter,
+    ) -> Result<Vec<String>> {
+        let mut all = vec![];
+        for attachment in self.get_attachments(key, trash).await? {
+            let p = attachment.path;
+            let k = self.get_item_key(attachment.id).await?;
+            match paths.resolve(&k, &p) {
+                Some(file) => {
+                    if !file.exists() {
+                        warn!("attachment file not found: {:?}", file);
+                    }
+                    all.push(file.display().to_string());
+                }
+                None => warn!("cannot resolve attachment path {} in {}", p, k),
+            }
+        }
+        Ok(all)
+    }
+}
+
 /// Return .pdf/.note attachements associated with the item in `key`
 async fn get_attachment_paths_from_key(key: &str) -> Result<Vec<String>> {
     let db = ZoteroDb::connect(DB_FILE).await?;
-
-    let mut all = vec![];
-    for attachment in db.get_attachments(key).await? {
-        let p = attachment.path;
-        let k = db.get_item_key(attachment.id).await?;
-        all.push(full_attachment_path(&k, &p));
-    }
-    Ok(all)
+    db.get_attachment_paths(key, &AttachmentPaths::default(), TrashFilter::default()).await
 }
+// c91d3b45 ends here
 
-// zotero's attachment path may have a "storage:" prefix
-fn full_attachment_path(key: &str, path: &str) -> String {
-    // FIXME: auto detect from zotero config
-    // FIXME: dirty hack
-    let zotero_storage_root = "/home/ybyygu/Documents/Data/zotero/storage";
-    let attach_path = if path.starts_with("storage:") { &path[8..] } else { path };
-    format!("{}/{}/{}", zotero_storage_root, key, attach_path)
+// [[file:../zotero.note::7a1f6d08][7a1f6d08]]
+impl ZoteroDb {
+    /// Quick search regular items with all words in `keyword` matching
+    /// title, creators or date.
+    pub(crate) async fn get_items_dwim(&self, keyword: &str, trash: TrashFilter) -> Result<Vec<Item>> {
+        match ItemQuery::words(keyword) {
+            Some(query) => self.get_items_by_query(&query, trash).await,
+            None => Ok(vec![]),
+        }
+    }
 }
-// c91d3b45 ends here
+// 7a1f6d08 ends here
 
 // [[file:../zotero.note::cdcbd2e6][cdcbd2e6]]
-static DB_FILE: &str = "/home/ybyygu/Documents/Data/zotero/zotero.sqlite";
+pub(crate) static DB_FILE: &str = "/home/ybyygu/Documents/Data/zotero/zotero.sqlite";
 
 #[tokio::main(flavor = "current_thread")]
 /// Quick search zotero items
 pub async fn get_items_dwim(keyword: &str) -> Result<Vec<Item>> {
     let db = ZoteroDb::connect(DB_FILE).await?;
 
-    // let items = db.get_items_dwim(keyword).await?;
-    // Ok(items)
-    todo!();
+    let items = db.get_items_dwim(keyword, TrashFilter::default()).await?;
+    Ok(items)
 }
 
 /// Extract item key from link in zotero protocol
@@ -455,7 +623,7 @@ async fn test_db() -> Result<()> {
     let x = get_attachment_paths_from_key("I9BXB5GH").await?;
     dbg!(x);
 
-    let x = zotero.get_related_items("FU5SDYIA").await?;
+    let x = zotero.get_related_items("FU5SDYIA", TrashFilter::default()).await?;
     dbg!(x);
 
     Ok(())
@@ -0,0 +1,470 @@
+// [[file:../zotero.note::*imports][imports:1]]
+use gut::prelude::*;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use sqlx::prelude::*;
+
+use crate::backend::SqliteBackend;
+use crate::db::ZoteroDb;
+use crate::trash::TrashFilter;
+// imports:1 ends here
+
+// [[file:../zotero.note::71c5e0ad][71c5e0ad]]
+// What duplicate detection needs to know about an item
+#[derive(Debug, Clone, Default)]
+struct Record {
+    key: String,
+    title: String,
+    year: Option<i64>,
+    first_author: Option<String>,
+    doi: Option<String>,
+    isbn: Option<String>,
+    date_added: String,
+    // number of fields, creators and child items filled
+    completeness: usize,
+}
+
+// Fold some accented latin letters, which are common in titles and names
+fn fold_char(c: char) -> char {
+    const FROM: &str = "àáâãäåçèéêëìíîïñòóôõöøùúûüýÿ";
+    const TO: &str = "aaaaaaceeeeiiiinoooooouuuuyy";
+    match FROM.chars().position(|x| x == c) {
+        Some(i) => TO.chars().nth(i).unwrap_or(c),
+        None => c,
+    }
+}
+
+/// Normalize `s` for comparison: lower case, accents removed, punctuation
+/// and spaces dropped. Letters in other scripts such as CJK are kept.
+fn normalize(s: &str) -> String {
+    s.chars()
+        .flat_map(|c| c.to_lowercase())
+        .map(fold_char)
+        .filter(|c| c.is_alphanumeric())
+        .collect()
+}
+
+fn normalize_doi(doi: &str) -> Option<String> {
+    let doi = doi.trim().to_lowercase();
+    let doi = ["https://doi.org/", "http://dx.doi.org/", "doi:"]
+        .iter()
+        .fold(doi.as_str(), |d, prefix| d.strip_prefix(prefix).unwrap_or(d))
+        .trim()
+        .to_string();
+    Some(doi).filter(|x| x.starts_with("10."))
+}
+
+// Normalize ISBN to ISBN-13 digits
+fn normalize_isbn(isbn: &str) -> Option<String> {
+    let digits: String = isbn
+        .chars()
+        .filter(|c| c.is_ascii_digit() || *c == 'X' || *c == 'x')
+        .collect();
+    match digits.len() {
+        13 => Some(digits),
+        10 => {
+            let isbn13 = format!("978{}", &digits[..9]);
+            let sum: u32 = isbn13
+                .chars()
+                .enumerate()
+                .map(|(i, c)| c.to_digit(10).unwrap_or(0) * if i % 2 == 0 { 1 } else { 3 })
+                .sum();
+            Some(format!("{}{}", isbn13, (10 - sum % 10) % 10))
+        }
+        _ => None,
+    }
+}
+
+// Dice coefficient over character bigrams of normalized strings, which works
+// for scripts without spaces between words too.
+fn similarity(a: &str, b: &str) -> f64 {
+    if a == b {
+        return 1.0;
+    }
+    let bigrams = |s: &str| -> HashMap<(char, char), usize> {
+        let chars: Vec<_> = s.chars().collect();
+        let mut counts = HashMap::new();
+        for w in chars.windows(2) {
+            *counts.entry((w[0], w[1])).or_insert(0) += 1;
+        }
+        counts
+    };
+    let (x, y) = (bigrams(a), bigrams(b));
+    let total: usize = x.values().sum::<usize>() + y.values().sum::<usize>();
+    if total == 0 {
+        return 0.0;
+    }
+    let common: usize = x.iter().map(|(k, n)| (*n).min(*y.get(k).unwrap_or(&0))).sum();
+    2.0 * common as f64 / total as f64
+}
+
+impl ZoteroDb {
+    // Load regular items filtered by `trash` for duplicate detection
+    async fn get_duplicate_records(&self, trash: TrashFilter) -> Result<Vec<Record>> {
+        let regular = format!(
+            r#"
+{}
+  AND items.itemID NOT IN (select itemID from itemAttachments)
+  AND items.itemID NOT IN (select itemID from itemNotes)
+  AND items.itemTypeID NOT IN (select itemTypeID from itemTypes where typeName = 'annotation')
+"#,
+            trash.sql(&["items.itemID"])
+        );
+        let sql = format!(
+            r#"
+SELECT items.key as key, CAST(items.dateAdded AS TEXT) as date_added,
+  (SELECT COUNT(*) FROM itemData WHERE itemData.itemID = items.itemID)
+  + (SELECT COUNT(*) FROM itemCreators WHERE itemCreators.itemID = items.itemID)
+  + (SELECT COUNT(*) FROM itemAttachments WHERE itemAttachments.parentItemID = items.itemID)
+  + (SELECT COUNT(*) FROM itemNotes WHERE itemNotes.parentItemID = items.itemID) as completeness
+FROM items WHERE {}
+"#,
+            regular
+        );
+        let mut records: BTreeMap<String, Record> = BTreeMap::new();
+        for rec in sqlx::query(&sql).fetch_all(self.pool()).await? {
+            let key: String = rec.try_get("key")?;
+            let record = Record {
+                key: key.clone(),
+                date_added: rec.try_get("date_added")?,
+                completeness: rec.try_get::<i64, _>("completeness")? as usize,
+                ..Default::default()
+            };
+            records.insert(key, record);
+        }
+
+        let sql = format!(
+            r#"
+SELECT items.key as key, fields.fieldName as name, itemDataValues.value as value
+FROM items
+    JOIN itemData USING (itemID)
+    JOIN fields USING (fieldID)
+    JOIN itemDataValues USING (valueID)
+WHERE fields.fieldName IN ('title', 'date', 'DOI', 'ISBN', 'extra') AND {}
+"#,
+            regular
+        );
+        for rec in sqlx::query(&sql).fetch_all(self.pool()).await? {
+            let key: String = rec.try_get("key")?;
+            let name: String = rec.try_get("name")?;
+            let value: String = rec.try_get("value")?;
+            let record = match records.get_mut(&key) {
+                Some(r) => r,
+                None => continue,
+            };
+            match name.as_str() {
+                "title" => record.title = value,
+                "date" => record.year = value.get(..4).and_then(|x| x.parse().ok()).filter(|&x| x > 0),
+                "DOI" => record.doi = normalize_doi(&value),
+                "ISBN" => record.isbn = value.split_whitespace().find_map(normalize_isbn),
+                // DOI of item types without DOI field is kept in extra
+                "extra" if record.doi.is_none() => {
+                    record.doi = value
+                        .lines()
+                        .find_map(|x| x.strip_prefix("DOI:"))
+                        .and_then(normalize_doi);
+                }
+                _ => {}
+            }
+        }
+
+        // the primary creator type of item type, e.g. author rather than
+        // editor for journal articles
+        let primary = if self.has_table("itemTypeCreatorTypes").await? {
+            "SELECT creatorTypeID FROM itemTypeCreatorTypes
+        WHERE itemTypeCreatorTypes.itemTypeID = items.itemTypeID AND primaryField = 1"
+        } else {
+            "SELECT creatorTypeID FROM creatorTypes WHERE creatorType = 'author'"
+        };
+        let sql = format!(
+            r#"
+SELECT items.key as key, IFNULL(creators.lastName, '') as name FROM itemCreators
+    JOIN items USING (itemID)
+    JOIN creators USING (creatorID)
+WHERE itemCreators.creatorTypeID IN ({})
+ORDER BY itemCreators.orderIndex DESC
+"#,
+            primary
+        );
+        let recs = sqlx::query(&sql).fetch_all(self.pool()).await?;
+        // the first author comes last
+        for rec in recs {
+            let key: String = rec.try_get("key")?;
+            let name: String = rec.try_get("name")?;
+            if let Some(record) = records.get_mut(&key) {
+                record.first_author = Some(normalize(&name)).filter(|x| !x.is_empty());
+            }
+        }
+
+        Ok(records.into_iter().map(|x| x.1).collect())
+    }
+}
+// 71c5e0ad ends here
+
+// [[file:../zotero.note::c8f12b3e][c8f12b3e]]
+/// Items considered duplicates of each other
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DuplicateCluster {
+    /// Keys of duplicate items, the recommended one to keep first
+    pub keys: Vec<String>,
+    /// The most complete item, recommended as master when merging
+    pub keep: String,
+    /// What the items have in common: "doi", "isbn" or "title"
+    pub reasons: Vec<String>,
+}
+
+/// Find duplicate items by identifiers and fuzzy title matching
+#[derive(Debug, Clone)]
+pub struct DuplicateFinder {
+    title_threshold: f64,
+    year_tolerance: i64,
+    match_author: bool,
+}
+
+impl Default for DuplicateFinder {
+    fn default() -> Self {
+        Self {
+            title_threshold: 0.9,
+            year_tolerance: 0,
+            match_author: true,
+        }
+    }
+}
+
+impl DuplicateFinder {
+    /// Minimum similarity of normalized titles, from 0 to 1. The default is 0.9.
+    pub fn title_threshold(mut self, threshold: f64) -> Self {
+        self.title_threshold = threshold;
+        self
+    }
+
+    /// Maximum difference in years for title matches. The default is 0.
+    pub fn year_tolerance(mut self, years: i64) -> Self {
+        self.year_tolerance = years;
+        self
+    }
+
+    /// Require the same last name of first authors for title matches. The
+    /// default is true.
+    pub fn match_author(mut self, yes: bool) -> Self {
+        self.match_author = yes;
+        self
+    }
+
+    // Return true if `a` and `b` are duplicates by their normalized titles
+    // `ta` and `tb`
+    fn title_matches(&self, a: &Record, ta: &str, b: &Record, tb: &str) -> bool {
+        // different works with similar titles, e.g. errata
+        if let (Some(x), Some(y)) = (&a.doi, &b.doi) {
+            if x != y {
+                return false;
+            }
+        }
+        if ta.is_empty() || tb.is_empty() {
+            return false;
+        }
+        // quick check by the bound of dice coefficient
+        let (na, nb) = (ta.chars().count(), tb.chars().count());
+        if 2.0 * na.min(nb) as f64 / (na + nb) as f64 + 1e-9 < self.title_threshold {
+            return false;
+        }
+        if let (Some(x), Some(y)) = (a.year, b.year) {
+            if (x - y).abs() > self.year_tolerance {
+                return false;
+            }
+        }
+        if self.match_author {
+            if let (Some(x), Some(y)) = (&a.first_author, &b.first_author) {
+                if x != y {
+                    return false;
+                }
+            }
+        }
+        similarity(ta, tb) >= self.title_threshold
+    }
+
+    // Return pairs of duplicate records with the reason. Identifiers are
+    // matched exactly in buckets, and titles are only compared within
+    // blocks of items in the same year (within tolerance) or by the same
+    // first author. Items of unknown year belong to every year block.
+    fn find_pairs(&self, records: &[Record]) -> Vec<(usize, usize, &'static str)> {
+        let mut pairs = vec![];
+        let mut by_doi: HashMap<&str, Vec<usize>> = HashMap::new();
+        let mut by_isbn: HashMap<&str, Vec<usize>> = HashMap::new();
+        for (i, record) in records.iter().enumerate() {
+            if let Some(doi) = &record.doi {
+                by_doi.entry(doi).or_default().push(i);
+            }
+            if let Some(isbn) = &record.isbn {
+                by_isbn.entry(isbn).or_default().push(i);
+            }
+        }
+        for ids in by_doi.values() {
+            pairs.extend(ids[1..].iter().map(|&j| (ids[0], j, "doi")));
+        }
+        for ids in by_isbn.values() {
+            for (n, &i) in ids.iter().enumerate() {
+                for &j in &ids[n + 1..] {
+                    match (&records[i].doi, &records[j].doi) {
+                        (Some(x), Some(y)) if x != y => {}
+                        _ => pairs.push((i, j, "isbn")),
+                    }
+                }
+            }
+        }
+
+        let titles: Vec<_> = records.iter().map(|x| normalize(&x.title)).collect();
+        let mut by_year: BTreeMap<i64, Vec<usize>> = BTreeMap::new();
+        let mut by_author: HashMap<&str, Vec<usize>> = HashMap::new();
+        let mut undated = vec![];
+        let mut candidates = HashSet::new();
+        for (i, record) in records.iter().enumerate() {
+            candidates.extend(undated.iter().map(|&j| (j, i)));
+            if let Some(year) = record.year {
+                let block = by_year.range(year - self.year_tolerance..=year + self.year_tolerance);
+                candidates.extend(block.flat_map(|x| x.1).map(|&j| (j, i)));
+                by_year.entry(year).or_default().push(i);
+            } else {
+                // compared with all items before
+                candidates.extend((0..i).map(|j| (j, i)));
+                undated.push(i);
+            }
+            if let Some(author) = &record.first_author {
+                let block = by_author.entry(author).or_default();
+                candidates.extend(block.iter().map(|&j| (j, i)));
+                block.push(i);
+            }
+        }
+        let mut candidates: Vec<_> = candidates.into_iter().collect();
+        candidates.sort_unstable();
+        for (i, j) in candidates {
+            if self.title_matches(&records[i], &titles[i], &records[j], &titles[j]) {
+                pairs.push((i, j, "title"));
+            }
+        }
+        pairs
+    }
+
+    fn find_in(&self, records: &[Record]) -> Vec<DuplicateCluster> {
+        // union find
+        let mut parent: Vec<usize> = (0..records.len()).collect();
+        fn root(parent: &mut [usize], i: usize) -> usize {
+            let mut i = i;
+            while parent[i] != i {
+                parent[i] = parent[parent[i]];
+                i = parent[i];
+            }
+            i
+        }
+
+        let pairs = self.find_pairs(records);
+        for &(i, j, _) in &pairs {
+            let (ri, rj) = (root(&mut parent, i), root(&mut parent, j));
+            parent[rj] = ri;
+        }
+        let mut reasons: HashMap<usize, HashSet<&str>> = HashMap::new();
+        for (i, _, reason) in pairs {
+            let r = root(&mut parent, i);
+            reasons.entry(r).or_default().insert(reason);
+        }
+
+        let mut groups: BTreeMap<usize, Vec<&Record>> = BTreeMap::new();
+        for (i, record) in records.iter().enumerate() {
+            let r = root(&mut parent, i);
+            groups.entry(r).or_default().push(record);
+        }
+        let mut clusters = vec![];
+        for (r, mut members) in groups.into_iter().filter(|x| x.1.len() > 1) {
+            // most complete first, then the oldest
+            members.sort_by(|a, b| {
+                b.completeness
+                    .cmp(&a.completeness)
+                    .then_with(|| a.date_added.cmp(&b.date_added))
+            });
+            let mut reasons: Vec<_> = reasons[&r].iter().map(|x| x.to_string()).collect();
+            reasons.sort();
+            clusters.push(DuplicateCluster {
+                keep: members[0].key.clone(),
+                keys: members.iter().map(|x| x.key.clone()).collect(),
+                reasons,
+            });
+        }
+        clusters.sort_by(|a, b| a.keep.cmp(&b.keep));
+        clusters
+    }
+
+    /// Find duplicate items in zotero database. Items in trash are filtered
+    /// by the trash filter of `backend`.
+    pub fn find(&self, backend: &SqliteBackend) -> Result<Vec<DuplicateCluster>> {
+        let records = backend
+            .runtime()
+            .block_on(backend.db().get_duplicate_records(backend.trash_filter()))?;
+        Ok(self.find_in(&records))
+    }
+}
+// c8f12b3e ends here
+
+// [[file:../zotero.note::*test][test:1]]
+#[test]
+fn test_duplicates() -> Result<()> {
+    use crate::fixture::TestDb;
+
+    assert_eq!(
+        normalize("Zéolite Catalysis: Thiophene-Cracking!"),
+        "zeolitecatalysisthiophenecracking"
+    );
+    assert_eq!(normalize("漢字はユニコード"), "漢字はユニコード");
+    assert_eq!(normalize_isbn("0-19-504279-4"), Some("9780195042795".into()));
+    assert_eq!(
+        normalize_doi("https://doi.org/10.1016/J.JCAT.2008.01.001"),
+        Some("10.1016/j.jcat.2008.01.001".into())
+    );
+    assert!(similarity("密度泛函理论研究", "密度泛函理论的研究") >= 0.8);
+
+    let test_db = TestDb::with_sql(
+        r#"
+INSERT INTO fields VALUES (11, 'ISBN');
+INSERT INTO items (itemID, itemTypeID, dateAdded, libraryID, key) VALUES
+    (6, 4, '2021-01-01 00:00:00', 1, 'FFFFFFFF'), (7, 4, '2021-01-01 00:00:00', 1, 'GGGGGGGG'),
+    (8, 2, '2021-01-01 00:00:00', 1, 'HHHHHHHH'), (9, 4, '2021-01-01 00:00:00', 1, 'IIIIIIII'),
+    (10, 4, '2021-01-01 00:00:00', 1, 'JJJJJJJJ'), (11, 4, '2021-01-01 00:00:00', 1, 'KKKKKKKK'),
+    (12, 2, '2021-01-01 00:00:00', 1, 'LLLLLLLL'), (13, 4, '2021-01-01 00:00:00', 1, 'MMMMMMMM');
+INSERT INTO deletedItems VALUES (13, '2021-01-01 00:00:00');
+INSERT INTO itemDataValues VALUES (11, 'https://doi.org/10.1016/J.JCAT.2008.01.001'), (12, 'Zéolite catalysis of thiophene-cracking'),
+    (13, '0-19-504279-4'), (14, '9780195042795'), (15, '基于密度泛函理论的沸石分子筛催化噻吩裂解研究'),
+    (16, '基于密度泛函理论的沸石分子筛催化噻吩裂解的研究'),
+    (17, '2008'), (18, 'Zeolite catalysis of thiophene cracking, part II'),
+    (19, 'Density Functional Theory of Atoms and Molecules');
+INSERT INTO itemData VALUES (6, 26, 11), (7, 1, 12), (7, 6, 17), (2, 11, 13), (8, 11, 14),
+    (9, 1, 15), (10, 1, 16), (11, 1, 18), (11, 6, 17), (12, 1, 19), (13, 26, 11);
+INSERT INTO creators VALUES (4, 'Jane', 'Smith', 0), (5, 'Li', 'Wang', 0), (6, 'Wei', 'Zhang', 0);
+INSERT INTO itemCreators VALUES (7, 4, 1, 0), (11, 5, 1, 0), (9, 6, 1, 0), (10, 5, 2, 0), (10, 6, 1, 1);
+"#,
+    );
+    let backend = SqliteBackend::open(test_db.path())?;
+    let clusters = DuplicateFinder::default().find(&backend)?;
+    assert_eq!(clusters.len(), 3);
+    // the original item has more fields and attachments
+    assert_eq!(clusters[0].keys, vec!["AAAAAAAA", "GGGGGGGG", "FFFFFFFF"]);
+    assert_eq!(clusters[0].reasons, vec!["doi", "title"]);
+    // undated item is compared with items of any year
+    assert_eq!(clusters[1].keep, "BBBBBBBB");
+    assert_eq!(clusters[1].keys.len(), 3);
+    assert!(clusters[1].keys.contains(&"LLLLLLLL".to_string()));
+    assert_eq!(clusters[1].reasons, vec!["isbn", "title"]);
+    // the first author ignoring editors
+    assert_eq!(clusters[2].keys, vec!["JJJJJJJJ", "IIIIIIII"]);
+
+    // items in trash
+    let trash = SqliteBackend::open(test_db.path())?.with_trash_filter(TrashFilter::Include);
+    let clusters = DuplicateFinder::default().find(&trash)?;
+    assert_eq!(clusters[0].keys.len(), 4);
+    assert!(clusters[0].keys.contains(&"MMMMMMMM".to_string()));
+
+    // titles differ too much for a strict threshold
+    let clusters = DuplicateFinder::default().title_threshold(0.99).find(&backend)?;
+    assert_eq!(clusters.len(), 2);
+
+    Ok(())
+}
+// test:1 ends here
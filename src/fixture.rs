@@ -0,0 +1,185 @@
+// [[file:../zotero.note::*imports][imports:1]]
+use gut::prelude::*;
+use std::path::{Path, PathBuf};
+
+use sqlx::prelude::*;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteConnection};
+// imports:1 ends here
+
+// [[file:../zotero.note::c3a8f5d1][c3a8f5d1]]
+// A subset of zotero database schema with a tiny library:
+//
+// - AAAAAAAA: journal article with a pdf attachment (CCCCCCCC), in collection
+//   "Catalysis", related to BBBBBBBB
+// - BBBBBBBB: book with a child note (EEEEEEEE)
+// - DDDDDDDD: journal article in trash
+const SCHEMA: &str = r##"
+CREATE TABLE libraries (libraryID INTEGER PRIMARY KEY, type TEXT NOT NULL, editable INT NOT NULL DEFAULT 1,
+    version INT NOT NULL DEFAULT 0);
+CREATE TABLE groups (groupID INTEGER PRIMARY KEY, libraryID INT NOT NULL UNIQUE, name TEXT NOT NULL,
+    description TEXT NOT NULL DEFAULT '', version INT NOT NULL DEFAULT 0);
+CREATE TABLE itemTypes (itemTypeID INTEGER PRIMARY KEY, typeName TEXT);
+CREATE TABLE fields (fieldID INTEGER PRIMARY KEY, fieldName TEXT);
+CREATE TABLE items (itemID INTEGER PRIMARY KEY, itemTypeID INT NOT NULL,
+    dateAdded TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    dateModified TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    clientDateModified TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    libraryID INT NOT NULL, key TEXT NOT NULL, version INT NOT NULL DEFAULT 0, synced INT NOT NULL DEFAULT 0);
+CREATE TABLE itemDataValues (valueID INTEGER PRIMARY KEY, value UNIQUE);
+CREATE TABLE itemData (itemID INT, fieldID INT, valueID INT, PRIMARY KEY (itemID, fieldID));
+CREATE TABLE creatorTypes (creatorTypeID INTEGER PRIMARY KEY, creatorType TEXT);
+CREATE TABLE creators (creatorID INTEGER PRIMARY KEY, firstName TEXT, lastName TEXT, fieldMode INT);
+CREATE TABLE itemCreators (itemID INT NOT NULL, creatorID INT NOT NULL, creatorTypeID INT NOT NULL DEFAULT 1,
+    orderIndex INT NOT NULL DEFAULT 0, PRIMARY KEY (itemID, creatorID, creatorTypeID, orderIndex));
+CREATE TABLE tags (tagID INTEGER PRIMARY KEY, name TEXT NOT NULL UNIQUE);
+CREATE TABLE itemTags (itemID INT NOT NULL, tagID INT NOT NULL, type INT NOT NULL, PRIMARY KEY (itemID, tagID));
+CREATE TABLE collections (collectionID INTEGER PRIMARY KEY, collectionName TEXT NOT NULL,
+    parentCollectionID INT DEFAULT NULL, clientDateModified TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    libraryID INT NOT NULL, key TEXT NOT NULL, version INT NOT NULL DEFAULT 0, synced INT NOT NULL DEFAULT 0);
+CREATE TABLE collectionItems (collectionID INT NOT NULL, itemID INT NOT NULL, orderIndex INT NOT NULL DEFAULT 0,
+    PRIMARY KEY (collectionID, itemID));
+CREATE TABLE deletedItems (itemID INTEGER PRIMARY KEY, dateDeleted DEFAULT CURRENT_TIMESTAMP NOT NULL);
+CREATE TABLE deletedCollections (collectionID INTEGER PRIMARY KEY, dateDeleted DEFAULT CURRENT_TIMESTAMP NOT NULL);
+CREATE TABLE itemAttachments (itemID INTEGER PRIMARY KEY, parentItemID INT, linkMode INT, contentType TEXT,
+    charsetID INT, path TEXT, syncState INT DEFAULT 0);
+CREATE TABLE itemNotes (itemID INTEGER PRIMARY KEY, parentItemID INT, note TEXT, title TEXT);
+CREATE TABLE itemAnnotations (itemID INTEGER PRIMARY KEY, parentItemID INT NOT NULL, type INTEGER NOT NULL,
+    text TEXT, comment TEXT, color TEXT, pageLabel TEXT, sortIndex TEXT NOT NULL DEFAULT '', position TEXT NOT NULL DEFAULT '{}');
+CREATE TABLE relationPredicates (predicateID INTEGER PRIMARY KEY, predicate TEXT UNIQUE);
+CREATE TABLE itemRelations (itemID INT NOT NULL, predicateID INT NOT NULL, object TEXT NOT NULL,
+    PRIMARY KEY (itemID, predicateID, object));
+CREATE TABLE syncObjectTypes (syncObjectTypeID INTEGER PRIMARY KEY, name TEXT);
+CREATE TABLE syncDeleteLog (syncObjectTypeID INT NOT NULL, libraryID INT NOT NULL, key TEXT NOT NULL,
+    dateDeleted TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP, UNIQUE (syncObjectTypeID, libraryID, key));
+CREATE TABLE syncedSettings (setting TEXT NOT NULL, libraryID INT NOT NULL, value NOT NULL,
+    version INT NOT NULL DEFAULT 0, synced INT NOT NULL DEFAULT 0, PRIMARY KEY (setting, libraryID));
+
+INSERT INTO libraries VALUES (1, 'user', 1, 42);
+INSERT INTO itemTypes VALUES (1, 'note'), (2, 'book'), (4, 'journalArticle'), (14, 'attachment'), (37, 'annotation');
+INSERT INTO fields VALUES (1, 'title'), (6, 'date'), (12, 'publicationTitle'), (16, 'extra'), (26, 'DOI'),
+    (1000, 'url');
+INSERT INTO creatorTypes VALUES (1, 'author'), (2, 'editor');
+INSERT INTO relationPredicates VALUES (1, 'owl:sameAs'), (2, 'dc:relation'), (3, 'dc:replaces');
+INSERT INTO syncObjectTypes VALUES (1, 'collection'), (2, 'creator'), (3, 'item'), (4, 'search'), (5, 'tag'),
+    (6, 'relation'), (7, 'setting');
+INSERT INTO syncedSettings VALUES ('tagColors', 1, '[{"name":"dft","color":"#FF6666"}]', 0, 0);
+
+INSERT INTO items (itemID, itemTypeID, dateAdded, dateModified, clientDateModified, libraryID, key, version) VALUES
+    (1, 4, '2020-01-01 08:00:00', '2020-01-02 08:00:00', '2020-01-02 08:00:00', 1, 'AAAAAAAA', 10),
+    (2, 2, '2020-02-01 08:00:00', '2020-02-01 08:00:00', '2020-02-01 08:00:00', 1, 'BBBBBBBB', 11),
+    (3, 14, '2020-01-01 08:10:00', '2020-01-01 08:10:00', '2020-01-01 08:10:00', 1, 'CCCCCCCC', 12),
+    (4, 4, '2020-03-01 08:00:00', '2020-03-01 08:00:00', '2020-03-01 08:00:00', 1, 'DDDDDDDD', 13),
+    (5, 1, '2020-02-02 08:00:00', '2020-02-02 08:00:00', '2020-02-02 08:00:00', 1, 'EEEEEEEE', 14);
+INSERT INTO itemDataValues VALUES (1, 'Zeolite catalysis of thiophene cracking'), (2, '2008-05-01 2008/05/01'),
+    (3, 'J. Catal.'), (4, 'Citation Key: smith2008zeolite'), (5, '10.1016/j.jcat.2008.01.001'),
+    (6, 'Density functional theory of atoms and molecules'), (7, '1989-00-00 1989'),
+    (8, 'smith2008.pdf'), (9, 'Deleted paper'), (10, '2021-00-00 2021');
+INSERT INTO itemData VALUES (1, 1, 1), (1, 6, 2), (1, 12, 3), (1, 16, 4), (1, 26, 5),
+    (2, 1, 6), (2, 6, 7), (3, 1, 8), (4, 1, 9), (4, 6, 10);
+INSERT INTO creators VALUES (1, 'John', 'Smith', 0), (2, 'Jane', 'Doe', 0), (3, 'Robert G.', 'Parr', 0);
+INSERT INTO itemCreators VALUES (1, 1, 1, 0), (1, 2, 1, 1), (2, 3, 1, 0), (4, 1, 1, 0);
+INSERT INTO tags VALUES (1, 'dft'), (2, 'zeolite');
+INSERT INTO itemTags VALUES (1, 1, 0), (1, 2, 1), (2, 1, 0), (4, 1, 0);
+INSERT INTO collections (collectionID, collectionName, parentCollectionID, libraryID, key) VALUES
+    (1, 'Catalysis', NULL, 1, 'COLLAAAA'), (2, 'Zeolites', 1, 1, 'COLLBBBB');
+INSERT INTO collectionItems VALUES (1, 1, 0), (2, 1, 0), (1, 4, 1);
+INSERT INTO deletedItems VALUES (4, '2021-01-01 00:00:00');
+INSERT INTO itemAttachments VALUES (3, 1, 0, 'application/pdf', NULL, 'storage:smith2008.pdf', 0);
+INSERT INTO itemNotes VALUES (5, 2, '<p>Read chapter 3</p>', 'Read chapter 3');
+INSERT INTO itemRelations VALUES (1, 2, 'http://zotero.org/users/15074/items/BBBBBBBB'),
+    (2, 2, 'http://zotero.org/users/15074/items/AAAAAAAA');
+"##;
+
+/// A zotero data directory with a tiny library in zotero.sqlite for testing,
+/// removed when dropped.
+pub struct TestDb {
+    dir: PathBuf,
+}
+
+impl TestDb {
+    /// Create test database in a temporary directory
+    pub fn new() -> Self {
+        Self::with_sql("")
+    }
+
+    /// Create test database, with additional `sql` statements applied
+    pub fn with_sql(sql: &str) -> Self {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let dir = std::env::temp_dir().join(format!("zotero-test-{:x}", nanos));
+        std::fs::create_dir_all(&dir).expect("create test dir");
+        let db = Self { dir };
+        db.create_sqlite("zotero.sqlite", &format!("{}\n{}", SCHEMA, sql));
+        db
+    }
+
+    /// Path to zotero.sqlite
+    pub fn path(&self) -> PathBuf {
+        self.dir.join("zotero.sqlite")
+    }
+
+    /// Apply `sql` statements to zotero.sqlite, e.g. for simulating changes
+    /// made by zotero.
+    pub fn execute(&self, sql: &str) {
+        self.create_sqlite("zotero.sqlite", sql);
+    }
+
+    /// Create another sqlite database `name` in data directory from `sql`.
+    /// `sql` is applied if the database exists already.
+    pub fn create_sqlite(&self, name: &str, sql: &str) -> PathBuf {
+        let path = self.dir.join(name);
+        let (path_, sql) = (path.clone(), sql.to_string());
+        // in its own thread, in case called from async tests
+        std::thread::spawn(move || {
+            let rt = tokio::runtime::Runtime::new()?;
+            rt.block_on(create_db(&path_, &sql))
+        })
+        .join()
+        .unwrap()
+        .expect("create test db");
+        path
+    }
+}
+
+impl Drop for TestDb {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// A temporary directory for test files, removed on drop
+pub struct TestDir {
+    dir: PathBuf,
+}
+
+impl TestDir {
+    pub fn new() -> Self {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let dir = std::env::temp_dir().join(format!("zotero-test-{:x}-{}", nanos, std::process::id()));
+        std::fs::create_dir_all(&dir).expect("create test dir");
+        Self { dir }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.dir
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+async fn create_db(path: &Path, sql: &str) -> Result<()> {
+    let options = SqliteConnectOptions::new().filename(path).create_if_missing(true);
+    let mut conn = SqliteConnection::connect_with(&options).await?;
+    conn.execute(sql).await?;
+    Ok(())
+}
+// c3a8f5d1 ends here
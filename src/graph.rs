@@ -0,0 +1,455 @@
+// [[file:../zotero.note::*imports][imports:1]]
+use gut::prelude::*;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use sqlx::prelude::*;
+
+use crate::backend::SqliteBackend;
+use crate::db::ZoteroDb;
+use crate::trash::TrashFilter;
+// imports:1 ends here
+
+// [[file:../zotero.note::4b7d92e1][4b7d92e1]]
+/// How two items in relation graph are linked
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EdgeKind {
+    /// Related items set in zotero
+    Relation,
+    /// Items in the same collection
+    Collection,
+    /// Items with the same tag
+    Tag,
+}
+
+impl EdgeKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Relation => "relation",
+            Self::Collection => "collection",
+            Self::Tag => "tag",
+        }
+    }
+}
+
+/// An item in relation graph
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GraphNode {
+    pub key: String,
+    pub title: String,
+    pub date: String,
+}
+
+/// An undirected edge between two items
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GraphEdge {
+    pub source: String,
+    pub target: String,
+    pub kind: EdgeKind,
+    /// Names of shared collections or tags, empty for relations
+    pub labels: Vec<String>,
+}
+
+/// Graph of items linked by relations, and optionally by shared
+/// collections and tags
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RelationGraph {
+    pub nodes: Vec<GraphNode>,
+    pub edges: Vec<GraphEdge>,
+}
+
+impl ZoteroDb {
+    // Return (item key, collection key, name) of collections containing items
+    // in `keys`, with items and collections filtered by `trash`
+    async fn get_collections_of_items(
+        &self,
+        keys: &[String],
+        trash: TrashFilter,
+    ) -> Result<Vec<(String, String, String)>> {
+        let trashed = self.trashed_collections().await?;
+        let sql = format!(
+            r#"
+SELECT items.key as key, collections.key as grp, collections.collectionName as name FROM collectionItems
+    JOIN items USING (itemID)
+    JOIN collections USING (collectionID)
+WHERE items.key IN ({{keys}})
+  AND {}
+  AND {}
+"#,
+            trash.sql(&["items.itemID"]),
+            trash.sql_in(&["collections.collectionID"], trashed)
+        );
+        self.get_key_group_names(&sql, keys).await
+    }
+
+    // Return (item key, tag name, tag name) of items in `keys` filtered by
+    // `trash`
+    async fn get_tags_of_items(&self, keys: &[String], trash: TrashFilter) -> Result<Vec<(String, String, String)>> {
+        let sql = format!(
+            r#"
+SELECT items.key as key, tags.name as grp, tags.name as name FROM itemTags
+    JOIN items USING (itemID)
+    JOIN tags USING (tagID)
+WHERE items.key IN ({{keys}})
+  AND {}
+"#,
+            trash.sql(&["items.itemID"])
+        );
+        self.get_key_group_names(&sql, keys).await
+    }
+
+    // Return keys of regular items in collection with `key_or_name`, matched
+    // exactly
+    async fn get_collection_item_keys(&self, key_or_name: &str, trash: TrashFilter) -> Result<Vec<String>> {
+        let found = sqlx::query("SELECT 1 FROM collections WHERE key = ? OR collectionName = ?")
+            .bind(key_or_name)
+            .bind(key_or_name)
+            .fetch_optional(self.pool())
+            .await?;
+        if found.is_none() {
+            bail!("collection not found: {}", key_or_name);
+        }
+        let sql = format!(
+            r#"
+SELECT items.key as key FROM collectionItems
+    JOIN items USING (itemID)
+    JOIN collections USING (collectionID)
+WHERE (collections.key = ? OR collections.collectionName = ?)
+  AND items.itemID NOT IN (select itemID from itemAttachments)
+  AND items.itemID NOT IN (select itemID from itemNotes)
+  AND {}
+ORDER BY items.dateAdded DESC
+"#,
+            trash.sql(&["items.itemID"])
+        );
+        let recs = sqlx::query(&sql)
+            .bind(key_or_name)
+            .bind(key_or_name)
+            .fetch_all(self.pool())
+            .await?;
+        recs.iter().map(|x| Ok(x.try_get("key")?)).collect()
+    }
+
+    async fn get_key_group_names(&self, sql: &str, keys: &[String]) -> Result<Vec<(String, String, String)>> {
+        let mut rows = vec![];
+        for rec in self.fetch_by_keys(sql, keys).await? {
+            rows.push((rec.try_get("key")?, rec.try_get("grp")?, rec.try_get("name")?));
+        }
+        Ok(rows)
+    }
+}
+
+// Add edges of `kind` between items in the same group of `rows` in (item
+// key, group, name), labeled by the group name. Collections are grouped by
+// key, as different collections can have the same name.
+fn add_shared_edges(
+    edges: &mut BTreeMap<(String, String, EdgeKind), Vec<String>>,
+    rows: Vec<(String, String, String)>,
+    kind: EdgeKind,
+) {
+    let mut groups: BTreeMap<String, (String, BTreeSet<String>)> = BTreeMap::new();
+    for (key, group, name) in rows {
+        groups
+            .entry(group)
+            .or_insert_with(|| (name, BTreeSet::new()))
+            .1
+            .insert(key);
+    }
+    for (name, keys) in groups.into_values() {
+        let keys: Vec<_> = keys.into_iter().collect();
+        for (i, a) in keys.iter().enumerate() {
+            for b in &keys[i + 1..] {
+                edges
+                    .entry((a.clone(), b.clone(), kind))
+                    .or_default()
+                    .push(name.clone());
+            }
+        }
+    }
+}
+// 4b7d92e1 ends here
+
+// [[file:../zotero.note::e2a06c5f][e2a06c5f]]
+/// Build relation graph starting from some items
+#[derive(Debug, Clone, Default)]
+pub struct GraphBuilder {
+    items: Vec<String>,
+    collections: Vec<String>,
+    depth: Option<usize>,
+    collection_edges: bool,
+    tag_edges: bool,
+}
+
+impl GraphBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start from item in `key`
+    pub fn item(mut self, key: &str) -> Self {
+        self.items.push(key.into());
+        self
+    }
+
+    /// Start from all items in collection `name`, which is the exact name
+    /// or key of collection
+    pub fn collection(mut self, name: &str) -> Self {
+        self.collections.push(name.into());
+        self
+    }
+
+    /// Follow relations at most `depth` hops away from starting items. The
+    /// default is to follow all relations transitively.
+    pub fn depth(mut self, depth: usize) -> Self {
+        self.depth = Some(depth);
+        self
+    }
+
+    /// Link items in the same collection
+    pub fn collection_edges(mut self, yes: bool) -> Self {
+        self.collection_edges = yes;
+        self
+    }
+
+    /// Link items with the same tag
+    pub fn tag_edges(mut self, yes: bool) -> Self {
+        self.tag_edges = yes;
+        self
+    }
+
+    async fn build_graph(&self, db: &ZoteroDb, trash: TrashFilter) -> Result<RelationGraph> {
+        let mut queue: VecDeque<(String, usize)> = self.items.iter().map(|x| (x.clone(), 0)).collect();
+        for name in &self.collections {
+            let keys = db.get_collection_item_keys(name, trash).await?;
+            queue.extend(keys.into_iter().map(|x| (x, 0)));
+        }
+
+        // breadth first traversal of relations
+        let mut nodes: BTreeMap<String, GraphNode> = BTreeMap::new();
+        let mut edges: BTreeMap<(String, String, EdgeKind), Vec<String>> = BTreeMap::new();
+        while let Some((key, hops)) = queue.pop_front() {
+            if nodes.contains_key(&key) {
+                continue;
+            }
+            let item = db.get_item(&key).await?;
+            nodes.insert(
+                key.clone(),
+                GraphNode {
+                    key: key.clone(),
+                    title: item.title().into(),
+                    date: item.date().into(),
+                },
+            );
+            if self.depth.map(|d| hops >= d).unwrap_or(false) {
+                continue;
+            }
+            for related in db.get_related_items(&key, trash).await? {
+                let other = related.key().to_string();
+                let pair = if key < other {
+                    (key.clone(), other.clone())
+                } else {
+                    (other.clone(), key.clone())
+                };
+                edges.entry((pair.0, pair.1, EdgeKind::Relation)).or_default();
+                queue.push_back((other, hops + 1));
+            }
+        }
+        // relations to items beyond the depth limit
+        edges.retain(|(a, b, _), _| nodes.contains_key(a) && nodes.contains_key(b));
+
+        let keys: Vec<_> = nodes.keys().cloned().collect();
+        if self.collection_edges && !keys.is_empty() {
+            let rows = db.get_collections_of_items(&keys, trash).await?;
+            add_shared_edges(&mut edges, rows, EdgeKind::Collection);
+        }
+        if self.tag_edges && !keys.is_empty() {
+            let rows = db.get_tags_of_items(&keys, trash).await?;
+            add_shared_edges(&mut edges, rows, EdgeKind::Tag);
+        }
+
+        let graph = RelationGraph {
+            nodes: nodes.into_iter().map(|x| x.1).collect(),
+            edges: edges
+                .into_iter()
+                .map(|((source, target, kind), labels)| GraphEdge {
+                    source,
+                    target,
+                    kind,
+                    labels,
+                })
+                .collect(),
+        };
+        Ok(graph)
+    }
+
+    /// Build relation graph from zotero database, with items filtered by the
+    /// trash filter of `backend`
+    pub fn build(&self, backend: &SqliteBackend) -> Result<RelationGraph> {
+        backend
+            .runtime()
+            .block_on(self.build_graph(backend.db(), backend.trash_filter()))
+    }
+}
+// e2a06c5f ends here
+
+// [[file:../zotero.note::9c37f8d4][9c37f8d4]]
+fn escape_dot(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+impl RelationGraph {
+    /// Return edges linking item in `key`
+    pub fn edges_of<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a GraphEdge> + 'a {
+        self.edges.iter().filter(move |x| x.source == key || x.target == key)
+    }
+
+    /// Format graph in JSON
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Format graph in DOT language of graphviz
+    pub fn to_dot(&self) -> String {
+        let mut lines = vec!["graph zotero {".to_string()];
+        for node in &self.nodes {
+            lines.push(format!("  \"{}\" [label=\"{}\"];", node.key, escape_dot(&node.title)));
+        }
+        for edge in &self.edges {
+            let style = match edge.kind {
+                EdgeKind::Relation => "solid",
+                EdgeKind::Collection => "dashed",
+                EdgeKind::Tag => "dotted",
+            };
+            lines.push(format!(
+                "  \"{}\" -- \"{}\" [kind={}, style={}, label=\"{}\"];",
+                edge.source,
+                edge.target,
+                edge.kind.as_str(),
+                style,
+                escape_dot(&edge.labels.join(", "))
+            ));
+        }
+        lines.push("}".into());
+        lines.join("\n") + "\n"
+    }
+
+    /// Format graph in GraphML
+    pub fn to_graphml(&self) -> String {
+        let mut lines = vec![
+            r#"<?xml version="1.0" encoding="UTF-8"?>"#.to_string(),
+            r#"<graphml xmlns="http://graphml.graphdrawing.org/xmlns">"#.into(),
+            r#"  <key id="title" for="node" attr.name="title" attr.type="string"/>"#.into(),
+            r#"  <key id="date" for="node" attr.name="date" attr.type="string"/>"#.into(),
+            r#"  <key id="kind" for="edge" attr.name="kind" attr.type="string"/>"#.into(),
+            r#"  <key id="labels" for="edge" attr.name="labels" attr.type="string"/>"#.into(),
+            r#"  <graph id="zotero" edgedefault="undirected">"#.into(),
+        ];
+        for node in &self.nodes {
+            lines.push(format!(r#"    <node id="{}">"#, escape_xml(&node.key)));
+            lines.push(format!(r#"      <data key="title">{}</data>"#, escape_xml(&node.title)));
+            lines.push(format!(r#"      <data key="date">{}</data>"#, escape_xml(&node.date)));
+            lines.push("    </node>".into());
+        }
+        for edge in &self.edges {
+            lines.push(format!(
+                r#"    <edge source="{}" target="{}">"#,
+                escape_xml(&edge.source),
+                escape_xml(&edge.target)
+            ));
+            lines.push(format!(r#"      <data key="kind">{}</data>"#, edge.kind.as_str()));
+            if !edge.labels.is_empty() {
+                lines.push(format!(
+                    r#"      <data key="labels">{}</data>"#,
+                    escape_xml(&edge.labels.join(", "))
+                ));
+            }
+            lines.push("    </edge>".into());
+        }
+        lines.push("  </graph>".into());
+        lines.push("</graphml>".into());
+        lines.join("\n") + "\n"
+    }
+}
+// 9c37f8d4 ends here
+
+// [[file:../zotero.note::*test][test:1]]
+#[test]
+fn test_relation_graph() -> Result<()> {
+    use crate::fixture::TestDb;
+
+    let test_db = TestDb::with_sql(
+        r#"
+INSERT INTO items (itemID, itemTypeID, libraryID, key) VALUES (6, 4, 1, 'FFFFFFFF');
+INSERT INTO itemDataValues VALUES (11, 'Zeolites & "acid" sites');
+INSERT INTO itemData VALUES (6, 1, 11);
+INSERT INTO collections (collectionID, collectionName, parentCollectionID, libraryID, key) VALUES
+    (3, 'Zeolites', NULL, 1, 'COLLCCCC'), (4, 'Trashed', NULL, 1, 'COLLDDDD');
+INSERT INTO collectionItems VALUES (1, 2, 2), (3, 2, 0), (4, 1, 0), (4, 2, 0);
+INSERT INTO deletedCollections VALUES (4, '2021-01-01 00:00:00');
+INSERT INTO itemRelations VALUES (2, 2, 'http://zotero.org/users/15074/items/FFFFFFFF'),
+    (6, 2, 'http://zotero.org/users/15074/items/BBBBBBBB');
+"#,
+    );
+    let backend = SqliteBackend::open(test_db.path())?;
+
+    // A -- B -- F
+    let graph = GraphBuilder::new().item("AAAAAAAA").build(&backend)?;
+    let keys: Vec<_> = graph.nodes.iter().map(|x| x.key.as_str()).collect();
+    assert_eq!(keys, vec!["AAAAAAAA", "BBBBBBBB", "FFFFFFFF"]);
+    assert_eq!(graph.edges.len(), 2);
+    assert_eq!(graph.edges_of("BBBBBBBB").count(), 2);
+
+    let graph = GraphBuilder::new()
+        .item("AAAAAAAA")
+        .depth(1)
+        .collection_edges(true)
+        .tag_edges(true)
+        .build(&backend)?;
+    assert_eq!(graph.nodes.len(), 2);
+    let kinds: Vec<_> = graph.edges.iter().map(|x| (x.kind, x.labels.clone())).collect();
+    // not linked by different collections of the same name, or collections
+    // in trash
+    assert_eq!(
+        kinds,
+        vec![
+            (EdgeKind::Relation, vec![]),
+            (EdgeKind::Collection, vec!["Catalysis".to_string()]),
+            (EdgeKind::Tag, vec!["dft".to_string()]),
+        ]
+    );
+
+    // by exact collection name or key, not substring
+    let graph = GraphBuilder::new().collection("COLLBBBB").depth(0).build(&backend)?;
+    let keys: Vec<_> = graph.nodes.iter().map(|x| x.key.as_str()).collect();
+    assert_eq!(keys, vec!["AAAAAAAA"]);
+    // two collections in the same name
+    let graph = GraphBuilder::new().collection("Zeolites").depth(0).build(&backend)?;
+    assert_eq!(graph.nodes.len(), 2);
+    let graph = GraphBuilder::new().collection("COLLAAAA").depth(0).build(&backend)?;
+    assert_eq!(graph.nodes.len(), 2);
+    assert!(GraphBuilder::new().collection("Zeolite").build(&backend).is_err());
+    // with items in trash
+    let trash = SqliteBackend::open(test_db.path())?.with_trash_filter(TrashFilter::Include);
+    let graph = GraphBuilder::new().collection("COLLAAAA").depth(0).build(&trash)?;
+    assert_eq!(graph.nodes.len(), 3);
+
+    let graph = GraphBuilder::new().item("FFFFFFFF").build(&backend)?;
+    assert!(graph
+        .to_dot()
+        .contains(r#""FFFFFFFF" [label="Zeolites & \"acid\" sites"];"#));
+    let graphml = graph.to_graphml();
+    assert!(graphml.contains(r#"<data key="title">Zeolites &amp; &quot;acid&quot; sites</data>"#));
+    roxmltree::Document::parse(&graphml)?;
+    let json: serde_json::Value = serde_json::from_str(&graph.to_json()?)?;
+    assert_eq!(json["edges"][0]["kind"], "relation");
+
+    Ok(())
+}
+// test:1 ends here
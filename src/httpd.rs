@@ -0,0 +1,194 @@
+// [[file:../zotero.note::*imports][imports:1]]
+use gut::prelude::*;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+// imports:1 ends here
+
+// [[file:../zotero.note::4e9d1a6c][4e9d1a6c]]
+/// A short-lived local HTTP server serving one file at a one-off url, so that
+/// zotero can download it as attachment when saving items through connector.
+/// The server stops when dropped.
+pub struct FileServer {
+    url: String,
+    stop: Arc<AtomicBool>,
+    downloaded: Arc<AtomicBool>,
+}
+
+impl FileServer {
+    /// Serve file in `path`. The content type is guessed from file extension.
+    pub fn serve_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read(path).with_context(|| format!("read file to serve: {:?}", path))?;
+        let name = path.file_name().and_then(|x| x.to_str()).unwrap_or("file");
+        Self::serve_bytes(name, content, guess_mime_type(path))
+    }
+
+    /// Serve `content` in `mime_type` as file `name`.
+    pub fn serve_bytes(name: &str, content: Vec<u8>, mime_type: &str) -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").context("bind local file server")?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        // unique path to avoid serving anything else by accident
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|x| x.as_nanos())
+            .unwrap_or_default();
+        let path = format!("/{:x}/{}", nanos, encode_path_segment(name));
+        let url = format!("http://{}{}", addr, path);
+        debug!("serving {} at {}", name, url);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let downloaded = Arc::new(AtomicBool::new(false));
+        let (stop_, downloaded_) = (stop.clone(), downloaded.clone());
+        let mime_type = mime_type.to_string();
+        std::thread::spawn(move || {
+            while !stop_.load(Ordering::SeqCst) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        let _ = stream.set_nonblocking(false);
+                        match handle_request(stream, &path, &content, &mime_type) {
+                            Ok(true) => downloaded_.store(true, Ordering::SeqCst),
+                            Ok(false) => {}
+                            Err(e) => debug!("file server error: {}", e),
+                        }
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                        std::thread::sleep(Duration::from_millis(20));
+                    }
+                    Err(e) => {
+                        debug!("file server stopped: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+
+        Ok(Self { url, stop, downloaded })
+    }
+
+    /// The one-off url of the served file
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Return true if the file has been downloaded
+    pub fn is_downloaded(&self) -> bool {
+        self.downloaded.load(Ordering::SeqCst)
+    }
+
+    /// Wait until the file has been downloaded, or `timeout` elapsed. Return
+    /// true if downloaded.
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let start = Instant::now();
+        while !self.is_downloaded() && start.elapsed() < timeout {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        self.is_downloaded()
+    }
+}
+
+impl Drop for FileServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+// Respond to one request. Return true if the file has been served.
+fn handle_request(mut stream: TcpStream, path: &str, content: &[u8], mime_type: &str) -> Result<bool> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(&stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let target = parts.next().unwrap_or_default().to_string();
+    // skip headers
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+    }
+
+    let found = target.split('?').next() == Some(path);
+    if found && (method == "GET" || method == "HEAD") {
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            mime_type,
+            content.len()
+        )?;
+        if method == "GET" {
+            stream.write_all(content)?;
+        }
+        stream.flush()?;
+        Ok(method == "GET")
+    } else {
+        let status = if found {
+            "405 Method Not Allowed"
+        } else {
+            "404 Not Found"
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            status
+        )?;
+        stream.flush()?;
+        Ok(false)
+    }
+}
+
+fn encode_path_segment(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Guess mime type of file in `path` from its extension.
+pub fn guess_mime_type(path: &Path) -> &'static str {
+    let ext = path
+        .extension()
+        .and_then(|x| x.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+    match ext.as_str() {
+        "pdf" => "application/pdf",
+        "org" | "note" => "application/x-note",
+        "html" | "htm" => "text/html",
+        "txt" | "md" => "text/plain",
+        "bib" => "application/x-bibtex",
+        "ris" => "application/x-research-info-systems",
+        "json" => "application/json",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        _ => "application/octet-stream",
+    }
+}
+// 4e9d1a6c ends here
+
+// [[file:../zotero.note::*test][test:1]]
+#[test]
+fn test_file_server() -> Result<()> {
+    let server = FileServer::serve_bytes("research note.org", b"* TODO".to_vec(), "application/x-note")?;
+    assert!(server.url().ends_with("/research%20note.org"));
+    assert!(!server.is_downloaded());
+
+    let resp = reqwest::blocking::get(&format!("{}x", server.url()))?;
+    assert_eq!(resp.status().as_u16(), 404);
+    let resp = reqwest::blocking::get(server.url())?;
+    assert_eq!(resp.headers()["content-type"], "application/x-note");
+    assert_eq!(resp.text()?, "* TODO");
+    assert!(server.wait(Duration::from_secs(1)));
+
+    assert_eq!(guess_mime_type("a/b.PDF".as_ref()), "application/pdf");
+    Ok(())
+}
+// test:1 ends here
@@ -10,42 +10,110 @@
 // pub mod schema;
 // mod database;
 
+mod api;
+mod attachment;
+mod audit;
+mod backend;
+mod changes;
+mod citekey;
+mod csl;
 mod db;
+mod duplicates;
+mod graph;
+mod httpd;
 mod profile;
+mod query;
+mod relation;
+mod search;
 mod server;
+mod stats;
+mod trash;
+mod watcher;
+mod web_api;
+
+#[cfg(test)]
+mod fixture;
+#[cfg(test)]
+mod mock;
 // mods:1 ends here
 
 // [[file:../zotero.note::*pub][pub:1]]
 use gut::prelude::*;
 
-// /// Return PDF attachment path from zotero protocol link
-// ///
-// /// # Parameters
-// /// ----------
-// /// * link: zotero item selection link, e.g: zotero://select/items/1_NIUYMGLJ
-// pub fn get_attachment_from_link(link: &str) -> Result<Option<String>> {
-//     use crate::database::*;
-//     use crate::server::*;
-
-//     let url = "/home/ybyygu/Data/zotero/zotero.sqlite.bak";
-//     let zot_server = ZoteroServer::default();
-//     let zot_db = ZoteroDb::connect(url)?;
+/// Return PDF attachment path from zotero protocol link
+///
+/// # Parameters
+/// ----------
+/// * link: zotero item selection link, e.g: zotero://select/items/1_NIUYMGLJ
+pub fn get_attachment_from_link(link: &str) -> Result<Option<String>> {
+    use crate::backend::*;
+    use crate::server::*;
 
-//     zot_server
-//         .get_attachment(link)
-//         .or(zot_db.get_attachment_from_link(link))
-// }
+    let key = get_item_key_from_link(link)?;
+    // discovered once for both server and local database
+    let install = crate::profile::discover_zotero_installs().into_iter().next();
+    let mut builder = ZoteroServer::builder();
+    if let Some(install) = &install {
+        builder = builder.install(install);
+    }
+    let paths = match builder.build()?.attachments(&key) {
+        Ok(paths) => paths,
+        // fall back to database of local zotero, opened only when needed
+        Err(e) => {
+            debug!("zotero server failed: {:?}, trying local database", e);
+            let install =
+                install.with_context(|| format!("no zotero database found after server failure: {:?}", e))?;
+            // the live database is locked by running zotero
+            let paths = crate::attachment::AttachmentPaths::for_install(&install);
+            SqliteBackend::open(install.cached_db()?)?
+                .with_attachment_paths(paths)
+                .attachments(&key)?
+        }
+    };
+    Ok(paths.into_iter().next())
+}
 
-/// Create a new `report` item in zotero with a .note (org-mode) attachment, and
-/// returns zotero uri of the new item.
-pub fn create_new_note() -> Result<Option<String>> {
+/// Create a new `report` item titled `title` in zotero with a .note (org-mode)
+/// attachment copied from `template`, and returns zotero uri of the new item.
+pub fn create_new_note(title: &str, template: &std::path::Path) -> Result<Option<String>> {
     use crate::server::*;
 
     let connector = ZoteroServer::default();
-    connector.create_new_note("xx")
+    connector.create_new_note(title, template)
 }
 
-pub use crate::db::{get_item_key_from_link, get_items_by_collection, get_items_by_tag, Item};
+pub use crate::api::{
+    ApiCollection, ApiCollectionData, ApiCreator, ApiItem, ApiItemData, ApiMeta, ApiQuery, ApiResults, ApiSearch,
+    ApiSearchCondition, ApiSearchData, ApiTag, ApiTagMeta, ApiTagRef, LocalApi,
+};
+pub use crate::attachment::{AttachmentFile, AttachmentPaths};
+pub use crate::audit::{DuplicateFiles, HealthCheck, HealthReport, LargeFile};
+pub use crate::backend::{Fallback, SqliteBackend, ZoteroBackend};
+pub use crate::changes::{changes_since, Changes, ItemCache, Watermark};
+pub use crate::citekey::{get_item_by_citekey, CitationKeys};
+pub use crate::csl::{
+    find_style, format_bibliography, CitationFormatter, CslDate, CslItem, CslLocale, CslName, CslStyle, OutputFormat,
+};
+pub use crate::db::{
+    get_item_key_from_link, get_items_by_collection, get_items_by_tag, get_items_dwim, Collection, Item, ItemCreator,
+};
+pub use crate::duplicates::{DuplicateCluster, DuplicateFinder};
+pub use crate::graph::{EdgeKind, GraphBuilder, GraphEdge, GraphNode, RelationGraph};
+pub use crate::httpd::FileServer;
+pub use crate::profile::{
+    discover_zotero_installs, read_profiles_ini, PrefValue, Prefs, Profile, SyncStorage, ZoteroInstall, ZoteroPrefs,
+};
+pub use crate::query::{search_items, ItemQuery};
+pub use crate::relation::{ItemRelation, ObjectLibrary, ObjectUri, RelationPredicate};
+pub use crate::search::{SearchHit, SearchIndex};
+pub use crate::server::{
+    Attachment, BbtAttachment, BbtCollection, BbtItem, ConnectorItem, Creator, SelectedCollection, ZoteroServer,
+    ZoteroServerBuilder, ZotxtBibliography, ZotxtPaths, ZotxtQuery, ZotxtQuickBibliography, ZotxtSearchMethod,
+};
+pub use crate::stats::{CollectionCount, Count, LibraryStats, ReadingProgress, Statistics, StorageUsage};
+pub use crate::trash::{TrashFilter, TrashedItem};
+pub use crate::watcher::{LibraryEvent, LibraryWatcher};
+pub use crate::web_api::{ApiDeleted, LibraryChanges, WebApi, WriteFailure, WriteResults};
 // pub:1 ends here
 
 // [[file:../zotero.note::*test][test:1]]
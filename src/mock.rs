@@ -0,0 +1,196 @@
+// [[file:../zotero.note::*imports][imports:1]]
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+// imports:1 ends here
+
+// [[file:../zotero.note::9a4f0b3e][9a4f0b3e]]
+/// A HTTP request received by mock server
+#[derive(Debug, Clone, Default)]
+pub struct MockRequest {
+    pub method: String,
+    // request target with query string
+    pub target: String,
+    // header names in lowercase
+    pub headers: HashMap<String, String>,
+    pub body: String,
+}
+
+impl MockRequest {
+    /// Request path without query string
+    pub fn path(&self) -> &str {
+        self.target.split('?').next().unwrap_or_default()
+    }
+
+    /// Return decoded value of query parameter `name`
+    pub fn query(&self, name: &str) -> Option<String> {
+        let q = self.target.split_once('?')?.1;
+        q.split('&').find_map(|kv| {
+            let mut parts = kv.splitn(2, '=');
+            let k = parts.next()?;
+            if percent_decode(k) == name {
+                Some(percent_decode(parts.next().unwrap_or_default()))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Return value of header `name` (case insensitive)
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_lowercase()).map(|x| x.as_str())
+    }
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("");
+                match u8::from_str_radix(hex, 16) {
+                    Ok(b) => {
+                        out.push(b);
+                        i += 2;
+                    }
+                    Err(_) => out.push(b'%'),
+                }
+            }
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// A canned HTTP response from mock server
+#[derive(Debug, Clone)]
+pub struct MockResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl MockResponse {
+    /// Response with status 200 and `body`
+    pub fn ok(body: &str) -> Self {
+        Self::status(200, body)
+    }
+
+    /// Response with `status` and `body`
+    pub fn status(status: u16, body: &str) -> Self {
+        Self {
+            status,
+            headers: vec![],
+            body: body.into(),
+        }
+    }
+
+    /// Add response header
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+}
+// 9a4f0b3e ends here
+
+// [[file:../zotero.note::2be61c8d][2be61c8d]]
+type Handler = Box<dyn Fn(&MockRequest) -> MockResponse + Send + Sync>;
+
+/// A minimal local HTTP server for testing clients. Requests are answered
+/// by `handler` and recorded for later inspection.
+pub struct MockServer {
+    url: String,
+    requests: Arc<Mutex<Vec<MockRequest>>>,
+}
+
+impl MockServer {
+    /// Start mock server on a random local port, serving requests in a
+    /// background thread.
+    pub fn start<F>(handler: F) -> Self
+    where
+        F: Fn(&MockRequest) -> MockResponse + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind mock server");
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+
+        let handler: Arc<Handler> = Arc::new(Box::new(handler));
+        let recorded = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().filter_map(|x| x.ok()) {
+                let handler = handler.clone();
+                let recorded = recorded.clone();
+                std::thread::spawn(move || {
+                    if let Some(req) = read_request(&stream) {
+                        let resp = handler(&req);
+                        recorded.lock().unwrap().push(req);
+                        let _ = write_response(stream, &resp);
+                    }
+                });
+            }
+        });
+
+        Self { url, requests }
+    }
+
+    /// Base url of the server, e.g. http://127.0.0.1:34567
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Return all requests received so far
+    pub fn requests(&self) -> Vec<MockRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+fn read_request(stream: &TcpStream) -> Option<MockRequest> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let mut parts = line.split_whitespace();
+    let mut req = MockRequest {
+        method: parts.next()?.to_string(),
+        target: parts.next()?.to_string(),
+        ..Default::default()
+    };
+
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(i) = line.find(':') {
+            let (k, v) = line.split_at(i);
+            req.headers.insert(k.trim().to_lowercase(), v[1..].trim().to_string());
+        }
+    }
+
+    let n: usize = req.header("content-length").and_then(|x| x.parse().ok()).unwrap_or(0);
+    let mut body = vec![0; n];
+    reader.read_exact(&mut body).ok()?;
+    req.body = String::from_utf8_lossy(&body).into_owned();
+    Some(req)
+}
+
+fn write_response(mut stream: TcpStream, resp: &MockResponse) -> std::io::Result<()> {
+    write!(stream, "HTTP/1.1 {} MOCK\r\n", resp.status)?;
+    for (k, v) in &resp.headers {
+        write!(stream, "{}: {}\r\n", k, v)?;
+    }
+    write!(
+        stream,
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        resp.body.len()
+    )?;
+    stream.write_all(resp.body.as_bytes())?;
+    stream.flush()
+}
+// 2be61c8d ends here
@@ -3,57 +3,615 @@ use gut::prelude::*;
 // imports:1 ends here
 
 // [[file:../zotero.note::*core][core:1]]
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
 
-// Find the path to zotero preference file `prefs.js`
-fn get_zotero_profile_path() -> Option<PathBuf> {
-    let home = std::env::var("HOME").ok()?;
-    let f = Path::new(&home).join(".zotero/zotero/profiles.ini");
-    debug!("reading zotero ini profile: {}", f.display());
-
-    for (sec, prop) in ini::Ini::load_from_file(&f).ok()?.iter() {
-        if sec.unwrap().starts_with("Profile") {
-            let d: HashMap<&str, &str> = prop.iter().collect();
-            if let Some(v) = d.get("Default") {
-                if *v == "1" {
-                    return d.get("Path").map(|x| f.with_file_name(x).join("prefs.js"));
-                }
+/// A profile listed in profiles.ini
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+    pub name: String,
+    /// Absolute path to profile directory
+    pub path: PathBuf,
+    /// True if this is the profile zotero starts with
+    pub default: bool,
+}
+
+impl Profile {
+    /// Path to prefs.js in profile directory
+    pub fn prefs_js(&self) -> PathBuf {
+        self.path.join("prefs.js")
+    }
+}
+
+/// Read profiles in `profiles.ini`. The default profile is the one chosen in
+/// an `Install*` section, as since Firefox 67, or marked with `Default=1`, or
+/// the only one.
+pub fn read_profiles_ini(ini_file: &Path) -> Result<Vec<Profile>> {
+    let ini = ini::Ini::load_from_file(ini_file).with_context(|| format!("read {:?}", ini_file))?;
+    let dir = ini_file.parent().unwrap_or_else(|| Path::new("."));
+
+    let mut install_default = None;
+    let mut profiles = vec![];
+    for (sec, prop) in ini.iter() {
+        let sec = sec.unwrap_or_default();
+        if sec.starts_with("Install") {
+            if let Some(path) = prop.get("Default") {
+                install_default = Some(dir.join(path));
             }
+        } else if sec.starts_with("Profile") {
+            let path = match prop.get("Path") {
+                Some(path) => path,
+                None => continue,
+            };
+            // relative to the directory of profiles.ini unless IsRelative=0
+            let path = if prop.get("IsRelative") == Some("0") {
+                PathBuf::from(path)
+            } else {
+                dir.join(path)
+            };
+            profiles.push(Profile {
+                name: prop.get("Name").unwrap_or_default().to_string(),
+                path,
+                default: prop.get("Default") == Some("1"),
+            });
+        }
+    }
+
+    if let Some(path) = install_default {
+        if profiles.iter().any(|p| p.path == path) {
+            for p in profiles.iter_mut() {
+                p.default = p.path == path;
+            }
+        }
+    }
+    if profiles.len() == 1 {
+        profiles[0].default = true;
+    }
+    Ok(profiles)
+}
+
+/// Value of a preference in prefs.js
+#[derive(Debug, Clone, PartialEq)]
+pub enum PrefValue {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+}
+
+impl PrefValue {
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::Str(s) => Some(s),
+            _ => None,
         }
     }
-    None
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Self::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Self::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+}
+
+/// Preferences in a Mozilla prefs.js file, written as
+///
+/// `user_pref("extensions.zotero.dataDir", "/home/ybyygu/Data/zotero");`
+#[derive(Debug, Clone, Default)]
+pub struct Prefs {
+    prefs: BTreeMap<String, PrefValue>,
 }
 
-// user_pref("extensions.zotero.dataDir", "/home/ybyygu/Data/zotero");
-fn parse_zotero_data_dir_from_pref_js(s: &str) -> Option<PathBuf> {
-    for line in s.lines() {
-        if line.contains("extensions.zotero.dataDir") {
-            for x in line.rsplit("\"") {
-                return Some(PathBuf::from(x));
+impl Prefs {
+    /// Read preferences from prefs.js in `path`
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let s = std::fs::read_to_string(path).with_context(|| format!("read {:?}", path))?;
+        s.parse().with_context(|| format!("parse {:?}", path))
+    }
+
+    /// Return value of preference in `name`
+    pub fn get(&self, name: &str) -> Option<&PrefValue> {
+        self.prefs.get(name)
+    }
+
+    /// Return zotero preferences, with the `extensions.zotero.` prefix
+    /// removed from their names.
+    pub fn zotero(&self) -> impl Iterator<Item = (&str, &PrefValue)> {
+        self.prefs
+            .iter()
+            .filter_map(|(k, v)| k.strip_prefix("extensions.zotero.").map(|k| (k, v)))
+    }
+
+    /// Return value of zotero preference in `name` without the
+    /// `extensions.zotero.` prefix.
+    pub fn get_zotero(&self, name: &str) -> Option<&PrefValue> {
+        self.prefs.get(&format!("extensions.zotero.{}", name))
+    }
+}
+
+// Parser for the prefs.js syntax: calls of `user_pref`, `pref` or
+// `sticky_pref` with a name and a string, integer or boolean value, and
+// comments in `//`, `/* */` or `#`.
+struct PrefsParser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl PrefsParser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn line(&self) -> usize {
+        let pos = self.pos.min(self.chars.len());
+        self.chars[..pos].iter().filter(|&&c| c == '\n').count() + 1
+    }
+
+    fn skip_space(&mut self) {
+        while let Some(c) = self.peek() {
+            if c.is_whitespace() {
+                self.pos += 1;
+            } else if c == '#' || self.chars[self.pos..].starts_with(&['/', '/']) {
+                while self.peek().map(|c| c != '\n').unwrap_or(false) {
+                    self.pos += 1;
+                }
+            } else if self.chars[self.pos..].starts_with(&['/', '*']) {
+                self.pos += 2;
+                while self.pos < self.chars.len() && !self.chars[self.pos..].starts_with(&['*', '/']) {
+                    self.pos += 1;
+                }
+                // unterminated comment ends at EOF
+                self.pos = (self.pos + 2).min(self.chars.len());
+            } else {
+                break;
             }
         }
     }
-    None
+
+    fn expect(&mut self, c: char) -> Result<()> {
+        self.skip_space();
+        if self.peek() != Some(c) {
+            bail!("expect {:?} in line {}", c, self.line());
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn word(&mut self) -> String {
+        let mut w = String::new();
+        while let Some(c) = self.peek().filter(|c| c.is_alphanumeric() || *c == '_' || *c == '-') {
+            w.push(c);
+            self.pos += 1;
+        }
+        w
+    }
+
+    fn string(&mut self) -> Result<String> {
+        self.skip_space();
+        let quote = match self.peek() {
+            Some(q) if q == '"' || q == '\'' => q,
+            _ => bail!("expect string in line {}", self.line()),
+        };
+        self.pos += 1;
+        let mut s = String::new();
+        loop {
+            let c = self
+                .peek()
+                .with_context(|| format!("unterminated string in line {}", self.line()))?;
+            self.pos += 1;
+            match c {
+                '\\' => {
+                    let c = self.peek().context("unterminated escape")?;
+                    self.pos += 1;
+                    match c {
+                        'n' => s.push('\n'),
+                        't' => s.push('\t'),
+                        'r' => s.push('\r'),
+                        'x' | 'u' => {
+                            let n = if c == 'x' { 2 } else { 4 };
+                            let hex: String = self.chars.iter().skip(self.pos).take(n).collect();
+                            let code = Some(&hex)
+                                .filter(|x| x.len() == n)
+                                .and_then(|x| u32::from_str_radix(x, 16).ok())
+                                .with_context(|| format!("invalid escape in line {}", self.line()))?;
+                            s.push(std::char::from_u32(code).unwrap_or('\u{fffd}'));
+                            self.pos += n;
+                        }
+                        c => s.push(c),
+                    }
+                }
+                c if c == quote => break,
+                c => s.push(c),
+            }
+        }
+        Ok(s)
+    }
+
+    fn value(&mut self) -> Result<PrefValue> {
+        self.skip_space();
+        match self.peek() {
+            Some('"') | Some('\'') => Ok(PrefValue::Str(self.string()?)),
+            Some(c) if c == '-' || c == '+' || c.is_ascii_digit() || c.is_alphabetic() => {
+                if c == '+' {
+                    self.pos += 1;
+                }
+                let w = self.word();
+                match w.as_str() {
+                    "true" => Ok(PrefValue::Bool(true)),
+                    "false" => Ok(PrefValue::Bool(false)),
+                    _ => w
+                        .parse()
+                        .map(PrefValue::Int)
+                        .with_context(|| format!("invalid value {:?} in line {}", w, self.line())),
+                }
+            }
+            _ => bail!("expect value in line {}", self.line()),
+        }
+    }
+
+    fn parse(&mut self) -> Result<BTreeMap<String, PrefValue>> {
+        let mut prefs = BTreeMap::new();
+        loop {
+            self.skip_space();
+            if self.peek().is_none() {
+                break;
+            }
+            let func = self.word();
+            if !["user_pref", "pref", "sticky_pref"].contains(&func.as_str()) {
+                bail!("unexpected {:?} in line {}", func, self.line());
+            }
+            self.expect('(')?;
+            let name = self.string()?;
+            self.expect(',')?;
+            let value = self.value()?;
+            self.expect(')')?;
+            self.skip_space();
+            if self.peek() == Some(';') {
+                self.pos += 1;
+            }
+            prefs.insert(name, value);
+        }
+        Ok(prefs)
+    }
 }
 
-/// Locate zotero data dir from preference
-pub(crate) fn guess_zotero_data_dir() -> Option<PathBuf> {
-    let pref_js = get_zotero_profile_path()?;
+impl std::str::FromStr for Prefs {
+    type Err = gut::prelude::Error;
 
-    let f = std::fs::read_to_string(&pref_js).ok()?;
-    parse_zotero_data_dir_from_pref_js(&f)
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parser = PrefsParser {
+            chars: s.chars().collect(),
+            pos: 0,
+        };
+        let prefs = parser.parse()?;
+        Ok(Self { prefs })
+    }
 }
 
 #[test]
 fn test_zotero_profile() {
-    let pref_js = get_zotero_profile_path().unwrap();
-    assert!(pref_js.exists());
+    let profile = discover_zotero_installs().into_iter().find_map(|x| x.profile).unwrap();
+    assert!(profile.prefs_js().exists());
 
-    assert!(guess_zotero_data_dir().is_some());
+    assert!(!discover_zotero_installs().is_empty());
+}
+
+#[test]
+fn test_prefs_js() -> Result<()> {
+    let s = r#"
+// Mozilla User Preferences
+/* Do not edit this file.
+ * user_pref("commented.out", 1); */
+user_pref("extensions.zotero.dataDir", "/home/ybyygu/Data/zotero");
+user_pref("extensions.zotero.useDataDir", true);
+user_pref("extensions.zotero.httpServer.port", 23120);
+user_pref("extensions.zotero.export.quickCopy.setting", "bibliography=\"apa\");\u00e9");
+user_pref('extensions.zotero.lastViewedFolder', 'L1'); # trailing comment
+user_pref("browser.cache.disk.capacity", -1);
+"#;
+    let prefs: Prefs = s.parse()?;
+    assert_eq!(prefs.get("commented.out"), None);
+    assert_eq!(
+        prefs.get_zotero("dataDir").and_then(|x| x.as_str()),
+        Some("/home/ybyygu/Data/zotero")
+    );
+    assert_eq!(prefs.get_zotero("useDataDir"), Some(&PrefValue::Bool(true)));
+    assert_eq!(
+        prefs.get_zotero("httpServer.port").and_then(|x| x.as_int()),
+        Some(23120)
+    );
+    let quick_copy = prefs.get_zotero("export.quickCopy.setting").and_then(|x| x.as_str());
+    assert_eq!(quick_copy, Some("bibliography=\"apa\");\u{e9}"));
+    assert_eq!(prefs.get("browser.cache.disk.capacity"), Some(&PrefValue::Int(-1)));
+    assert_eq!(prefs.zotero().count(), 5);
+    assert!("user_pref(\"x\", );".parse::<Prefs>().is_err());
+    assert!("user_pref(\"x\", \"unterminated);".parse::<Prefs>().is_err());
+    // input ending in escape or comment
+    assert!("user_pref(\"a\\x4".parse::<Prefs>().is_err());
+    assert!("user_pref(\"a\\u00".parse::<Prefs>().is_err());
+    assert!("user_pref(\"x\" /*".parse::<Prefs>().is_err());
+    assert!("user_pref(\"x\", 1); /*".parse::<Prefs>().is_ok());
+
+    let dir = std::env::temp_dir().join(format!("zotero-profiles-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let ini = dir.join("profiles.ini");
+    std::fs::write(
+        &ini,
+        r#"[General]
+StartWithLastProfile=1
+
+[Profile1]
+Name=work
+IsRelative=0
+Path=/opt/zotero/work
+
+[Profile0]
+Name=default
+IsRelative=1
+Path=Profiles/abcd.default
+Default=1
+
+[Install4F96D1932A9F858E]
+Default=/opt/zotero/work
+Locked=1
+"#,
+    )?;
+    let profiles = read_profiles_ini(&ini)?;
+    std::fs::remove_dir_all(&dir)?;
+    assert_eq!(profiles.len(), 2);
+    assert_eq!(profiles[0].path, PathBuf::from("/opt/zotero/work"));
+    assert_eq!(profiles[1].path, dir.join("Profiles/abcd.default"));
+    // the install section takes precedence
+    assert!(profiles[0].default);
+    assert!(!profiles[1].default);
+
+    Ok(())
 }
 // core:1 ends here
 
+// [[file:../zotero.note::3f9c0e6b][3f9c0e6b]]
+/// A zotero data directory found on this machine
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZoteroInstall {
+    /// How it was found: "env", "profile", "flatpak", "snap" or "default"
+    pub source: String,
+    /// The profile using this data directory, if any
+    pub profile: Option<Profile>,
+    /// Directory containing zotero.sqlite
+    pub data_dir: PathBuf,
+    /// Size of zotero.sqlite in bytes
+    pub db_size: u64,
+}
+
+// Directories containing profiles.ini relative to home directory
+const PROFILE_ROOTS: &[(&str, &str)] = &[
+    ("profile", ".zotero/zotero"),
+    ("profile", "Library/Application Support/Zotero"),
+    ("profile", "AppData/Roaming/Zotero/Zotero"),
+    ("flatpak", ".var/app/org.zotero.Zotero/.zotero/zotero"),
+    ("snap", "snap/zotero-snap/current/.zotero/zotero"),
+    ("snap", "snap/zotero-snap/common/.zotero/zotero"),
+];
+
+// Data directory configured in prefs.js of `profile`. Zotero uses ~/Zotero
+// unless `useDataDir` is set.
+fn profile_data_dir(profile: &Profile, home: &Path) -> PathBuf {
+    let prefs = Prefs::from_file(profile.prefs_js()).unwrap_or_default();
+    let custom = prefs
+        .get_zotero("useDataDir")
+        .and_then(|x| x.as_bool())
+        .unwrap_or(false);
+    match prefs.get_zotero("dataDir").and_then(|x| x.as_str()) {
+        Some(dir) if custom => PathBuf::from(dir),
+        _ => home.join("Zotero"),
+    }
+}
+
+// Discover zotero data directories under `home`, with `env_data_dir` from
+// environment variable taking precedence.
+fn discover_in(home: &Path, env_data_dir: Option<&Path>) -> Vec<ZoteroInstall> {
+    let mut candidates = vec![];
+    if let Some(dir) = env_data_dir {
+        candidates.push(("env", None, dir.to_owned()));
+    }
+    for (source, root) in PROFILE_ROOTS {
+        let ini = home.join(root).join("profiles.ini");
+        if !ini.exists() {
+            continue;
+        }
+        let mut profiles = match read_profiles_ini(&ini) {
+            Ok(profiles) => profiles,
+            Err(e) => {
+                warn!("{:?}", e);
+                continue;
+            }
+        };
+        // default profile first
+        profiles.sort_by_key(|p| !p.default);
+        for profile in profiles {
+            let data_dir = profile_data_dir(&profile, home);
+            // data dir of zotero 4 inside profile
+            let legacy = profile.path.join("zotero");
+            candidates.push((source, Some(profile.clone()), data_dir));
+            candidates.push((source, Some(profile), legacy));
+        }
+    }
+    candidates.push(("default", None, home.join("Zotero")));
+
+    let mut installs: Vec<ZoteroInstall> = vec![];
+    for (source, profile, data_dir) in candidates {
+        let db_size = match std::fs::metadata(data_dir.join("zotero.sqlite")) {
+            Ok(m) => m.len(),
+            Err(_) => continue,
+        };
+        if installs.iter().any(|x| x.data_dir == data_dir) {
+            continue;
+        }
+        installs.push(ZoteroInstall {
+            source: source.to_string(),
+            profile,
+            data_dir,
+            db_size,
+        });
+    }
+    installs
+}
+
+impl ZoteroInstall {
+    /// Zotero preferences in the profile using this data directory, if any
+    pub fn prefs(&self) -> Option<ZoteroPrefs> {
+        let profile = self.profile.as_ref()?;
+        match Prefs::from_file(profile.prefs_js()) {
+            Ok(prefs) => Some(ZoteroPrefs::from(&prefs)),
+            Err(e) => {
+                warn!("failed to read zotero prefs: {:?}", e);
+                None
+            }
+        }
+    }
+
+    /// Copy zotero.sqlite into cache directory when it has been updated, and
+    /// return path to the cached copy, which is readable while zotero is
+    /// running and locking the database.
+    pub fn cached_db(&self) -> Result<PathBuf> {
+        use sha2::{Digest, Sha256};
+
+        let cache_dir = std::env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+            .unwrap_or_else(std::env::temp_dir);
+        // one cached copy for each data directory
+        let digest = Sha256::digest(self.data_dir.to_string_lossy().as_bytes());
+        let id: String = digest.iter().take(4).map(|x| format!("{:02x}", x)).collect();
+        let cached = cache_dir.join("libzotero").join(format!("zotero-{}.sqlite", id));
+        update_zotero_db_cache(&self.data_dir.join("zotero.sqlite"), &cached)?;
+        Ok(cached)
+    }
+}
+
+/// Return all zotero data directories found on this machine, in order of
+/// preference: `ZOTERO_DATA_DIR` environment variable, data directories of
+/// zotero profiles (the default profile first) in the standard, Flatpak or
+/// Snap locations, and the default `~/Zotero`.
+pub fn discover_zotero_installs() -> Vec<ZoteroInstall> {
+    let env_data_dir = std::env::var_os("ZOTERO_DATA_DIR").map(PathBuf::from);
+    match std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE")) {
+        Some(home) => discover_in(Path::new(&home), env_data_dir.as_deref()),
+        None => discover_in(Path::new("/nonexistent"), env_data_dir.as_deref()),
+    }
+}
+// 3f9c0e6b ends here
+
+// [[file:../zotero.note::a5d82f3e][a5d82f3e]]
+/// Default port of zotero HTTP server
+pub const DEFAULT_HTTP_PORT: u16 = 23119;
+
+/// File sync settings of zotero
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SyncStorage {
+    pub enabled: bool,
+    /// "zotero" or "webdav"
+    pub protocol: String,
+    /// WebDAV server url without scheme
+    pub url: Option<String>,
+    pub scheme: Option<String>,
+    pub username: Option<String>,
+    /// When to download files in my library: "on-sync" or "on-demand"
+    pub download_mode: Option<String>,
+}
+
+/// Zotero settings relevant to us, read from prefs.js
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZoteroPrefs {
+    /// Custom data directory, or None for the default ~/Zotero
+    pub data_dir: Option<PathBuf>,
+    /// Base directory for linked attachments
+    pub base_attachment_path: Option<PathBuf>,
+    /// Save linked attachments relative to `base_attachment_path`
+    pub save_relative_attachment_path: bool,
+    /// Port of zotero HTTP server
+    pub http_server_port: u16,
+    /// Quick copy format, e.g. "bibliography=http://www.zotero.org/styles/apa"
+    pub quick_copy: Option<String>,
+    /// ID of last used export translator
+    pub last_translator: Option<String>,
+    /// Options of export translators, in json
+    pub translator_settings: Option<serde_json::Value>,
+    /// Last used citation style for bibliography
+    pub last_style: Option<String>,
+    pub sync_storage: SyncStorage,
+}
+
+impl Default for ZoteroPrefs {
+    fn default() -> Self {
+        Self::from(&Prefs::default())
+    }
+}
+
+impl From<&Prefs> for ZoteroPrefs {
+    fn from(prefs: &Prefs) -> Self {
+        let string = |name: &str| prefs.get_zotero(name).and_then(|x| x.as_str()).map(|x| x.to_string());
+        let boolean = |name: &str| prefs.get_zotero(name).and_then(|x| x.as_bool());
+
+        let data_dir = match boolean("useDataDir") {
+            Some(true) => string("dataDir").map(PathBuf::from),
+            _ => None,
+        };
+        let http_server_port = prefs
+            .get_zotero("httpServer.port")
+            .and_then(|x| x.as_int())
+            .and_then(|x| std::convert::TryFrom::try_from(x).ok())
+            .unwrap_or(DEFAULT_HTTP_PORT);
+        let translator_settings = string("export.translatorSettings").and_then(|x| serde_json::from_str(&x).ok());
+        let sync_storage = SyncStorage {
+            enabled: boolean("sync.storage.enabled").unwrap_or(true),
+            protocol: string("sync.storage.protocol").unwrap_or_else(|| "zotero".into()),
+            url: string("sync.storage.url"),
+            scheme: string("sync.storage.scheme"),
+            username: string("sync.storage.username"),
+            download_mode: string("sync.storage.downloadMode.personal"),
+        };
+
+        Self {
+            data_dir,
+            base_attachment_path: string("baseAttachmentPath").map(PathBuf::from),
+            save_relative_attachment_path: boolean("saveRelativeAttachmentPath").unwrap_or(false),
+            http_server_port,
+            quick_copy: string("export.quickCopy.setting"),
+            last_translator: string("export.lastTranslator"),
+            translator_settings,
+            last_style: string("export.lastStyle"),
+            sync_storage,
+        }
+    }
+}
+
+impl ZoteroPrefs {
+    /// Read settings from prefs.js of default zotero profile
+    pub fn load() -> Result<Self> {
+        let profile = discover_zotero_installs()
+            .into_iter()
+            .find_map(|x| x.profile)
+            .context("no zotero profile found")?;
+        let prefs = Prefs::from_file(profile.prefs_js())?;
+        Ok(Self::from(&prefs))
+    }
+
+    /// Url of zotero HTTP server on local machine
+    pub fn server_url(&self) -> String {
+        format!("http://127.0.0.1:{}", self.http_server_port)
+    }
+}
+// a5d82f3e ends here
+
 // [[file:../zotero.note::bc5986f8][bc5986f8]]
 /// Update zotero db file to a cached location when it has been updated.
 pub fn update_zotero_db_cache(dbfile: &Path, cached: &Path) -> Result<()> {
@@ -93,4 +651,73 @@ fn test_db_cache() -> Result<()> {
     update_zotero_db_cache(dbfile, cached)?;
     Ok(())
 }
+#[test]
+fn test_discover_zotero_installs() -> Result<()> {
+    let home = std::env::temp_dir().join(format!("zotero-home-{}", std::process::id()));
+    let touch = |path: &Path, content: &str| -> Result<()> {
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(path, content)?;
+        Ok(())
+    };
+    assert!(discover_in(&home, None).is_empty());
+
+    // flatpak profile with custom data dir
+    let root = home.join(".var/app/org.zotero.Zotero/.zotero/zotero");
+    touch(
+        &root.join("profiles.ini"),
+        "[Profile0]\nName=default\nIsRelative=1\nPath=abcd.default\nDefault=1\n",
+    )?;
+    let custom = home.join("Data/zotero");
+    let prefs = format!(
+        "user_pref(\"extensions.zotero.dataDir\", \"{}\");\nuser_pref(\"extensions.zotero.useDataDir\", true);\n",
+        custom.display()
+    );
+    touch(&root.join("abcd.default/prefs.js"), &prefs)?;
+    touch(&custom.join("zotero.sqlite"), "0123456789")?;
+    touch(&home.join("Zotero/zotero.sqlite"), "01234")?;
+    let env_dir = home.join("env");
+    touch(&env_dir.join("zotero.sqlite"), "0")?;
+
+    let installs = discover_in(&home, Some(&env_dir));
+    let installs_: Vec<_> = installs.iter().map(|x| (x.source.as_str(), x.db_size)).collect();
+    assert_eq!(installs_, vec![("env", 1), ("flatpak", 10), ("default", 5)]);
+    assert_eq!(installs[1].data_dir, custom);
+    assert_eq!(installs[1].profile.as_ref().unwrap().name, "default");
+
+    // ~/Zotero is used when useDataDir is not set
+    touch(&root.join("abcd.default/prefs.js"), "")?;
+    let installs = discover_in(&home, None);
+    std::fs::remove_dir_all(&home)?;
+    assert_eq!(installs.len(), 1);
+    assert_eq!(installs[0].source, "flatpak");
+    assert_eq!(installs[0].data_dir, home.join("Zotero"));
+
+    Ok(())
+}
+#[test]
+fn test_zotero_prefs() -> Result<()> {
+    let s = r#"
+user_pref("extensions.zotero.baseAttachmentPath", "/home/ybyygu/Papers");
+user_pref("extensions.zotero.saveRelativeAttachmentPath", true);
+user_pref("extensions.zotero.dataDir", "/home/ybyygu/Data/zotero");
+user_pref("extensions.zotero.httpServer.port", 23120);
+user_pref("extensions.zotero.export.lastStyle", "http://www.zotero.org/styles/apa");
+user_pref("extensions.zotero.export.translatorSettings", "{\"exportNotes\":true}");
+user_pref("extensions.zotero.sync.storage.protocol", "webdav");
+user_pref("extensions.zotero.sync.storage.url", "dav.example.com/zotero");
+"#;
+    let prefs = ZoteroPrefs::from(&s.parse::<Prefs>()?);
+    // dataDir is ignored without useDataDir
+    assert_eq!(prefs.data_dir, None);
+    assert_eq!(prefs.base_attachment_path, Some("/home/ybyygu/Papers".into()));
+    assert!(prefs.save_relative_attachment_path);
+    assert_eq!(prefs.server_url(), "http://127.0.0.1:23120");
+    assert_eq!(prefs.last_style.as_deref(), Some("http://www.zotero.org/styles/apa"));
+    assert_eq!(prefs.translator_settings.unwrap()["exportNotes"], true);
+    assert_eq!(prefs.sync_storage.protocol, "webdav");
+    assert!(prefs.sync_storage.enabled);
+    assert_eq!(ZoteroPrefs::default().http_server_port, DEFAULT_HTTP_PORT);
+
+    Ok(())
+}
 // test:1 ends here
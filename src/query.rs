@@ -0,0 +1,456 @@
+// [[file:../zotero.note::*imports][imports:1]]
+use gut::prelude::*;
+
+use sqlx::prelude::*;
+
+use crate::backend::SqliteBackend;
+use crate::db::{Item, ZoteroDb};
+use crate::trash::TrashFilter;
+// imports:1 ends here
+
+// [[file:../zotero.note::6a0d3e52][6a0d3e52]]
+/// Query for zotero items, parsed from text such as
+///
+/// `tag:dft (author:smith OR author:doe) year:2019..2021 NOT has:pdf`
+///
+/// Terms without a field match title, creators or date. Terms next to each
+/// other are combined with AND. Text matching is case insensitive and by
+/// substring.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ItemQuery {
+    /// Word in title, creators or date
+    Word(String),
+    Tag(String),
+    /// Name or key of collection
+    Collection(String),
+    Author(String),
+    Title(String),
+    /// Inclusive range of publication year
+    Year(i64, i64),
+    /// Item type, e.g. journalArticle
+    Type(String),
+    /// Having child `pdf`, `note` or `attachment`
+    Has(String),
+    /// Compare date added with `op` in `<`, `<=`, `>`, `>=` or `=`, e.g. `added:>2024-01-01`
+    Added(String, String),
+    And(Box<ItemQuery>, Box<ItemQuery>),
+    Or(Box<ItemQuery>, Box<ItemQuery>),
+    Not(Box<ItemQuery>),
+}
+
+impl ItemQuery {
+    /// Match all words in `text` in title, creators or date
+    pub fn words(text: &str) -> Option<Self> {
+        text.split_whitespace()
+            .map(|w| Self::Word(w.into()))
+            .reduce(|a, b| Self::And(Box::new(a), Box::new(b)))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Text(String),
+}
+
+fn tokenize(input: &str) -> Vec<Token> {
+    let mut tokens = vec![];
+    let mut text = String::new();
+    let mut quoted = false;
+    for c in input.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            '(' | ')' if !quoted => {
+                if !text.is_empty() {
+                    tokens.push(Token::Text(std::mem::take(&mut text)));
+                }
+                tokens.push(if c == '(' { Token::Open } else { Token::Close });
+            }
+            c if c.is_whitespace() && !quoted => {
+                if !text.is_empty() {
+                    tokens.push(Token::Text(std::mem::take(&mut text)));
+                }
+            }
+            _ => text.push(c),
+        }
+    }
+    if !text.is_empty() {
+        tokens.push(Token::Text(text));
+    }
+    tokens
+}
+
+// Recursive descent parser. OR binds looser than AND, which binds looser than
+// NOT.
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Text(x)) if x == keyword)
+    }
+
+    fn parse_or(&mut self) -> Result<ItemQuery> {
+        let mut query = self.parse_and()?;
+        while self.peek_keyword("OR") {
+            self.pos += 1;
+            let rhs = self.parse_and()?;
+            query = ItemQuery::Or(Box::new(query), Box::new(rhs));
+        }
+        Ok(query)
+    }
+
+    fn parse_and(&mut self) -> Result<ItemQuery> {
+        let mut query = self.parse_not()?;
+        loop {
+            match self.peek() {
+                None | Some(Token::Close) => break,
+                _ if self.peek_keyword("OR") => break,
+                _ if self.peek_keyword("AND") => self.pos += 1,
+                _ => {}
+            }
+            let rhs = self.parse_not()?;
+            query = ItemQuery::And(Box::new(query), Box::new(rhs));
+        }
+        Ok(query)
+    }
+
+    fn parse_not(&mut self) -> Result<ItemQuery> {
+        if self.peek_keyword("NOT") {
+            self.pos += 1;
+            return Ok(ItemQuery::Not(Box::new(self.parse_not()?)));
+        }
+        self.parse_atom()
+    }
+
+    fn parse_atom(&mut self) -> Result<ItemQuery> {
+        let token = self.peek().cloned();
+        self.pos += 1;
+        match token {
+            Some(Token::Open) => {
+                let query = self.parse_or()?;
+                if self.peek() != Some(&Token::Close) {
+                    bail!("missing closing parenthesis");
+                }
+                self.pos += 1;
+                Ok(query)
+            }
+            Some(Token::Text(text)) => parse_term(&text),
+            Some(Token::Close) => bail!("unexpected closing parenthesis"),
+            None => bail!("incomplete query"),
+        }
+    }
+}
+
+fn parse_year_range(s: &str) -> Result<(i64, i64)> {
+    let year = |x: &str, default: i64| -> Result<i64> {
+        if x.is_empty() {
+            Ok(default)
+        } else {
+            x.parse().with_context(|| format!("invalid year: {}", x))
+        }
+    };
+    match s.split_once("..") {
+        Some((a, b)) => Ok((year(a, 0)?, year(b, 9999)?)),
+        None => {
+            let y = year(s, 0)?;
+            Ok((y, y))
+        }
+    }
+}
+
+fn parse_added(s: &str) -> Result<(String, String)> {
+    let (op, date) = ["<=", ">=", "<", ">", "="]
+        .iter()
+        .find_map(|op| s.strip_prefix(op).map(|d| (*op, d)))
+        .unwrap_or(("=", s));
+    chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d").with_context(|| format!("invalid date: {}", date))?;
+    Ok((op.into(), date.into()))
+}
+
+fn parse_term(text: &str) -> Result<ItemQuery> {
+    let (field, value) = match text.split_once(':') {
+        Some((field, value)) if !value.is_empty() => (field, value.to_string()),
+        _ => return Ok(ItemQuery::Word(text.into())),
+    };
+    let query = match field {
+        "tag" => ItemQuery::Tag(value),
+        "collection" => ItemQuery::Collection(value),
+        "author" | "creator" => ItemQuery::Author(value),
+        "title" => ItemQuery::Title(value),
+        "type" => ItemQuery::Type(value),
+        "year" => {
+            let (y1, y2) = parse_year_range(&value)?;
+            ItemQuery::Year(y1, y2)
+        }
+        "has" => match value.as_str() {
+            "pdf" | "note" | "attachment" => ItemQuery::Has(value),
+            _ => bail!("invalid has: {}, expect pdf, note or attachment", value),
+        },
+        "added" => {
+            let (op, date) = parse_added(&value)?;
+            ItemQuery::Added(op, date)
+        }
+        // not a field, e.g. a DOI
+        _ => ItemQuery::Word(text.into()),
+    };
+    Ok(query)
+}
+
+impl std::str::FromStr for ItemQuery {
+    type Err = gut::prelude::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parser = Parser {
+            tokens: tokenize(s),
+            pos: 0,
+        };
+        if parser.tokens.is_empty() {
+            bail!("empty query");
+        }
+        let query = parser.parse_or()?;
+        if parser.pos < parser.tokens.len() {
+            bail!("unexpected closing parenthesis");
+        }
+        Ok(query)
+    }
+}
+// 6a0d3e52 ends here
+
+// [[file:../zotero.note::c47e1b89][c47e1b89]]
+#[derive(Debug, Clone)]
+enum Param {
+    Text(String),
+    Int(i64),
+}
+
+// Escape wildcards in `s` for LIKE patterns with `ESCAPE '\'`
+fn escape_like(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+}
+
+// Compile `query` into a condition on `items.itemID`, with parameters pushed
+// into `params` in order. Child items are filtered by `trash` together with
+// their parents.
+fn compile(query: &ItemQuery, trash: TrashFilter, params: &mut Vec<Param>) -> Result<String> {
+    use ItemQuery::*;
+
+    let like = |s: &str| Param::Text(format!("%{}%", escape_like(&s.to_lowercase())));
+    let sql = match query {
+        Word(w) => {
+            params.extend(vec![like(w), like(w), like(w)]);
+            r#"items.itemID IN (
+    SELECT itemID FROM itemData
+        JOIN fields USING (fieldID)
+        JOIN itemDataValues USING (valueID)
+        WHERE fields.fieldName IN ("title", "date") AND LOWER(itemDataValues.value) LIKE ? ESCAPE '\'
+    UNION
+    SELECT itemID FROM itemCreators
+        JOIN creators USING (creatorID)
+        WHERE LOWER(creators.lastName) LIKE ? ESCAPE '\' OR LOWER(creators.firstName) LIKE ? ESCAPE '\')"#
+                .into()
+        }
+        Tag(tag) => {
+            params.push(like(tag));
+            r#"items.itemID IN (
+    SELECT itemID FROM itemTags JOIN tags USING (tagID) WHERE LOWER(tags.name) LIKE ? ESCAPE '\')"#
+                .into()
+        }
+        Collection(name) => {
+            params.push(like(name));
+            params.push(Param::Text(name.into()));
+            r#"items.itemID IN (
+    SELECT itemID FROM collectionItems JOIN collections USING (collectionID)
+        WHERE LOWER(collections.collectionName) LIKE ? ESCAPE '\' OR collections.key = ?)"#
+                .into()
+        }
+        Author(name) => {
+            params.push(like(name));
+            params.push(like(name));
+            r#"items.itemID IN (
+    SELECT itemID FROM itemCreators JOIN creators USING (creatorID)
+        WHERE LOWER(creators.lastName) LIKE ? ESCAPE '\' OR LOWER(creators.firstName) LIKE ? ESCAPE '\')"#
+                .into()
+        }
+        Title(title) => {
+            params.push(like(title));
+            r#"items.itemID IN (
+    SELECT itemID FROM itemData JOIN fields USING (fieldID) JOIN itemDataValues USING (valueID)
+        WHERE fields.fieldName = "title" AND LOWER(itemDataValues.value) LIKE ? ESCAPE '\')"#
+                .into()
+        }
+        Year(y1, y2) => {
+            params.push(Param::Int(*y1));
+            params.push(Param::Int(*y2));
+            // date is stored as "2008-05-01 2008/05/01"
+            r#"items.itemID IN (
+    SELECT itemID FROM itemData JOIN fields USING (fieldID) JOIN itemDataValues USING (valueID)
+        WHERE fields.fieldName = "date" AND CAST(SUBSTR(itemDataValues.value, 1, 4) AS INTEGER) BETWEEN ? AND ?)"#
+                .into()
+        }
+        Type(name) => {
+            params.push(Param::Text(name.to_lowercase()));
+            "items.itemTypeID IN (SELECT itemTypeID FROM itemTypes WHERE LOWER(typeName) = ?)".into()
+        }
+        Has(child) => {
+            let children = match child.as_str() {
+                "pdf" => "SELECT parentItemID FROM itemAttachments WHERE contentType = 'application/pdf'",
+                "attachment" => "SELECT parentItemID FROM itemAttachments WHERE parentItemID IS NOT NULL",
+                "note" => "SELECT parentItemID FROM itemNotes WHERE parentItemID IS NOT NULL",
+                _ => bail!("invalid has: {}", child),
+            };
+            format!(
+                "items.itemID IN ({} AND {})",
+                children,
+                trash.sql(&["itemID", "parentItemID"])
+            )
+        }
+        Added(op, date) => {
+            if !["<", "<=", ">", ">=", "="].contains(&op.as_str()) {
+                bail!("invalid operator: {}", op);
+            }
+            params.push(Param::Text(date.into()));
+            format!("DATE(items.dateAdded) {} DATE(?)", op)
+        }
+        And(a, b) => format!("({} AND {})", compile(a, trash, params)?, compile(b, trash, params)?),
+        Or(a, b) => format!("({} OR {})", compile(a, trash, params)?, compile(b, trash, params)?),
+        Not(a) => format!("NOT {}", compile(a, trash, params)?),
+    };
+    Ok(sql)
+}
+
+impl ZoteroDb {
+    /// Return regular items matching `query`, newest added first. Items in
+    /// trash are filtered by `trash`.
+    pub(crate) async fn get_items_by_query(&self, query: &ItemQuery, trash: TrashFilter) -> Result<Vec<Item>> {
+        let mut params = vec![];
+        let cond = compile(query, trash, &mut params)?;
+        let sql = format!(
+            r#"
+SELECT items.key FROM items
+WHERE {}
+  AND items.itemID NOT IN (select itemID from itemAttachments)
+  AND items.itemID NOT IN (select itemID from itemNotes)
+  AND {}
+ORDER BY items.dateAdded DESC
+"#,
+            trash.sql(&["items.itemID"]),
+            cond
+        );
+        let mut q = sqlx::query(&sql);
+        for p in params {
+            q = match p {
+                Param::Text(s) => q.bind(s),
+                Param::Int(i) => q.bind(i),
+            };
+        }
+        let recs = q.fetch_all(self.pool()).await?;
+
+        let mut all = vec![];
+        for rec in recs {
+            let key: String = rec.try_get("key")?;
+            all.push(self.get_item(&key).await?);
+        }
+        Ok(all)
+    }
+}
+
+impl SqliteBackend {
+    /// Search items by `query` in the query language of `ItemQuery`, e.g.
+    /// `tag:dft year:2019..2021 NOT has:pdf`
+    pub fn query(&self, query: &str) -> Result<Vec<Item>> {
+        let query = query.parse()?;
+        self.runtime()
+            .block_on(self.db().get_items_by_query(&query, self.trash_filter()))
+    }
+}
+
+/// Search zotero items in database `db_file` by `query`, e.g. `tag:dft
+/// (author:smith OR author:doe)`
+pub fn search_items<P: AsRef<std::path::Path>>(db_file: P, query: &str) -> Result<Vec<Item>> {
+    SqliteBackend::open(db_file)?.query(query)
+}
+// c47e1b89 ends here
+
+// [[file:../zotero.note::*test][test:1]]
+#[tokio::test]
+async fn test_item_query() -> Result<()> {
+    use crate::fixture::TestDb;
+    use ItemQuery::*;
+
+    let q: ItemQuery = "tag:dft (author:smith OR author:parr) NOT has:pdf".parse()?;
+    let expected = And(
+        Box::new(And(
+            Box::new(Tag("dft".into())),
+            Box::new(Or(Box::new(Author("smith".into())), Box::new(Author("parr".into())))),
+        )),
+        Box::new(Not(Box::new(Has("pdf".into())))),
+    );
+    assert_eq!(q, expected);
+    assert_eq!(
+        "title:\"zeolite catalysis\"".parse::<ItemQuery>()?,
+        Title("zeolite catalysis".into())
+    );
+    assert!("(tag:dft".parse::<ItemQuery>().is_err());
+    assert!("tag:dft)".parse::<ItemQuery>().is_err());
+    assert!("added:>2024-13-01".parse::<ItemQuery>().is_err());
+    assert!("has:video".parse::<ItemQuery>().is_err());
+
+    // a pdf trashed together with D
+    let test_db = TestDb::with_sql(
+        r#"
+INSERT INTO items (itemID, itemTypeID, libraryID, key) VALUES (6, 14, 1, 'FFFFFFFF');
+INSERT INTO itemAttachments VALUES (6, 4, 0, 'application/pdf', NULL, 'storage:deleted.pdf', 0);
+INSERT INTO deletedItems VALUES (6, '2021-01-01 00:00:00');
+"#,
+    );
+    let db = ZoteroDb::connect(test_db.path().to_str().unwrap()).await?;
+    let trash_keys = |q: &'static str, trash: TrashFilter| {
+        let db = &db;
+        async move {
+            let items = db.get_items_by_query(&q.parse()?, trash).await?;
+            Result::<Vec<String>>::Ok(items.iter().map(|x| x.key().to_string()).collect())
+        }
+    };
+    let keys = |q: &'static str| trash_keys(q, TrashFilter::default());
+    assert_eq!(keys("tag:dft").await?, vec!["BBBBBBBB", "AAAAAAAA"]);
+    assert_eq!(keys("tag:dft NOT has:pdf").await?, vec!["BBBBBBBB"]);
+    assert_eq!(
+        keys("has:note OR collection:zeolites").await?,
+        vec!["BBBBBBBB", "AAAAAAAA"]
+    );
+    assert_eq!(keys("collection:COLLBBBB").await?, vec!["AAAAAAAA"]);
+    assert_eq!(keys("year:2000..2010 type:journalArticle").await?, vec!["AAAAAAAA"]);
+    assert_eq!(keys("year:..1990").await?, vec!["BBBBBBBB"]);
+    assert_eq!(keys("added:>2020-01-15").await?, vec!["BBBBBBBB"]);
+    assert_eq!(keys("smith zeolite").await?, vec!["AAAAAAAA"]);
+    assert_eq!(keys("title:density AND author:robert").await?, vec!["BBBBBBBB"]);
+    // items in trash are excluded
+    assert!(keys("title:deleted").await?.is_empty());
+    assert_eq!(keys("has:pdf").await?, vec!["AAAAAAAA"]);
+    assert_eq!(trash_keys("has:pdf", TrashFilter::Only).await?, vec!["DDDDDDDD"]);
+    assert_eq!(
+        trash_keys("has:pdf", TrashFilter::Include).await?,
+        vec!["DDDDDDDD", "AAAAAAAA"]
+    );
+    // wildcards are matched literally
+    assert!(keys("title:_eolite").await?.is_empty());
+    assert!(keys("tag:%").await?.is_empty());
+    assert_eq!(escape_like(r"100%_a\b"), r"100\%\_a\\b");
+
+    // in given database
+    let db_file = test_db.path().to_owned();
+    let items = tokio::task::spawn_blocking(move || search_items(db_file, "tag:dft NOT has:pdf")).await??;
+    assert_eq!(items.len(), 1);
+    assert_eq!(items[0].key(), "BBBBBBBB");
+
+    Ok(())
+}
+// test:1 ends here
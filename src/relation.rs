@@ -0,0 +1,390 @@
+// [[file:../zotero.note::*imports][imports:1]]
+use gut::prelude::*;
+use std::collections::HashMap;
+
+use sqlx::prelude::*;
+
+use crate::backend::SqliteBackend;
+use crate::db::{Item, ZoteroDb};
+use crate::trash::TrashFilter;
+// imports:1 ends here
+
+// [[file:../zotero.note::d51a7c08][d51a7c08]]
+/// Predicate of a relation between zotero items
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum RelationPredicate {
+    /// `dc:relation`: related items set by user
+    Relation,
+    /// `dc:replaces`: the subject was merged from the object as duplicate
+    Replaces,
+    /// `owl:sameAs`: copies of the same item in different libraries
+    SameAs,
+    Other(String),
+}
+
+impl RelationPredicate {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Relation => "dc:relation",
+            Self::Replaces => "dc:replaces",
+            Self::SameAs => "owl:sameAs",
+            Self::Other(s) => s,
+        }
+    }
+}
+
+impl From<String> for RelationPredicate {
+    fn from(s: String) -> Self {
+        match s.as_str() {
+            "dc:relation" => Self::Relation,
+            "dc:replaces" => Self::Replaces,
+            "owl:sameAs" => Self::SameAs,
+            _ => Self::Other(s),
+        }
+    }
+}
+
+impl From<RelationPredicate> for String {
+    fn from(p: RelationPredicate) -> Self {
+        p.as_str().to_string()
+    }
+}
+
+impl std::fmt::Display for RelationPredicate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Library of an object in relation URI
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ObjectLibrary {
+    /// The user library, with user ID or `local/ID` before syncing
+    User(String),
+    /// A group library with group ID
+    Group(i64),
+}
+
+/// An object URI in item relations, such as
+///
+/// - http://zotero.org/users/15074/items/M2S2HTNN
+/// - http://zotero.org/users/local/a1b2c3d4/items/M2S2HTNN
+/// - http://zotero.org/groups/2380/items/M2S2HTNN
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ObjectUri {
+    pub library: ObjectLibrary,
+    pub key: String,
+}
+
+impl ObjectUri {
+    /// Parse an item URI. Return None for other objects or URIs not in
+    /// zotero.org.
+    pub fn parse(uri: &str) -> Option<Self> {
+        let path = uri
+            .strip_prefix("http://zotero.org/")
+            .or_else(|| uri.strip_prefix("https://zotero.org/"))?;
+        let (library, key) = path.rsplit_once("/items/")?;
+        if key.is_empty() || key.contains('/') {
+            return None;
+        }
+        let library = if let Some(user) = library.strip_prefix("users/") {
+            ObjectLibrary::User(user.into())
+        } else {
+            ObjectLibrary::Group(library.strip_prefix("groups/")?.parse().ok()?)
+        };
+        Some(Self {
+            library,
+            key: key.into(),
+        })
+    }
+}
+
+/// A relation between zotero items, as stored in database
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ItemRelation {
+    /// Key of subject item
+    pub subject: String,
+    /// Library ID of subject item
+    pub library: i64,
+    pub predicate: RelationPredicate,
+    /// Key of object item, which may not be in local database
+    pub object: String,
+    /// Library ID of object item, or None if its library is not in local
+    /// database
+    pub object_library: Option<i64>,
+    /// Object URI as stored
+    pub uri: String,
+}
+// d51a7c08 ends here
+
+// [[file:../zotero.note::6f20b3e9][6f20b3e9]]
+// Relations with subject, predicate and object URI
+const RELATIONS: &str = r#"
+SELECT items.key as subject, items.libraryID as library, relationPredicates.predicate as predicate,
+       itemRelations.object as uri
+FROM itemRelations
+    JOIN items USING (itemID)
+    JOIN relationPredicates USING (predicateID)
+"#;
+
+impl ZoteroDb {
+    // Return libraryID of `library` in object URI, or None if it is not in
+    // local database
+    async fn get_uri_library_id(&self, library: &ObjectLibrary) -> Result<Option<i64>> {
+        let rec = match library {
+            ObjectLibrary::User(_) => {
+                sqlx::query("SELECT libraryID FROM libraries WHERE type = 'user'")
+                    .fetch_optional(self.pool())
+                    .await?
+            }
+            ObjectLibrary::Group(id) => {
+                sqlx::query("SELECT libraryID FROM groups WHERE groupID = ?")
+                    .bind(id)
+                    .fetch_optional(self.pool())
+                    .await?
+            }
+        };
+        rec.map(|x| x.try_get("libraryID")).transpose().map_err(Into::into)
+    }
+
+    // Parse relations in `recs`, with object libraries resolved
+    async fn parse_relations(&self, recs: Vec<sqlx::sqlite::SqliteRow>) -> Result<Vec<ItemRelation>> {
+        let mut libraries: HashMap<String, Option<i64>> = HashMap::new();
+        let mut relations = vec![];
+        for rec in recs {
+            let uri: String = rec.try_get("uri")?;
+            let object = match ObjectUri::parse(&uri) {
+                Some(object) => object,
+                None => continue,
+            };
+            let library_uri = uri.rsplit_once("/items/").map(|x| x.0).unwrap_or_default().to_string();
+            let object_library = match libraries.get(&library_uri) {
+                Some(id) => *id,
+                None => {
+                    let id = self.get_uri_library_id(&object.library).await?;
+                    libraries.insert(library_uri, id);
+                    id
+                }
+            };
+            relations.push(ItemRelation {
+                subject: rec.try_get("subject")?,
+                library: rec.try_get("library")?,
+                predicate: rec.try_get::<String, _>("predicate")?.into(),
+                object: object.key,
+                object_library,
+                uri,
+            });
+        }
+        Ok(relations)
+    }
+
+    /// Return relations of item in `key`, both as subject and as object. An
+    /// object URI refers to the item only in the same library.
+    pub(crate) async fn get_item_relations(&self, key: &str) -> Result<Vec<ItemRelation>> {
+        let libraries: Vec<i64> = sqlx::query("SELECT libraryID FROM items WHERE key = ?")
+            .bind(key)
+            .fetch_all(self.pool())
+            .await?
+            .iter()
+            .map(|x| x.try_get("libraryID"))
+            .collect::<Result<_, _>>()?;
+
+        let sql = format!(
+            "{} WHERE items.key = ? OR itemRelations.object LIKE ? ORDER BY items.key, relationPredicates.predicate",
+            RELATIONS
+        );
+        let recs = sqlx::query(&sql)
+            .bind(key)
+            .bind(format!("%/items/{}", key))
+            .fetch_all(self.pool())
+            .await?;
+        let relations = self.parse_relations(recs).await?;
+        let found = relations
+            .into_iter()
+            .filter(|x| {
+                x.subject == key
+                    || (x.object == key && x.object_library.map(|l| libraries.contains(&l)).unwrap_or(false))
+            })
+            .collect();
+        Ok(found)
+    }
+
+    // Return true if item in `key` of `library` is a regular item or note
+    // kept by `trash`
+    async fn is_live_item(&self, key: &str, library: i64, trash: TrashFilter) -> Result<bool> {
+        let sql = format!(
+            r#"
+SELECT COUNT(*) as n FROM items
+JOIN itemTypes USING (itemTypeID)
+WHERE items.key = ? AND items.libraryID = ?
+  AND itemTypes.typeName NOT IN ('attachment', 'annotation')
+  AND {}
+"#,
+            trash.sql(&["items.itemID"])
+        );
+        let rec = sqlx::query(&sql).bind(key).bind(library).fetch_one(self.pool()).await?;
+        Ok(rec.try_get::<i64, _>("n")? > 0)
+    }
+
+    /// Return the item currently standing for item in `key` of `library`,
+    /// filtered by `trash`. Items merged as duplicates are moved into trash,
+    /// or removed after emptying trash, and the master item records them with
+    /// `dc:replaces`.
+    pub(crate) async fn get_current_item_key(
+        &self,
+        key: &str,
+        library: i64,
+        trash: TrashFilter,
+    ) -> Result<Option<String>> {
+        let mut key = key.to_string();
+        let mut seen = vec![];
+        loop {
+            if self.is_live_item(&key, library, trash).await? {
+                return Ok(Some(key));
+            }
+            seen.push(key.clone());
+            let sql = format!(
+                "{} WHERE relationPredicates.predicate = 'dc:replaces' AND itemRelations.object LIKE ? AND items.libraryID = ?",
+                RELATIONS
+            );
+            let recs = sqlx::query(&sql)
+                .bind(format!("%/items/{}", key))
+                .bind(library)
+                .fetch_all(self.pool())
+                .await?;
+            let master = self
+                .parse_relations(recs)
+                .await?
+                .into_iter()
+                .filter(|x| x.object == key && x.object_library == Some(library))
+                .map(|x| x.subject)
+                .find(|x| !seen.contains(x));
+            match master {
+                Some(master) => key = master,
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Return items related to item in `key` with `dc:relation`, in either
+    /// direction, filtered by `trash`. Relations to merged duplicates are
+    /// resolved to their master items.
+    pub(crate) async fn get_related_items(&self, key: &str, trash: TrashFilter) -> Result<Vec<Item>> {
+        let mut keys: Vec<String> = vec![];
+        for relation in self.get_item_relations(key).await? {
+            if relation.predicate != RelationPredicate::Relation {
+                continue;
+            }
+            let (this, other) = if relation.subject == key {
+                (Some(relation.library), (relation.object, relation.object_library))
+            } else {
+                (relation.object_library, (relation.subject, Some(relation.library)))
+            };
+            // the item itself needs to be alive
+            match this {
+                Some(library) if self.is_live_item(key, library, trash).await? => {}
+                _ => continue,
+            }
+            let (other, library) = match other {
+                (other, Some(library)) => (other, library),
+                _ => continue,
+            };
+            if let Some(other) = self.get_current_item_key(&other, library, trash).await? {
+                if other != key && !keys.contains(&other) {
+                    keys.push(other);
+                }
+            }
+        }
+
+        let mut related = vec![];
+        for key in keys {
+            related.push(self.get_item(&key).await?);
+        }
+        Ok(related)
+    }
+}
+
+impl SqliteBackend {
+    /// Return all relations of item in `key`
+    pub fn relations(&self, key: &str) -> Result<Vec<ItemRelation>> {
+        self.runtime().block_on(self.db().get_item_relations(key))
+    }
+
+    /// Return items related to item in `key`. Items in trash follow the trash
+    /// filter.
+    pub fn related_items(&self, key: &str) -> Result<Vec<Item>> {
+        self.runtime()
+            .block_on(self.db().get_related_items(key, self.trash_filter()))
+    }
+}
+// 6f20b3e9 ends here
+
+// [[file:../zotero.note::*test][test:1]]
+#[test]
+fn test_item_relations() -> Result<()> {
+    use crate::fixture::TestDb;
+
+    let uri = ObjectUri::parse("http://zotero.org/groups/2380/items/M2S2HTNN").unwrap();
+    assert_eq!(uri.library, ObjectLibrary::Group(2380));
+    let uri = ObjectUri::parse("http://zotero.org/users/local/a1b2c3d4/items/M2S2HTNN").unwrap();
+    assert_eq!(uri.library, ObjectLibrary::User("local/a1b2c3d4".into()));
+    assert_eq!(
+        ObjectUri::parse("http://zotero.org/users/15074/collections/M2S2HTNN"),
+        None
+    );
+
+    // F relates to A only one way, G replaces the trashed D, H (in group
+    // library) relates to D. F relates to B in group library, which is not
+    // the B in user library.
+    let test_db = TestDb::with_sql(
+        r#"
+INSERT INTO items (itemID, itemTypeID, libraryID, key) VALUES (6, 4, 1, 'FFFFFFFF'), (7, 4, 1, 'GGGGGGGG'),
+    (8, 4, 2, 'HHHHHHHH');
+INSERT INTO itemRelations VALUES (6, 2, 'http://zotero.org/users/15074/items/AAAAAAAA'),
+    (7, 3, 'http://zotero.org/users/15074/items/DDDDDDDD'),
+    (7, 1, 'http://zotero.org/groups/2380/items/HHHHHHHH'),
+    (8, 2, 'http://zotero.org/users/15074/items/DDDDDDDD'),
+    (6, 2, 'http://zotero.org/groups/2380/items/BBBBBBBB');
+INSERT INTO libraries VALUES (2, 'group', 1, 5);
+INSERT INTO groups (groupID, libraryID, name) VALUES (2380, 2, 'catalysis');
+"#,
+    );
+    let backend = SqliteBackend::open(test_db.path())?;
+
+    let related: Vec<_> = backend
+        .related_items("AAAAAAAA")?
+        .iter()
+        .map(|x| x.key().to_string())
+        .collect();
+    assert_eq!(related, vec!["BBBBBBBB", "FFFFFFFF"]);
+    let related: Vec<_> = backend
+        .related_items("HHHHHHHH")?
+        .iter()
+        .map(|x| x.key().to_string())
+        .collect();
+    assert_eq!(related, vec!["GGGGGGGG"]);
+    // attachments have no related items
+    assert!(backend.related_items("CCCCCCCC")?.is_empty());
+    // the trashed D itself when trash is included
+    let backend = SqliteBackend::open(test_db.path())?.with_trash_filter(TrashFilter::Include);
+    let related: Vec<_> = backend
+        .related_items("HHHHHHHH")?
+        .iter()
+        .map(|x| x.key().to_string())
+        .collect();
+    assert_eq!(related, vec!["DDDDDDDD"]);
+
+    let relations = backend.relations("GGGGGGGG")?;
+    let predicates: Vec<_> = relations.iter().map(|x| x.predicate.as_str()).collect();
+    assert_eq!(predicates, vec!["dc:replaces", "owl:sameAs"]);
+    assert_eq!(relations[1].object, "HHHHHHHH");
+    assert_eq!(relations[1].object_library, Some(2));
+    assert!(backend.relations("BBBBBBBB")?.iter().all(|x| x.subject != "FFFFFFFF"));
+    assert_eq!(backend.relations("FFFFFFFF")?.len(), 2);
+    let json = serde_json::to_value(&relations[0])?;
+    assert_eq!(json["predicate"], "dc:replaces");
+
+    Ok(())
+}
+// test:1 ends here
@@ -0,0 +1,620 @@
+// [[file:../zotero.note::*imports][imports:1]]
+use gut::prelude::*;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use sqlx::prelude::*;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool};
+
+use crate::backend::SqliteBackend;
+use crate::changes::{Changes, Watermark};
+use crate::db::ZoteroDb;
+use crate::trash::TrashFilter;
+// imports:1 ends here
+
+// [[file:../zotero.note::4f7a2c91][4f7a2c91]]
+// Text of a regular zotero item to be indexed
+#[derive(Debug, Default)]
+struct SearchDocument {
+    key: String,
+    year: Option<i64>,
+    title: String,
+    creators: String,
+    abstract_note: String,
+    tags: String,
+    notes: String,
+    annotations: String,
+    // keys of attachments, for reading full text cached by zotero
+    attachments: Vec<String>,
+}
+
+// Strip tags in zotero notes
+fn html_to_text(html: &str) -> String {
+    let mut text = String::new();
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => {
+                in_tag = false;
+                text.push(' ');
+            }
+            _ if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    text.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&amp;", "&")
+}
+
+impl ZoteroDb {
+    pub(crate) async fn has_table(&self, name: &str) -> Result<bool> {
+        let recs = sqlx::query("SELECT name FROM sqlite_master WHERE type = 'table' AND name = ?")
+            .bind(name)
+            .fetch_all(self.pool())
+            .await?;
+        Ok(!recs.is_empty())
+    }
+
+    // Return key of parent item of attachment, note or annotation in `key`
+    async fn get_parent_key(&self, key: &str) -> Result<Option<String>> {
+        let mut children = "SELECT itemID, parentItemID FROM itemAttachments
+    UNION SELECT itemID, parentItemID FROM itemNotes"
+            .to_string();
+        if self.has_table("itemAnnotations").await? {
+            children.push_str(" UNION SELECT itemID, parentItemID FROM itemAnnotations");
+        }
+        let sql = format!(
+            r#"
+SELECT p.key as parent FROM items i
+    JOIN ({}) c ON c.itemID = i.itemID
+    JOIN items p ON p.itemID = c.parentItemID
+    WHERE i.key = ?
+"#,
+            children
+        );
+        let rec = sqlx::query(&sql).bind(key).fetch_optional(self.pool()).await?;
+        Ok(rec.map(|x| x.try_get("parent")).transpose()?)
+    }
+
+    // Return key of top level item `key` belongs to, e.g. the parent item of
+    // an annotation in a pdf attachment.
+    async fn get_top_item_key(&self, key: &str) -> Result<String> {
+        let mut key = key.to_string();
+        // item > attachment > annotation
+        for _ in 0..3 {
+            match self.get_parent_key(&key).await? {
+                Some(parent) => key = parent,
+                None => break,
+            }
+        }
+        Ok(key)
+    }
+
+    // Collect text of regular item in `key`. Return None if the item is not
+    // found, filtered out by `trash`, or not a regular item.
+    async fn get_search_document(&self, key: &str, trash: TrashFilter) -> Result<Option<SearchDocument>> {
+        let mut sql = format!(
+            r#"
+SELECT itemID FROM items
+WHERE key = ?
+  AND {}
+  AND itemID NOT IN (select itemID from itemAttachments)
+  AND itemID NOT IN (select itemID from itemNotes)
+"#,
+            trash.sql(&["itemID"])
+        );
+        let has_annotations = self.has_table("itemAnnotations").await?;
+        if has_annotations {
+            sql.push_str("  AND itemID NOT IN (select itemID from itemAnnotations)");
+        }
+        let rec = match sqlx::query(&sql).bind(key).fetch_optional(self.pool()).await? {
+            Some(rec) => rec,
+            None => return Ok(None),
+        };
+        let id: i64 = rec.try_get("itemID")?;
+
+        let fields = self.get_item_fields(key).await?;
+        let field = |name: &str| fields.get(name).cloned().unwrap_or_default();
+        let date = field("date");
+        let year = date.get(..4).and_then(|x| x.parse().ok()).filter(|&x| x > 0);
+        let creators = self
+            .get_item_creators(key)
+            .await?
+            .into_iter()
+            .map(|x| format!("{} {}", x.first_name, x.last_name).trim().to_string())
+            .collect::<Vec<_>>()
+            .join("; ");
+
+        let texts = |sql: String| async move {
+            let recs = sqlx::query(&sql).bind(id).fetch_all(self.pool()).await?;
+            recs.iter()
+                .map(|x| Ok(x.try_get::<Option<String>, _>(0)?.unwrap_or_default()))
+                .collect::<Result<Vec<String>>>()
+        };
+        let tags =
+            texts("SELECT tags.name FROM itemTags JOIN tags USING (tagID) WHERE itemTags.itemID = ?".into()).await?;
+        // child items are filtered together with their parents
+        let children = trash.sql(&["itemID", "parentItemID"]);
+        let notes = texts(format!(
+            "SELECT note FROM itemNotes WHERE parentItemID = ? AND {}",
+            children
+        ))
+        .await?;
+        let attachments = texts(format!(
+            r#"
+SELECT items.key FROM itemAttachments JOIN items USING (itemID)
+    WHERE itemAttachments.parentItemID = ?
+    AND {}
+"#,
+            trash.sql(&["itemAttachments.itemID", "itemAttachments.parentItemID"])
+        ))
+        .await?;
+        let annotations = if has_annotations {
+            texts(format!(
+                r#"
+SELECT IFNULL(a.text, '') || ' ' || IFNULL(a.comment, '') FROM itemAnnotations a
+    JOIN itemAttachments p ON p.itemID = a.parentItemID
+    WHERE p.parentItemID = ?
+    AND {}
+"#,
+                trash.sql(&["p.itemID", "p.parentItemID"])
+            ))
+            .await?
+        } else {
+            vec![]
+        };
+
+        let doc = SearchDocument {
+            key: key.to_string(),
+            year,
+            title: field("title"),
+            creators,
+            abstract_note: field("abstractNote"),
+            tags: tags.join("; "),
+            notes: notes.iter().map(|x| html_to_text(x)).collect::<Vec<_>>().join("\n"),
+            annotations: annotations.join("\n"),
+            attachments,
+        };
+        Ok(Some(doc))
+    }
+}
+// 4f7a2c91 ends here
+
+// [[file:../zotero.note::9b3e5d08][9b3e5d08]]
+// columns of the full text index, with weights in ranking
+const COLUMNS: &[(&str, f64)] = &[
+    ("title", 10.0),
+    ("creators", 5.0),
+    ("abstract", 3.0),
+    ("tags", 3.0),
+    ("notes", 1.0),
+    ("annotations", 1.0),
+    ("fulltext", 0.5),
+];
+
+// Query for full text index, parsed from user input
+#[derive(Debug, Default, PartialEq)]
+struct SearchQuery {
+    // FTS5 match expression
+    terms: Vec<String>,
+    // inclusive year range
+    years: Option<(i64, i64)>,
+}
+
+// Split `input` by whitespace, keeping double quoted phrases together.
+fn split_query(input: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    let mut token = String::new();
+    let mut quoted = false;
+    for c in input.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                token.push(c);
+            }
+            c if c.is_whitespace() && !quoted => {
+                if !token.is_empty() {
+                    tokens.push(std::mem::take(&mut token));
+                }
+            }
+            _ => token.push(c),
+        }
+    }
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+    tokens
+}
+
+fn parse_year_range(s: &str) -> Result<(i64, i64)> {
+    let year = |x: &str, default: i64| -> Result<i64> {
+        if x.is_empty() {
+            Ok(default)
+        } else {
+            x.parse().with_context(|| format!("invalid year: {}", x))
+        }
+    };
+    match s.split_once("..") {
+        Some((a, b)) => Ok((year(a, 0)?, year(b, 9999)?)),
+        None => {
+            let y = year(s, 0)?;
+            Ok((y, y))
+        }
+    }
+}
+
+// FTS5 expression for `text`: prefix search for plain words, exact match for
+// quoted phrases.
+fn match_expr(text: &str) -> String {
+    if let Some(phrase) = text.strip_prefix('"') {
+        let phrase = phrase.strip_suffix('"').unwrap_or(phrase);
+        format!("\"{}\"", phrase.replace('"', "\"\""))
+    } else {
+        format!("\"{}\"*", text.replace('"', "\"\""))
+    }
+}
+
+// Parse user query such as `zeolite author:smith year:2019..2021`
+fn parse_search_query(input: &str) -> Result<SearchQuery> {
+    let mut query = SearchQuery::default();
+    for token in split_query(input) {
+        // boolean operators of FTS5
+        if matches!(token.as_str(), "AND" | "OR" | "NOT") {
+            query.terms.push(token);
+            continue;
+        }
+        let (field, text) = match token.split_once(':') {
+            Some((field, text)) if !text.is_empty() && !field.starts_with('"') => (field.to_lowercase(), text),
+            _ => (String::new(), token.as_str()),
+        };
+        let column = match field.as_str() {
+            "" => None,
+            "year" => {
+                query.years = Some(parse_year_range(text)?);
+                continue;
+            }
+            "title" => Some("title"),
+            "author" | "creator" => Some("creators"),
+            "abstract" => Some("abstract"),
+            "tag" => Some("tags"),
+            "note" => Some("notes"),
+            "annotation" => Some("annotations"),
+            "fulltext" | "text" => Some("fulltext"),
+            // not a field, e.g. a DOI
+            _ => None,
+        };
+        match column {
+            Some(column) => query.terms.push(format!("{} : {}", column, match_expr(text))),
+            None => query.terms.push(match_expr(&token)),
+        }
+    }
+    Ok(query)
+}
+// 9b3e5d08 ends here
+
+// [[file:../zotero.note::e2c6a8f4][e2c6a8f4]]
+/// A zotero item found in `SearchIndex`
+#[derive(Debug, Clone)]
+pub struct SearchHit {
+    pub key: String,
+    pub year: Option<i64>,
+    /// Title with matches highlighted
+    pub title: String,
+    /// Text around the best match, highlighted
+    pub snippet: String,
+    /// Relevance by BM25, the larger the better
+    pub score: f64,
+}
+
+/// Full text search index of zotero items in a side sqlite database, ranked
+/// by BM25. The index covers titles, creators, abstracts, tags, notes,
+/// annotations and full text of attachments cached by zotero.
+pub struct SearchIndex {
+    pool: SqlitePool,
+    rt: tokio::runtime::Runtime,
+    storage: Option<PathBuf>,
+    highlight: (String, String),
+}
+
+impl SearchIndex {
+    /// Open search index in `path`, which will be created if missing.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        let options = SqliteConnectOptions::new().filename(path).create_if_missing(true);
+        let pool = rt
+            .block_on(async {
+                let pool = SqlitePool::connect_with(options).await?;
+                let columns: Vec<_> = COLUMNS.iter().map(|x| x.0).collect();
+                let sql = format!(
+                    r#"
+CREATE TABLE IF NOT EXISTS entries (id INTEGER PRIMARY KEY, key TEXT NOT NULL UNIQUE, year INTEGER);
+CREATE TABLE IF NOT EXISTS state (name TEXT PRIMARY KEY, value TEXT NOT NULL);
+CREATE VIRTUAL TABLE IF NOT EXISTS docs USING fts5({}, prefix = '2 3', tokenize = 'unicode61 remove_diacritics 2');
+"#,
+                    columns.join(", ")
+                );
+                pool.execute(sql.as_str()).await?;
+                Result::<_>::Ok(pool)
+            })
+            .with_context(|| format!("open search index: {:?}", path))?;
+
+        Ok(Self {
+            pool,
+            rt,
+            storage: None,
+            highlight: ("*".into(), "*".into()),
+        })
+    }
+
+    /// Read full text cached by zotero from `storage` directory. The default
+    /// is the storage directory next to the database being indexed.
+    pub fn storage_dir<P: AsRef<Path>>(mut self, storage: P) -> Self {
+        self.storage = Some(storage.as_ref().to_owned());
+        self
+    }
+
+    /// Mark matches in search results with `open` and `close`. The default
+    /// is org-mode bold markup.
+    pub fn highlight_with(mut self, open: &str, close: &str) -> Self {
+        self.highlight = (open.into(), close.into());
+        self
+    }
+
+    /// The watermark of database changes indexed so far
+    pub fn watermark(&self) -> Result<Watermark> {
+        self.rt.block_on(async {
+            let rec = sqlx::query("SELECT value FROM state WHERE name = 'watermark'")
+                .fetch_optional(&self.pool)
+                .await?;
+            match rec {
+                Some(rec) => {
+                    let value: String = rec.try_get("value")?;
+                    Ok(serde_json::from_str(&value)?)
+                }
+                None => Ok(Watermark::default()),
+            }
+        })
+    }
+
+    /// Update index with items changed in zotero database since last sync.
+    /// Everything is indexed on first sync, or when erased items can not be
+    /// identified. Items in trash are indexed following the trash filter of
+    /// `backend`.
+    pub fn sync(&self, backend: &SqliteBackend) -> Result<Changes> {
+        let mut changes = backend.changes_since(&self.watermark()?)?;
+        let reindex = changes.incomplete;
+        if reindex {
+            info!("some erased items are unknown, rebuilding search index");
+            changes = backend.changes_since(&Watermark::default())?;
+            changes.incomplete = true;
+        }
+
+        let trash = backend.trash_filter();
+        let (docs, removed) = backend.runtime().block_on(async {
+            let db = backend.db();
+            let mut keys = BTreeSet::new();
+            for key in changes.added.iter().chain(&changes.modified).chain(&changes.deleted) {
+                keys.insert(db.get_top_item_key(key).await?);
+            }
+            // trashed items may still be wanted
+            let mut removed = vec![];
+            let mut docs = vec![];
+            for key in keys {
+                match db.get_search_document(&key, trash).await? {
+                    Some(doc) => docs.push(doc),
+                    None => removed.push(key),
+                }
+            }
+            Result::<_>::Ok((docs, removed))
+        })?;
+
+        let storage = self
+            .storage
+            .clone()
+            .unwrap_or_else(|| backend.path().with_file_name("storage"));
+        let watermark = serde_json::to_string(&changes.watermark)?;
+        self.rt.block_on(async {
+            let mut tx = self.pool.begin().await?;
+            if reindex {
+                sqlx::query("DELETE FROM docs").execute(&mut *tx).await?;
+                sqlx::query("DELETE FROM entries").execute(&mut *tx).await?;
+            }
+            for key in removed.iter().chain(docs.iter().map(|x| &x.key)) {
+                sqlx::query("DELETE FROM docs WHERE rowid IN (SELECT id FROM entries WHERE key = ?)")
+                    .bind(key)
+                    .execute(&mut *tx)
+                    .await?;
+                sqlx::query("DELETE FROM entries WHERE key = ?")
+                    .bind(key)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            for doc in &docs {
+                let fulltext: Vec<_> = doc
+                    .attachments
+                    .iter()
+                    .filter_map(|key| std::fs::read(storage.join(key).join(".zotero-ft-cache")).ok())
+                    .map(|x| String::from_utf8_lossy(&x).into_owned())
+                    .collect();
+                let id = sqlx::query("INSERT INTO entries (key, year) VALUES (?, ?)")
+                    .bind(&doc.key)
+                    .bind(doc.year)
+                    .execute(&mut *tx)
+                    .await?
+                    .last_insert_rowid();
+                sqlx::query("INSERT INTO docs (rowid, title, creators, abstract, tags, notes, annotations, fulltext) VALUES (?, ?, ?, ?, ?, ?, ?, ?)")
+                    .bind(id)
+                    .bind(&doc.title)
+                    .bind(&doc.creators)
+                    .bind(&doc.abstract_note)
+                    .bind(&doc.tags)
+                    .bind(&doc.notes)
+                    .bind(&doc.annotations)
+                    .bind(fulltext.join("\n"))
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            sqlx::query("INSERT OR REPLACE INTO state (name, value) VALUES ('watermark', ?)")
+                .bind(&watermark)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+            Result::<_>::Ok(())
+        })?;
+
+        Ok(changes)
+    }
+
+    /// Search items by `query`, returning at most `limit` best matches.
+    /// Words are matched by prefix, "quoted phrases" exactly. Search can be
+    /// restricted to a field, e.g. `author:smith year:2019..2021`. Fields are
+    /// title, author, abstract, tag, note, annotation, fulltext and year.
+    pub fn search(&self, query: &str, limit: usize) -> Result<Vec<SearchHit>> {
+        let query = parse_search_query(query)?;
+        let (open, close) = &self.highlight;
+        let mut filters = vec![];
+        if !query.terms.is_empty() {
+            filters.push("docs MATCH ?");
+        }
+        if query.years.is_some() {
+            filters.push("entries.year BETWEEN ? AND ?");
+        }
+        if filters.is_empty() {
+            bail!("empty search query");
+        }
+        let sql = if query.terms.is_empty() {
+            // no ranking without MATCH
+            format!(
+                r#"
+SELECT entries.key as key, entries.year as year, docs.title as title, '' as snippet, 0.0 as score
+FROM docs JOIN entries ON entries.id = docs.rowid
+WHERE {}
+ORDER BY entries.year DESC LIMIT ?
+"#,
+                filters.join(" AND ")
+            )
+        } else {
+            let weights: Vec<_> = COLUMNS.iter().map(|x| format!("{:.1}", x.1)).collect();
+            format!(
+                r#"
+SELECT entries.key as key, entries.year as year,
+       highlight(docs, 0, ?, ?) as title,
+       snippet(docs, -1, ?, ?, '...', 16) as snippet,
+       -bm25(docs, {}) as score
+FROM docs JOIN entries ON entries.id = docs.rowid
+WHERE {}
+ORDER BY score DESC LIMIT ?
+"#,
+                weights.join(", "),
+                filters.join(" AND ")
+            )
+        };
+
+        let mut q = sqlx::query(&sql);
+        if !query.terms.is_empty() {
+            q = q.bind(open).bind(close).bind(open).bind(close);
+            q = q.bind(query.terms.join(" "));
+        }
+        if let Some((y1, y2)) = query.years {
+            q = q.bind(y1).bind(y2);
+        }
+        let q = q.bind(limit as i64);
+
+        let recs = self.rt.block_on(q.fetch_all(&self.pool))?;
+        recs.iter()
+            .map(|x| {
+                Ok(SearchHit {
+                    key: x.try_get("key")?,
+                    year: x.try_get("year")?,
+                    title: x.try_get("title")?,
+                    snippet: x.try_get("snippet")?,
+                    score: x.try_get("score")?,
+                })
+            })
+            .collect()
+    }
+}
+// e2c6a8f4 ends here
+
+// [[file:../zotero.note::*test][test:1]]
+#[test]
+fn test_search_index() -> Result<()> {
+    use crate::fixture::TestDb;
+
+    let query = parse_search_query(r#"zeo author:smith "thiophene cracking" year:2019.. doi:10.1016"#)?;
+    assert_eq!(
+        query.terms,
+        vec![
+            r#""zeo"*"#,
+            r#"creators : "smith"*"#,
+            r#""thiophene cracking""#,
+            r#""doi:10.1016"*"#
+        ]
+    );
+    assert_eq!(query.years, Some((2019, 9999)));
+    assert!(parse_search_query("year:20x").is_err());
+
+    let test_db = TestDb::with_sql(
+        r#"
+INSERT INTO items (itemID, itemTypeID, libraryID, key) VALUES (6, 37, 1, 'GGGGGGGG');
+INSERT INTO itemAnnotations (itemID, parentItemID, type, text, comment) VALUES
+    (6, 3, 1, 'acid sites in ZSM-5', 'important');
+"#,
+    );
+    let storage = test_db.path().with_file_name("storage");
+    std::fs::create_dir_all(storage.join("CCCCCCCC"))?;
+    std::fs::write(storage.join("CCCCCCCC/.zotero-ft-cache"), "desulfurization of gasoline")?;
+
+    let index_file = test_db.path().with_file_name("search.sqlite");
+    let index = SearchIndex::open(&index_file)?;
+    index.sync(&SqliteBackend::open(test_db.path())?)?;
+    let hits = index.search("zeol", 10)?;
+    assert_eq!(hits.len(), 1);
+    assert_eq!(hits[0].key, "AAAAAAAA");
+    assert_eq!(hits[0].title, "*Zeolite* catalysis of thiophene cracking");
+    // note and annotation are indexed with their parent items
+    assert_eq!(index.search("note:chapter", 10)?[0].key, "BBBBBBBB");
+    assert_eq!(index.search("zsm", 10)?[0].key, "AAAAAAAA");
+    assert_eq!(index.search("fulltext:desulfur", 10)?[0].key, "AAAAAAAA");
+    // trashed item is not indexed
+    assert!(index.search("deleted", 10)?.is_empty());
+    assert!(index.search("title:smith", 10)?.is_empty());
+    assert_eq!(index.search("author:smith year:2000..2010", 10)?.len(), 1);
+    assert_eq!(index.search("year:1989", 10)?[0].key, "BBBBBBBB");
+    // title matches rank higher
+    let hits = index.search("dft OR density", 10)?;
+    assert_eq!(hits[0].key, "BBBBBBBB");
+
+    test_db.execute(
+        r#"
+INSERT INTO itemDataValues VALUES (11, 'Zeolite membranes');
+INSERT INTO items (itemID, itemTypeID, libraryID, key) VALUES (7, 2, 1, 'FFFFFFFF');
+INSERT INTO itemData VALUES (7, 1, 11);
+INSERT INTO deletedItems VALUES (1, '2030-01-01 00:00:00');
+"#,
+    );
+    let changes = index.sync(&SqliteBackend::open(test_db.path())?)?;
+    assert_eq!(changes.added, vec!["FFFFFFFF"]);
+    let hits = index.search("zeolite", 10)?;
+    assert_eq!(hits.len(), 1);
+    assert_eq!(hits[0].key, "FFFFFFFF");
+    // reopened index continues from stored watermark
+    let index = SearchIndex::open(&index_file)?;
+    assert!(index.sync(&SqliteBackend::open(test_db.path())?)?.is_empty());
+
+    // only items in trash
+    let index = SearchIndex::open(test_db.path().with_file_name("trash.sqlite"))?;
+    index.sync(&SqliteBackend::open(test_db.path())?.with_trash_filter(TrashFilter::Only))?;
+    assert_eq!(index.search("deleted", 10)?[0].key, "DDDDDDDD");
+    let hits = index.search("zeolite", 10)?;
+    assert_eq!(hits.len(), 1);
+    assert_eq!(hits[0].key, "AAAAAAAA");
+
+    Ok(())
+}
+// test:1 ends here
@@ -2,18 +2,303 @@
 use gut::prelude::*;
 use serde::*;
 
-pub(crate) struct ZoteroServer {
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// Client for the HTTP server of a running zotero, including connector and
+/// zotxt endpoints.
+pub struct ZoteroServer {
     base_url: String,
+    client: reqwest::blocking::Client,
+    max_retries: usize,
+    backoff: Duration,
+    // zotero reports keys of items saved through connector
+    reports_saved_keys: AtomicBool,
 }
 
 impl Default for ZoteroServer {
+    fn default() -> Self {
+        Self::builder().build().expect("zotero server client")
+    }
+}
+
+impl ZoteroServer {
+    /// Construct a client for zotero server at `base_url`, e.g. http://127.0.0.1:23119
+    pub fn new(base_url: &str) -> Self {
+        Self::builder()
+            .base_url(base_url)
+            .build()
+            .expect("zotero server client")
+    }
+
+    /// Return a builder for configuring zotero server client
+    pub fn builder() -> ZoteroServerBuilder {
+        ZoteroServerBuilder::default()
+    }
+}
+// imports:1 ends here
+
+// [[file:../zotero.note::e1a94c07][e1a94c07]]
+/// Environment variable for overriding the default url of zotero server,
+/// which can also be set in `.env` file.
+pub const ZOTERO_SERVER_URL_ENV: &str = "ZOTERO_SERVER_URL";
+
+const DEFAULT_BASE_URL: &str = "http://127.0.0.1:23119";
+
+/// Builder for `ZoteroServer` client
+#[derive(Debug, Clone)]
+pub struct ZoteroServerBuilder {
+    base_url: Option<String>,
+    connect_timeout: Duration,
+    timeout: Duration,
+    max_retries: usize,
+    backoff: Duration,
+    install: Option<crate::profile::ZoteroInstall>,
+}
+
+impl Default for ZoteroServerBuilder {
     fn default() -> Self {
         Self {
-            base_url: "http://127.0.0.1:23119".into(),
+            base_url: None,
+            install: None,
+            connect_timeout: Duration::from_secs(2),
+            timeout: Duration::from_secs(10),
+            max_retries: 2,
+            backoff: Duration::from_millis(200),
         }
     }
 }
-// imports:1 ends here
+
+impl ZoteroServerBuilder {
+    /// Set url of zotero server, which takes precedence over the
+    /// `ZOTERO_SERVER_URL` environment variable and the port in zotero
+    /// preferences.
+    pub fn base_url(mut self, url: &str) -> Self {
+        self.base_url = Some(url.trim_end_matches('/').into());
+        self
+    }
+
+    /// Use the HTTP server port configured in zotero `prefs`
+    pub fn prefs(self, prefs: &crate::profile::ZoteroPrefs) -> Self {
+        self.base_url(&prefs.server_url())
+    }
+
+    /// Use the server port in preferences of zotero `install` already
+    /// discovered, unless overridden by `ZOTERO_SERVER_URL` environment
+    /// variable.
+    pub fn install(mut self, install: &crate::profile::ZoteroInstall) -> Self {
+        self.install = Some(install.clone());
+        self
+    }
+
+    /// Set timeout for connecting to zotero server
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Set timeout for the whole request, including reading response
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set max number of retries for transient errors, such as connection
+    /// failure, timeout or server error (5xx)
+    pub fn max_retries(mut self, n: usize) -> Self {
+        self.max_retries = n;
+        self
+    }
+
+    /// Set initial delay between retries, which is doubled for each retry
+    pub fn backoff(mut self, delay: Duration) -> Self {
+        self.backoff = delay;
+        self
+    }
+
+    /// Build the client
+    pub fn build(self) -> Result<ZoteroServer> {
+        let base_url = match self.base_url {
+            Some(url) => url,
+            None => {
+                dotenv::dotenv().ok();
+                default_base_url(std::env::var(ZOTERO_SERVER_URL_ENV).ok(), self.install.as_ref())
+            }
+        };
+        debug!("zotero server url: {}", base_url);
+
+        let client = reqwest::blocking::Client::builder()
+            .connect_timeout(self.connect_timeout)
+            .timeout(self.timeout)
+            .build()
+            .context("build http client")?;
+
+        let server = ZoteroServer {
+            base_url,
+            client,
+            max_retries: self.max_retries,
+            backoff: self.backoff,
+            reports_saved_keys: AtomicBool::new(false),
+        };
+        Ok(server)
+    }
+}
+
+// Server url from value of `ZOTERO_SERVER_URL` environment variable, or the
+// port configured in zotero preferences of `install` or of the discovered one
+// if any
+fn default_base_url(env_url: Option<String>, install: Option<&crate::profile::ZoteroInstall>) -> String {
+    env_url
+        .map(|x| x.trim_end_matches('/').to_string())
+        .or_else(|| match install {
+            Some(install) => install.prefs().map(|x| x.server_url()),
+            None => crate::profile::ZoteroPrefs::load().ok().map(|x| x.server_url()),
+        })
+        .unwrap_or_else(|| DEFAULT_BASE_URL.into())
+}
+// e1a94c07 ends here
+
+// [[file:../zotero.note::7d3f52b8][7d3f52b8]]
+use reqwest::blocking::{Client, RequestBuilder, Response};
+
+impl ZoteroServer {
+    /// The url of zotero server
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Send request created by `build`, retrying with backoff on transient
+    /// errors if request method is idempotent, such as GET. The delay
+    /// requested in `Retry-After` header is respected.
+    pub(crate) fn execute<F>(&self, build: F) -> Result<Response>
+    where
+        F: Fn(&Client) -> RequestBuilder,
+    {
+        self.execute_with(build, false)
+    }
+
+    /// Same as `execute`, but retry POST requests too, which is only safe
+    /// when repeating the request has no extra effect.
+    pub(crate) fn execute_retrying<F>(&self, build: F) -> Result<Response>
+    where
+        F: Fn(&Client) -> RequestBuilder,
+    {
+        self.execute_with(build, true)
+    }
+
+    fn execute_with<F>(&self, build: F, retry_any: bool) -> Result<Response>
+    where
+        F: Fn(&Client) -> RequestBuilder,
+    {
+        let mut delay = self.backoff;
+        let mut attempt = 0;
+        loop {
+            let req = build(&self.client).build().context("build request")?;
+            let retry = (retry_any || req.method().is_idempotent()) && attempt < self.max_retries;
+            let mut wait = delay;
+            match self.client.execute(req) {
+                Ok(resp) if is_transient(&resp) && retry => {
+                    info!("zotero server error: {}, retrying ...", resp.status());
+                    if let Some(secs) = resp
+                        .headers()
+                        .get("retry-after")
+                        .and_then(|x| x.to_str().ok())
+                        .and_then(|x| x.trim().parse().ok())
+                    {
+                        wait = Duration::from_secs(secs);
+                    }
+                }
+                Ok(resp) => return Ok(resp),
+                Err(e) if (e.is_timeout() || e.is_connect()) && retry => {
+                    info!("failed to reach zotero server: {}, retrying ...", e);
+                }
+                Err(e) => return Err(e).context("request zotero server"),
+            }
+            attempt += 1;
+            std::thread::sleep(wait);
+            delay *= 2;
+        }
+    }
+
+    /// Check if zotero server is running and reachable.
+    pub fn ping(&self) -> Result<()> {
+        let url = format!("{}/connector/ping", self.base_url);
+        let resp = self.execute(|client| client.get(&url))?;
+        if !resp.status().is_success() {
+            bail!("zotero server ping failed: {}", resp.status());
+        }
+        Ok(())
+    }
+}
+
+// Server errors or rate limiting (429 Too Many Requests)
+fn is_transient(resp: &Response) -> bool {
+    resp.status().is_server_error() || resp.status().as_u16() == 429
+}
+
+#[test]
+fn test_server_builder() -> Result<()> {
+    use crate::mock::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // fail twice before success
+    let count = AtomicUsize::new(0);
+    let posts = AtomicUsize::new(0);
+    let mock = MockServer::start(move |req| match req.path() {
+        "/connector/ping" if count.fetch_add(1, Ordering::SeqCst) < 2 => MockResponse::status(503, "busy"),
+        "/connector/ping" => MockResponse::ok("Zotero is running"),
+        "/post" if [0, 2].contains(&posts.fetch_add(1, Ordering::SeqCst)) => MockResponse::status(503, "busy"),
+        "/post" => MockResponse::ok("done"),
+        "/zotxt/items" => {
+            std::thread::sleep(Duration::from_millis(500));
+            MockResponse::ok("[]")
+        }
+        _ => MockResponse::status(404, "not found"),
+    });
+
+    let server = ZoteroServer::builder()
+        .base_url(mock.url())
+        .max_retries(1)
+        .backoff(Duration::from_millis(10))
+        .build()?;
+    assert!(server.ping().is_err());
+    let server = ZoteroServer::builder()
+        .base_url(mock.url())
+        .max_retries(2)
+        .backoff(Duration::from_millis(10))
+        .timeout(Duration::from_millis(100))
+        .build()?;
+    server.ping()?;
+    // POST requests are only retried on request
+    let url = format!("{}/post", mock.url());
+    assert_eq!(server.execute(|client| client.post(&url))?.status().as_u16(), 503);
+    assert_eq!(server.execute(|client| client.post(&url))?.status().as_u16(), 200);
+    assert_eq!(
+        server.execute_retrying(|client| client.post(&url))?.status().as_u16(),
+        200
+    );
+    assert_eq!(mock.requests().iter().filter(|x| x.method == "POST").count(), 4);
+    // hanging server
+    assert!(server.zotxt_keys(&ZotxtQuery::All).is_err());
+
+    // environment variable overrides the default url
+    assert_eq!(
+        default_base_url(Some("http://127.0.0.1:23120/".into()), None),
+        "http://127.0.0.1:23120"
+    );
+    assert_eq!(ZoteroServer::new(mock.url()).base_url(), mock.url());
+    let prefs = crate::profile::ZoteroPrefs {
+        http_server_port: 23124,
+        ..Default::default()
+    };
+    assert_eq!(
+        ZoteroServer::builder().prefs(&prefs).build()?.base_url(),
+        "http://127.0.0.1:23124"
+    );
+
+    Ok(())
+}
+// 7d3f52b8 ends here
 
 // [[file:../zotero.note::*get attachment][get attachment:1]]
 impl ZoteroServer {
@@ -24,8 +309,7 @@ impl ZoteroServer {
         let p = "zotero://select/items/";
         if link.starts_with(p) {
             let key = &link[p.len()..];
-            let url = format!("{}/zotxt/items?key={}&format=paths", self.base_url, key);
-            let resp = zotxt_client_call(&url)?;
+            let resp = self.zotxt_paths(&ZotxtQuery::Key(key.into()))?;
 
             let path = if resp.len() > 0 && resp[0].paths.len() > 0 {
                 let path = resp[0].paths[0].clone();
@@ -41,15 +325,10 @@ impl ZoteroServer {
 
     /// Get attachment of current selected item in zotero
     pub fn get_attachment_of_selected_item(&self) -> Result<Option<String>> {
-        let url = format!(
-            "{}/zotxt/items?selected=selected&format=paths",
-            self.base_url
-        );
-        let resp = zotxt_client_call(&url)?;
+        let resp = self.zotxt_paths(&ZotxtQuery::Selected)?;
 
         if resp.len() == 1 {
-            let path = resp[0].paths[0].clone();
-            Ok(Some(path))
+            Ok(resp[0].paths.first().cloned())
         } else {
             Ok(None)
         }
@@ -57,8 +336,7 @@ impl ZoteroServer {
 
     /// Get zotero url of current selected item in zotero
     pub fn get_uri_of_selected_item(&self) -> Result<Option<String>> {
-        let url = format!("{}/zotxt/items?selected=selected&format=paths", self.base_url);
-        let resp = zotxt_client_call(&url)?;
+        let resp = self.zotxt_paths(&ZotxtQuery::Selected)?;
 
         if resp.len() == 1 {
             let key = resp[0].key.clone();
@@ -69,40 +347,289 @@ impl ZoteroServer {
         }
     }
 }
+// get attachment:1 ends here
 
-fn zotxt_client_call(url: &str) -> Result<Vec<ResponseItem>> {
-    let x = reqwest::blocking::get(url)?.text()?;
-    let items = serde_json::from_str(&x)?;
-    Ok(items)
+// [[file:../zotero.note::3c8e1f47][3c8e1f47]]
+/// Item selection in zotxt queries
+#[derive(Debug, Clone)]
+pub enum ZotxtQuery {
+    /// zotero item key with library prefix, e.g. 1_BHDGEJJP
+    Key(String),
+    /// zotxt easy key, e.g. smith:2020catalysis
+    EasyKey(String),
+    /// Better BibTeX citation key, e.g. smith2020catalysis
+    BetterBibtexKey(String),
+    /// items in collection
+    Collection(String),
+    /// all items in zotero library
+    All,
+    /// current selected items in zotero
+    Selected,
 }
 
-#[derive(Debug, Deserialize)]
-struct ResponseItem {
-    key: String,
-    paths: Vec<String>,
+impl ZotxtQuery {
+    fn param(&self) -> (&str, &str) {
+        match self {
+            Self::Key(k) => ("key", k),
+            Self::EasyKey(k) => ("easykey", k),
+            Self::BetterBibtexKey(k) => ("betterbibtexkey", k),
+            Self::Collection(c) => ("collection", c),
+            Self::All => ("all", "all"),
+            Self::Selected => ("selected", "selected"),
+        }
+    }
 }
-// get attachment:1 ends here
+
+/// Search method for zotxt search
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZotxtSearchMethod {
+    TitleCreatorYear,
+    Fields,
+    Everything,
+}
+
+impl ZotxtSearchMethod {
+    fn as_str(&self) -> &str {
+        match self {
+            Self::TitleCreatorYear => "titleCreatorYear",
+            Self::Fields => "fields",
+            Self::Everything => "everything",
+        }
+    }
+}
+
+/// Attachment paths of zotero item, in zotxt `paths` format
+#[derive(Debug, Clone, Deserialize)]
+pub struct ZotxtPaths {
+    pub key: String,
+    pub paths: Vec<String>,
+}
+
+/// Formatted bibliography of zotero item, in zotxt `bibliography` format
+#[derive(Debug, Clone, Deserialize)]
+pub struct ZotxtBibliography {
+    pub key: String,
+    pub html: String,
+    pub text: String,
+}
+
+/// Short bibliography of zotero item, in zotxt `quickBibliography` format
+#[derive(Debug, Clone, Deserialize)]
+pub struct ZotxtQuickBibliography {
+    pub key: String,
+    #[serde(rename = "quickBib")]
+    pub quick_bib: String,
+}
+// 3c8e1f47 ends here
+
+// [[file:../zotero.note::b5d27a90][b5d27a90]]
+impl ZoteroServer {
+    // GET `path` with `params` from zotero server, returning response text
+    fn get_text(&self, path: &str, params: &[(&str, &str)]) -> Result<String> {
+        let url = format!("{}{}", self.base_url, path);
+        let resp = self.execute(|client| client.get(&url).query(params))?;
+        let status = resp.status();
+        let text = resp.text()?;
+        if !status.is_success() {
+            bail!("zotero server error {}: {}", status, text);
+        }
+        Ok(text)
+    }
+
+    fn zotxt_items(&self, query: &ZotxtQuery, format: &str, extra: &[(&str, &str)]) -> Result<String> {
+        let mut params = vec![query.param(), ("format", format)];
+        params.extend_from_slice(extra);
+        self.get_text("/zotxt/items", &params)
+    }
+
+    /// Return attachment paths of items in `query`
+    pub fn zotxt_paths(&self, query: &ZotxtQuery) -> Result<Vec<ZotxtPaths>> {
+        let x = self.zotxt_items(query, "paths", &[])?;
+        Ok(serde_json::from_str(&x)?)
+    }
+
+    /// Return formatted bibliography of items in `query` using CSL `style`
+    /// (style id or url as installed in zotero) if any
+    pub fn zotxt_bibliography(&self, query: &ZotxtQuery, style: Option<&str>) -> Result<Vec<ZotxtBibliography>> {
+        let extra: Vec<_> = style.into_iter().map(|x| ("style", x)).collect();
+        let x = self.zotxt_items(query, "bibliography", &extra)?;
+        Ok(serde_json::from_str(&x)?)
+    }
+
+    /// Return short bibliography of items in `query`
+    pub fn zotxt_quick_bibliography(&self, query: &ZotxtQuery) -> Result<Vec<ZotxtQuickBibliography>> {
+        let x = self.zotxt_items(query, "quickBibliography", &[])?;
+        Ok(serde_json::from_str(&x)?)
+    }
+
+    /// Return BibTeX entries of items in `query`
+    pub fn zotxt_bibtex(&self, query: &ZotxtQuery) -> Result<String> {
+        self.zotxt_items(query, "bibtex", &[])
+    }
+
+    /// Return CSL JSON records of items in `query`
+    pub fn zotxt_json(&self, query: &ZotxtQuery) -> Result<Vec<serde_json::Value>> {
+        let x = self.zotxt_items(query, "json", &[])?;
+        Ok(serde_json::from_str(&x)?)
+    }
+
+    /// Return item keys (with library prefix) of items in `query`
+    pub fn zotxt_keys(&self, query: &ZotxtQuery) -> Result<Vec<String>> {
+        let x = self.zotxt_items(query, "key", &[])?;
+        Ok(serde_json::from_str(&x)?)
+    }
+
+    /// Return Better BibTeX citation keys of items in `query`
+    pub fn zotxt_citekeys(&self, query: &ZotxtQuery) -> Result<Vec<String>> {
+        let x = self.zotxt_items(query, "citekey", &[])?;
+        Ok(serde_json::from_str(&x)?)
+    }
+
+    /// Search items matching `q` using `method`, returning item keys
+    pub fn zotxt_search(&self, q: &str, method: ZotxtSearchMethod) -> Result<Vec<String>> {
+        let params = [("q", q), ("method", method.as_str()), ("format", "key")];
+        let x = self.get_text("/zotxt/search", &params)?;
+        Ok(serde_json::from_str(&x)?)
+    }
+
+    /// Return easy keys starting with `prefix`
+    pub fn zotxt_complete(&self, prefix: &str) -> Result<Vec<String>> {
+        let x = self.get_text("/zotxt/complete", &[("easykey", prefix)])?;
+        Ok(serde_json::from_str(&x)?)
+    }
+
+    /// Select item in `query` in zotero window
+    pub fn zotxt_select(&self, query: &ZotxtQuery) -> Result<()> {
+        let _ = self.get_text("/zotxt/select", &[query.param()])?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_zotxt_client() -> Result<()> {
+    use crate::mock::*;
+
+    let mock = MockServer::start(|req| match (req.path(), req.query("format").as_deref()) {
+        // zotxt reports missing items as bad request
+        _ if req.query("key").as_deref() == Some("1_NOTFOUND") => MockResponse::status(400, "No items found"),
+        ("/zotxt/items", Some("paths")) => MockResponse::ok(r#"[{"key": "1_ABCD1234", "paths": ["/tmp/a.pdf"]}]"#),
+        ("/zotxt/items", Some("bibliography")) => {
+            MockResponse::ok(r#"[{"key": "1_ABCD1234", "html": "<i>Zeolite</i>", "text": "Zeolite"}]"#)
+        }
+        ("/zotxt/items", Some("quickBibliography")) => {
+            MockResponse::ok(r#"[{"key": "1_ABCD1234", "quickBib": "Smith - Zeolite"}]"#)
+        }
+        ("/zotxt/items", Some("bibtex")) => MockResponse::ok("@article{smith2020, title={Zeolite}}"),
+        ("/zotxt/items", Some("json")) => MockResponse::ok(r#"[{"id": "1_ABCD1234", "title": "Zeolite"}]"#),
+        ("/zotxt/items", Some("key")) => MockResponse::ok(r#"["1_ABCD1234"]"#),
+        ("/zotxt/items", Some("citekey")) => MockResponse::ok(r#"["smith2020"]"#),
+        ("/zotxt/search", Some("key")) => MockResponse::ok(r#"["1_ABCD1234", "1_EFGH5678"]"#),
+        ("/zotxt/complete", _) => MockResponse::ok(r#"["smith:2020zeolite"]"#),
+        ("/zotxt/select", _) => MockResponse::ok(""),
+        _ => MockResponse::status(404, "not found"),
+    });
+    let server = ZoteroServer::new(mock.url());
+
+    let key = ZotxtQuery::Key("1_ABCD1234".into());
+    assert_eq!(server.zotxt_paths(&key)?[0].paths, vec!["/tmp/a.pdf"]);
+    assert_eq!(
+        server.get_attachment("zotero://select/items/1_ABCD1234")?,
+        Some("/tmp/a.pdf".into())
+    );
+    assert_eq!(server.zotxt_bibliography(&key, Some("apa"))?[0].text, "Zeolite");
+    assert_eq!(server.zotxt_quick_bibliography(&key)?[0].quick_bib, "Smith - Zeolite");
+    assert!(server
+        .zotxt_bibtex(&ZotxtQuery::EasyKey("smith:2020zeolite".into()))?
+        .starts_with("@article"));
+    assert_eq!(
+        server.zotxt_json(&ZotxtQuery::BetterBibtexKey("smith2020".into()))?[0]["title"],
+        "Zeolite"
+    );
+    assert_eq!(
+        server.zotxt_keys(&ZotxtQuery::Collection("zeolite".into()))?,
+        vec!["1_ABCD1234"]
+    );
+    assert_eq!(server.zotxt_citekeys(&ZotxtQuery::All)?, vec!["smith2020"]);
+    assert_eq!(
+        server
+            .zotxt_search("zeolite 2020", ZotxtSearchMethod::Everything)?
+            .len(),
+        2
+    );
+    assert_eq!(server.zotxt_complete("smith")?, vec!["smith:2020zeolite"]);
+    server.zotxt_select(&key)?;
+
+    let requests = mock.requests();
+    assert!(requests.iter().all(|x| x.method == "GET"));
+    let search = requests.iter().find(|x| x.path() == "/zotxt/search").unwrap();
+    assert_eq!(search.query("q").as_deref(), Some("zeolite 2020"));
+    assert_eq!(search.query("method").as_deref(), Some("everything"));
+    let bib = requests
+        .iter()
+        .find(|x| x.query("format").as_deref() == Some("bibliography"))
+        .unwrap();
+    assert_eq!(bib.query("style").as_deref(), Some("apa"));
+    assert!(requests
+        .iter()
+        .any(|x| x.query("collection").as_deref() == Some("zeolite")));
+
+    assert!(server.zotxt_paths(&ZotxtQuery::Key("1_NOTFOUND".into())).is_err());
+    Ok(())
+}
+// b5d27a90 ends here
 
 // [[file:../zotero.note::*save item][save item:1]]
-#[derive(Serialize)]
+use crate::httpd::{guess_mime_type, FileServer};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// A creator (author, editor, ...) of item saved through zotero connector
+#[derive(Debug, Clone, Serialize)]
 #[serde(rename_all = "camelCase")]
-struct Creator {
+pub struct Creator {
+    #[serde(skip_serializing_if = "String::is_empty")]
     first_name: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
     last_name: String,
+    // single field name, such as institutions
+    #[serde(skip_serializing_if = "String::is_empty")]
+    name: String,
+    creator_type: String,
 }
 
-impl Default for Creator {
-    fn default() -> Self {
+impl Creator {
+    /// Construct an author with two-field name
+    pub fn new(first_name: &str, last_name: &str) -> Self {
+        Self {
+            first_name: first_name.into(),
+            last_name: last_name.into(),
+            name: String::new(),
+            creator_type: "author".into(),
+        }
+    }
+
+    /// Construct an author with single field name, such as an institution
+    pub fn single_field(name: &str) -> Self {
         Self {
-            first_name: "Wenping".into(),
-            last_name: "Guo".into(),
+            first_name: String::new(),
+            last_name: String::new(),
+            name: name.into(),
+            creator_type: "author".into(),
         }
     }
+
+    /// Set zotero creator type, such as author, editor or contributor
+    pub fn creator_type(mut self, creator_type: &str) -> Self {
+        self.creator_type = creator_type.into();
+        self
+    }
 }
 
-#[derive(Serialize)]
+/// An attachment of item saved through zotero connector, which will be
+/// downloaded by zotero from `url`.
+#[derive(Debug, Clone, Serialize)]
 #[serde(rename_all = "camelCase")]
-struct Attachment {
+pub struct Attachment {
     title: String,
     url: String,
     mime_type: String,
@@ -110,73 +637,678 @@ struct Attachment {
     proxy: bool,
 }
 
-impl Default for Attachment {
-    fn default() -> Self {
+impl Attachment {
+    /// Construct an attachment in `mime_type` (e.g. application/pdf) to be
+    /// downloaded from `url`
+    pub fn new(title: &str, url: &str, mime_type: &str) -> Self {
         Self {
-            title: "research note".into(),
-            url: "http://localhost:23120/template.org".into(),
-            mime_type: "application/x-note".into(),
+            title: title.into(),
+            url: url.into(),
+            mime_type: mime_type.into(),
             snapshot: true,
             proxy: false,
         }
     }
 }
 
-#[derive(Serialize)]
+#[derive(Debug, Clone, Serialize)]
+struct Tag {
+    tag: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Note {
+    note: String,
+}
+
+/// An item to be saved through zotero connector
+#[derive(Debug, Clone, Serialize)]
 #[serde(rename_all = "camelCase")]
-struct ConnectorItem {
+pub struct ConnectorItem {
     item_type: String,
     title: String,
-    extra: String,
-    place: String,
     date: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    extra: String,
     creators: Vec<Creator>,
+    tags: Vec<Tag>,
+    notes: Vec<Note>,
     attachments: Vec<Attachment>,
+    // other zotero fields, such as place or publicationTitle
+    #[serde(flatten)]
+    fields: HashMap<String, String>,
 }
 
-impl Default for ConnectorItem {
-    fn default() -> Self {
+impl ConnectorItem {
+    /// Construct an item in zotero item type (e.g. report or journalArticle)
+    /// with `title`, dated today.
+    pub fn new(item_type: &str, title: &str) -> Self {
         Self {
-            item_type: "report".into(),
-            title: "research.note".into(),
-            extra: "this is a test".into(),
-            place: "Beijing".into(),
-            // FIXME: using current date
-            date: "2020/08/01".into(),
-            creators: vec![Creator::default()],
-            attachments: vec![Attachment::default()],
+            item_type: item_type.into(),
+            title: title.into(),
+            date: chrono::Local::now().format("%Y-%m-%d").to_string(),
+            extra: String::new(),
+            creators: vec![],
+            tags: vec![],
+            notes: vec![],
+            attachments: vec![],
+            fields: HashMap::new(),
         }
     }
+
+    /// Set item date, e.g. 2020-08-01
+    pub fn date(mut self, date: &str) -> Self {
+        self.date = date.into();
+        self
+    }
+
+    /// Set contents of `extra` field
+    pub fn extra(mut self, extra: &str) -> Self {
+        self.extra = extra.into();
+        self
+    }
+
+    /// Add a creator
+    pub fn creator(mut self, creator: Creator) -> Self {
+        self.creators.push(creator);
+        self
+    }
+
+    /// Add a tag
+    pub fn tag(mut self, tag: &str) -> Self {
+        self.tags.push(Tag { tag: tag.into() });
+        self
+    }
+
+    /// Add a child note in html
+    pub fn note(mut self, note: &str) -> Self {
+        self.notes.push(Note { note: note.into() });
+        self
+    }
+
+    /// Add an attachment
+    pub fn attachment(mut self, attachment: Attachment) -> Self {
+        self.attachments.push(attachment);
+        self
+    }
+
+    /// Set other zotero field, e.g. `place` or `publicationTitle`
+    pub fn field(mut self, name: &str, value: &str) -> Self {
+        self.fields.insert(name.into(), value.into());
+        self
+    }
+}
+
+fn new_session_id() -> String {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|x| x.as_nanos())
+        .unwrap_or_default();
+    format!("{:x}{:x}", nanos, std::process::id())
+}
+
+// Strip library ID prefix from item key in zotxt, e.g. 1_ABCD1234
+fn bare_key(key: &str) -> &str {
+    match key.split_once('_') {
+        Some((lib, key)) if lib.chars().all(|c| c.is_ascii_digit()) => key,
+        _ => key,
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct SavedItem {
+    key: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SaveItemsResponse {
+    #[serde(default)]
+    items: Vec<SavedItem>,
 }
 
 impl ZoteroServer {
-    /// Create a new report item with an attached .note file
-    ///
-    /// Return the file path to the attached file
-    pub fn create_new_note(&self, f: &str) -> Result<Option<String>> {
+    /// Save `items` into current selected collection of zotero through
+    /// connector, and return keys of the new items, without library prefix.
+    pub fn save_items(&self, items: &[ConnectorItem]) -> Result<Vec<String>> {
+        // items with the same titles before saving, so that we can find out
+        // the new ones later, unless zotero is known to report saved items
+        let existing = if self.reports_saved_keys.load(Ordering::SeqCst) {
+            None
+        } else {
+            let existing: Result<Vec<_>> = items.iter().map(|x| self.search_title(&x.title)).collect();
+            Some(existing?)
+        };
+        let call = serde_json::json!({
+            "sessionID": new_session_id(),
+            "items": items,
+        });
+
         let url = format!("{}/connector/saveItems", self.base_url);
-        let mut call = std::collections::HashMap::new();
-        let items = vec![ConnectorItem::default()];
-        call.insert("items", items);
-        // let json = serde_json::to_string_pretty(&call).unwrap();
-        // println!("{}", json);
-        let new = reqwest::blocking::Client::new()
-            .post(&url)
-            .json(&call)
-            .send()?;
+        let resp = self.execute(|client| client.post(&url).json(&call))?;
+        let status = resp.status();
+        let text = resp.text().context("client requests to create item")?;
+        debug!("server response: {}", text);
+        if !status.is_success() {
+            bail!("failed to save items: {} {}", status, text);
+        }
+
+        // some zotero versions report saved items in response
+        if let Ok(saved) = serde_json::from_str::<SaveItemsResponse>(&text) {
+            if saved.items.len() == items.len() {
+                self.reports_saved_keys.store(true, Ordering::SeqCst);
+                return Ok(saved.items.iter().map(|x| bare_key(&x.key).to_string()).collect());
+            }
+        }
+        self.reports_saved_keys.store(false, Ordering::SeqCst);
+        match existing {
+            Some(existing) => self.find_new_items(items, &existing),
+            None => bail!("zotero did not report saved items, and they cannot be told apart from old ones"),
+        }
+    }
 
-        let resp = new.text().context("client requests to create item")?;
-        debug!("server response: {}", resp);
+    // Keys of items titled `title`
+    fn search_title(&self, title: &str) -> Result<Vec<String>> {
+        let keys = self
+            .zotxt_search(title, ZotxtSearchMethod::TitleCreatorYear)
+            .with_context(|| format!("search items titled {:?}", title))?;
+        Ok(keys.iter().map(|x| bare_key(x).to_string()).collect())
+    }
 
-        self.get_uri_of_selected_item()
+    // Find new items by title, excluding `existing` ones before saving.
+    // Zotero saves items in background, so we may need to wait for a while.
+    fn find_new_items(&self, items: &[ConnectorItem], existing: &[Vec<String>]) -> Result<Vec<String>> {
+        let mut delay = self.backoff;
+        for _ in 0..=self.max_retries.max(3) {
+            let mut keys: Vec<String> = vec![];
+            for (item, existing) in items.iter().zip(existing) {
+                let key = self
+                    .search_title(&item.title)?
+                    .into_iter()
+                    .find(|x| !existing.contains(x) && !keys.contains(x));
+                match key {
+                    Some(key) => keys.push(key),
+                    None => break,
+                }
+            }
+            if keys.len() == items.len() {
+                return Ok(keys);
+            }
+            std::thread::sleep(delay);
+            delay *= 2;
+        }
+        bail!("cannot find saved items in zotero");
+    }
+
+    /// Create a new report item titled `title` with an attached .note file
+    /// copied from `template`, and return zotero uri of the new item.
+    pub fn create_new_note(&self, title: &str, template: &Path) -> Result<Option<String>> {
+        // serve template for zotero to download
+        let file = FileServer::serve_file(template)?;
+        let attachment = Attachment::new("research note", file.url(), "application/x-note");
+        let item = ConnectorItem::new("report", title).attachment(attachment);
+        let keys = self.save_items(&[item])?;
+        // zotero downloads attachments in background
+        if !file.wait(Duration::from_secs(30)) {
+            warn!("attachment has not been downloaded by zotero: {:?}", template);
+        }
+        Ok(keys.first().map(|key| format!("zotero://select/items/1_{}", key)))
     }
 }
 
+#[test]
+fn test_connector_save_items() -> Result<()> {
+    use crate::mock::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // an item in the same title exists before saving
+    let saved = AtomicUsize::new(0);
+    let mock = MockServer::start(move |req| match req.path() {
+        "/connector/saveItems" if req.body.contains("key-in-response") => {
+            MockResponse::status(201, r#"{"items": [{"key": "ABCD1234"}]}"#)
+        }
+        "/zotxt/search" if req.query("q").as_deref() == Some("search-fails") => {
+            MockResponse::status(400, "bad request")
+        }
+        "/connector/saveItems" => {
+            saved.fetch_add(1, Ordering::SeqCst);
+            // download attachments like zotero
+            let call: serde_json::Value = serde_json::from_str(&req.body).unwrap();
+            for x in call["items"][0]["attachments"].as_array().unwrap() {
+                let text = reqwest::blocking::get(x["url"].as_str().unwrap())
+                    .unwrap()
+                    .text()
+                    .unwrap();
+                assert_eq!(text, "#+title: research note");
+            }
+            MockResponse::status(201, "")
+        }
+        "/zotxt/search" => match saved.load(Ordering::SeqCst) {
+            0 => MockResponse::ok(r#"["1_IJKL9012"]"#),
+            1 => MockResponse::ok(r#"["1_IJKL9012", "1_EFGH5678"]"#),
+            _ => MockResponse::ok(r#"["1_MNOP3456", "1_IJKL9012", "1_EFGH5678"]"#),
+        },
+        _ => MockResponse::status(404, "not found"),
+    });
+    let server = ZoteroServer::new(mock.url());
+
+    let item = ConnectorItem::new("report", "research.note").date("2020-08-01");
+    let dir = crate::fixture::TestDir::new();
+    let template = dir.path().join("template.org");
+    std::fs::write(&template, "#+title: research note")?;
+    let uri = server.create_new_note("research.note", &template)?;
+    assert_eq!(uri, Some("zotero://select/items/1_EFGH5678".into()));
+    assert_eq!(crate::db::get_item_key_from_link(&uri.unwrap())?, "EFGH5678");
+    assert_eq!(server.save_items(&[item])?, vec!["MNOP3456"]);
+    // failed search is not taken as no item found
+    let item = ConnectorItem::new("report", "search-fails");
+    assert!(server.save_items(&[item]).is_err());
+
+    let item = ConnectorItem::new("journalArticle", "key-in-response")
+        .creator(Creator::new("John", "Smith"))
+        .creator(Creator::single_field("Zotero Team").creator_type("contributor"))
+        .tag("zeolite")
+        .note("<p>hello</p>")
+        .field("publicationTitle", "Journal of Tests")
+        .extra("tex.key: smith2020");
+    assert_eq!(server.save_items(std::slice::from_ref(&item))?, vec!["ABCD1234"]);
+    // no more searches once zotero reports saved items
+    assert_eq!(server.save_items(&[item])?, vec!["ABCD1234"]);
+
+    let requests = mock.requests();
+    let save = requests.iter().rfind(|x| x.path() == "/connector/saveItems").unwrap();
+    let call: serde_json::Value = serde_json::from_str(&save.body)?;
+    let item = &call["items"][0];
+    assert_eq!(item["itemType"], "journalArticle");
+    assert_eq!(item["creators"][0]["lastName"], "Smith");
+    assert_eq!(item["creators"][1]["name"], "Zotero Team");
+    assert_eq!(item["creators"][1]["creatorType"], "contributor");
+    assert_eq!(item["tags"][0]["tag"], "zeolite");
+    assert_eq!(item["notes"][0]["note"], "<p>hello</p>");
+    assert_eq!(item["publicationTitle"], "Journal of Tests");
+    assert_eq!(item["date"], chrono::Local::now().format("%Y-%m-%d").to_string());
+    assert_eq!(item["extra"], "tex.key: smith2020");
+
+    // new items are searched by title
+    let searches: Vec<_> = requests
+        .iter()
+        .filter(|x| x.path() == "/zotxt/search")
+        .map(|x| x.query("q").unwrap())
+        .collect();
+    assert_eq!(searches.iter().filter(|x| *x == "research.note").count(), 4);
+    assert_eq!(searches.iter().filter(|x| *x == "key-in-response").count(), 1);
+
+    Ok(())
+}
+
 #[test]
 #[ignore]
 fn test_connector_json() {
     let connector = ZoteroServer::default();
-    let x = connector.create_new_note("xx").unwrap();
+    let x = connector
+        .create_new_note("research.note", "template.org".as_ref())
+        .unwrap();
     dbg!(x);
 }
 // save item:1 ends here
+
+// [[file:../zotero.note::61f0c2ad][61f0c2ad]]
+/// The collection (or library) currently selected in zotero, where new items
+/// saved through connector go.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SelectedCollection {
+    #[serde(rename = "libraryID")]
+    pub library_id: i64,
+    #[serde(rename = "libraryName", default)]
+    pub library_name: String,
+    #[serde(rename = "libraryEditable", default)]
+    pub library_editable: bool,
+    #[serde(default)]
+    pub editable: bool,
+    /// collection id, none for library root
+    #[serde(default)]
+    pub id: Option<serde_json::Value>,
+    /// collection name, or library name for library root
+    #[serde(default)]
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ImportedItem {
+    key: String,
+}
+
+impl ZoteroServer {
+    /// Return the collection currently selected in zotero
+    pub fn selected_collection(&self) -> Result<SelectedCollection> {
+        let url = format!("{}/connector/getSelectedCollection", self.base_url);
+        let resp = self.execute_retrying(|client| client.post(&url).json(&serde_json::json!({})))?;
+        let status = resp.status();
+        let text = resp.text()?;
+        if !status.is_success() {
+            bail!("failed to get selected collection: {} {}", status, text);
+        }
+        Ok(serde_json::from_str(&text)?)
+    }
+
+    /// Import local PDF file in `path` as a standalone attachment into
+    /// current selected collection. Zotero will retrieve its metadata
+    /// automatically when enabled in preferences.
+    pub fn import_pdf(&self, path: &Path) -> Result<()> {
+        let file = FileServer::serve_file(path)?;
+        let title = path.file_stem().and_then(|x| x.to_str()).unwrap_or_default();
+        let call = serde_json::json!({
+            "sessionID": new_session_id(),
+            "url": file.url(),
+            "title": title,
+            "pdf": true,
+        });
+
+        let url = format!("{}/connector/saveSnapshot", self.base_url);
+        let resp = self.execute(|client| client.post(&url).json(&call))?;
+        let status = resp.status();
+        let text = resp.text()?;
+        debug!("server response: {}", text);
+        if !status.is_success() {
+            bail!("failed to import {:?}: {} {}", path, status, text);
+        }
+        if !file.wait(Duration::from_secs(30)) {
+            bail!("file has not been downloaded by zotero: {:?}", path);
+        }
+        Ok(())
+    }
+
+    /// Import citations in BibTeX, RIS or other formats zotero can detect
+    /// into current selected collection, and return keys of imported items.
+    pub fn import_citations(&self, content: &str) -> Result<Vec<String>> {
+        let session = new_session_id();
+        let url = format!("{}/connector/import", self.base_url);
+        let resp = self.execute(|client| {
+            client
+                .post(&url)
+                .query(&[("session", &session)])
+                .header("Content-Type", "text/plain")
+                .body(content.to_string())
+        })?;
+        let status = resp.status();
+        let text = resp.text()?;
+        if !status.is_success() {
+            bail!("failed to import citations: {} {}", status, text);
+        }
+        let items: Vec<ImportedItem> = serde_json::from_str(&text).context("parse imported items")?;
+        Ok(items.into_iter().map(|x| x.key).collect())
+    }
+
+    /// Import local files in `paths` into current selected collection, and
+    /// return the collection. PDF files are imported as standalone attachments,
+    /// other files (e.g. reports) are attached to new `document` items.
+    pub fn import_files_to_selected_collection(&self, paths: &[&Path]) -> Result<SelectedCollection> {
+        let collection = self.selected_collection()?;
+        if !collection.editable {
+            bail!("selected collection is not editable: {}", collection.name);
+        }
+        info!("importing {} files into {}", paths.len(), collection.name);
+
+        for path in paths {
+            if guess_mime_type(path) == "application/pdf" {
+                self.import_pdf(path)?;
+            } else {
+                let file = FileServer::serve_file(path)?;
+                let title = path.file_name().and_then(|x| x.to_str()).unwrap_or_default();
+                let attachment = Attachment::new(title, file.url(), guess_mime_type(path));
+                let item = ConnectorItem::new("document", title).attachment(attachment);
+                self.save_items(&[item])?;
+                if !file.wait(Duration::from_secs(30)) {
+                    bail!("file has not been downloaded by zotero: {:?}", path);
+                }
+            }
+        }
+        Ok(collection)
+    }
+}
+
+#[test]
+fn test_connector_import() -> Result<()> {
+    use crate::mock::*;
+
+    // download attachments like zotero
+    fn download(url: &serde_json::Value) -> String {
+        reqwest::blocking::get(url.as_str().unwrap()).unwrap().text().unwrap()
+    }
+    let mock = MockServer::start(|req| {
+        let call: serde_json::Value = serde_json::from_str(&req.body).unwrap_or_default();
+        match req.path() {
+            "/connector/getSelectedCollection" => MockResponse::ok(
+                r#"{"libraryID": 1, "libraryName": "My Library", "libraryEditable": true,
+                    "editable": true, "id": 12, "name": "reports"}"#,
+            ),
+            "/connector/saveSnapshot" => {
+                assert_eq!(call["pdf"], true);
+                assert_eq!(download(&call["url"]), "%PDF-1.4");
+                MockResponse::status(201, "")
+            }
+            "/connector/saveItems" => {
+                assert_eq!(download(&call["items"][0]["attachments"][0]["url"]), "* report");
+                MockResponse::status(201, r#"{"items": [{"key": "ABCD1234"}]}"#)
+            }
+            "/zotxt/search" => MockResponse::ok("[]"),
+            "/connector/import" if req.query("session").is_some() && req.body.starts_with("@article") => {
+                MockResponse::status(201, r#"[{"key": "EFGH5678", "itemType": "journalArticle"}]"#)
+            }
+            _ => MockResponse::status(400, "bad request"),
+        }
+    });
+    let server = ZoteroServer::new(mock.url());
+
+    let collection = server.selected_collection()?;
+    assert_eq!(collection.name, "reports");
+    assert_eq!(collection.library_id, 1);
+
+    let keys = server.import_citations("@article{smith2020, title={Zeolite}}")?;
+    assert_eq!(keys, vec!["EFGH5678"]);
+    assert!(server.import_citations("TY  - JOUR").is_err());
+
+    let dir = crate::fixture::TestDir::new();
+    let pdf = dir.path().join("import.pdf");
+    let report = dir.path().join("import.org");
+    std::fs::write(&pdf, "%PDF-1.4")?;
+    std::fs::write(&report, "* report")?;
+    let collection = server.import_files_to_selected_collection(&[&pdf, &report])?;
+    assert_eq!(collection.name, "reports");
+
+    let requests = mock.requests();
+    assert!(requests.iter().any(|x| x.path() == "/connector/saveSnapshot"));
+    assert!(requests.iter().any(|x| x.path() == "/connector/saveItems"));
+    Ok(())
+}
+// 61f0c2ad ends here
+
+// [[file:../zotero.note::4b7e2d19][4b7e2d19]]
+/// Item found by Better BibTeX `item.search`, in CSL-JSON with citation key
+#[derive(Debug, Clone, Deserialize)]
+pub struct BbtItem {
+    pub citekey: String,
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(rename = "libraryID", default)]
+    pub library_id: Option<i64>,
+    /// other CSL-JSON fields
+    #[serde(flatten)]
+    pub fields: HashMap<String, serde_json::Value>,
+}
+
+/// Attachment returned by Better BibTeX `item.attachments`
+#[derive(Debug, Clone, Deserialize)]
+pub struct BbtAttachment {
+    /// link to open the attachment in zotero, e.g. zotero://open-pdf/library/items/ABCD1234
+    pub open: String,
+    pub path: String,
+    #[serde(default)]
+    pub annotations: Vec<serde_json::Value>,
+}
+
+/// A collection in Better BibTeX responses
+#[derive(Debug, Clone, Deserialize)]
+pub struct BbtCollection {
+    #[serde(rename = "libraryID")]
+    pub library_id: i64,
+    pub key: String,
+    /// auto export id, for `autoexport.add`
+    #[serde(default)]
+    pub id: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonRpcError {
+    code: i64,
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonRpcResponse {
+    #[serde(default)]
+    result: Option<serde_json::Value>,
+    #[serde(default)]
+    error: Option<JsonRpcError>,
+}
+
+impl ZoteroServer {
+    /// Call Better BibTeX JSON-RPC `method` with `params`, returning the result.
+    pub fn bbt_call<T: serde::de::DeserializeOwned>(&self, method: &str, params: serde_json::Value) -> Result<T> {
+        let url = format!("{}/better-bibtex/json-rpc", self.base_url);
+        let call = serde_json::json!({"jsonrpc": "2.0", "method": method, "params": params, "id": 1});
+        let resp = self.execute(|client| client.post(&url).json(&call))?;
+        let status = resp.status();
+        let text = resp.text()?;
+        if !status.is_success() {
+            bail!("better bibtex error {}: {}", status, text);
+        }
+
+        let resp: JsonRpcResponse =
+            serde_json::from_str(&text).with_context(|| format!("invalid json-rpc response: {}", text))?;
+        if let Some(e) = resp.error {
+            bail!("better bibtex {} failed ({}): {}", method, e.code, e.message);
+        }
+        let result = resp.result.unwrap_or_default();
+        serde_json::from_value(result).with_context(|| format!("unexpected result of {}", method))
+    }
+
+    /// Return versions of zotero and Better BibTeX if ready
+    pub fn bbt_ready(&self) -> Result<serde_json::Value> {
+        self.bbt_call("api.ready", serde_json::json!([]))
+    }
+
+    /// Search items matching `terms`
+    pub fn bbt_search(&self, terms: &str) -> Result<Vec<BbtItem>> {
+        self.bbt_call("item.search", serde_json::json!([terms]))
+    }
+
+    /// Return citation keys of items in `item_keys`, mapped from item key.
+    /// Items without citation key are mapped to None.
+    pub fn bbt_citation_keys(&self, item_keys: &[&str]) -> Result<HashMap<String, Option<String>>> {
+        self.bbt_call("item.citationkey", serde_json::json!([item_keys]))
+    }
+
+    /// Format bibliography of items cited as `citekeys` in citation `style`
+    /// (style id, e.g. http://www.zotero.org/styles/apa), or the zotero
+    /// quick copy style if None. `content_type` is "text" or "html".
+    pub fn bbt_bibliography(&self, citekeys: &[&str], style: Option<&str>, content_type: &str) -> Result<String> {
+        let mut format = serde_json::json!({ "contentType": content_type });
+        match style {
+            Some(style) => format["id"] = style.into(),
+            None => format["quickCopy"] = true.into(),
+        }
+        self.bbt_call("item.bibliography", serde_json::json!([citekeys, format]))
+    }
+
+    /// Return attachments of item cited as `citekey`
+    pub fn bbt_attachments(&self, citekey: &str) -> Result<Vec<BbtAttachment>> {
+        self.bbt_call("item.attachments", serde_json::json!([citekey]))
+    }
+
+    /// Export items cited as `citekeys` with `translator`, e.g. "Better BibLaTeX"
+    pub fn bbt_export(&self, citekeys: &[&str], translator: &str) -> Result<String> {
+        let result: serde_json::Value = self.bbt_call("item.export", serde_json::json!([citekeys, translator]))?;
+        // older versions return [status, content type, text]
+        let text = match &result {
+            serde_json::Value::String(text) => text.as_str(),
+            serde_json::Value::Array(x) if x.len() == 3 => x[2].as_str().unwrap_or_default(),
+            _ => bail!("unexpected export result: {}", result),
+        };
+        Ok(text.to_string())
+    }
+
+    /// Create or update `collection` with items cited in LaTeX .aux file in `aux_path`
+    pub fn bbt_scan_aux(&self, collection: &str, aux_path: &Path) -> Result<BbtCollection> {
+        self.bbt_call("collection.scanAUX", serde_json::json!([collection, aux_path]))
+    }
+
+    /// Set up auto export of `collection` to file in `path` with `translator`.
+    /// Existing auto export of the file will be replaced if `replace` is true.
+    pub fn bbt_autoexport_add(
+        &self,
+        collection: &str,
+        translator: &str,
+        path: &Path,
+        replace: bool,
+    ) -> Result<BbtCollection> {
+        let params = serde_json::json!([collection, translator, path, {}, replace]);
+        self.bbt_call("autoexport.add", params)
+    }
+}
+
+#[test]
+fn test_bbt_json_rpc() -> Result<()> {
+    use crate::mock::*;
+
+    let mock = MockServer::start(|req| {
+        let call: serde_json::Value = serde_json::from_str(&req.body).unwrap_or_default();
+        let params = &call["params"];
+        let result = match call["method"].as_str().unwrap_or_default() {
+            "item.search" if params[0] == "zeolite" => {
+                r#"[{"citekey": "smith2008zeolite", "title": "Zeolite", "libraryID": 1, "DOI": "10.1/x"}]"#
+            }
+            "item.search" => "[]",
+            "item.citationkey" => r#"{"AAAAAAAA": "smith2008zeolite", "BBBBBBBB": null}"#,
+            "item.bibliography" if params[1]["id"].is_string() && params[1]["contentType"] == "text" => {
+                r#""Smith, J. (2008). Zeolite.""#
+            }
+            "item.attachments" => r#"[{"open": "zotero://open-pdf/library/items/CCCCCCCC", "path": "/x/a.pdf"}]"#,
+            "item.export" if params[1] == "Better BibLaTeX" => r#"[200, "text/plain", "@article{smith2008zeolite}"]"#,
+            "item.export" => r#""@article{smith2008zeolite,}""#,
+            "collection.scanAUX" => r#"{"libraryID": 1, "key": "COLLAAAA"}"#,
+            "autoexport.add" if params[4] == true => r#"{"libraryID": 1, "key": "COLLAAAA", "id": 3}"#,
+            _ => {
+                return MockResponse::ok(
+                    r#"{"jsonrpc": "2.0", "error": {"code": -32601, "message": "Method not found"}, "id": 1}"#,
+                )
+            }
+        };
+        MockResponse::ok(&format!(r#"{{"jsonrpc": "2.0", "result": {}, "id": 1}}"#, result))
+    });
+    let server = ZoteroServer::new(mock.url());
+
+    let found = server.bbt_search("zeolite")?;
+    assert_eq!(found[0].citekey, "smith2008zeolite");
+    assert_eq!(found[0].fields["DOI"], "10.1/x");
+    assert!(server.bbt_search("nothing")?.is_empty());
+    let keys = server.bbt_citation_keys(&["AAAAAAAA", "BBBBBBBB"])?;
+    assert_eq!(keys["AAAAAAAA"].as_deref(), Some("smith2008zeolite"));
+    assert_eq!(keys["BBBBBBBB"], None);
+    let bib = server.bbt_bibliography(&["smith2008zeolite"], Some("http://www.zotero.org/styles/apa"), "text")?;
+    assert_eq!(bib, "Smith, J. (2008). Zeolite.");
+    assert_eq!(server.bbt_attachments("smith2008zeolite")?[0].path, "/x/a.pdf");
+    let bib = server.bbt_export(&["smith2008zeolite"], "Better BibLaTeX")?;
+    assert_eq!(bib, "@article{smith2008zeolite}");
+    let bib = server.bbt_export(&["smith2008zeolite"], "Better BibTeX")?;
+    assert_eq!(bib, "@article{smith2008zeolite,}");
+    let collection = server.bbt_scan_aux("thesis", "/x/thesis.aux".as_ref())?;
+    assert_eq!(collection.key, "COLLAAAA");
+    let export = server.bbt_autoexport_add("thesis", "Better BibLaTeX", "/x/thesis.bib".as_ref(), true)?;
+    assert_eq!(export.id, Some(3));
+
+    let err = server.bbt_ready().unwrap_err();
+    assert!(format!("{:?}", err).contains("Method not found"));
+    Ok(())
+}
+// 4b7e2d19 ends here
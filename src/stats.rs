@@ -0,0 +1,553 @@
+// [[file:../zotero.note::*imports][imports:1]]
+use gut::prelude::*;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use sqlx::prelude::*;
+
+use crate::backend::SqliteBackend;
+use crate::db::ZoteroDb;
+use crate::trash::TrashFilter;
+// imports:1 ends here
+
+// [[file:../zotero.note::0b8e6f4c][0b8e6f4c]]
+/// Number of items in a group, e.g. items of a type or with a tag
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Count {
+    pub name: String,
+    pub count: usize,
+}
+
+/// Number of items in a collection
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CollectionCount {
+    /// Key of collection, as names may be the same
+    pub key: String,
+    pub collection: String,
+    pub count: usize,
+}
+
+/// Attachment files of items in a collection
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StorageUsage {
+    /// Key of collection, as names may be the same
+    pub key: String,
+    pub collection: String,
+    pub files: usize,
+    /// Total size of files in bytes
+    pub bytes: u64,
+}
+
+/// Reading progress measured by annotations in attachments
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ReadingProgress {
+    /// Items with attachments to read
+    pub items_with_attachments: usize,
+    /// Items with annotations in their attachments
+    pub items_annotated: usize,
+    pub annotations: usize,
+    /// Titles of items with most annotations
+    pub most_annotated: Vec<Count>,
+}
+
+/// Statistics of regular items in zotero library
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LibraryStats {
+    pub total: usize,
+    pub items_by_type: Vec<Count>,
+    pub items_by_year: Vec<Count>,
+    pub items_by_tag: Vec<Count>,
+    pub items_by_collection: Vec<CollectionCount>,
+    /// Items added in each month, as 2021-03
+    pub added_by_month: Vec<Count>,
+    pub top_authors: Vec<Count>,
+    pub top_journals: Vec<Count>,
+    pub storage_by_collection: Vec<StorageUsage>,
+    pub reading: ReadingProgress,
+}
+
+// Items selected for statistics, as a common table expression
+fn selected_items(trash: TrashFilter, year: Option<i32>) -> String {
+    let added = match year {
+        Some(year) => format!("CAST(strftime('%Y', items.dateAdded) AS INTEGER) = {}", year),
+        None => "1".into(),
+    };
+    format!(
+        r#"
+WITH selected AS (
+    SELECT items.itemID as itemID FROM items JOIN itemTypes USING (itemTypeID)
+    WHERE itemTypes.typeName NOT IN ('attachment', 'note', 'annotation')
+      AND {}
+      AND {})
+"#,
+        trash.sql(&["items.itemID"]),
+        added
+    )
+}
+
+impl ZoteroDb {
+    // Return counts from `sql` with columns `name` and `count`
+    async fn get_counts(&self, sql: &str) -> Result<Vec<Count>> {
+        let recs = sqlx::query(sql).fetch_all(self.pool()).await?;
+        recs.iter()
+            .map(|x| {
+                Ok(Count {
+                    name: x.try_get("name")?,
+                    count: x.try_get::<i64, _>("count")? as usize,
+                })
+            })
+            .collect()
+    }
+
+    async fn get_library_stats(&self, selected: &str, trash: TrashFilter, top: usize) -> Result<LibraryStats> {
+        let field_values = |field: &str| {
+            format!(
+                r#"
+SELECT itemID, itemDataValues.value as value FROM itemData
+    JOIN fields USING (fieldID) JOIN itemDataValues USING (valueID)
+WHERE fields.fieldName = '{}'"#,
+                field
+            )
+        };
+        let mut stats = LibraryStats::default();
+
+        let sql = format!("{} SELECT 'total' as name, COUNT(*) as count FROM selected", selected);
+        stats.total = self.get_counts(&sql).await?.first().map(|x| x.count).unwrap_or(0);
+
+        let sql = format!(
+            r#"{}
+SELECT itemTypes.typeName as name, COUNT(*) as count FROM selected
+    JOIN items USING (itemID) JOIN itemTypes USING (itemTypeID)
+GROUP BY name ORDER BY count DESC, name
+"#,
+            selected
+        );
+        stats.items_by_type = self.get_counts(&sql).await?;
+
+        // date is stored as "2008-05-01 2008/05/01", "0000" for unknown
+        let sql = format!(
+            r#"{}
+SELECT SUBSTR(dates.value, 1, 4) as name, COUNT(*) as count FROM selected
+    JOIN ({}) dates USING (itemID)
+WHERE name != '0000'
+GROUP BY name ORDER BY name
+"#,
+            selected,
+            field_values("date")
+        );
+        stats.items_by_year = self.get_counts(&sql).await?;
+
+        let sql = format!(
+            r#"{}
+SELECT tags.name as name, COUNT(*) as count FROM selected
+    JOIN itemTags USING (itemID) JOIN tags USING (tagID)
+GROUP BY tags.name ORDER BY count DESC, name
+"#,
+            selected
+        );
+        stats.items_by_tag = self.get_counts(&sql).await?;
+
+        let sql = format!(
+            r#"{}
+SELECT collections.key as key, collections.collectionName as name, COUNT(*) as count FROM selected
+    JOIN collectionItems USING (itemID) JOIN collections USING (collectionID)
+WHERE {}
+GROUP BY collections.key ORDER BY count DESC, name, collections.key
+"#,
+            selected,
+            trash.sql_in(&["collections.collectionID"], self.trashed_collections().await?)
+        );
+        for rec in sqlx::query(&sql).fetch_all(self.pool()).await? {
+            stats.items_by_collection.push(CollectionCount {
+                key: rec.try_get("key")?,
+                collection: rec.try_get("name")?,
+                count: rec.try_get::<i64, _>("count")? as usize,
+            });
+        }
+
+        let sql = format!(
+            r#"{}
+SELECT strftime('%Y-%m', items.dateAdded) as name, COUNT(*) as count FROM selected
+    JOIN items USING (itemID)
+GROUP BY name ORDER BY name
+"#,
+            selected
+        );
+        stats.added_by_month = self.get_counts(&sql).await?;
+
+        // single field names are stored in lastName with fieldMode 1
+        let sql = format!(
+            r#"{}
+SELECT CASE WHEN creators.fieldMode = 1 OR IFNULL(creators.firstName, '') = '' THEN creators.lastName
+            ELSE creators.lastName || ', ' || creators.firstName END as name,
+       COUNT(DISTINCT itemCreators.itemID) as count
+FROM selected
+    JOIN itemCreators USING (itemID)
+    JOIN creators USING (creatorID)
+    JOIN creatorTypes USING (creatorTypeID)
+WHERE creatorTypes.creatorType = 'author'
+GROUP BY name ORDER BY count DESC, name LIMIT {}
+"#,
+            selected, top
+        );
+        stats.top_authors = self.get_counts(&sql).await?;
+
+        let sql = format!(
+            r#"{}
+SELECT journals.value as name, COUNT(*) as count FROM selected
+    JOIN ({}) journals USING (itemID)
+GROUP BY name ORDER BY count DESC, name LIMIT {}
+"#,
+            selected,
+            field_values("publicationTitle"),
+            top
+        );
+        stats.top_journals = self.get_counts(&sql).await?;
+
+        let sql = format!(
+            r#"{}
+SELECT COUNT(DISTINCT parentItemID) as count, '' as name FROM itemAttachments
+WHERE parentItemID IN (select itemID from selected)
+"#,
+            selected
+        );
+        stats.reading.items_with_attachments = self.get_counts(&sql).await?.first().map(|x| x.count).unwrap_or(0);
+
+        // annotations are stored in database since zotero 6
+        if !self.has_table("itemAnnotations").await? {
+            return Ok(stats);
+        }
+        let sql = format!(
+            r#"{}
+SELECT IFNULL(titles.value, items.key) as name, COUNT(*) as count FROM selected
+    JOIN items USING (itemID)
+    JOIN itemAttachments a ON a.parentItemID = items.itemID
+    JOIN itemAnnotations ON itemAnnotations.parentItemID = a.itemID
+    LEFT JOIN ({}) titles ON titles.itemID = items.itemID
+GROUP BY items.itemID ORDER BY count DESC, name
+"#,
+            selected,
+            field_values("title")
+        );
+        let annotated = self.get_counts(&sql).await?;
+        stats.reading.items_annotated = annotated.len();
+        stats.reading.annotations = annotated.iter().map(|x| x.count).sum();
+        stats.reading.most_annotated = annotated.into_iter().take(top).collect();
+
+        Ok(stats)
+    }
+
+    // Return (collection key, collection name, item key) of selected items,
+    // with collections filtered by `trash`
+    async fn get_selected_collection_items(
+        &self,
+        selected: &str,
+        trash: TrashFilter,
+    ) -> Result<Vec<(String, String, String)>> {
+        let sql = format!(
+            r#"{}
+SELECT collections.key as collection, collections.collectionName as name, items.key as key FROM selected
+    JOIN items USING (itemID)
+    JOIN collectionItems USING (itemID)
+    JOIN collections USING (collectionID)
+WHERE {}
+"#,
+            selected,
+            trash.sql_in(&["collections.collectionID"], self.trashed_collections().await?)
+        );
+        let recs = sqlx::query(&sql).fetch_all(self.pool()).await?;
+        recs.iter()
+            .map(|x| Ok((x.try_get("collection")?, x.try_get("name")?, x.try_get("key")?)))
+            .collect()
+    }
+}
+// 0b8e6f4c ends here
+
+// [[file:../zotero.note::7d4c19a2][7d4c19a2]]
+/// Collect statistics of zotero library
+#[derive(Debug, Clone)]
+pub struct Statistics {
+    top: usize,
+    year: Option<i32>,
+}
+
+impl Default for Statistics {
+    fn default() -> Self {
+        Self { top: 10, year: None }
+    }
+}
+
+impl Statistics {
+    /// Number of top authors, journals and annotated items to list. The
+    /// default is 10.
+    pub fn top(mut self, n: usize) -> Self {
+        self.top = n;
+        self
+    }
+
+    /// Only count items added in `year`, for yearly reports
+    pub fn added_in(mut self, year: i32) -> Self {
+        self.year = Some(year);
+        self
+    }
+
+    /// Collect statistics of items in zotero database. Items in trash are
+    /// filtered as set in `backend`.
+    pub fn run(&self, backend: &SqliteBackend) -> Result<LibraryStats> {
+        let trash = backend.trash_filter();
+        let selected = selected_items(trash, self.year);
+        let db = backend.db();
+        let (mut stats, collection_items) = backend.runtime().block_on(async {
+            let stats = db.get_library_stats(&selected, trash, self.top).await?;
+            let pairs = db.get_selected_collection_items(&selected, trash).await?;
+            Result::<_>::Ok((stats, pairs))
+        })?;
+
+        // sizes of attachment files by parent item
+        let mut sizes: HashMap<String, Vec<u64>> = HashMap::new();
+        for attachment in backend.attachment_files()? {
+            let size = match attachment.file.as_ref().and_then(|f| f.metadata().ok()) {
+                Some(m) if m.is_file() => m.len(),
+                _ => continue,
+            };
+            if let Some(parent) = attachment.parent {
+                sizes.entry(parent).or_default().push(size);
+            }
+        }
+        let mut usage: BTreeMap<String, StorageUsage> = BTreeMap::new();
+        let mut counted: HashSet<(String, String)> = HashSet::new();
+        for (collection_key, collection, key) in collection_items {
+            if !counted.insert((collection_key.clone(), key.clone())) {
+                continue;
+            }
+            let files = sizes.get(&key).map(|x| x.as_slice()).unwrap_or(&[]);
+            let entry = usage.entry(collection_key.clone()).or_insert_with(|| StorageUsage {
+                key: collection_key,
+                collection,
+                files: 0,
+                bytes: 0,
+            });
+            entry.files += files.len();
+            entry.bytes += files.iter().sum::<u64>();
+        }
+        stats.storage_by_collection = usage.into_iter().map(|x| x.1).collect();
+        stats.storage_by_collection.sort_by(|a, b| {
+            b.bytes
+                .cmp(&a.bytes)
+                .then_with(|| a.collection.cmp(&b.collection))
+                .then_with(|| a.key.cmp(&b.key))
+        });
+
+        Ok(stats)
+    }
+}
+
+impl SqliteBackend {
+    /// Return statistics of library with default settings
+    pub fn statistics(&self) -> Result<LibraryStats> {
+        Statistics::default().run(self)
+    }
+}
+// 7d4c19a2 ends here
+
+// [[file:../zotero.note::f3a25d90][f3a25d90]]
+// Format rows as org table with columns aligned by display width
+fn org_table(header: &[&str], rows: &[Vec<String>]) -> String {
+    use unicode_width::*;
+
+    let mut widths: Vec<_> = header.iter().map(|x| x.width()).collect();
+    for row in rows {
+        for (w, cell) in widths.iter_mut().zip(row) {
+            *w = (*w).max(cell.width());
+        }
+    }
+    let line = |cells: Vec<&str>| {
+        let cells: Vec<_> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, w)| format!("{}{}", cell, " ".repeat(w - cell.width())))
+            .collect();
+        format!("| {} |\n", cells.join(" | "))
+    };
+    let mut table = line(header.to_vec());
+    let rule: Vec<_> = widths.iter().map(|w| "-".repeat(w + 2)).collect();
+    table.push_str(&format!("|{}|\n", rule.join("+")));
+    for row in rows {
+        table.push_str(&line(row.iter().map(|x| x.as_str()).collect()));
+    }
+    table
+}
+
+fn count_rows(counts: &[Count]) -> Vec<Vec<String>> {
+    counts
+        .iter()
+        .map(|x| vec![x.name.clone(), x.count.to_string()])
+        .collect()
+}
+
+impl LibraryStats {
+    /// Format statistics in JSON
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Format statistics as org sections with tables
+    pub fn to_org(&self) -> String {
+        let mut org = String::new();
+        let mut section = |title: &str, table: String| {
+            org.push_str(&format!("* {}\n{}\n", title, table));
+        };
+
+        let reading = &self.reading;
+        let summary = vec![
+            vec!["items".into(), self.total.to_string()],
+            vec![
+                "items with attachments".into(),
+                reading.items_with_attachments.to_string(),
+            ],
+            vec!["items annotated".into(), reading.items_annotated.to_string()],
+            vec!["annotations".into(), reading.annotations.to_string()],
+        ];
+        section("Summary", org_table(&["", "count"], &summary));
+        section(
+            "Items by type",
+            org_table(&["type", "count"], &count_rows(&self.items_by_type)),
+        );
+        section(
+            "Items by year",
+            org_table(&["year", "count"], &count_rows(&self.items_by_year)),
+        );
+        section(
+            "Items by tag",
+            org_table(&["tag", "count"], &count_rows(&self.items_by_tag)),
+        );
+        let collections: Vec<_> = self
+            .items_by_collection
+            .iter()
+            .map(|x| vec![x.collection.clone(), x.key.clone(), x.count.to_string()])
+            .collect();
+        section(
+            "Items by collection",
+            org_table(&["collection", "key", "count"], &collections),
+        );
+        section(
+            "Items added by month",
+            org_table(&["month", "count"], &count_rows(&self.added_by_month)),
+        );
+        section(
+            "Top authors",
+            org_table(&["author", "items"], &count_rows(&self.top_authors)),
+        );
+        section(
+            "Top journals",
+            org_table(&["journal", "items"], &count_rows(&self.top_journals)),
+        );
+        let storage: Vec<_> = self
+            .storage_by_collection
+            .iter()
+            .map(|x| {
+                vec![
+                    x.collection.clone(),
+                    x.key.clone(),
+                    x.files.to_string(),
+                    x.bytes.to_string(),
+                ]
+            })
+            .collect();
+        section(
+            "Storage by collection",
+            org_table(&["collection", "key", "files", "bytes"], &storage),
+        );
+        section(
+            "Most annotated",
+            org_table(&["title", "annotations"], &count_rows(&reading.most_annotated)),
+        );
+        org
+    }
+}
+// f3a25d90 ends here
+
+// [[file:../zotero.note::*test][test:1]]
+#[test]
+fn test_library_stats() -> Result<()> {
+    use crate::attachment::AttachmentPaths;
+    use crate::fixture::TestDb;
+
+    let test_db = TestDb::with_sql(
+        r#"
+INSERT INTO items (itemID, itemTypeID, libraryID, key) VALUES (6, 37, 1, 'FFFFFFFF'), (7, 37, 1, 'GGGGGGGG');
+INSERT INTO itemAnnotations (itemID, parentItemID, type, text) VALUES (6, 3, 1, 'zeolite'), (7, 3, 1, 'thiophene');
+INSERT INTO collections (collectionID, collectionName, parentCollectionID, libraryID, key) VALUES
+    (3, 'Catalysis', NULL, 1, 'COLLCCCC'), (4, 'Trashed', NULL, 1, 'COLLDDDD');
+INSERT INTO collectionItems VALUES (3, 2, 0), (4, 1, 0), (4, 4, 1);
+INSERT INTO deletedCollections VALUES (4, '2021-01-01 00:00:00');
+"#,
+    );
+    let data_dir = test_db.path().parent().unwrap().to_owned();
+    let storage = data_dir.join("storage/CCCCCCCC");
+    std::fs::create_dir_all(&storage)?;
+    std::fs::write(storage.join("smith2008.pdf"), "%PDF-1.4\n")?;
+    let backend = SqliteBackend::open(test_db.path())?.with_attachment_paths(AttachmentPaths::new(&data_dir));
+
+    let stats = backend.statistics()?;
+    assert_eq!(stats.total, 2);
+    let counts =
+        |counts: &[Count]| -> Vec<(String, usize)> { counts.iter().map(|x| (x.name.clone(), x.count)).collect() };
+    assert_eq!(
+        counts(&stats.items_by_year),
+        vec![("1989".into(), 1), ("2008".into(), 1)]
+    );
+    assert_eq!(
+        counts(&stats.items_by_tag),
+        vec![("dft".into(), 2), ("zeolite".into(), 1)]
+    );
+    assert_eq!(
+        counts(&stats.added_by_month),
+        vec![("2020-01".into(), 1), ("2020-02".into(), 1)]
+    );
+    // trashed D of Smith is not counted by default
+    let smith = stats.top_authors.iter().find(|x| x.name == "Smith, John");
+    assert_eq!(smith.map(|x| x.count), Some(1));
+    assert_eq!(stats.top_journals[0].name, "J. Catal.");
+    // collections of the same name are counted apart, and collections in
+    // trash are ignored
+    let collections: Vec<_> = stats
+        .items_by_collection
+        .iter()
+        .map(|x| (x.key.as_str(), x.collection.as_str(), x.count))
+        .collect();
+    assert_eq!(
+        collections,
+        vec![
+            ("COLLAAAA", "Catalysis", 1),
+            ("COLLCCCC", "Catalysis", 1),
+            ("COLLBBBB", "Zeolites", 1)
+        ]
+    );
+    let storage: Vec<_> = stats
+        .storage_by_collection
+        .iter()
+        .map(|x| (x.key.as_str(), x.bytes))
+        .collect();
+    assert_eq!(storage, vec![("COLLAAAA", 9), ("COLLBBBB", 9), ("COLLCCCC", 0)]);
+    assert_eq!(stats.reading.items_annotated, 1);
+    assert_eq!(stats.reading.annotations, 2);
+
+    let stats = Statistics::default().added_in(2019).run(&backend)?;
+    assert_eq!(stats.total, 0);
+
+    let trash = SqliteBackend::open(test_db.path())?.with_trash_filter(TrashFilter::Only);
+    let stats = Statistics::default().run(&trash)?;
+    let keys: Vec<_> = stats.items_by_collection.iter().map(|x| x.key.as_str()).collect();
+    assert_eq!(keys, vec!["COLLDDDD"]);
+
+    let stats = backend.statistics()?;
+    let org = stats.to_org();
+    assert!(org.contains(
+        "* Items by collection\n| collection | key      | count |\n|------------+----------+-------|\n| Catalysis  | COLLAAAA | 1     |"
+    ));
+    let json: serde_json::Value = serde_json::from_str(&stats.to_json()?)?;
+    assert_eq!(json["reading"]["most_annotated"][0]["count"], 2);
+
+    Ok(())
+}
+// test:1 ends here
@@ -0,0 +1,189 @@
+// [[file:../zotero.note::*imports][imports:1]]
+use gut::prelude::*;
+
+use sqlx::prelude::*;
+
+use crate::backend::SqliteBackend;
+use crate::db::ZoteroDb;
+// imports:1 ends here
+
+// [[file:../zotero.note::5e3a81d6][5e3a81d6]]
+/// How to treat items in trash in queries
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrashFilter {
+    /// Ignore items in trash, as zotero does in library views
+    #[default]
+    Exclude,
+    /// Items in trash or not
+    Include,
+    /// Only items in trash
+    Only,
+}
+
+impl std::str::FromStr for TrashFilter {
+    type Err = gut::prelude::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "exclude" => Ok(Self::Exclude),
+            "include" => Ok(Self::Include),
+            "only" => Ok(Self::Only),
+            _ => bail!("invalid trash filter: {}", s),
+        }
+    }
+}
+
+impl TrashFilter {
+    /// SQL condition on item IDs in `columns`. A row counts as trashed if
+    /// any of them is in trash, e.g. an attachment whose parent is in
+    /// trash. NULL columns are fine.
+    pub(crate) fn sql(&self, columns: &[&str]) -> String {
+        self.sql_in(columns, "select itemID from deletedItems")
+    }
+
+    /// The same as `sql`, with IDs in trash selected by `trashed` query,
+    /// e.g. for collections.
+    pub(crate) fn sql_in(&self, columns: &[&str], trashed: &str) -> String {
+        let trashed: Vec<_> = columns
+            .iter()
+            .map(|col| format!("IFNULL({}, 0) IN ({})", col, trashed))
+            .collect();
+        let trashed = trashed.join(" OR ");
+        match self {
+            Self::Exclude => format!("NOT ({})", trashed),
+            Self::Include => "1".into(),
+            Self::Only => format!("({})", trashed),
+        }
+    }
+}
+
+impl ZoteroDb {
+    /// Query selecting IDs of collections in trash for `TrashFilter::sql_in`.
+    /// Collections can be trashed since zotero 7.
+    pub(crate) async fn trashed_collections(&self) -> Result<&'static str> {
+        if self.has_table("deletedCollections").await? {
+            Ok("select collectionID from deletedCollections")
+        } else {
+            Ok("select NULL where 0")
+        }
+    }
+}
+// 5e3a81d6 ends here
+
+// [[file:../zotero.note::c0f4e92a][c0f4e92a]]
+/// An item in trash
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TrashedItem {
+    pub key: String,
+    /// Type name of item, e.g. journalArticle, attachment
+    pub item_type: String,
+    pub title: String,
+    pub date_deleted: String,
+}
+
+impl ZoteroDb {
+    /// Return items in trash, oldest deleted first. With `days`, only items
+    /// deleted more than `days` ago.
+    pub(crate) async fn get_trash_items(&self, days: Option<u32>) -> Result<Vec<TrashedItem>> {
+        let recs = sqlx::query(
+            r#"
+SELECT items.key as key, itemTypes.typeName as item_type, IFNULL(titles.value, '') as title,
+       CAST(deletedItems.dateDeleted AS TEXT) as date_deleted
+FROM deletedItems
+    JOIN items USING (itemID)
+    JOIN itemTypes USING (itemTypeID)
+    LEFT JOIN (
+        SELECT itemID, itemDataValues.value as value FROM itemData
+            JOIN fields USING (fieldID) JOIN itemDataValues USING (valueID)
+        WHERE fields.fieldName = 'title') titles USING (itemID)
+WHERE ? IS NULL OR deletedItems.dateDeleted < datetime('now', ?)
+ORDER BY deletedItems.dateDeleted
+"#,
+        )
+        .bind(days)
+        .bind(days.map(|d| format!("-{} days", d)))
+        .fetch_all(self.pool())
+        .await?;
+
+        recs.iter()
+            .map(|x| {
+                Ok(TrashedItem {
+                    key: x.try_get("key")?,
+                    item_type: x.try_get("item_type")?,
+                    title: x.try_get("title")?,
+                    date_deleted: x.try_get("date_deleted")?,
+                })
+            })
+            .collect()
+    }
+}
+
+impl SqliteBackend {
+    /// Return all items in trash with deletion date
+    pub fn trash(&self) -> Result<Vec<TrashedItem>> {
+        self.runtime().block_on(self.db().get_trash_items(None))
+    }
+}
+// c0f4e92a ends here
+
+// [[file:../zotero.note::*test][test:1]]
+#[test]
+fn test_trash_filter() -> Result<()> {
+    use crate::attachment::AttachmentPaths;
+    use crate::backend::ZoteroBackend;
+    use crate::fixture::TestDb;
+
+    // attachment G of trashed item D, and a trashed collection
+    let test_db = TestDb::with_sql(
+        r#"
+INSERT INTO items (itemID, itemTypeID, libraryID, key) VALUES (6, 14, 1, 'GGGGGGGG');
+INSERT INTO itemAttachments VALUES (6, 4, 0, 'application/pdf', NULL, 'storage:deleted.pdf', 0);
+INSERT INTO collections (collectionID, collectionName, parentCollectionID, libraryID, key) VALUES
+    (3, 'Old', NULL, 1, 'COLLCCCC');
+INSERT INTO deletedCollections VALUES (3, '2021-01-01 00:00:00');
+"#,
+    );
+    let data_dir = test_db.path().parent().unwrap().to_owned();
+    let open = |trash| -> Result<SqliteBackend> {
+        let backend = SqliteBackend::open(test_db.path())?
+            .with_attachment_paths(AttachmentPaths::new(&data_dir))
+            .with_trash_filter(trash);
+        Ok(backend)
+    };
+    let keys = |items: Vec<crate::db::Item>| -> Vec<String> { items.iter().map(|x| x.key().to_string()).collect() };
+
+    let backend = open(TrashFilter::Exclude)?;
+    assert_eq!(keys(backend.query("tag:dft")?), vec!["BBBBBBBB", "AAAAAAAA"]);
+    let collections: Vec<_> = backend.collections()?.into_iter().map(|x| x.name).collect();
+    assert_eq!(collections, vec!["Catalysis", "Zeolites"]);
+    assert_eq!(keys(backend.query("collection:catalysis")?), vec!["AAAAAAAA"]);
+    assert!(backend.attachments("DDDDDDDD")?.is_empty());
+    let files: Vec<_> = backend.attachment_files()?.into_iter().map(|x| x.key).collect();
+    assert_eq!(files, vec!["CCCCCCCC"]);
+
+    let backend = open(TrashFilter::Include)?;
+    assert_eq!(
+        keys(backend.query("tag:dft")?),
+        vec!["DDDDDDDD", "BBBBBBBB", "AAAAAAAA"]
+    );
+    assert_eq!(backend.attachments("DDDDDDDD")?.len(), 1);
+
+    let backend = open(TrashFilter::Only)?;
+    assert_eq!(keys(backend.query("collection:catalysis")?), vec!["DDDDDDDD"]);
+    assert_eq!(keys(backend.search("deleted")?), vec!["DDDDDDDD"]);
+    assert_eq!(backend.tags()?, vec!["dft"]);
+    let collections: Vec<_> = backend.collections()?.into_iter().map(|x| x.name).collect();
+    assert_eq!(collections, vec!["Old"]);
+    let files: Vec<_> = backend.attachment_files()?.into_iter().map(|x| x.key).collect();
+    assert_eq!(files, vec!["GGGGGGGG"]);
+
+    let trash = backend.trash()?;
+    assert_eq!(trash.len(), 1);
+    assert_eq!(trash[0].title, "Deleted paper");
+    assert_eq!(trash[0].date_deleted, "2021-01-01 00:00:00");
+    assert_eq!("only".parse::<TrashFilter>()?, TrashFilter::Only);
+
+    Ok(())
+}
+// test:1 ends here
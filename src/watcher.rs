@@ -0,0 +1,331 @@
+// [[file:../zotero.note::*imports][imports:1]]
+use gut::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use sqlx::prelude::*;
+
+use crate::backend::SqliteBackend;
+use crate::changes::Watermark;
+use crate::db::ZoteroDb;
+use crate::profile::update_zotero_db_cache;
+// imports:1 ends here
+
+// [[file:../zotero.note::5c2e8f17][5c2e8f17]]
+/// Changes in zotero library reported by `LibraryWatcher`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LibraryEvent {
+    /// New regular item or note in `key`
+    ItemAdded(String),
+    ItemModified(String),
+    /// Item moved to trash
+    ItemTrashed(String),
+    /// Item erased from database
+    ItemDeleted(String),
+    /// New attachment item, with key of its parent item if any
+    AttachmentAdded {
+        key: String,
+        parent: Option<String>,
+    },
+    /// Files changed in storage directory of attachment item
+    AttachmentFileChanged(String),
+    /// Tag created or no longer used
+    TagChanged(String),
+    /// Synced setting changed, such as tag colors
+    SettingChanged(String),
+    /// Some changes could not be identified, all items should be reloaded
+    Reloaded,
+}
+
+// What the watcher knows about the library
+#[derive(Debug, Default)]
+struct Snapshot {
+    watermark: Watermark,
+    tags: HashSet<String>,
+}
+
+impl ZoteroDb {
+    // Return attachments in `keys`, mapped to their parent item keys
+    async fn get_attachment_parents(&self, keys: &[String]) -> Result<HashMap<String, Option<String>>> {
+        let sql = r#"
+SELECT a.key as key, p.key as parent FROM itemAttachments
+    JOIN items a ON a.itemID = itemAttachments.itemID
+    LEFT JOIN items p ON p.itemID = itemAttachments.parentItemID
+    WHERE a.key IN ({keys})
+"#;
+        let mut parents = HashMap::new();
+        for rec in self.fetch_by_keys(sql, keys).await? {
+            parents.insert(rec.try_get("key")?, rec.try_get("parent")?);
+        }
+        Ok(parents)
+    }
+
+    // Return keys in `keys` still in database
+    async fn get_existing_keys(&self, keys: &[String]) -> Result<HashSet<String>> {
+        let recs = self
+            .fetch_by_keys("SELECT key FROM items WHERE key IN ({keys})", keys)
+            .await?;
+        recs.iter().map(|x| Ok(x.try_get("key")?)).collect()
+    }
+}
+
+// Diff database in `path` against `prev` snapshot
+fn diff_snapshot(path: &Path, prev: &Snapshot) -> Result<(Snapshot, Vec<LibraryEvent>)> {
+    let backend = SqliteBackend::open(path)?;
+    let changes = backend.changes_since(&prev.watermark)?;
+    let (parents, existing) = backend.runtime().block_on(async {
+        let parents = backend.db().get_attachment_parents(&changes.added).await?;
+        let existing = backend.db().get_existing_keys(&changes.deleted).await?;
+        Result::<_>::Ok((parents, existing))
+    })?;
+
+    let mut events = vec![];
+    if changes.incomplete {
+        events.push(LibraryEvent::Reloaded);
+    }
+    for key in changes.added {
+        match parents.get(&key) {
+            Some(parent) => events.push(LibraryEvent::AttachmentAdded {
+                key,
+                parent: parent.clone(),
+            }),
+            None => events.push(LibraryEvent::ItemAdded(key)),
+        }
+    }
+    events.extend(changes.modified.into_iter().map(LibraryEvent::ItemModified));
+    for key in changes.deleted {
+        if existing.contains(&key) {
+            events.push(LibraryEvent::ItemTrashed(key));
+        } else {
+            events.push(LibraryEvent::ItemDeleted(key));
+        }
+    }
+
+    use crate::backend::ZoteroBackend;
+    let tags: HashSet<String> = backend.tags()?.into_iter().collect();
+    let mut changed_tags: Vec<_> = tags.symmetric_difference(&prev.tags).cloned().collect();
+    changed_tags.sort();
+    events.extend(changed_tags.into_iter().map(LibraryEvent::TagChanged));
+    events.extend(changes.settings.into_iter().map(LibraryEvent::SettingChanged));
+
+    let snapshot = Snapshot {
+        watermark: changes.watermark,
+        tags,
+    };
+    Ok((snapshot, events))
+}
+// 5c2e8f17 ends here
+
+// [[file:../zotero.note::e83b6d05][e83b6d05]]
+/// Watch zotero.sqlite and storage directory in zotero data directory, and
+/// report library changes as events over a channel. Changes are read from a
+/// cached copy of the database, which is updated after writes settled down.
+pub struct LibraryWatcher {
+    data_dir: PathBuf,
+    cached: PathBuf,
+    debounce: Duration,
+    watcher: Option<RecommendedWatcher>,
+    stop: Arc<AtomicBool>,
+}
+
+impl LibraryWatcher {
+    /// Watch zotero data directory `data_dir`, caching database at `cached`.
+    pub fn new<P: AsRef<Path>, Q: AsRef<Path>>(data_dir: P, cached: Q) -> Self {
+        Self {
+            data_dir: data_dir.as_ref().to_owned(),
+            cached: cached.as_ref().to_owned(),
+            debounce: Duration::from_secs(2),
+            watcher: None,
+            stop: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Wait until no writes happened for `debounce` before reading changes.
+    /// The default is 2 seconds.
+    pub fn debounce(mut self, debounce: Duration) -> Self {
+        self.debounce = debounce;
+        self
+    }
+
+    /// Start watching in background. Events are sent until the watcher is
+    /// dropped or the receiver hung up.
+    pub fn start(&mut self) -> Result<Receiver<LibraryEvent>> {
+        let dbfile = self.data_dir.join("zotero.sqlite");
+        let storage = self.data_dir.join("storage");
+        update_zotero_db_cache(&dbfile, &self.cached)?;
+        // changes before watching are not reported
+        let (snapshot, _) = diff_snapshot(&self.cached, &Snapshot::default())?;
+
+        let (fs_tx, fs_rx) = channel();
+        let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| match res {
+            Ok(event) => {
+                let _ = fs_tx.send(event.paths);
+            }
+            Err(e) => warn!("watch error: {:?}", e),
+        })?;
+        watcher.watch(&self.data_dir, RecursiveMode::NonRecursive)?;
+        if storage.is_dir() {
+            watcher.watch(&storage, RecursiveMode::Recursive)?;
+        }
+        self.watcher = Some(watcher);
+
+        let (tx, rx) = channel();
+        let worker = Worker {
+            dbfile,
+            storage,
+            cached: self.cached.clone(),
+            debounce: self.debounce,
+            stop: self.stop.clone(),
+        };
+        std::thread::spawn(move || worker.run(snapshot, fs_rx, tx));
+        Ok(rx)
+    }
+}
+
+impl Drop for LibraryWatcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+struct Worker {
+    dbfile: PathBuf,
+    storage: PathBuf,
+    cached: PathBuf,
+    debounce: Duration,
+    stop: Arc<AtomicBool>,
+}
+
+impl Worker {
+    fn run(&self, mut snapshot: Snapshot, fs_rx: Receiver<Vec<PathBuf>>, tx: Sender<LibraryEvent>) {
+        let mut last_write: Option<Instant> = None;
+        let mut db_changed = false;
+        let mut files_changed = vec![];
+        while !self.stop.load(Ordering::SeqCst) {
+            match fs_rx.recv_timeout(Duration::from_millis(100)) {
+                Ok(paths) => {
+                    for path in paths {
+                        if let Some(key) = self.attachment_key(&path) {
+                            files_changed.push(key);
+                        } else if self.is_db_file(&path) {
+                            db_changed = true;
+                        } else {
+                            continue;
+                        }
+                        last_write = Some(Instant::now());
+                    }
+                    continue;
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            match last_write {
+                Some(t) if t.elapsed() >= self.debounce => last_write = None,
+                _ => continue,
+            }
+
+            let mut events = vec![];
+            if db_changed {
+                db_changed = false;
+                match self.refresh(&snapshot) {
+                    Ok((new, changes)) => {
+                        snapshot = new;
+                        events.extend(changes);
+                    }
+                    Err(e) => warn!("failed to read zotero db changes: {:?}", e),
+                }
+            }
+            files_changed.sort();
+            files_changed.dedup();
+            events.extend(files_changed.drain(..).map(LibraryEvent::AttachmentFileChanged));
+            for event in events {
+                if tx.send(event).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    fn refresh(&self, snapshot: &Snapshot) -> Result<(Snapshot, Vec<LibraryEvent>)> {
+        update_zotero_db_cache(&self.dbfile, &self.cached)?;
+        diff_snapshot(&self.cached, snapshot)
+    }
+
+    // zotero.sqlite or its journal files
+    fn is_db_file(&self, path: &Path) -> bool {
+        path.parent() == self.dbfile.parent()
+            && path
+                .file_name()
+                .and_then(|x| x.to_str())
+                .map(|x| x.starts_with("zotero.sqlite"))
+                .unwrap_or(false)
+    }
+
+    // storage/KEY/file.pdf
+    fn attachment_key(&self, path: &Path) -> Option<String> {
+        let rel = path.strip_prefix(&self.storage).ok()?;
+        let mut parts = rel.components();
+        let key = parts.next()?.as_os_str().to_str()?;
+        parts.next()?;
+        Some(key.to_string())
+    }
+}
+// e83b6d05 ends here
+
+// [[file:../zotero.note::*test][test:1]]
+#[test]
+fn test_library_watcher() -> Result<()> {
+    use crate::fixture::TestDb;
+
+    let test_db = TestDb::new();
+    let data_dir = test_db.path().parent().unwrap().to_owned();
+    std::fs::create_dir_all(data_dir.join("storage/CCCCCCCC"))?;
+    let cached = data_dir.join("cache/zotero.sqlite");
+    let mut watcher = LibraryWatcher::new(&data_dir, &cached).debounce(Duration::from_millis(200));
+    let rx = watcher.start()?;
+
+    test_db.execute(
+        r#"
+INSERT INTO items (itemID, itemTypeID, libraryID, key) VALUES (6, 4, 1, 'FFFFFFFF'), (7, 14, 1, 'GGGGGGGG');
+INSERT INTO itemAttachments VALUES (7, 6, 0, 'application/pdf', NULL, 'storage:new.pdf', 0);
+INSERT INTO tags VALUES (3, 'catalysis');
+INSERT INTO itemTags VALUES (6, 3, 0);
+INSERT INTO deletedItems VALUES (2, '2030-01-01 00:00:00');
+"#,
+    );
+    std::fs::write(data_dir.join("storage/CCCCCCCC/smith2008.pdf"), "%PDF-1.4")?;
+
+    let mut events = vec![];
+    while let Ok(event) = rx.recv_timeout(Duration::from_secs(5)) {
+        events.push(event);
+        if events.len() >= 5 {
+            break;
+        }
+    }
+    assert!(events.contains(&LibraryEvent::ItemAdded("FFFFFFFF".into())));
+    let attachment = LibraryEvent::AttachmentAdded {
+        key: "GGGGGGGG".into(),
+        parent: Some("FFFFFFFF".into()),
+    };
+    assert!(events.contains(&attachment));
+    assert!(events.contains(&LibraryEvent::ItemTrashed("BBBBBBBB".into())));
+    assert!(events.contains(&LibraryEvent::TagChanged("catalysis".into())));
+    assert!(events.contains(&LibraryEvent::AttachmentFileChanged("CCCCCCCC".into())));
+
+    // more keys than sqlite allows in one query
+    let backend = SqliteBackend::open(test_db.path())?;
+    let mut keys: Vec<_> = (0..2000).map(|i| format!("{:08}", i)).collect();
+    keys.push("GGGGGGGG".into());
+    let parents = backend.runtime().block_on(backend.db().get_attachment_parents(&keys))?;
+    assert_eq!(parents["GGGGGGGG"].as_deref(), Some("FFFFFFFF"));
+    let existing = backend.runtime().block_on(backend.db().get_existing_keys(&keys))?;
+    assert_eq!(existing.len(), 1);
+
+    Ok(())
+}
+// test:1 ends here
@@ -0,0 +1,401 @@
+// [[file:../zotero.note::*imports][imports:1]]
+use gut::prelude::*;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::api::*;
+use crate::db::Item;
+use crate::server::ZoteroServer;
+use reqwest::blocking::{Client, RequestBuilder, Response};
+// imports:1 ends here
+
+// [[file:../zotero.note::8c41d7e3][8c41d7e3]]
+const WEB_API_URL: &str = "https://api.zotero.org";
+
+/// Client for zotero Web API v3, or any server compatible with it.
+pub struct WebApi {
+    server: ZoteroServer,
+    api_key: String,
+    library: String,
+    // requests are delayed until then as asked by `Backoff` header
+    backoff_until: Mutex<Option<Instant>>,
+}
+
+impl WebApi {
+    /// Client for personal library of user in `user_id`, authorized with `api_key`.
+    pub fn user(api_key: &str, user_id: i64) -> Self {
+        Self::new(api_key, format!("users/{}", user_id))
+    }
+
+    /// Client for group library in `group_id`, authorized with `api_key`.
+    pub fn group(api_key: &str, group_id: i64) -> Self {
+        Self::new(api_key, format!("groups/{}", group_id))
+    }
+
+    fn new(api_key: &str, library: String) -> Self {
+        Self {
+            server: ZoteroServer::new(WEB_API_URL),
+            api_key: api_key.into(),
+            library,
+            backoff_until: Mutex::new(None),
+        }
+    }
+
+    /// Send requests through `server` instead of api.zotero.org, for
+    /// self-hosted servers or custom timeouts and retries.
+    pub fn with_server(mut self, server: ZoteroServer) -> Self {
+        self.server = server;
+        self
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}/{}{}", self.server.base_url(), self.library, path)
+    }
+
+    // Send authorized request, respecting `Backoff` header from server.
+    fn send<F>(&self, build: F) -> Result<Response>
+    where
+        F: Fn(&Client) -> RequestBuilder,
+    {
+        self.send_with(build, false)
+    }
+
+    // Send authorized request, and also retry POST requests if `retry_post`
+    fn send_with<F>(&self, build: F, retry_post: bool) -> Result<Response>
+    where
+        F: Fn(&Client) -> RequestBuilder,
+    {
+        let until = *self.backoff_until.lock().unwrap();
+        if let Some(until) = until {
+            let now = Instant::now();
+            if until > now {
+                info!("zotero api asked to back off, waiting {:?}", until - now);
+                std::thread::sleep(until - now);
+            }
+        }
+
+        let build = |client: &Client| {
+            build(client)
+                .header("Zotero-API-Version", "3")
+                .header("Zotero-API-Key", &self.api_key)
+        };
+        let resp = if retry_post {
+            self.server.execute_retrying(build)?
+        } else {
+            self.server.execute(build)?
+        };
+        let backoff: Option<u64> = header_value(&resp, "Backoff");
+        *self.backoff_until.lock().unwrap() = backoff.map(|secs| Instant::now() + Duration::from_secs(secs));
+        Ok(resp)
+    }
+
+    fn get(&self, url: &str, params: &[(String, String)]) -> Result<Response> {
+        self.send(|client| client.get(url).query(params))
+    }
+
+    fn get_all<T>(
+        &self,
+        path: &str,
+        params: Vec<(String, String)>,
+        parse: fn(&str) -> Result<Vec<T>>,
+    ) -> Result<ApiResults<T>> {
+        collect_pages(&self.url(path), params, |url, params| self.get(url, params), parse)
+    }
+}
+// 8c41d7e3 ends here
+
+// [[file:../zotero.note::f25a0c69][f25a0c69]]
+impl WebApi {
+    /// List items in `query`, collecting all pages.
+    pub fn items(&self, query: &ApiQuery) -> Result<ApiResults<ApiItem>> {
+        self.get_all(&items_path(query, "/items"), query.params(), parse_json)
+    }
+
+    /// Return item in `key`
+    pub fn item(&self, key: &str) -> Result<ApiItem> {
+        let resp = self.get(&self.url(&format!("/items/{}", key)), &[])?;
+        let text = response_text(resp)?;
+        serde_json::from_str(&text).with_context(|| format!("invalid item json: {}", text))
+    }
+
+    /// Return items in `query`, in the same model as sqlite backend
+    pub fn get_items(&self, query: &ApiQuery) -> Result<Vec<Item>> {
+        let items = self.items(query)?.items.iter().map(|x| x.to_item()).collect();
+        Ok(items)
+    }
+
+    /// List all collections
+    pub fn collections(&self) -> Result<Vec<ApiCollection>> {
+        Ok(self.get_all("/collections", vec![], parse_json)?.items)
+    }
+
+    /// List all tags in library
+    pub fn tags(&self) -> Result<Vec<ApiTag>> {
+        Ok(self.get_all("/tags", vec![], parse_json)?.items)
+    }
+}
+// f25a0c69 ends here
+
+// [[file:../zotero.note::39b8e6d2][39b8e6d2]]
+/// Objects deleted from library since a version
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ApiDeleted {
+    #[serde(default)]
+    pub items: Vec<String>,
+    #[serde(default)]
+    pub collections: Vec<String>,
+    #[serde(default)]
+    pub searches: Vec<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// Library changes since a known version
+#[derive(Debug, Clone)]
+pub struct LibraryChanges {
+    /// Current library version, to be used in next `changes_since` call
+    pub version: i64,
+    /// Created or modified items
+    pub items: Vec<ApiItem>,
+    pub deleted: ApiDeleted,
+}
+
+impl WebApi {
+    /// Fetch library changes since library `version`. Return None if library
+    /// has not been modified. Use 0 to fetch the whole library.
+    pub fn changes_since(&self, version: i64) -> Result<Option<LibraryChanges>> {
+        let params = vec![
+            ("since".to_string(), version.to_string()),
+            ("format".to_string(), "versions".to_string()),
+        ];
+        let url = self.url("/items");
+        let resp = self.send(|client| {
+            client
+                .get(&url)
+                .query(&params)
+                .header("If-Modified-Since-Version", version.to_string())
+        })?;
+        if resp.status().as_u16() == 304 {
+            return Ok(None);
+        }
+        let new_version: i64 = header_value(&resp, "Last-Modified-Version").unwrap_or(version);
+        let versions: HashMap<String, i64> = serde_json::from_str(&response_text(resp)?)?;
+
+        // fetch modified items in batches
+        let mut keys: Vec<_> = versions.keys().map(|x| x.as_str()).collect();
+        keys.sort();
+        let mut items = vec![];
+        for chunk in keys.chunks(50) {
+            let query = ApiQuery::default().item_keys(chunk).limit(50);
+            items.extend(self.items(&query)?.items);
+        }
+
+        let params = vec![("since".to_string(), version.to_string())];
+        let resp = self.get(&self.url("/deleted"), &params)?;
+        let deleted = serde_json::from_str(&response_text(resp)?)?;
+
+        Ok(Some(LibraryChanges {
+            version: new_version,
+            items,
+            deleted,
+        }))
+    }
+}
+// 39b8e6d2 ends here
+
+// [[file:../zotero.note::d7a9134e][d7a9134e]]
+/// Results of a write request, mapping index of submitted object to its key
+/// or failure.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct WriteResults {
+    #[serde(default)]
+    pub success: HashMap<String, String>,
+    #[serde(default)]
+    pub unchanged: HashMap<String, String>,
+    #[serde(default)]
+    pub failed: HashMap<String, WriteFailure>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WriteFailure {
+    #[serde(default)]
+    pub key: Option<String>,
+    pub code: i64,
+    pub message: String,
+}
+
+// Random token identifying a write request, so that zotero server can reject
+// the same request sent again
+fn new_write_token() -> String {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|x| x.as_nanos())
+        .unwrap_or_default();
+    let n = COUNTER.fetch_add(1, Ordering::SeqCst);
+    format!("{:016x}{:08x}{:08x}", nanos as u64, std::process::id(), n as u32)
+}
+
+impl WebApi {
+    /// Create or update items in `items` (50 at most). If `library_version`
+    /// is given, the request fails when library has been modified after
+    /// that version.
+    pub fn save_items(&self, items: &[ApiItemData], library_version: Option<i64>) -> Result<WriteResults> {
+        if items.len() > 50 {
+            bail!("too many items to save at once: {}", items.len());
+        }
+        let url = self.url("/items");
+        // the same token in retries, so that items are not created twice
+        let token = new_write_token();
+        let resp = self.send_with(
+            |client| {
+                let req = client.post(&url).json(items).header("Zotero-Write-Token", &token);
+                match library_version {
+                    Some(v) => req.header("If-Unmodified-Since-Version", v.to_string()),
+                    None => req,
+                }
+            },
+            true,
+        )?;
+        if resp.status().as_u16() == 412 {
+            bail!("library has been modified since version {:?}", library_version);
+        }
+        let text = response_text(resp)?;
+        serde_json::from_str(&text).with_context(|| format!("invalid write response: {}", text))
+    }
+
+    /// Update existing item with changed fields in `data`. The item is only
+    /// updated when its version on server matches `data.version`. Return the
+    /// new item version.
+    pub fn update_item(&self, data: &ApiItemData) -> Result<i64> {
+        if data.key.is_empty() {
+            bail!("missing key of item to update");
+        }
+        let url = self.url(&format!("/items/{}", data.key));
+        let resp = self.send(|client| {
+            client
+                .patch(&url)
+                .json(data)
+                .header("If-Unmodified-Since-Version", data.version.to_string())
+        })?;
+        if resp.status().as_u16() == 412 {
+            bail!("item {} has been modified since version {}", data.key, data.version);
+        }
+        let version = header_value(&resp, "Last-Modified-Version");
+        response_text(resp)?;
+        version.with_context(|| format!("no version returned for updated item {}", data.key))
+    }
+}
+
+impl ApiItemData {
+    /// New item data in `item_type` for creating items.
+    pub fn new(item_type: &str) -> Self {
+        Self {
+            item_type: item_type.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Set item field `name` to `value`
+    pub fn set_field(&mut self, name: &str, value: &str) {
+        match name {
+            "title" => self.title = Some(value.into()),
+            "date" => self.date = Some(value.into()),
+            "extra" => self.extra = Some(value.into()),
+            _ => {
+                self.fields.insert(name.into(), Value::from(value));
+            }
+        }
+    }
+}
+// d7a9134e ends here
+
+// [[file:../zotero.note::*test][test:1]]
+#[test]
+fn test_web_api() -> Result<()> {
+    use crate::mock::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let item = r#"{"key": "AAAAAAAA", "version": 8, "meta": {},
+                   "data": {"key": "AAAAAAAA", "version": 8, "itemType": "book", "title": "one", "date": "2019"}}"#;
+    let items = format!("[{}]", item);
+    let limited = AtomicUsize::new(0);
+    let writes = AtomicUsize::new(0);
+    let mock = MockServer::start(move |req| {
+        if req.header("zotero-api-key") != Some("secret") {
+            return MockResponse::status(403, "Forbidden");
+        }
+        let since = req.header("if-modified-since-version");
+        match (req.method.as_str(), req.path()) {
+            ("GET", "/users/1/items") if since == Some("8") => MockResponse::status(304, ""),
+            ("GET", "/users/1/items") if req.query("format").as_deref() == Some("versions") => {
+                MockResponse::ok(r#"{"AAAAAAAA": 8}"#).header("Last-Modified-Version", "8")
+            }
+            ("GET", "/users/1/items") if req.query("itemKey").as_deref() == Some("AAAAAAAA") => {
+                MockResponse::ok(&items).header("Backoff", "1")
+            }
+            ("GET", "/users/1/deleted") => MockResponse::ok(r#"{"items": ["BBBBBBBB"], "collections": []}"#),
+            ("GET", "/users/1/tags") if limited.fetch_add(1, Ordering::SeqCst) == 0 => {
+                MockResponse::status(429, "Too Many Requests").header("Retry-After", "1")
+            }
+            ("GET", "/users/1/tags") => MockResponse::ok(r#"[{"tag": "dft"}]"#),
+            ("POST", "/users/1/items") if writes.fetch_add(1, Ordering::SeqCst) == 0 => {
+                MockResponse::status(503, "Service Unavailable")
+            }
+            ("POST", "/users/1/items") if req.header("if-unmodified-since-version") == Some("8") => {
+                MockResponse::ok(r#"{"success": {"0": "CCCCCCCC"}, "unchanged": {}, "failed": {}}"#)
+            }
+            ("POST", "/users/1/items") => MockResponse::status(412, "Precondition Failed"),
+            ("PATCH", "/users/1/items/AAAAAAAA") if req.header("if-unmodified-since-version") == Some("8") => {
+                MockResponse::status(204, "").header("Last-Modified-Version", "9")
+            }
+            ("PATCH", _) => MockResponse::status(412, "Precondition Failed"),
+            _ => MockResponse::status(404, "Not found"),
+        }
+    });
+
+    let server = ZoteroServer::builder()
+        .base_url(mock.url())
+        .backoff(Duration::from_millis(10))
+        .build()?;
+    let api = WebApi::user("secret", 1).with_server(server);
+    // delayed by `Backoff` and `Retry-After`
+    let start = Instant::now();
+    let changes = api.changes_since(5)?.expect("library changes");
+    assert_eq!(changes.version, 8);
+    assert_eq!(changes.items[0].to_item().title(), "one");
+    assert_eq!(changes.deleted.items, vec!["BBBBBBBB"]);
+    assert!(api.changes_since(8)?.is_none());
+
+    assert_eq!(api.tags()?[0].tag, "dft");
+    assert!(start.elapsed() >= Duration::from_secs(2));
+
+    let mut data = ApiItemData::new("book");
+    data.set_field("title", "new book");
+    data.set_field("ISBN", "978-3-16-148410-0");
+    let results = api.save_items(&[data.clone()], Some(8))?;
+    assert_eq!(results.success["0"], "CCCCCCCC");
+    assert!(api.save_items(&[data], Some(7)).is_err());
+    let posted: Vec<_> = mock.requests().into_iter().filter(|x| x.method == "POST").collect();
+    assert!(posted[0].body.contains(r#""ISBN":"978-3-16-148410-0""#));
+    assert!(!posted[0].body.contains(r#""key""#));
+    // write token is kept in retry, but not in another write
+    let tokens: Vec<_> = posted.iter().map(|x| x.header("zotero-write-token").unwrap()).collect();
+    assert_eq!(tokens.len(), 3);
+    assert_eq!(tokens[0].len(), 32);
+    assert_eq!(tokens[0], tokens[1]);
+    assert_ne!(tokens[1], tokens[2]);
+
+    let mut data = changes.items[0].data.clone();
+    data.set_field("title", "one (2nd edition)");
+    assert_eq!(api.update_item(&data)?, 9);
+    data.version = 7;
+    assert!(api.update_item(&data).is_err());
+
+    Ok(())
+}
+// test:1 ends here